@@ -1,5 +1,67 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::middlewares::login_throttle::LoginThrottle;
+use crate::middlewares::rate_limit::RateLimiter;
+use crate::models::session::write_revoked_tokens;
+use crate::utility::encryption::ProjectKeys;
+
+/// Unwrapped project data keys, filled on login. Keys are never written to
+/// disk, so after a restart a project has to log in again before encrypted
+/// images can be read or written.
+pub type KeyRing = Arc<RwLock<HashMap<Uuid, ProjectKeys>>>;
+
+/// Projects with a key rotation currently running in this process.
+pub type RotationJobs = Arc<Mutex<HashSet<Uuid>>>;
+
+/// Ids of revoked access tokens with their expiry time, checked by
+/// `jwt_validator`. Entries are dropped once the token would have expired.
+pub type RevokedTokens = Arc<RwLock<HashMap<Uuid, u64>>>;
+
+/// Held around every read, change and write of the files shared by all
+/// projects, so requests on different workers don't overwrite each other.
+#[derive(Debug, Clone, Default)]
+pub struct FileLocks {
+    pub sessions: Arc<tokio::sync::Mutex<()>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct AppData {
-    pub data_path: String,
-    pub input_path: String,
+    pub config: Arc<Config>,
+    pub key_ring: KeyRing,
+    pub rotation_jobs: RotationJobs,
+    pub revoked_tokens: RevokedTokens,
+    pub login_throttle: LoginThrottle,
+    pub rate_limiter: RateLimiter,
+    pub file_locks: FileLocks,
+}
+
+impl AppData {
+    pub fn get_project_keys(&self, project_id: &Uuid) -> Option<ProjectKeys> {
+        self.key_ring.read().unwrap().get(project_id).cloned()
+    }
+
+    pub fn set_project_keys(&self, project_id: Uuid, project_keys: ProjectKeys) {
+        self.key_ring
+            .write()
+            .unwrap()
+            .insert(project_id, project_keys);
+    }
+
+    pub fn is_token_revoked(&self, jti: &Uuid) -> bool {
+        self.revoked_tokens.read().unwrap().contains_key(jti)
+    }
+
+    pub fn revoke_token(&self, jti: Uuid, exp: u64) {
+        let now = chrono::Utc::now().timestamp() as u64;
+        let mut revoked_tokens = self.revoked_tokens.write().unwrap();
+        revoked_tokens.retain(|_, token_exp| *token_exp > now);
+        revoked_tokens.insert(jti, exp);
+
+        if let Err(err) = write_revoked_tokens(&self.config.storage.data_path, &revoked_tokens) {
+            println!("{}", err);
+        }
+    }
 }
@@ -0,0 +1,639 @@
+use actix_web::web;
+use dotenv::dotenv;
+use image::ImageFormat;
+use std::collections::HashMap;
+use std::env::{args, var};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use server::config::parse_args;
+use server::config::{BackupConfig, BlobStoreConfig};
+use server::middlewares::login_throttle::{project_key, request_unlock};
+use server::migrations::read_line;
+use server::models::api_key::delete_project_api_keys;
+use server::models::backup::*;
+use server::models::fsck::*;
+use server::models::image_data::*;
+use server::models::project_archive::*;
+use server::models::project_info::*;
+use server::models::session::{delete_project_sessions, revoke_sessions};
+use server::utility::blob_store::init_blob_store;
+use server::utility::encryption::ProjectKeys;
+
+const USAGE: &str = "Usage: sorter-admin [--data-path <dir>] <command>
+
+Works directly on the data folder (env DATA_PATH). Stop the server before
+running commands that change anything.
+
+Commands:
+  list                                   List projects
+  create <project>                       Create a project
+  delete <project> [--yes]               Delete a project with all its images
+  reset-password <project> [--force]     Set a new project password
+  unlock <project>                       Lift the login lockout of a project,
+                                         the running server applies it on the
+                                         next login
+  import <project> <dir> [--tags <a;b>] [--encrypt]
+                                         Add the images in a folder
+  export <project> <dir>                 Write the images decrypted to a folder
+  export-archive <project> <file>        Write the project as an archive for
+                                         another server
+  import-archive <file> [--name <project>]
+                                         Add the project in an archive
+  verify <project>                       Read back and check every image
+  fsck [project] [--repair quarantine|reindex]
+                                         Check the data folder, or one project,
+                                         and optionally repair it
+  stats [project]                        Image counts and sizes
+  backup [--target <dir>] [--keep-last <n>]
+                                         Take a backup snapshot now
+  snapshots [--target <dir>]             List the backup snapshots
+  restore <snapshot> [project] [--target <dir>] [--yes]
+                                         Put the data folder, or one project,
+                                         back as it was in a snapshot; the
+                                         snapshot is an id, latest, or a time
+                                         like 2023-11-02T14:00:00
+
+The backup target defaults to env BACKUP_TARGET, keep-last to env
+BACKUP_KEEP_LAST and older snapshots are kept for env BACKUP_KEEP_DAYS.
+Backups only cover the data folder, not blobs kept in S3.
+
+Image blobs are read from the blob store the server uses, set with env
+BLOB_STORE=s3 and S3_ENDPOINT, S3_BUCKET, S3_REGION, S3_ACCESS_KEY,
+S3_SECRET_KEY and S3_PREFIX.";
+
+type Flags = HashMap<String, String>;
+
+#[actix_web::main]
+async fn main() {
+    dotenv().ok();
+
+    let args: Vec<String> = args().skip(1).collect();
+    let (flags, positional) = match parse_args(&args, &["yes", "force", "encrypt", "help"]) {
+        Ok(parsed) => parsed,
+        Err(err) => exit_with(&err),
+    };
+
+    if flags.contains_key("help") || positional.is_empty() {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let data_path = match flags.get("data-path").cloned().or(var("DATA_PATH").ok()) {
+        Some(data_path) if Path::new(&data_path).is_dir() => data_path,
+        Some(data_path) => exit_with(&format!("'{}' is not a directory.", data_path)),
+        None => exit_with("Set --data-path or DATA_PATH."),
+    };
+    if let Err(err) = init_store(&data_path) {
+        exit_with(&err);
+    }
+
+    let positional: Vec<&str> = positional.iter().map(|arg| arg.as_str()).collect();
+    let result = match positional[..] {
+        ["list"] => list_projects(&data_path).await,
+        ["create", project_name] => create_project(&data_path, project_name).await,
+        ["delete", project_name] => delete_project(&data_path, project_name, &flags).await,
+        ["reset-password", project_name] => reset_password(&data_path, project_name, &flags).await,
+        ["unlock", project_name] => unlock_login(&data_path, project_name).await,
+        ["import", project_name, dir] => import_dir(&data_path, project_name, dir, &flags).await,
+        ["export", project_name, dir] => export_project(&data_path, project_name, dir).await,
+        ["export-archive", project_name, file] => {
+            export_archive(&data_path, project_name, file).await
+        }
+        ["import-archive", file] => import_archive(&data_path, file, flags.get("name")).await,
+        ["verify", project_name] => fsck(&data_path, Some(project_name), None).await,
+        ["fsck"] => fsck(&data_path, None, flags.get("repair")).await,
+        ["fsck", project_name] => fsck(&data_path, Some(project_name), flags.get("repair")).await,
+        ["backup"] => backup(&data_path, &flags),
+        ["snapshots"] => list_snapshots(&flags),
+        ["restore", at] => restore(&data_path, at, None, &flags).await,
+        ["restore", at, project_name] => restore(&data_path, at, Some(project_name), &flags).await,
+        ["stats"] => print_stats(&data_path, None).await,
+        ["stats", project_name] => print_stats(&data_path, Some(project_name)).await,
+        _ => Err(format!("Unknown command.\n\n{}", USAGE)),
+    };
+
+    if let Err(err) = result {
+        exit_with(&err);
+    }
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+async fn list_projects(data_path: &str) -> Result<(), String> {
+    let projects = get_all_project_infos(data_path)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    for project in projects {
+        println!(
+            "{}  {}  {}",
+            project.project_id, project.created_date, project.project_name
+        );
+    }
+    Ok(())
+}
+
+async fn create_project(data_path: &str, project_name: &str) -> Result<(), String> {
+    let password = read_line(&format!("Password for project {}:", project_name))?;
+    if password.is_empty() {
+        return Err("The password can't be empty.".to_owned());
+    }
+
+    let project_creation = ProjectLoginInfo {
+        project_name: project_name.to_owned(),
+        password,
+    };
+    let project = create_project_info(data_path, &project_creation)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    println!(
+        "Created project {} ({}).",
+        project.project_name, project.project_id
+    );
+    Ok(())
+}
+
+async fn delete_project(data_path: &str, project_name: &str, flags: &Flags) -> Result<(), String> {
+    let project = find_project_by_name(data_path, project_name)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if !flags.contains_key("yes") {
+        let answer = read_line(&format!(
+            "Delete project {} and all its images? Type the project name to confirm:",
+            project.project_name
+        ))?;
+        if answer != project.project_name {
+            return Err("Not deleted.".to_owned());
+        }
+    }
+
+    delete_project_info(data_path, &project.project_id)
+        .await
+        .map_err(|err| err.to_string())?;
+    delete_project_sessions(data_path, &project.project_id)
+        .await
+        .map_err(|err| err.to_string())?;
+    delete_project_api_keys(data_path, &project.project_id)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    println!("Deleted project {}.", project.project_name);
+    Ok(())
+}
+
+async fn reset_password(data_path: &str, project_name: &str, flags: &Flags) -> Result<(), String> {
+    let project = find_project_by_name(data_path, project_name)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let password = read_line(&format!(
+        "New password for project {}:",
+        project.project_name
+    ))?;
+    if password.is_empty() {
+        return Err("The password can't be empty.".to_owned());
+    }
+
+    let force = flags.contains_key("force");
+    match reset_project_password(data_path, &project.project_id, &password, force).await {
+        Ok(_) => {}
+        Err(err @ ProjectInfoErrors::EncryptedImages(_)) => {
+            return Err(format!("{}. Use --force to reset anyway.", err))
+        }
+        Err(err) => return Err(err.to_string()),
+    }
+    revoke_sessions(data_path, Some(&project.project_id), None)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    println!("Reset the password of project {}.", project.project_name);
+    Ok(())
+}
+
+async fn unlock_login(data_path: &str, project_name: &str) -> Result<(), String> {
+    let project = find_project_by_name(data_path, project_name)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    request_unlock(data_path, &project_key(&project.project_name))
+        .map_err(|err| err.to_string())?;
+
+    println!("Unlocked logins to project {}.", project.project_name);
+    Ok(())
+}
+
+async fn import_dir(
+    data_path: &str,
+    project_name: &str,
+    dir: &str,
+    flags: &Flags,
+) -> Result<(), String> {
+    let project = find_project_by_name(data_path, project_name)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let encrypt = flags.contains_key("encrypt");
+    let project_keys = match encrypt {
+        true => Some(unlock_project(data_path, &project).await?),
+        false => None,
+    };
+    let image_tags = flags.get("tags").cloned().unwrap_or_default();
+
+    let mut file_names: Vec<String> = fs::read_dir(dir)
+        .map_err(|err| format!("{}: {}", dir, err))?
+        .flatten()
+        .filter(|entry| entry.path().is_file() && ImageFormat::from_path(entry.path()).is_ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    file_names.sort();
+
+    let mut imported = 0;
+    for file_name in file_names {
+        let upload = UploadImage {
+            image_path: file_name.to_owned(),
+            image_name: None,
+            image_tags: image_tags.to_owned(),
+            encrypt,
+        };
+
+        match upload_image(
+            data_path,
+            dir,
+            upload,
+            project.project_id,
+            project_keys.as_ref(),
+            &ProjectQuota::default(),
+        )
+        .await
+        {
+            Ok(image) => {
+                println!("{} -> {}", file_name, image.image_id);
+                imported += 1;
+            }
+            Err(err) => println!("{}: {}", file_name, err),
+        }
+    }
+
+    println!(
+        "Imported {} image(s) into {}.",
+        imported, project.project_name
+    );
+    Ok(())
+}
+
+async fn export_project(data_path: &str, project_name: &str, dir: &str) -> Result<(), String> {
+    let project = find_project_by_name(data_path, project_name)
+        .await
+        .map_err(|err| err.to_string())?;
+    let project_keys = unlock_if_encrypted(data_path, &project).await?;
+
+    let exported = export_project_images(
+        data_path,
+        &project.project_id,
+        project_keys.as_ref(),
+        Path::new(dir),
+    )
+    .await
+    .map_err(|err| err.to_string())?;
+
+    println!("Exported {} image(s) to {}.", exported, dir);
+    Ok(())
+}
+
+async fn export_archive(data_path: &str, project_name: &str, file: &str) -> Result<(), String> {
+    let project = find_project_by_name(data_path, project_name)
+        .await
+        .map_err(|err| err.to_string())?;
+    let chunks = export_project_archive(data_path, &project.project_id)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let mut out = fs::File::create(file).map_err(|err| format!("{}: {}", file, err))?;
+    for chunk in chunks {
+        let chunk = chunk.map_err(|err| err.to_string())?;
+        out.write_all(&chunk)
+            .map_err(|err| format!("{}: {}", file, err))?;
+    }
+
+    println!("Exported project {} to {}.", project.project_name, file);
+    Ok(())
+}
+
+async fn import_archive(
+    data_path: &str,
+    file: &str,
+    project_name: Option<&String>,
+) -> Result<(), String> {
+    let project = import_project_archive(
+        data_path,
+        Path::new(file),
+        project_name.map(|name| name.as_str()),
+    )
+    .await
+    .map_err(|err| err.to_string())?;
+
+    println!(
+        "Imported project {} ({}).",
+        project.project_name, project.project_id
+    );
+    Ok(())
+}
+
+async fn fsck(
+    data_path: &str,
+    project_name: Option<&str>,
+    repair: Option<&String>,
+) -> Result<(), String> {
+    let repair = match repair.map(|repair| repair.as_str()) {
+        None => None,
+        Some("quarantine") => Some(RepairMode::Quarantine),
+        Some("reindex") => Some(RepairMode::Reindex),
+        Some(repair) => return Err(format!("Unknown repair mode '{}'.", repair)),
+    };
+
+    let project = match project_name {
+        Some(project_name) => {
+            let project = find_project_by_name(data_path, project_name)
+                .await
+                .map_err(|err| err.to_string())?;
+            // reindexing tries the project keys on files the index lost
+            let project_keys = match repair {
+                Some(RepairMode::Reindex) => Some(unlock_project(data_path, &project).await?),
+                _ => unlock_if_encrypted(data_path, &project).await?,
+            };
+            Some((project.project_id, project_keys))
+        }
+        None => None,
+    };
+
+    // fsck locks the image indexes, which can't be done on the async runtime
+    let data_path = data_path.to_owned();
+    let report = web::block(move || match project {
+        Some((project_id, project_keys)) => {
+            fsck_project(&data_path, &project_id, project_keys.as_ref(), repair)
+        }
+        // encrypted images are only size checked, there'd be a password to ask for every project
+        None => fsck_data_dir(&data_path, &HashMap::new(), repair),
+    })
+    .await
+    .map_err(|err| err.to_string())?
+    .map_err(|err| err.to_string())?;
+
+    println!(
+        "{} image(s) read back fine, {} only size checked (key not unlocked).",
+        report.images_checked, report.images_skipped
+    );
+    for issue in &report.issues {
+        println!("{}", serde_json::to_string(issue).unwrap());
+    }
+    for repair in &report.repairs {
+        println!("repaired: {}", repair);
+    }
+
+    match report.is_ok() || !report.repairs.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Found {} problem(s).", report.issues.len())),
+    }
+}
+
+async fn print_stats(data_path: &str, project_name: Option<&str>) -> Result<(), String> {
+    let projects = match project_name {
+        Some(project_name) => vec![find_project_by_name(data_path, project_name)
+            .await
+            .map_err(|err| err.to_string())?],
+        None => {
+            let mut projects = vec![];
+            for project in get_all_project_infos(data_path)
+                .await
+                .map_err(|err| err.to_string())?
+            {
+                projects.push(
+                    find_project(data_path, &project.project_id)
+                        .await
+                        .map_err(|err| err.to_string())?,
+                );
+            }
+            projects
+        }
+    };
+
+    let mut total = ProjectUsage {
+        bytes: 0,
+        images: 0,
+    };
+    for project in &projects {
+        let images = read_project_images(data_path, &project.project_id)
+            .await
+            .map_err(|err| err.to_string())?;
+        let usage = get_project_usage(&images);
+        let encrypted = images.iter().filter(|image| image.is_encrypted).count();
+        let legacy = images
+            .iter()
+            .filter(|image| image.is_encrypted && image.key_id.is_none())
+            .count();
+
+        println!(
+            "{}: {} image(s), {} bytes, {} encrypted ({} with the legacy key)",
+            project.project_name, usage.images, usage.bytes, encrypted, legacy
+        );
+
+        total.images += usage.images;
+        total.bytes += usage.bytes;
+    }
+
+    if project_name.is_none() {
+        println!(
+            "{} project(s), {} image(s), {} bytes",
+            projects.len(),
+            total.images,
+            total.bytes
+        );
+    }
+    Ok(())
+}
+
+/// The blob store the server is configured with, from the environment.
+fn init_store(data_path: &str) -> Result<(), String> {
+    let mut config = BlobStoreConfig::default();
+    let mut errors = vec![];
+    config.apply_env(&mut errors);
+    config.validate(&mut errors);
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    init_blob_store(&config, data_path)
+}
+
+fn backup_config(flags: &Flags) -> Result<BackupConfig, String> {
+    let mut config = BackupConfig {
+        target: flags.get("target").cloned().or(var("BACKUP_TARGET").ok()),
+        ..BackupConfig::default()
+    };
+
+    if let Some(keep_last) = flags
+        .get("keep-last")
+        .cloned()
+        .or(var("BACKUP_KEEP_LAST").ok())
+    {
+        config.keep_last = keep_last
+            .parse()
+            .map_err(|_| format!("'{}' is not a number of snapshots.", keep_last))?;
+    }
+    if let Ok(keep_days) = var("BACKUP_KEEP_DAYS") {
+        config.keep_days = Some(
+            keep_days
+                .parse()
+                .map_err(|_| format!("'{}' is not a number of days.", keep_days))?,
+        );
+    }
+
+    if config.target.is_none() {
+        return Err("Set --target or BACKUP_TARGET.".to_owned());
+    }
+    Ok(config)
+}
+
+fn backup(data_path: &str, flags: &Flags) -> Result<(), String> {
+    let config = backup_config(flags)?;
+    let mut errors = vec![];
+    config.validate(data_path, &mut errors);
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    let target = config.target.as_deref().unwrap();
+    let summary =
+        create_snapshot(data_path, Path::new(target), &config).map_err(|err| err.to_string())?;
+
+    println!(
+        "Snapshot {}: {} file(s), {} bytes, {} of them new.",
+        summary.snapshot_id, summary.files, summary.bytes, summary.new_bytes
+    );
+    Ok(())
+}
+
+fn list_snapshots(flags: &Flags) -> Result<(), String> {
+    let config = backup_config(flags)?;
+    let catalog = read_catalog(Path::new(config.target.as_deref().unwrap()))
+        .map_err(|err| err.to_string())?;
+
+    for summary in catalog {
+        println!(
+            "{}  {} file(s)  {} bytes  {} new",
+            summary.snapshot_id, summary.files, summary.bytes, summary.new_bytes
+        );
+    }
+    Ok(())
+}
+
+async fn restore(
+    data_path: &str,
+    at: &str,
+    project_name: Option<&str>,
+    flags: &Flags,
+) -> Result<(), String> {
+    let config = backup_config(flags)?;
+    let target = Path::new(config.target.as_deref().unwrap());
+    let snapshot = find_snapshot(target, at).map_err(|err| err.to_string())?;
+
+    let project_name = match project_name {
+        None => None,
+        Some(project_name) => Some(project_name_in_snapshot(target, &snapshot, project_name)?),
+    };
+
+    if !flags.contains_key("yes") {
+        let what = match &project_name {
+            Some(project) => format!("project {}", project.project_name),
+            None => "the whole data folder".to_owned(),
+        };
+        let answer = read_line(&format!(
+            "Restore {} as of snapshot {}? What is there now is moved to the quarantine folder. Type yes to confirm:",
+            what, snapshot.snapshot_id
+        ))?;
+        if answer != "yes" {
+            return Err("Not restored.".to_owned());
+        }
+    }
+
+    match project_name {
+        Some(project) => {
+            restore_project(data_path, target, &snapshot, &project.project_id)
+                .map_err(|err| err.to_string())?;
+            println!(
+                "Restored project {} as of snapshot {}.",
+                project.project_name, snapshot.snapshot_id
+            );
+        }
+        None => {
+            let files =
+                restore_data_dir(data_path, target, &snapshot).map_err(|err| err.to_string())?;
+            println!(
+                "Restored {} file(s) as of snapshot {}.",
+                files, snapshot.snapshot_id
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The project of that name in the snapshot's project index, it may have been
+/// deleted or renamed since.
+fn project_name_in_snapshot(
+    target: &Path,
+    snapshot: &Snapshot,
+    project_name: &str,
+) -> Result<ProjectInfo, String> {
+    let index = snapshot
+        .files
+        .iter()
+        .find(|file| file.path == "project.json")
+        .ok_or("The snapshot has no project index.")?;
+    let projects: Vec<ProjectInfo> =
+        serde_json::from_slice(&read_snapshot_file(target, index).map_err(|err| err.to_string())?)
+            .map_err(|err| err.to_string())?;
+
+    projects
+        .into_iter()
+        .find(|project| project.project_name == project_name)
+        .ok_or(format!(
+            "There is no project {} in snapshot {}.",
+            project_name, snapshot.snapshot_id
+        ))
+}
+
+async fn unlock_project(data_path: &str, project: &ProjectInfo) -> Result<ProjectKeys, String> {
+    let login = ProjectLoginInfo {
+        project_name: project.project_name.to_owned(),
+        password: read_line(&format!("Password for project {}:", project.project_name))?,
+    };
+
+    project_login(data_path, &login)
+        .await
+        .map(|(_, project_keys)| project_keys)
+        .map_err(|err| err.to_string())
+}
+
+/// Asks for the project password when the project has images encrypted with
+/// a data key.
+async fn unlock_if_encrypted(
+    data_path: &str,
+    project: &ProjectInfo,
+) -> Result<Option<ProjectKeys>, String> {
+    // a broken index is for fsck to find, there's nothing to unlock then
+    let images = read_project_images(data_path, &project.project_id)
+        .await
+        .unwrap_or_default();
+
+    match images.iter().any(|image| image.key_id.is_some()) {
+        true => Ok(Some(unlock_project(data_path, project).await?)),
+        false => Ok(None),
+    }
+}
@@ -0,0 +1,662 @@
+use ::serde::{Deserialize, Serialize};
+use argon2::Params;
+use std::collections::HashMap;
+use std::env::var;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::middlewares::rate_limit::RateLimitRule;
+use crate::models::project_info::ProjectQuota;
+
+/// Used when neither `--config` nor `CONFIG_FILE` is given, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Server configuration, loaded once at startup. Every setting comes from, in
+/// order of precedence: a command line flag, an environment variable, the
+/// TOML config file, the default.
+///
+/// ```toml
+/// [server]
+/// host = "0.0.0.0"
+/// port = 8080
+/// log_level = "actix_web=info"
+///
+/// [storage]
+/// data_path = "/var/lib/sorter/data"
+/// input_path = "/var/lib/sorter/input"
+///
+/// [jwt]
+/// issuer = "sorter"
+/// audience = "sorter"
+/// keys_dir = "/etc/sorter/keys"
+/// signing_kid = "2023-11"
+///
+/// [rate_limits]
+/// save = "60/60"
+///
+/// [backup]
+/// target = "/mnt/backup/sorter"
+/// interval_minutes = 360
+/// keep_last = 14
+///
+/// [blob_store]
+/// backend = "s3"
+/// endpoint = "http://127.0.0.1:9000"
+/// bucket = "sorter"
+/// access_key = "sorter"
+/// secret_key = "..."
+///
+/// [webhooks]
+/// max_attempts = 5
+/// retry_delay_seconds = 2
+/// max_log_entries = 1000
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub jwt: JwtConfig,
+    pub password_hash: PasswordHashConfig,
+    /// Overrides of the rate limit rules by route name, as `requests/seconds`.
+    pub rate_limits: HashMap<String, RateLimitRule>,
+    /// Quota of projects that don't have their own.
+    pub quota: ProjectQuota,
+    pub backup: BackupConfig,
+    pub blob_store: BlobStoreConfig,
+    pub webhooks: WebhookConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// `env_logger` filter, e.g. `actix_web=info,server=debug`.
+    pub log_level: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub data_path: String,
+    pub input_path: String,
+    /// Largest project archive `/project/import` accepts.
+    pub max_archive_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    pub issuer: String,
+    pub audience: String,
+    /// HS256 secret, used when `keys_dir` isn't set.
+    pub secret: Option<String>,
+    /// Directory with `<kid>.pub.pem` verifying keys and the signing key.
+    pub keys_dir: Option<String>,
+    pub signing_kid: Option<String>,
+    pub access_token_minutes: i64,
+    pub refresh_token_days: i64,
+}
+
+/// Incremental backups of the data folder, see `models::backup`. Blobs kept
+/// in S3 aren't part of it, the bucket needs its own versioning or backups.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    /// Folder the snapshots go to, outside of the data folder.
+    pub target: Option<String>,
+    /// Take a snapshot this often while the server runs, none when unset.
+    pub interval_minutes: Option<u64>,
+    /// Snapshots always kept, the newest ones.
+    pub keep_last: usize,
+    /// Older snapshots are kept as long as they are younger than this.
+    pub keep_days: Option<u64>,
+}
+
+/// Where image blobs are kept, see `utility::blob_store`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlobStoreConfig {
+    pub backend: BlobBackend,
+    /// S3 compatible endpoint, e.g. `https://s3.eu-central-1.amazonaws.com` or
+    /// a MinIO server. Buckets are addressed path style.
+    pub endpoint: Option<String>,
+    pub bucket: Option<String>,
+    pub region: String,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    /// Put in front of every blob key, to share a bucket.
+    pub prefix: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobBackend {
+    /// Files in the project folders of the data folder.
+    #[default]
+    Fs,
+    S3,
+}
+
+/// How project webhooks are delivered, see `models::webhook`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Tries per delivery, including the first one.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for every one after it.
+    pub retry_delay_seconds: u64,
+    /// How long a receiver gets to answer.
+    pub timeout_seconds: u64,
+    /// Delivery attempts kept in a project's log, older ones are dropped.
+    pub max_log_entries: usize,
+    /// Lets webhooks point at loopback, link-local and private network
+    /// addresses, e.g. a receiver on the same host. Off by default, so a
+    /// project can't use its webhooks to reach into the server's network.
+    pub allow_private_addresses: bool,
+}
+
+/// Argon2id cost parameters for new password hashes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordHashConfig {
+    /// Memory in KiB.
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "127.0.0.1".to_owned(),
+            port: 8080,
+            log_level: "actix_web=info".to_owned(),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            data_path: String::new(),
+            input_path: String::new(),
+            max_archive_bytes: 4 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            issuer: String::new(),
+            audience: String::new(),
+            secret: None,
+            keys_dir: None,
+            signing_kid: None,
+            access_token_minutes: 15,
+            refresh_token_days: 30,
+        }
+    }
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            target: None,
+            interval_minutes: None,
+            keep_last: 14,
+            keep_days: None,
+        }
+    }
+}
+
+impl Default for BlobStoreConfig {
+    fn default() -> Self {
+        BlobStoreConfig {
+            backend: BlobBackend::Fs,
+            endpoint: None,
+            bucket: None,
+            region: "us-east-1".to_owned(),
+            access_key: None,
+            secret_key: None,
+            prefix: String::new(),
+        }
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            max_attempts: 5,
+            retry_delay_seconds: 2,
+            timeout_seconds: 10,
+            max_log_entries: 1000,
+            allow_private_addresses: false,
+        }
+    }
+}
+
+impl FromStr for BlobBackend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fs" => Ok(BlobBackend::Fs),
+            "s3" => Ok(BlobBackend::S3),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for PasswordHashConfig {
+    fn default() -> Self {
+        PasswordHashConfig {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Everything wrong with the configuration, so it can be fixed in one go.
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for err in &self.0 {
+            writeln!(f, "  - {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+const USAGE: &str = "Usage: server [OPTIONS] [migrate-keys <project_name>]
+                             [migrate-content-types <project_name>]
+
+Options:
+  --config <file>       TOML config file (env CONFIG_FILE, default config.toml)
+  --host <address>      Address to listen on (env HOST)
+  --port <port>         Port to listen on (env PORT)
+  --log-level <filter>  env_logger filter (env RUST_LOG)
+  --data-path <dir>     Data directory (env DATA_PATH)
+  --input-path <dir>    Upload directory (env INPUT_PATH)
+  --backup-target <dir> Backup folder (env BACKUP_TARGET)
+  --help                Show this message";
+
+impl Config {
+    /// Loads the configuration from the config file, the environment and the
+    /// command line `args` (without the program name). Returns the validated
+    /// config and the positional arguments.
+    pub fn load(args: &[String]) -> Result<(Config, Vec<String>), ConfigErrors> {
+        let mut errors = vec![];
+        let (flags, positional) =
+            parse_args(args, &["help"]).map_err(|err| ConfigErrors(vec![err]))?;
+
+        if flags.contains_key("help") {
+            println!("{}", USAGE);
+            std::process::exit(0);
+        }
+
+        let config_file = flags
+            .get("config")
+            .cloned()
+            .or_else(|| var("CONFIG_FILE").ok());
+        let mut config = match config_file {
+            Some(path) => Config::from_file(&path).map_err(|err| ConfigErrors(vec![err]))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(DEFAULT_CONFIG_FILE).map_err(|err| ConfigErrors(vec![err]))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply_env(&mut errors);
+        config.apply_flags(&flags, &mut errors);
+        config.validate(&mut errors);
+
+        match errors.is_empty() {
+            true => Ok((config, positional)),
+            false => Err(ConfigErrors(errors)),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let data = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        toml::from_str(&data).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    fn apply_env(&mut self, errors: &mut Vec<String>) {
+        env_string("HOST", &mut self.server.host);
+        env_parse("PORT", &mut self.server.port, errors);
+        env_string("RUST_LOG", &mut self.server.log_level);
+
+        env_string("DATA_PATH", &mut self.storage.data_path);
+        env_string("INPUT_PATH", &mut self.storage.input_path);
+        env_parse(
+            "MAX_ARCHIVE_BYTES",
+            &mut self.storage.max_archive_bytes,
+            errors,
+        );
+
+        env_string("JWT_ISSUER", &mut self.jwt.issuer);
+        env_string("JWT_AUDIENCE", &mut self.jwt.audience);
+        env_option("JWT_SECRET", &mut self.jwt.secret);
+        env_option("JWT_KEYS_DIR", &mut self.jwt.keys_dir);
+        env_option("JWT_SIGNING_KID", &mut self.jwt.signing_kid);
+        env_parse(
+            "ACCESS_TOKEN_MINUTES",
+            &mut self.jwt.access_token_minutes,
+            errors,
+        );
+        env_parse(
+            "REFRESH_TOKEN_DAYS",
+            &mut self.jwt.refresh_token_days,
+            errors,
+        );
+
+        env_parse(
+            "PASSWORD_HASH_M_COST",
+            &mut self.password_hash.m_cost,
+            errors,
+        );
+        env_parse(
+            "PASSWORD_HASH_T_COST",
+            &mut self.password_hash.t_cost,
+            errors,
+        );
+        env_parse(
+            "PASSWORD_HASH_P_COST",
+            &mut self.password_hash.p_cost,
+            errors,
+        );
+
+        if let Ok(rate_limits) = var("RATE_LIMITS") {
+            for entry in rate_limits
+                .split(',')
+                .filter(|entry| !entry.trim().is_empty())
+            {
+                match entry
+                    .split_once('=')
+                    .map(|(route, rule)| (route.trim(), rule.parse::<RateLimitRule>()))
+                {
+                    Some((route, Ok(rule))) => {
+                        self.rate_limits.insert(route.to_owned(), rule);
+                    }
+                    _ => errors.push(format!("RATE_LIMITS: invalid entry '{}'", entry)),
+                }
+            }
+        }
+
+        env_parse_option("PROJECT_QUOTA_BYTES", &mut self.quota.max_bytes, errors);
+        env_parse_option("PROJECT_QUOTA_IMAGES", &mut self.quota.max_images, errors);
+
+        env_option("BACKUP_TARGET", &mut self.backup.target);
+        env_parse_option(
+            "BACKUP_INTERVAL_MINUTES",
+            &mut self.backup.interval_minutes,
+            errors,
+        );
+        env_parse("BACKUP_KEEP_LAST", &mut self.backup.keep_last, errors);
+        env_parse_option("BACKUP_KEEP_DAYS", &mut self.backup.keep_days, errors);
+
+        self.blob_store.apply_env(errors);
+
+        env_parse(
+            "WEBHOOK_MAX_ATTEMPTS",
+            &mut self.webhooks.max_attempts,
+            errors,
+        );
+        env_parse(
+            "WEBHOOK_RETRY_DELAY_SECONDS",
+            &mut self.webhooks.retry_delay_seconds,
+            errors,
+        );
+        env_parse(
+            "WEBHOOK_TIMEOUT_SECONDS",
+            &mut self.webhooks.timeout_seconds,
+            errors,
+        );
+        env_parse(
+            "WEBHOOK_MAX_LOG_ENTRIES",
+            &mut self.webhooks.max_log_entries,
+            errors,
+        );
+        env_parse(
+            "WEBHOOK_ALLOW_PRIVATE_ADDRESSES",
+            &mut self.webhooks.allow_private_addresses,
+            errors,
+        );
+    }
+
+    fn apply_flags(&mut self, flags: &HashMap<String, String>, errors: &mut Vec<String>) {
+        for (flag, value) in flags {
+            match flag.as_str() {
+                "config" => {}
+                "host" => self.server.host = value.to_owned(),
+                "port" => match value.parse() {
+                    Ok(port) => self.server.port = port,
+                    Err(_) => errors.push(format!("--port: '{}' is not a port number", value)),
+                },
+                "log-level" => self.server.log_level = value.to_owned(),
+                "data-path" => self.storage.data_path = value.to_owned(),
+                "input-path" => self.storage.input_path = value.to_owned(),
+                "backup-target" => self.backup.target = Some(value.to_owned()),
+                _ => errors.push(format!("unknown flag --{}, see --help", flag)),
+            }
+        }
+    }
+
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.server.host.parse::<IpAddr>().is_err() {
+            errors.push(format!(
+                "server.host: '{}' is not an IP address",
+                self.server.host
+            ));
+        }
+        if self.server.port == 0 {
+            errors.push("server.port: must not be 0".to_owned());
+        }
+
+        for (name, path) in [
+            ("storage.data_path", &self.storage.data_path),
+            ("storage.input_path", &self.storage.input_path),
+        ] {
+            if path.is_empty() {
+                errors.push(format!("{}: not set", name));
+            } else if !Path::new(path).is_dir() {
+                errors.push(format!("{}: '{}' is not a directory", name, path));
+            }
+        }
+
+        if self.storage.max_archive_bytes == 0 {
+            errors.push("storage.max_archive_bytes: must be positive".to_owned());
+        }
+
+        if self.jwt.issuer.is_empty() {
+            errors.push("jwt.issuer: not set".to_owned());
+        }
+        if self.jwt.audience.is_empty() {
+            errors.push("jwt.audience: not set".to_owned());
+        }
+        match &self.jwt.keys_dir {
+            Some(keys_dir) => {
+                if !Path::new(keys_dir).is_dir() {
+                    errors.push(format!("jwt.keys_dir: '{}' is not a directory", keys_dir));
+                }
+                if self.jwt.signing_kid.is_none() {
+                    errors.push("jwt.signing_kid: needed with jwt.keys_dir".to_owned());
+                }
+            }
+            None => match &self.jwt.secret {
+                Some(secret) if !secret.is_empty() => {}
+                _ => errors.push("jwt: set either keys_dir or secret".to_owned()),
+            },
+        }
+        if self.jwt.access_token_minutes <= 0 {
+            errors.push("jwt.access_token_minutes: must be positive".to_owned());
+        }
+        if self.jwt.refresh_token_days <= 0 {
+            errors.push("jwt.refresh_token_days: must be positive".to_owned());
+        }
+
+        if let Err(err) = self.password_hash.params() {
+            errors.push(format!("password_hash: {}", err));
+        }
+
+        self.backup.validate(&self.storage.data_path, errors);
+        self.blob_store.validate(errors);
+
+        if self.webhooks.max_attempts == 0 {
+            errors.push("webhooks.max_attempts: must be at least 1".to_owned());
+        }
+        if self.webhooks.timeout_seconds == 0 {
+            errors.push("webhooks.timeout_seconds: must be positive".to_owned());
+        }
+        if self.webhooks.max_log_entries == 0 {
+            errors.push("webhooks.max_log_entries: must be at least 1".to_owned());
+        }
+    }
+}
+
+impl BackupConfig {
+    pub fn validate(&self, data_path: &str, errors: &mut Vec<String>) {
+        if let Some(target) = &self.target {
+            match (
+                Path::new(target).canonicalize(),
+                Path::new(data_path).canonicalize(),
+            ) {
+                (Ok(target), Ok(data_path)) if target.starts_with(&data_path) => {
+                    errors.push("backup.target: must be outside of storage.data_path".to_owned())
+                }
+                (Err(_), _) => {
+                    errors.push(format!("backup.target: '{}' is not a directory", target))
+                }
+                _ => {}
+            }
+        }
+        if self.interval_minutes == Some(0) {
+            errors.push("backup.interval_minutes: must be positive".to_owned());
+        }
+        if self.interval_minutes.is_some() && self.target.is_none() {
+            errors.push("backup.target: needed with backup.interval_minutes".to_owned());
+        }
+        if self.keep_last == 0 {
+            errors.push("backup.keep_last: must be at least 1".to_owned());
+        }
+    }
+}
+
+impl BlobStoreConfig {
+    pub fn apply_env(&mut self, errors: &mut Vec<String>) {
+        env_parse("BLOB_STORE", &mut self.backend, errors);
+        env_option("S3_ENDPOINT", &mut self.endpoint);
+        env_option("S3_BUCKET", &mut self.bucket);
+        env_string("S3_REGION", &mut self.region);
+        env_option("S3_ACCESS_KEY", &mut self.access_key);
+        env_option("S3_SECRET_KEY", &mut self.secret_key);
+        env_string("S3_PREFIX", &mut self.prefix);
+    }
+
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if self.backend != BlobBackend::S3 {
+            return;
+        }
+
+        match &self.endpoint {
+            Some(endpoint)
+                if endpoint.starts_with("http://") || endpoint.starts_with("https://") => {}
+            Some(endpoint) => errors.push(format!(
+                "blob_store.endpoint: '{}' is not an http(s) URL",
+                endpoint
+            )),
+            None => errors.push("blob_store.endpoint: needed with the s3 backend".to_owned()),
+        }
+        for (name, value) in [
+            ("bucket", &self.bucket),
+            ("access_key", &self.access_key),
+            ("secret_key", &self.secret_key),
+        ] {
+            if value.as_deref().unwrap_or_default().is_empty() {
+                errors.push(format!("blob_store.{}: needed with the s3 backend", name));
+            }
+        }
+        if self.region.is_empty() {
+            errors.push("blob_store.region: not set".to_owned());
+        }
+    }
+}
+
+impl PasswordHashConfig {
+    pub fn params(&self) -> Result<Params, argon2::Error> {
+        Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+    }
+}
+
+fn env_string(name: &str, target: &mut String) {
+    if let Ok(value) = var(name) {
+        *target = value;
+    }
+}
+
+fn env_option(name: &str, target: &mut Option<String>) {
+    if let Ok(value) = var(name) {
+        *target = Some(value);
+    }
+}
+
+fn env_parse<T: FromStr>(name: &str, target: &mut T, errors: &mut Vec<String>) {
+    if let Ok(value) = var(name) {
+        match value.parse() {
+            Ok(value) => *target = value,
+            Err(_) => errors.push(format!("{}: invalid value '{}'", name, value)),
+        }
+    }
+}
+
+fn env_parse_option<T: FromStr>(name: &str, target: &mut Option<T>, errors: &mut Vec<String>) {
+    if let Ok(value) = var(name) {
+        match value.parse() {
+            Ok(value) => *target = Some(value),
+            Err(_) => errors.push(format!("{}: invalid value '{}'", name, value)),
+        }
+    }
+}
+
+/// Splits `--flag value` and `--flag=value` from positional arguments. Flags in
+/// `switches` take no value.
+pub fn parse_args(
+    args: &[String],
+    switches: &[&str],
+) -> Result<(HashMap<String, String>, Vec<String>), String> {
+    let mut flags = HashMap::new();
+    let mut positional = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => {
+                positional.push(arg.to_owned());
+                continue;
+            }
+        };
+
+        let (flag, value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag, value.to_owned()),
+            None if switches.contains(&flag) => (flag, String::new()),
+            None => match args.next() {
+                Some(value) => (flag, value.to_owned()),
+                None => return Err(format!("--{}: missing value", flag)),
+            },
+        };
+        flags.insert(flag.to_owned(), value);
+    }
+
+    Ok((flags, positional))
+}
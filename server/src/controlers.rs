@@ -1,2 +1,9 @@
+pub mod api_key;
+pub mod audit_log;
 pub mod image_data;
+pub mod openapi;
+pub mod project_events;
 pub mod project_info;
+pub mod session;
+pub mod user_info;
+pub mod webhook;
@@ -0,0 +1,105 @@
+use actix_web::{
+    delete, get, post,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    controlers::audit_log::audit,
+    middlewares::auth::check_permission,
+    models::{api_key::*, audit_log::*, user_info::Permission},
+    utility::jwt_token::Claims,
+};
+
+pub fn api_key_routes(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/keys")
+        .service(get_keys)
+        .service(create_key)
+        .service(revoke_key);
+
+    config.service(scope);
+}
+
+fn api_key_error_response(err: ApiKeyErrors) -> HttpResponse {
+    println!("{:#?}", err);
+    match err {
+        ApiKeyErrors::NoScopes | ApiKeyErrors::InvalidIpAllowlist(_) => {
+            HttpResponse::BadRequest().body(err.to_string())
+        }
+        ApiKeyErrors::ApiKeyDosentExist => HttpResponse::NotFound().finish(),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("")]
+pub async fn get_keys(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    match get_api_keys(&data.config.storage.data_path, &claims.project_id).await {
+        Ok(api_keys) => HttpResponse::Ok().json(json!(api_keys)),
+        Err(err) => api_key_error_response(err),
+    }
+}
+
+/// Creates a key. The response is the only time the full key is shown.
+#[post("")]
+pub async fn create_key(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_key: web::Json<NewApiKey>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    let api_key =
+        create_api_key(&data.config.storage.data_path, &claims.project_id, &new_key).await;
+    let mut entry = AuditEntry::from_result((&*claims).into(), AuditAction::ApiKeyCreate, &api_key);
+    if let Ok(api_key) = &api_key {
+        entry = entry.with_detail(format!("key {}", api_key.info.key_id));
+    }
+    audit(&data, &req, &claims.project_id, entry);
+
+    match api_key {
+        Ok(api_key) => HttpResponse::Ok().json(api_key),
+        Err(err) => api_key_error_response(err),
+    }
+}
+
+#[delete("/{key_id}")]
+pub async fn revoke_key(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    key_id: web::Path<Uuid>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    let revoked = revoke_api_key(&data.config.storage.data_path, &claims.project_id, &key_id).await;
+    let entry = AuditEntry::from_result((&*claims).into(), AuditAction::ApiKeyRevoke, &revoked);
+    audit(
+        &data,
+        &req,
+        &claims.project_id,
+        entry.with_detail(format!("key {}", key_id)),
+    );
+
+    match revoked {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => api_key_error_response(err),
+    }
+}
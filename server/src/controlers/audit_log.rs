@@ -0,0 +1,87 @@
+use actix_web::{
+    get,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    middlewares::auth::check_permission,
+    models::{audit_log::*, user_info::Permission},
+    utility::jwt_token::Claims,
+};
+
+pub fn audit_log_routes(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/audit")
+        .service(get_audit_log)
+        .service(export_audit_log);
+
+    config.service(scope);
+}
+
+/// Writes `entry` to the project's audit log with the client address of
+/// `req`. A failed write is printed, it doesn't fail the request.
+pub fn audit(data: &AppData, req: &HttpRequest, project_id: &Uuid, mut entry: AuditEntry) {
+    entry.ip = req.peer_addr().map(|addr| addr.ip().to_string());
+
+    if let Err(err) = write_audit_entry(&data.config.storage.data_path, project_id, &entry) {
+        println!("{}: {:?}", err, entry);
+    }
+}
+
+#[get("")]
+pub async fn get_audit_log(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    match read_audit_log(&data.config.storage.data_path, &claims.project_id, &query).await {
+        Ok(entries) => HttpResponse::Ok().json(json!(entries)),
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The matching entries as JSON Lines, one entry per line.
+#[get("/export")]
+pub async fn export_audit_log(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    match read_audit_log(&data.config.storage.data_path, &claims.project_id, &query).await {
+        Ok(entries) => {
+            let mut body = String::new();
+            for entry in entries {
+                body.push_str(&serde_json::to_string(&entry).unwrap());
+                body.push('\n');
+            }
+
+            HttpResponse::Ok()
+                .content_type("application/x-ndjson")
+                .insert_header((
+                    "Content-Disposition",
+                    "attachment; filename=\"audit_log.jsonl\"",
+                ))
+                .body(body)
+        }
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -1,22 +1,66 @@
 use actix_web::{
-    get, post,
+    delete, get,
+    http::{
+        header::{
+            CacheControl, CacheDirective, Charset, ContentDisposition, ContentRange,
+            ContentRangeSpec, DispositionParam, DispositionType, ETag, EntityTag, ExtendedValue,
+            HeaderName, HeaderValue, IfModifiedSince, IfNoneMatch, LastModified, Range,
+            ACCEPT_RANGES, CONTENT_TYPE, LINK,
+        },
+        StatusCode,
+    },
+    post,
     web::{self, ReqData},
-    HttpResponse, Responder,
+    HttpMessage, HttpRequest, HttpResponse, Responder,
 };
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 
-use crate::{app_data::AppData, models::image_data::*, utility::jwt_token::Claims};
+use crate::{
+    app_data::AppData,
+    controlers::audit_log::audit,
+    middlewares::{auth::check_permission, rate_limit::RateLimit},
+    models::{audit_log::*, image_data::*, user_info::Permission},
+    utility::{encrypted_blob::chunk_stream, jwt_token::Claims},
+};
 
 pub fn image_routes(config: &mut web::ServiceConfig) {
+    // every request on an image shares the project's "get" bucket
+    let images = web::scope("/images")
+        .wrap(RateLimit::new("get"))
+        .service(list_images)
+        .service(get_image_metadata)
+        .service(get_image_raw)
+        .service(get_image_content)
+        .service(delete_image_by_id);
+
     let scope = web::scope("")
         .service(save_image)
         .service(get_image)
+        .service(download_images_zip)
+        .service(set_image_tags)
         .service(get_project_info);
 
-    config.service(scope);
+    config.service(images).service(scope);
 }
 
-#[post("/save")]
+#[utoipa::path(
+    post,
+    path = "/api/save",
+    tag = "images",
+    request_body = UploadImage,
+    responses(
+        (status = 200, description = "The image was saved"),
+        (status = 400, description = "A plain upload that isn't an image"),
+        (status = 401, description = "Not logged in, or the project encryption key isn't unlocked"),
+        (status = 409, description = "The project key changed during the upload, try again"),
+        (status = 413, description = "The project quota would be exceeded"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/save", wrap = "RateLimit::new(\"save\")")]
 pub async fn save_image(
+    req: HttpRequest,
     data: web::Data<AppData>,
     req_user: Option<ReqData<Claims>>,
     form: web::Json<UploadImage>,
@@ -44,40 +88,535 @@ pub async fn save_image(
     //     _ => {}
     // }
 
-    let project_id = req_user.unwrap().project_id;
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Upload) {
+        return res;
+    }
+    let project_id = claims.project_id;
 
-    println!("{:#?}", form.0);
+    let project_keys = data.get_project_keys(&project_id);
+    let img = upload_image(
+        &data.config.storage.data_path,
+        &data.config.storage.input_path,
+        form.0,
+        project_id,
+        project_keys.as_ref(),
+        &data.config.quota,
+    )
+    .await;
 
-    let img = upload_image(&data.data_path, &data.input_path, form.0, project_id).await;
+    let entry = AuditEntry::from_result((&*claims).into(), AuditAction::Upload, &img);
+    let entry = match &img {
+        Ok(image) => entry.with_image(image.image_id),
+        Err(_) => entry,
+    };
+    audit(&data, &req, &project_id, entry);
 
     match img {
-        Ok(_) => HttpResponse::Ok(),
-        Err(_) => HttpResponse::InternalServerError().into(),
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(ImageDataError::EncryptionKeyUnavailable) => {
+            HttpResponse::Unauthorized().body("Log in again to unlock the project encryption key.")
+        }
+        Err(err @ ImageDataError::QuotaExceeded(_)) => {
+            HttpResponse::PayloadTooLarge().body(err.to_string())
+        }
+        Err(err @ (ImageDataError::InvalidImage(_) | ImageDataError::InvalidUploadPath(_))) => {
+            HttpResponse::BadRequest().body(err.to_string())
+        }
+        Err(err @ ImageDataError::KeyChanged) => HttpResponse::Conflict().body(err.to_string()),
+        Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
 
-#[get("/get")]
+/// Deprecated, the image id in a GET body can't be sent by browsers. Use
+/// `GET /api/images/{image_id}` instead.
+#[utoipa::path(
+    get,
+    path = "/api/get",
+    tag = "images",
+    request_body = ReqImageData,
+    responses(
+        (status = 200, description = "The image"),
+        (status = 206, description = "The requested range of the image"),
+        (status = 304, description = "The cached image is current"),
+        (status = 401, description = "Not logged in, or the project encryption key isn't unlocked"),
+        (status = 404, description = "No such image"),
+        (status = 416, description = "The range is outside the image"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/get", wrap = "RateLimit::new(\"get\")")]
 pub async fn get_image(
+    req: HttpRequest,
     data: web::Data<AppData>,
     req_user: Option<ReqData<Claims>>,
     image_req: web::Json<ReqImageData>,
 ) -> HttpResponse {
-    let project_id = req_user.unwrap().project_id;
+    let mut res = fetch_image(
+        &req,
+        &data,
+        req_user.unwrap(),
+        image_req.image_id,
+        image_req.download,
+    )
+    .await;
+
+    let successor = format!(
+        "</api/images/{}>; rel=\"successor-version\"",
+        image_req.image_id
+    );
+    if let Ok(successor) = HeaderValue::from_str(&successor) {
+        res.headers_mut().insert(LINK, successor);
+    }
+    res.headers_mut().insert(
+        HeaderName::from_static("deprecation"),
+        HeaderValue::from_static("true"),
+    );
+    res
+}
+
+/// A page of the project's images, see `ImageListQuery`.
+#[utoipa::path(
+    get,
+    path = "/api/images",
+    tag = "images",
+    params(ImageListQuery),
+    responses(
+        (status = 200, description = "A page of images", body = ImagePage),
+        (status = 400, description = "Unknown field or bad cursor"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("")]
+pub async fn list_images(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    query: web::Query<ImageListQuery>,
+) -> HttpResponse {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Read) {
+        return res;
+    }
+
+    match list_project_images(&data.config.storage.data_path, &claims.project_id, &query).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(err @ ImageDataError::InvalidListQuery(_)) => {
+            HttpResponse::BadRequest().body(err.to_string())
+        }
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The image, to be shown inline.
+#[utoipa::path(
+    get,
+    path = "/api/images/{image_id}",
+    tag = "images",
+    params(("image_id" = Uuid, Path)),
+    responses(
+        (status = 200, description = "The image"),
+        (status = 206, description = "The requested range of the image"),
+        (status = 304, description = "The cached image is current"),
+        (status = 401, description = "Not logged in, or the project encryption key isn't unlocked"),
+        (status = 404, description = "No such image"),
+        (status = 416, description = "The range is outside the image"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/{image_id}")]
+pub async fn get_image_content(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    image_id: web::Path<Uuid>,
+) -> HttpResponse {
+    fetch_image(&req, &data, req_user.unwrap(), *image_id, false).await
+}
+
+/// The image as a download named like the uploaded file.
+#[utoipa::path(
+    get,
+    path = "/api/images/{image_id}/raw",
+    tag = "images",
+    params(("image_id" = Uuid, Path)),
+    responses(
+        (status = 200, description = "The image, as an attachment"),
+        (status = 206, description = "The requested range of the image"),
+        (status = 304, description = "The cached image is current"),
+        (status = 401, description = "Not logged in, or the project encryption key isn't unlocked"),
+        (status = 404, description = "No such image"),
+        (status = 416, description = "The range is outside the image"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/{image_id}/raw")]
+pub async fn get_image_raw(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    image_id: web::Path<Uuid>,
+) -> HttpResponse {
+    fetch_image(&req, &data, req_user.unwrap(), *image_id, true).await
+}
 
-    let project_images = get_saved_image(&data.data_path, &project_id, &image_req.0.image_id).await;
+#[utoipa::path(
+    get,
+    path = "/api/images/{image_id}/metadata",
+    tag = "images",
+    params(("image_id" = Uuid, Path)),
+    responses(
+        (status = 200, description = "The image metadata", body = ImageData),
+        (status = 404, description = "No such image"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/{image_id}/metadata")]
+pub async fn get_image_metadata(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    image_id: web::Path<Uuid>,
+) -> HttpResponse {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Read) {
+        return res;
+    }
+
+    match get_project_image(
+        &data.config.storage.data_path,
+        &claims.project_id,
+        &image_id,
+    )
+    .await
+    {
+        Ok(image) => HttpResponse::Ok().json(image),
+        Err(err @ ImageDataError::ImageNotFound) => HttpResponse::NotFound().body(err.to_string()),
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/images/{image_id}",
+    tag = "images",
+    params(("image_id" = Uuid, Path)),
+    responses(
+        (status = 200, description = "The deleted image", body = ImageData),
+        (status = 403, description = "The role is not allowed to manage the project"),
+        (status = 404, description = "No such image"),
+    ),
+    security(("bearer" = [])),
+)]
+#[delete("/{image_id}")]
+pub async fn delete_image_by_id(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    image_id: web::Path<Uuid>,
+) -> HttpResponse {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    let deleted = delete_image(
+        &data.config.storage.data_path,
+        &claims.project_id,
+        &image_id,
+    )
+    .await;
+    let entry = AuditEntry::from_result((&*claims).into(), AuditAction::Delete, &deleted);
+    audit(&data, &req, &claims.project_id, entry.with_image(*image_id));
+
+    match deleted {
+        Ok(image) => HttpResponse::Ok().json(image),
+        Err(err @ ImageDataError::ImageNotFound) => HttpResponse::NotFound().body(err.to_string()),
+        Err(err) => {
+            log::warn!("failed to delete image {}: {}", image_id, err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn fetch_image(
+    req: &HttpRequest,
+    data: &AppData,
+    claims: ReqData<Claims>,
+    image_id: Uuid,
+    download: bool,
+) -> HttpResponse {
+    if let Err(res) = check_permission(&claims, Permission::Read) {
+        return res;
+    }
+    let project_id = claims.project_id;
+
+    let project_keys = data.get_project_keys(&project_id);
+    let project_images = get_saved_image(
+        &data.config.storage.data_path,
+        &project_id,
+        &image_id,
+        project_keys.as_ref(),
+    )
+    .await;
+
+    audit(
+        data,
+        req,
+        &project_id,
+        AuditEntry::from_result((&*claims).into(), AuditAction::Fetch, &project_images)
+            .with_image(image_id),
+    );
 
     match project_images {
-        Ok(images) => HttpResponse::Ok().body(images.data),
-        Err(err) => HttpResponse::NotFound().body(format!("{:?}", err)),
+        Ok(images) => image_response(req, &images, download).await,
+        Err(ImageDataError::DecryptionError(err)) => HttpResponse::InternalServerError().body(err),
+        Err(ImageDataError::EncryptionKeyUnavailable) => {
+            HttpResponse::Unauthorized().body("Log in again to unlock the project encryption key.")
+        }
+        Err(err) => HttpResponse::NotFound().body(err.to_string()),
     }
 }
 
+/// The image content, honoring conditional and `Range` headers. The ETag is
+/// the content hash and Last-Modified the upload time, images never change
+/// once saved.
+async fn image_response(
+    req: &HttpRequest,
+    image: &ResponseImageData,
+    download: bool,
+) -> HttpResponse {
+    let etag = image
+        .metadata
+        .sha256
+        .as_ref()
+        .map(|sha256| EntityTag::new_strong(sha256.to_owned()));
+    let created = image.metadata.created_date.and_utc().timestamp().max(0) as u64;
+    let last_modified = UNIX_EPOCH + Duration::from_secs(created);
+
+    let not_modified = match (req.get_header::<IfNoneMatch>(), &etag) {
+        (Some(IfNoneMatch::Any), _) => true,
+        (Some(IfNoneMatch::Items(tags)), Some(etag)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        (Some(IfNoneMatch::Items(_)), None) => false,
+        // If-Modified-Since only counts without If-None-Match
+        (None, _) => req
+            .get_header::<IfModifiedSince>()
+            .map(|since| SystemTime::from(since.0) >= last_modified)
+            .unwrap_or(false),
+    };
+
+    let mut res = match not_modified {
+        true => HttpResponse::NotModified(),
+        false => HttpResponse::Ok(),
+    };
+    if let Some(etag) = &etag {
+        res.insert_header(ETag(etag.clone()));
+    }
+    res.insert_header(LastModified(last_modified.into()))
+        .insert_header(CacheControl(vec![CacheDirective::Private]))
+        .insert_header((ACCEPT_RANGES, "bytes"));
+    if not_modified {
+        return res.finish();
+    }
+    res.insert_header((CONTENT_TYPE, image.metadata.mime_type()));
+    if download {
+        res.insert_header(attachment(&image.metadata.original_image_name));
+    }
+
+    let total = image.content_len;
+    let range = match req.get_header::<Range>() {
+        // several ranges are answered with the whole image
+        Some(Range::Bytes(specs)) if specs.len() == 1 => {
+            match specs[0].to_satisfiable_range(total) {
+                Some((start, end)) => Some(ByteRange {
+                    start,
+                    len: end - start + 1,
+                }),
+                None => {
+                    return res
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                            range: None,
+                            instance_length: Some(total),
+                        }))
+                        .finish();
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let (chunks, range) = match image.open(range).await {
+        Ok(opened) => opened,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let len = match range {
+        Some(range) => {
+            res.status(StatusCode::PARTIAL_CONTENT)
+                .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                    range: Some((range.start, range.start + range.len - 1)),
+                    instance_length: Some(total),
+                }));
+            range.len
+        }
+        None => total,
+    };
+
+    res.no_chunking(len).streaming(chunk_stream(chunks))
+}
+
+/// `Content-Disposition` of a download named `file_name`, with an ASCII only
+/// fallback for clients that don't read `filename*`.
+fn attachment(file_name: &str) -> ContentDisposition {
+    let fallback = file_name
+        .chars()
+        .map(|c| match c.is_ascii() && !c.is_ascii_control() {
+            true => c,
+            false => '_',
+        })
+        .collect::<String>();
+
+    let mut parameters = vec![DispositionParam::Filename(fallback.clone())];
+    if fallback != file_name {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_owned()),
+            language_tag: None,
+            value: file_name.as_bytes().to_vec(),
+        }));
+    }
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters,
+    }
+}
+
+/// Several images at once as a zip archive, see `BulkDownload`.
+#[utoipa::path(
+    post,
+    path = "/api/download",
+    tag = "images",
+    request_body = BulkDownload,
+    responses(
+        (status = 200, description = "The images as a zip archive", content_type = "application/zip"),
+        (status = 401, description = "Not logged in, or the project encryption key isn't unlocked"),
+        (status = 404, description = "One of the listed images doesn't exist"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/download", wrap = "RateLimit::new(\"download\")")]
+pub async fn download_images_zip(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    download: web::Json<BulkDownload>,
+) -> HttpResponse {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Read) {
+        return res;
+    }
+    let project_id = claims.project_id;
+
+    let project_keys = data.get_project_keys(&project_id);
+    let archive = download_images(
+        &data.config.storage.data_path,
+        &project_id,
+        &download,
+        project_keys.as_ref(),
+    )
+    .await;
+
+    let entry = AuditEntry::from_result((&*claims).into(), AuditAction::Download, &archive);
+    let entry = match &archive {
+        Ok((count, _)) => entry.with_detail(format!("{} image(s)", count)),
+        Err(_) => entry,
+    };
+    audit(&data, &req, &project_id, entry);
+
+    match archive {
+        Ok((_, chunks)) => HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header(("Content-Disposition", "attachment; filename=\"images.zip\""))
+            .streaming(chunk_stream(chunks)),
+        Err(ImageDataError::EncryptionKeyUnavailable) => {
+            HttpResponse::Unauthorized().body("Log in again to unlock the project encryption key.")
+        }
+        Err(err @ ImageDataError::ImageNotFound) => HttpResponse::NotFound().body(err.to_string()),
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/tags",
+    tag = "images",
+    request_body = ImageTags,
+    responses(
+        (status = 200, description = "The image with its new tags", body = ImageData),
+        (status = 404, description = "No such image"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/tags", wrap = "RateLimit::new(\"tags\")")]
+pub async fn set_image_tags(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    image_tags: web::Json<ImageTags>,
+) -> HttpResponse {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Tag) {
+        return res;
+    }
+    let project_id = claims.project_id;
+
+    let image = update_image_tags(&data.config.storage.data_path, &project_id, &image_tags).await;
+
+    audit(
+        &data,
+        &req,
+        &project_id,
+        AuditEntry::from_result((&*claims).into(), AuditAction::Tag, &image)
+            .with_image(image_tags.image_id),
+    );
+
+    match image {
+        Ok(image) => HttpResponse::Ok().json(image),
+        Err(err) => {
+            println!("{:#?}", err);
+            match err {
+                ImageDataError::FailedToSaveImage | ImageDataError::FailedToReadIndex(_) => {
+                    HttpResponse::InternalServerError().finish()
+                }
+                _ => HttpResponse::NotFound().finish(),
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/info",
+    tag = "images",
+    responses((status = 200, description = "Nothing yet")),
+    security(("bearer" = [])),
+)]
 #[get("/info")]
 pub async fn get_project_info(
-    data: web::Data<AppData>,
+    _data: web::Data<AppData>,
     req_user: Option<ReqData<Claims>>,
 ) -> HttpResponse {
-    let project_id = req_user.unwrap().project_id;
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Read) {
+        return res;
+    }
+    let _project_id = claims.project_id;
 
     HttpResponse::Ok().body("")
 }
@@ -0,0 +1,182 @@
+use actix_web::web;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::Deprecated;
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::controlers::{image_data, project_info};
+use crate::models::image_data::{ImageSort, SortOrder};
+
+/// The OpenAPI document of the image and project endpoints, built from the
+/// `#[utoipa::path]` of their handlers.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Sorter API"),
+    paths(
+        image_data::save_image,
+        image_data::get_image,
+        image_data::download_images_zip,
+        image_data::set_image_tags,
+        image_data::get_project_info,
+        image_data::list_images,
+        image_data::get_image_content,
+        image_data::get_image_raw,
+        image_data::get_image_metadata,
+        image_data::delete_image_by_id,
+        project_info::get_all_project_info,
+        project_info::create_project,
+        project_info::login_project,
+        project_info::import_project,
+        project_info::change_password,
+        project_info::rotate_key,
+        project_info::get_rotate_key_status,
+        project_info::unlock_login,
+        project_info::get_quota,
+        project_info::get_fsck,
+        project_info::repair_fsck,
+        project_info::export_project,
+    ),
+    // only referenced from query parameters, which don't register schemas
+    components(schemas(ImageSort, SortOrder)),
+    modifiers(&ApiModifier),
+    tags(
+        (name = "images", description = "Saving, finding and downloading images"),
+        (name = "projects", description = "Project login and maintenance"),
+    )
+)]
+pub struct ApiDoc;
+
+struct ApiModifier;
+
+impl Modify for ApiModifier {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .build(),
+                ),
+            );
+        }
+
+        // kept for old clients, see `GET /api/images/{image_id}`
+        if let Some(get) = openapi
+            .paths
+            .paths
+            .get_mut("/api/get")
+            .and_then(|path| path.get.as_mut())
+        {
+            get.deprecated = Some(Deprecated::True);
+        }
+    }
+}
+
+/// `/api/openapi.json` and the docs UI at `/api/docs/`. Has to be registered
+/// before the `/api` scope, they are readable without logging in.
+pub fn openapi_routes(config: &mut web::ServiceConfig) {
+    config.service(SwaggerUi::new("/api/docs/{_:.*}").url("/api/openapi.json", ApiDoc::openapi()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controlers::image_data::image_routes;
+    use crate::controlers::project_info::{project_pre_auth, project_routes};
+    use actix_web::http::Method;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use std::collections::BTreeSet;
+
+    /// `(handler, method)` of every route macro in a controller's source.
+    fn source_routes(source: &str) -> BTreeSet<(String, String)> {
+        let mut routes = BTreeSet::new();
+        let mut method = None;
+
+        for line in source.lines().map(str::trim) {
+            for name in ["get", "post", "put", "delete"] {
+                if line.starts_with(&format!("#[{}(\"", name)) {
+                    method = Some(name.to_owned());
+                }
+            }
+            if let (Some(handler), Some(name)) = (line.strip_prefix("pub async fn "), &method) {
+                let handler = handler.split('(').next().unwrap().to_owned();
+                routes.insert((handler, name.clone()));
+                method = None;
+            }
+        }
+        routes
+    }
+
+    /// `(path, handler, method)` of every operation in the spec.
+    fn spec_operations() -> Vec<(String, String, Method)> {
+        let spec = ApiDoc::openapi();
+        let mut operations = vec![];
+
+        for (path, item) in spec.paths.paths {
+            let methods = [
+                (&item.get, Method::GET),
+                (&item.post, Method::POST),
+                (&item.put, Method::PUT),
+                (&item.delete, Method::DELETE),
+            ];
+            for (operation, method) in methods {
+                if let Some(operation) = operation {
+                    let handler = operation.operation_id.clone().unwrap_or_default();
+                    operations.push((path.clone(), handler, method));
+                }
+            }
+        }
+        operations
+    }
+
+    #[test]
+    fn spec_documents_every_route() {
+        let mut routes = source_routes(include_str!("image_data.rs"));
+        routes.extend(source_routes(include_str!("project_info.rs")));
+
+        let documented: BTreeSet<(String, String)> = spec_operations()
+            .into_iter()
+            .map(|(_, handler, method)| (handler, method.as_str().to_lowercase()))
+            .collect();
+
+        assert_eq!(routes, documented);
+    }
+
+    #[actix_web::test]
+    async fn spec_paths_are_routed() {
+        // without app data every matched handler fails extracting it, so
+        // only unrouted requests end up in the default service
+        let app = init_service(
+            App::new()
+                .service(web::scope("/api/auth").configure(project_pre_auth))
+                .service(
+                    web::scope("/api")
+                        .configure(project_routes)
+                        .configure(image_routes),
+                )
+                .default_service(web::to(HttpResponse::ImATeapot)),
+        )
+        .await;
+
+        for (path, handler, method) in spec_operations() {
+            let uri = path.replace("{image_id}", &uuid::Uuid::nil().to_string());
+            let req = TestRequest::default()
+                .method(method.clone())
+                .uri(&uri)
+                .to_request();
+            let res = call_service(&app, req).await;
+
+            assert_ne!(
+                res.status(),
+                actix_web::http::StatusCode::IM_A_TEAPOT,
+                "{} {} ({}) is documented but not routed",
+                method,
+                path,
+                handler
+            );
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective},
+    rt::time::timeout,
+    web::{self, ReqData},
+    HttpResponse,
+};
+use futures_util::{stream, Stream};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    app_data::AppData,
+    middlewares::auth::check_permission,
+    models::{project_events::*, user_info::Permission},
+    utility::jwt_token::Claims,
+};
+
+/// How long the stream may be quiet before a comment is sent, so proxies
+/// don't close it.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+pub fn project_event_routes(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/events").service(get_project_events);
+
+    config.service(scope);
+}
+
+/// The project's events as server-sent events, named after their `type`. A
+/// `lagged` event tells a client that was too slow how many it missed. The
+/// stream ends when the access token expires or is revoked.
+#[get("")]
+pub async fn get_project_events(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> HttpResponse {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Read) {
+        return res;
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(project_event_stream(data, claims.into_inner(), KEEP_ALIVE))
+}
+
+fn project_event_stream(
+    data: web::Data<AppData>,
+    claims: Claims,
+    keep_alive: Duration,
+) -> impl Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    let events = subscribe(&claims.project_id);
+
+    stream::unfold(events, move |mut events| {
+        let (data, claims) = (data.clone(), claims.clone());
+        async move {
+            let now = chrono::Utc::now().timestamp() as u64;
+            if claims.exp <= now || data.is_token_revoked(&claims.jti) {
+                return None;
+            }
+            let until_expiry = Duration::from_secs(claims.exp - now);
+
+            let message = match timeout(keep_alive.min(until_expiry), events.recv()).await {
+                Ok(Ok(event)) => format!(
+                    "event: {}\ndata: {}\n\n",
+                    event.name(),
+                    serde_json::to_string(&event).unwrap()
+                ),
+                Ok(Err(RecvError::Lagged(missed))) => {
+                    format!("event: lagged\ndata: {{\"missed\":{}}}\n\n", missed)
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => ": keep-alive\n\n".to_owned(),
+            };
+            Some((Ok(web::Bytes::from(message)), events))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::middlewares::{login_throttle::LoginThrottle, rate_limit::RateLimiter};
+    use crate::models::user_info::Role;
+    use futures_util::StreamExt;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex, RwLock};
+    use uuid::Uuid;
+
+    fn app_data() -> web::Data<AppData> {
+        web::Data::new(AppData {
+            config: Arc::new(Config::default()),
+            key_ring: Arc::new(RwLock::new(HashMap::new())),
+            rotation_jobs: Arc::new(Mutex::new(HashSet::new())),
+            revoked_tokens: Arc::new(RwLock::new(HashMap::new())),
+            login_throttle: LoginThrottle::default(),
+            rate_limiter: RateLimiter::from_config(&HashMap::new()),
+            file_locks: Default::default(),
+        })
+    }
+
+    fn claims(expires_in: u64) -> Claims {
+        let now = chrono::Utc::now().timestamp() as u64;
+        Claims {
+            nbf: now,
+            iat: now,
+            exp: now + expires_in,
+            iss: String::new(),
+            aud: String::new(),
+            project_id: Uuid::new_v4(),
+            user_id: None,
+            role: Role::Viewer,
+            jti: Uuid::new_v4(),
+            scopes: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn ends_when_the_token_expires() {
+        let stream = project_event_stream(app_data(), claims(2), KEEP_ALIVE);
+
+        let messages = timeout(Duration::from_secs(5), stream.collect::<Vec<_>>())
+            .await
+            .expect("the stream outlived its token");
+        assert!(messages.len() <= 2);
+    }
+
+    #[actix_web::test]
+    async fn ends_on_the_keep_alive_after_a_revocation() {
+        let data = app_data();
+        let claims = claims(60 * 60);
+        let mut stream = Box::pin(project_event_stream(
+            data.clone(),
+            claims.clone(),
+            Duration::from_millis(50),
+        ));
+
+        let keep_alive = stream.next().await.unwrap().unwrap();
+        assert_eq!(keep_alive, web::Bytes::from(": keep-alive\n\n"));
+
+        data.revoked_tokens
+            .write()
+            .unwrap()
+            .insert(claims.jti, claims.exp);
+        let rest = timeout(Duration::from_secs(1), stream.collect::<Vec<_>>())
+            .await
+            .expect("the stream outlived the revocation");
+        assert!(rest.is_empty());
+    }
+}
@@ -1,69 +1,701 @@
 use actix_web::{
     get, post,
-    web::{self},
-    HttpResponse, Responder,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
 };
+use futures_util::StreamExt;
 use serde_json::json;
+use std::fs::{self, File};
+use std::io::Write;
+use uuid::Uuid;
 
-use crate::{app_data::AppData, models::project_info::*, utility::jwt_token::generate_token};
+use crate::{
+    app_data::AppData,
+    controlers::{audit_log::audit, session::session_error_response},
+    middlewares::{auth::check_permission, login_throttle::*},
+    models::{
+        audit_log::*,
+        fsck::*,
+        image_data::{get_project_usage, read_project_images},
+        key_rotation::*,
+        project_archive::*,
+        project_info::*,
+        session::*,
+        user_info::*,
+    },
+    utility::{
+        encrypted_blob::chunk_stream, encryption::ProjectKeys, file_utilities::imports_dir,
+        jwt_token::Claims,
+    },
+};
 
 pub fn project_pre_auth(config: &mut web::ServiceConfig) {
     let scope = web::scope("")
-        .service(get_all_project_info)
         .service(create_project)
         .service(login_project);
 
     config.service(scope);
 }
 
-#[get("/")]
-pub async fn get_all_project_info(data: web::Data<AppData>) -> impl Responder {
-    let projects = get_all_project_infos(&data.data_path).await;
+pub fn project_routes(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/project")
+        .service(get_all_project_info)
+        .service(change_password)
+        .service(rotate_key)
+        .service(get_rotate_key_status)
+        .service(unlock_login)
+        .service(get_quota)
+        .service(get_fsck)
+        .service(repair_fsck)
+        .service(export_project)
+        .service(import_project);
+
+    config.service(scope);
+}
+
+/// Every project on the server. Logins don't tell a missing project from a
+/// wrong password, so the names are only listed to project managers.
+#[utoipa::path(
+    get,
+    path = "/api/project/all",
+    tag = "projects",
+    responses(
+        (status = 200, description = "Every project", body = Vec<Projects>),
+        (status = 403, description = "The role is not allowed to manage the project"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/all")]
+pub async fn get_all_project_info(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    let projects = get_all_project_infos(&data.config.storage.data_path).await;
 
     match projects {
         Ok(projects) => HttpResponse::Ok().json(json!(projects)),
         Err(err) => {
             println!("{:#?}", err);
-            HttpResponse::InternalServerError().into()
+            HttpResponse::InternalServerError().finish()
         }
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/auth/create",
+    tag = "projects",
+    request_body = ProjectLoginInfo,
+    responses((status = 200, description = "The new project", body = Projects)),
+)]
 #[post("/create")]
 pub async fn create_project(
     data: web::Data<AppData>,
     new_project: web::Json<ProjectLoginInfo>,
 ) -> impl Responder {
-    let project = create_project_info(&data.data_path, &new_project).await;
+    let project = create_project_info(&data.config.storage.data_path, &new_project).await;
 
     match project {
-        Ok(project) => HttpResponse::Ok().json(json!(project)),
+        Ok(project) => HttpResponse::Ok().json(Projects::from(&project)),
         Err(err) => {
             println!("{:#?}", err);
-            HttpResponse::InternalServerError().into()
+            HttpResponse::InternalServerError().finish()
         }
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/auth/login",
+    tag = "projects",
+    request_body = ProjectLoginInfo,
+    responses(
+        (status = 200, description = "Tokens of the new session", body = TokenPair),
+        (status = 401, description = "Wrong project name or password"),
+        (status = 429, description = "Too many failed logins"),
+    ),
+)]
 #[post("/login")]
 pub async fn login_project(
+    req: HttpRequest,
     data: web::Data<AppData>,
     project_info: web::Json<ProjectLoginInfo>,
 ) -> impl Responder {
-    let project = project_login(&data.data_path, &project_info).await;
+    let name_key = project_key(&project_info.project_name);
+    let ip_key = ip_key(req.peer_addr().map(|addr| addr.ip()));
+    data.login_throttle
+        .apply_unlock_requests(&data.config.storage.data_path);
+    if let Err(retry_after) = data.login_throttle.check(&[&name_key, &ip_key]) {
+        return too_many_attempts(retry_after);
+    }
+
+    let project = project_login(&data.config.storage.data_path, &project_info).await;
 
     match project {
-        Ok(project) => HttpResponse::Ok().body(generate_token(&project)),
+        Ok((project, project_keys)) => {
+            data.login_throttle.clear(&name_key);
+            audit(
+                &data,
+                &req,
+                &project.project_id,
+                AuditEntry::new(
+                    AuditActor::Project,
+                    AuditAction::Login,
+                    AuditOutcome::Success,
+                ),
+            );
+            data.set_project_keys(project.project_id, project_keys.clone());
+            if project_keys.previous.is_some() {
+                // a key rotation didn't finish before the server stopped
+                spawn_key_rotation(data.clone(), project.project_id, project_keys);
+            }
+            let _sessions = data.file_locks.sessions.lock().await;
+            match create_session(&data.config.storage.data_path, &project, None, Role::Owner).await
+            {
+                Ok(token_pair) => HttpResponse::Ok().json(token_pair),
+                Err(err) => session_error_response(err),
+            }
+        }
         Err(err) => {
             println!("{:#?}", err);
             match err {
-                ProjectInfoErrors::ProjectDosentExist => HttpResponse::NotFound().into(),
-                ProjectInfoErrors::WrongPassword => HttpResponse::Unauthorized().into(),
+                // the same answer for both, so names of projects can't be guessed
+                ProjectInfoErrors::ProjectDosentExist | ProjectInfoErrors::WrongPassword => {
+                    data.login_throttle
+                        .record_failure(&[(&name_key, &NAME_POLICY), (&ip_key, &IP_POLICY)]);
+                    if let Ok(project) = find_project_by_name(
+                        &data.config.storage.data_path,
+                        &project_info.project_name,
+                    )
+                    .await
+                    {
+                        let entry = AuditEntry::new(
+                            AuditActor::Anonymous,
+                            AuditAction::Login,
+                            AuditOutcome::Failure,
+                        );
+                        audit(
+                            &data,
+                            &req,
+                            &project.project_id,
+                            entry.with_detail("wrong password"),
+                        );
+                    }
+                    HttpResponse::Unauthorized().body("Wrong project name or password.")
+                }
                 _ => {
                     // should never reach here
-                    HttpResponse::InternalServerError().into()
+                    HttpResponse::InternalServerError().finish()
                 }
             }
         }
     }
 }
+
+#[utoipa::path(
+    post,
+    path = "/api/project/password",
+    tag = "projects",
+    request_body = ProjectPasswordChange,
+    responses(
+        (status = 200, description = "The password was changed, other sessions are logged out"),
+        (status = 401, description = "Wrong old password"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/password")]
+pub async fn change_password(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    password_change: web::Json<ProjectPasswordChange>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+    let project_id = claims.project_id;
+
+    let project = change_project_password(
+        &data.config.storage.data_path,
+        &project_id,
+        &password_change,
+    )
+    .await;
+    audit(
+        &data,
+        &req,
+        &project_id,
+        AuditEntry::from_result((&*claims).into(), AuditAction::PasswordChange, &project),
+    );
+
+    match project {
+        Ok(_) => {
+            // sessions that logged in with the old password end here
+            let _sessions = data.file_locks.sessions.lock().await;
+            if let Err(err) =
+                revoke_sessions(&data.config.storage.data_path, Some(&project_id), None).await
+            {
+                println!("{}", err);
+            }
+            HttpResponse::Ok().finish()
+        }
+        Err(ProjectInfoErrors::WrongPassword) => HttpResponse::Unauthorized().finish(),
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/project/rotate-key",
+    tag = "projects",
+    request_body = ProjectPassword,
+    responses(
+        (status = 202, description = "The rotation was started"),
+        (status = 401, description = "Wrong password"),
+        (status = 409, description = "A rotation is already running"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/rotate-key")]
+pub async fn rotate_key(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    project_password: web::Json<ProjectPassword>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+    let project_id = claims.project_id;
+
+    if data.rotation_jobs.lock().unwrap().contains(&project_id) {
+        return HttpResponse::Conflict().body("A key rotation is already running.");
+    }
+
+    let rotation = begin_key_rotation(
+        &data.config.storage.data_path,
+        &project_id,
+        &project_password.password,
+    )
+    .await;
+    audit(
+        &data,
+        &req,
+        &project_id,
+        AuditEntry::from_result((&*claims).into(), AuditAction::KeyRotation, &rotation),
+    );
+
+    match rotation {
+        Ok((_, project_keys)) => {
+            data.set_project_keys(project_id, project_keys.clone());
+            match spawn_key_rotation(data.clone(), project_id, project_keys) {
+                true => HttpResponse::Accepted().finish(),
+                false => HttpResponse::Conflict().body("A key rotation is already running."),
+            }
+        }
+        Err(ProjectInfoErrors::WrongPassword) => HttpResponse::Unauthorized().finish(),
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/project/rotate-key",
+    tag = "projects",
+    responses(
+        (status = 200, description = "Progress of the last key rotation", body = Object),
+        (status = 404, description = "The key was never rotated"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/rotate-key")]
+pub async fn get_rotate_key_status(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+    let project_id = claims.project_id;
+
+    let rotation = get_key_rotation(&data.config.storage.data_path, &project_id).await;
+
+    match rotation {
+        Ok(Some(rotation)) => {
+            let running = data.rotation_jobs.lock().unwrap().contains(&project_id);
+            HttpResponse::Ok().json(json!({ "running": running, "rotation": rotation }))
+        }
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The storage quota of the project and how much of it is used.
+#[utoipa::path(
+    get,
+    path = "/api/project/quota",
+    tag = "projects",
+    responses((status = 200, description = "`quota` and `usage` of the project", body = Object)),
+    security(("bearer" = [])),
+)]
+#[get("/quota")]
+pub async fn get_quota(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Read) {
+        return res;
+    }
+
+    let project = find_project(&data.config.storage.data_path, &claims.project_id).await;
+    let images = read_project_images(&data.config.storage.data_path, &claims.project_id).await;
+
+    match (project, images) {
+        (Ok(project), Ok(images)) => HttpResponse::Ok().json(json!({
+            "quota": project.get_quota(&data.config.quota),
+            "usage": get_project_usage(&images),
+        })),
+        _ => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Lifts the login lockout of the project after too many wrong passwords, for
+/// the name and the addresses that tried it. An owner who is locked out can
+/// use an admin API key, or `sorter-admin unlock <project>`.
+#[utoipa::path(
+    post,
+    path = "/api/project/unlock",
+    tag = "projects",
+    responses(
+        (status = 200, description = "The project can be logged in to again"),
+        (status = 403, description = "Needs the manage permission or an admin API key"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/unlock")]
+pub async fn unlock_login(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    match find_project(&data.config.storage.data_path, &claims.project_id).await {
+        Ok(project) => {
+            data.login_throttle
+                .unlock(&project_key(&project.project_name));
+            audit(
+                &data,
+                &req,
+                &project.project_id,
+                AuditEntry::new(
+                    (&*claims).into(),
+                    AuditAction::LoginUnlock,
+                    AuditOutcome::Success,
+                ),
+            );
+            HttpResponse::Ok().finish()
+        }
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::NotFound().finish()
+        }
+    }
+}
+
+/// Runs the key rotation of a project in the background, unless one is already
+/// running. Returns whether a job was started.
+pub fn spawn_key_rotation(
+    data: web::Data<AppData>,
+    project_id: Uuid,
+    project_keys: ProjectKeys,
+) -> bool {
+    if !data.rotation_jobs.lock().unwrap().insert(project_id) {
+        return false;
+    }
+
+    actix_web::rt::spawn(async move {
+        let rotation =
+            run_key_rotation(&data.config.storage.data_path, &project_id, &project_keys).await;
+
+        match rotation {
+            Ok(rotation) => {
+                println!("{:#?}", rotation);
+                if rotation.status == RotationStatus::Completed {
+                    data.set_project_keys(
+                        project_id,
+                        ProjectKeys {
+                            current: project_keys.current,
+                            previous: None,
+                        },
+                    );
+                }
+            }
+            Err(err) => println!("key rotation of project {} failed: {}", project_id, err),
+        }
+
+        data.rotation_jobs.lock().unwrap().remove(&project_id);
+    });
+
+    true
+}
+
+/// Checks the project's index and image files without changing anything.
+#[utoipa::path(
+    get,
+    path = "/api/project/fsck",
+    tag = "projects",
+    responses(
+        (status = 200, description = "The problems found", body = Object),
+        (status = 409, description = "A key rotation is running"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/fsck")]
+pub async fn get_fsck(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    run_fsck(&data, &claims.project_id, None).await
+}
+
+/// Checks the project and repairs what it can, see `RepairMode`.
+#[utoipa::path(
+    post,
+    path = "/api/project/fsck",
+    tag = "projects",
+    request_body = FsckRepair,
+    responses(
+        (status = 200, description = "The problems found and what was done about them", body = Object),
+        (status = 409, description = "A key rotation is running"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/fsck")]
+pub async fn repair_fsck(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    fsck_repair: web::Json<FsckRepair>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    let res = run_fsck(&data, &claims.project_id, Some(fsck_repair.repair)).await;
+    audit(
+        &data,
+        &req,
+        &claims.project_id,
+        AuditEntry::new(
+            (&*claims).into(),
+            AuditAction::Fsck,
+            match res.status().is_success() {
+                true => AuditOutcome::Success,
+                false => AuditOutcome::Failure,
+            },
+        ),
+    );
+    res
+}
+
+async fn run_fsck(data: &AppData, project_id: &Uuid, repair: Option<RepairMode>) -> HttpResponse {
+    // a rotation rewrites blobs while they'd be checked
+    if data.rotation_jobs.lock().unwrap().contains(project_id) {
+        return HttpResponse::Conflict().body("A key rotation is running.");
+    }
+
+    let project_keys = data.get_project_keys(project_id);
+    let (data_path, project_id) = (data.config.storage.data_path.clone(), *project_id);
+    // every blob is read back, which takes long enough to block a worker
+    let report =
+        web::block(move || fsck_project(&data_path, &project_id, project_keys.as_ref(), repair))
+            .await;
+
+    match report {
+        Ok(Ok(report)) => HttpResponse::Ok().json(report),
+        Ok(Err(FsckErrors::ProjectDosentExist)) => HttpResponse::NotFound().finish(),
+        Ok(Err(err)) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The project as a tar archive for `/import` on another server.
+#[utoipa::path(
+    get,
+    path = "/api/project/export",
+    tag = "projects",
+    responses(
+        (status = 200, description = "The project as a tar archive", content_type = "application/x-tar"),
+        (status = 409, description = "A key rotation is running"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/export")]
+pub async fn export_project(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+    let project_id = claims.project_id;
+
+    if data.rotation_jobs.lock().unwrap().contains(&project_id) {
+        return HttpResponse::Conflict().body("A key rotation is running.");
+    }
+
+    let archive = export_project_archive(&data.config.storage.data_path, &project_id).await;
+    audit(
+        &data,
+        &req,
+        &project_id,
+        AuditEntry::from_result((&*claims).into(), AuditAction::Export, &archive),
+    );
+
+    match archive {
+        Ok(chunks) => HttpResponse::Ok()
+            .content_type("application/x-tar")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.tar\"", project_id),
+            ))
+            .streaming(chunk_stream(chunks)),
+        Err(ProjectArchiveErrors::RotationInProgress) => {
+            HttpResponse::Conflict().body("A key rotation hasn't finished.")
+        }
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// Adds a project from an archive made by `/project/export`. The archive is
+/// the request body; `?project_name=` imports it under another name. Needs
+/// `Manage` on the project logged in to, the archive brings its own password.
+#[utoipa::path(
+    post,
+    path = "/api/project/import",
+    tag = "projects",
+    params(ArchiveImport),
+    request_body(content = Vec<u8>, description = "An archive from `/api/project/export`", content_type = "application/x-tar"),
+    responses(
+        (status = 200, description = "The imported project", body = Projects),
+        (status = 400, description = "Not a valid archive"),
+        (status = 409, description = "A project with the name exists"),
+        (status = 413, description = "The archive is larger than `storage.max_archive_bytes`"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/import")]
+pub async fn import_project(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    query: web::Query<ArchiveImport>,
+    mut payload: web::Payload,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+    let data_path = &data.config.storage.data_path;
+    let archive_path = imports_dir(data_path).join(format!("{}.tar", Uuid::new_v4()));
+
+    let mut file = match fs::create_dir_all(imports_dir(data_path))
+        .and_then(|_| File::create(&archive_path))
+    {
+        Ok(file) => file,
+        Err(err) => {
+            println!("{:#?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let mut archive_len = 0;
+    while let Some(chunk) = payload.next().await {
+        let written = match chunk {
+            Ok(chunk) => {
+                archive_len += chunk.len() as u64;
+                if archive_len > data.config.storage.max_archive_bytes {
+                    let _ = fs::remove_file(&archive_path);
+                    return HttpResponse::PayloadTooLarge().finish();
+                }
+                file.write_all(&chunk).map_err(|err| err.to_string())
+            }
+            Err(err) => Err(err.to_string()),
+        };
+        if let Err(err) = written {
+            let _ = fs::remove_file(&archive_path);
+            return HttpResponse::BadRequest().body(err);
+        }
+    }
+    drop(file);
+
+    let project =
+        import_project_archive(data_path, &archive_path, query.project_name.as_deref()).await;
+    let _ = fs::remove_file(&archive_path);
+
+    match project {
+        Ok(project) => {
+            audit(
+                &data,
+                &req,
+                &project.project_id,
+                AuditEntry::new(
+                    (&*claims).into(),
+                    AuditAction::Import,
+                    AuditOutcome::Success,
+                )
+                .with_detail(format!("by project {}", claims.project_id)),
+            );
+            HttpResponse::Ok().json(Projects::from(&project))
+        }
+        Err(err @ ProjectArchiveErrors::ProjectAllreadyExists) => {
+            HttpResponse::Conflict().body(err.to_string())
+        }
+        Err(
+            err @ (ProjectArchiveErrors::InvalidArchive(_)
+            | ProjectArchiveErrors::RotationInProgress),
+        ) => HttpResponse::BadRequest().body(err.to_string()),
+        Err(err) => {
+            println!("{:#?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
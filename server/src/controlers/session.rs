@@ -0,0 +1,76 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+
+use crate::{
+    app_data::AppData,
+    controlers::audit_log::audit,
+    models::{audit_log::*, session::*},
+    utility::jwt_token::{get_jwks, validate_token},
+};
+
+pub fn session_pre_auth(config: &mut web::ServiceConfig) {
+    // registered without a scope, `project_pre_auth` already owns the empty one
+    config.service(refresh_token).service(logout);
+}
+
+pub fn well_known_routes(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/.well-known").service(jwks);
+
+    config.service(scope);
+}
+
+pub fn session_error_response(err: SessionErrors) -> HttpResponse {
+    println!("{:#?}", err);
+    match err {
+        SessionErrors::FailedToReadSessions | SessionErrors::FailedToSaveSessions => {
+            HttpResponse::InternalServerError().finish()
+        }
+        _ => HttpResponse::Unauthorized().body(err.to_string()),
+    }
+}
+
+/// Issues a new access token and replaces the refresh token.
+#[post("/refresh")]
+pub async fn refresh_token(
+    data: web::Data<AppData>,
+    refresh_info: web::Json<RefreshTokenInfo>,
+) -> impl Responder {
+    let _sessions = data.file_locks.sessions.lock().await;
+    match refresh_session(&data.config.storage.data_path, &refresh_info.refresh_token).await {
+        Ok(token_pair) => HttpResponse::Ok().json(token_pair),
+        Err(err) => session_error_response(err),
+    }
+}
+
+/// Ends the session of the refresh token. When the access token is sent along
+/// as bearer token it is revoked as well.
+#[post("/logout")]
+pub async fn logout(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    credentials: Option<BearerAuth>,
+    refresh_info: web::Json<RefreshTokenInfo>,
+) -> impl Responder {
+    if let Some(claims) = credentials.and_then(|c| validate_token(c.token()).ok()) {
+        data.revoke_token(claims.jti, claims.exp);
+        audit(
+            &data,
+            &req,
+            &claims.project_id,
+            AuditEntry::new((&claims).into(), AuditAction::Logout, AuditOutcome::Success),
+        );
+    }
+
+    let _sessions = data.file_locks.sessions.lock().await;
+    match revoke_session(&data.config.storage.data_path, &refresh_info.refresh_token).await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => session_error_response(err),
+    }
+}
+
+/// Public keys for other services to verify access tokens with. Empty when
+/// tokens are signed with the HS256 secret.
+#[get("/jwks.json")]
+pub async fn jwks() -> impl Responder {
+    HttpResponse::Ok().json(get_jwks())
+}
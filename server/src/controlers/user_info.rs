@@ -0,0 +1,313 @@
+use actix_web::{
+    delete, get, post,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    controlers::{
+        audit_log::audit, project_info::spawn_key_rotation, session::session_error_response,
+    },
+    middlewares::{auth::check_permission, login_throttle::*},
+    models::{audit_log::*, project_info::find_project_by_name, session::*, user_info::*},
+    utility::jwt_token::Claims,
+};
+
+pub fn user_pre_auth(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/user").service(register).service(login_user);
+
+    config.service(scope);
+}
+
+pub fn user_routes(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/user")
+        .service(change_password)
+        .service(get_projects);
+
+    config.service(scope);
+}
+
+pub fn member_routes(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/members")
+        .service(get_members)
+        .service(add_member)
+        .service(update_member)
+        .service(remove_member);
+
+    config.service(scope);
+}
+
+fn user_error_response(err: UserInfoErrors) -> HttpResponse {
+    println!("{:#?}", err);
+    match err {
+        UserInfoErrors::UserAllreadyExists
+        | UserInfoErrors::MemberAllreadyExists
+        | UserInfoErrors::LastOwner => HttpResponse::Conflict().body(err.to_string()),
+        UserInfoErrors::UserDosentExist
+        | UserInfoErrors::ProjectDosentExist
+        | UserInfoErrors::NotAMember => HttpResponse::NotFound().body(err.to_string()),
+        UserInfoErrors::WrongPassword => HttpResponse::Unauthorized().finish(),
+        UserInfoErrors::EncryptionKeyUnavailable => {
+            HttpResponse::Unauthorized().body("Log in again to unlock the project encryption key.")
+        }
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/register")]
+pub async fn register(
+    data: web::Data<AppData>,
+    register_info: web::Json<UserRegisterInfo>,
+) -> impl Responder {
+    let user = register_user(&data.config.storage.data_path, &register_info).await;
+
+    match user {
+        Ok(user) => HttpResponse::Ok().json(json!({
+            "user_id": user.user_id,
+            "user_name": user.user_name,
+            "created_date": user.created_date,
+        })),
+        Err(err) => user_error_response(err),
+    }
+}
+
+#[post("/login")]
+pub async fn login_user(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    login_info: web::Json<UserLoginInfo>,
+) -> impl Responder {
+    let name_key = user_key(&login_info.user_name);
+    let ip_key = ip_key(req.peer_addr().map(|addr| addr.ip()));
+    data.login_throttle
+        .apply_unlock_requests(&data.config.storage.data_path);
+    if let Err(retry_after) = data.login_throttle.check(&[&name_key, &ip_key]) {
+        return too_many_attempts(retry_after);
+    }
+
+    let login = user_login(&data.config.storage.data_path, &login_info).await;
+
+    match login {
+        Ok((project, user, member, project_keys)) => {
+            data.login_throttle.clear(&name_key);
+            audit(
+                &data,
+                &req,
+                &project.project_id,
+                AuditEntry::new(
+                    AuditActor::User(user.user_id),
+                    AuditAction::Login,
+                    AuditOutcome::Success,
+                ),
+            );
+            if let Some(project_keys) = project_keys {
+                data.set_project_keys(project.project_id, project_keys.clone());
+                if project_keys.previous.is_some() {
+                    // a key rotation didn't finish before the server stopped
+                    spawn_key_rotation(data.clone(), project.project_id, project_keys);
+                }
+            }
+            let _sessions = data.file_locks.sessions.lock().await;
+            match create_session(
+                &data.config.storage.data_path,
+                &project,
+                Some(user.user_id),
+                member.role,
+            )
+            .await
+            {
+                Ok(token_pair) => HttpResponse::Ok().json(token_pair),
+                Err(err) => session_error_response(err),
+            }
+        }
+        Err(
+            err @ (UserInfoErrors::UserDosentExist
+            | UserInfoErrors::WrongPassword
+            | UserInfoErrors::ProjectDosentExist
+            | UserInfoErrors::NotAMember),
+        ) => {
+            data.login_throttle
+                .record_failure(&[(&name_key, &NAME_POLICY), (&ip_key, &IP_POLICY)]);
+            if let Ok(project) =
+                find_project_by_name(&data.config.storage.data_path, &login_info.project_name).await
+            {
+                let entry = AuditEntry::new(
+                    AuditActor::Anonymous,
+                    AuditAction::Login,
+                    AuditOutcome::Failure,
+                )
+                .with_detail(format!("user '{}': {}", login_info.user_name, err));
+                audit(&data, &req, &project.project_id, entry);
+            }
+            HttpResponse::Unauthorized().body("Wrong user name, password or project.")
+        }
+        Err(err) => user_error_response(err),
+    }
+}
+
+#[post("/password")]
+pub async fn change_password(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    password_change: web::Json<UserPasswordChange>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    let user_id = match claims.user_id {
+        Some(user_id) => user_id,
+        None => return HttpResponse::BadRequest().body("Not logged in as a user."),
+    };
+
+    let changed =
+        change_user_password(&data.config.storage.data_path, &user_id, &password_change).await;
+    let entry = AuditEntry::from_result((&*claims).into(), AuditAction::PasswordChange, &changed);
+    audit(
+        &data,
+        &req,
+        &claims.project_id,
+        entry.with_detail("user password"),
+    );
+
+    match changed {
+        Ok(_) => {
+            let _sessions = data.file_locks.sessions.lock().await;
+            if let Err(err) =
+                revoke_sessions(&data.config.storage.data_path, None, Some(&user_id)).await
+            {
+                println!("{}", err);
+            }
+            HttpResponse::Ok().finish()
+        }
+        Err(err) => user_error_response(err),
+    }
+}
+
+#[get("/projects")]
+pub async fn get_projects(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let user_id = match req_user.unwrap().user_id {
+        Some(user_id) => user_id,
+        None => return HttpResponse::BadRequest().body("Not logged in as a user."),
+    };
+
+    match get_user_projects(&data.config.storage.data_path, &user_id).await {
+        Ok(projects) => HttpResponse::Ok().json(json!(projects)),
+        Err(err) => user_error_response(err),
+    }
+}
+
+#[get("")]
+pub async fn get_members(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    match get_project_members(&data.config.storage.data_path, &claims.project_id).await {
+        Ok(members) => HttpResponse::Ok().json(json!(members)),
+        Err(err) => user_error_response(err),
+    }
+}
+
+#[post("")]
+pub async fn add_member(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_member: web::Json<NewProjectMember>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    let project_keys = data.get_project_keys(&claims.project_id);
+    let member = add_project_member(
+        &data.config.storage.data_path,
+        &claims.project_id,
+        &new_member,
+        project_keys.as_ref(),
+    )
+    .await;
+
+    let mut entry = AuditEntry::from_result((&*claims).into(), AuditAction::MemberAdd, &member);
+    if let Ok(member) = &member {
+        entry = entry.with_detail(format!("user {} as {:?}", member.user_id, member.role));
+    }
+    audit(&data, &req, &claims.project_id, entry);
+
+    match member {
+        Ok(member) => HttpResponse::Ok().json(json!(member)),
+        Err(err) => user_error_response(err),
+    }
+}
+
+#[post("/{user_id}")]
+pub async fn update_member(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    user_id: web::Path<Uuid>,
+    member_role: web::Json<MemberRole>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    let updated = update_member_role(
+        &data.config.storage.data_path,
+        &claims.project_id,
+        &user_id,
+        member_role.role,
+    )
+    .await;
+    let entry = AuditEntry::from_result((&*claims).into(), AuditAction::MemberUpdate, &updated);
+    audit(
+        &data,
+        &req,
+        &claims.project_id,
+        entry.with_detail(format!("user {} as {:?}", user_id, member_role.role)),
+    );
+
+    match updated {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => user_error_response(err),
+    }
+}
+
+#[delete("/{user_id}")]
+pub async fn remove_member(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    user_id: web::Path<Uuid>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    let removed =
+        remove_project_member(&data.config.storage.data_path, &claims.project_id, &user_id).await;
+    let entry = AuditEntry::from_result((&*claims).into(), AuditAction::MemberRemove, &removed);
+    audit(
+        &data,
+        &req,
+        &claims.project_id,
+        entry.with_detail(format!("user {}", user_id)),
+    );
+
+    match removed {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => user_error_response(err),
+    }
+}
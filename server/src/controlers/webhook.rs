@@ -0,0 +1,169 @@
+use actix_web::{
+    delete, get, post,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    controlers::audit_log::audit,
+    middlewares::auth::check_permission,
+    models::{audit_log::*, user_info::Permission, webhook::*},
+    utility::jwt_token::Claims,
+};
+
+pub fn webhook_routes(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/webhooks")
+        .service(get_project_webhooks)
+        .service(create_project_webhook)
+        .service(delete_project_webhook)
+        .service(get_deliveries)
+        .service(send_test_delivery);
+
+    config.service(scope);
+}
+
+fn webhook_error_response(err: WebhookErrors) -> HttpResponse {
+    println!("{:#?}", err);
+    match err {
+        WebhookErrors::InvalidUrl(_)
+        | WebhookErrors::AddressNotAllowed(_)
+        | WebhookErrors::NoEvents => HttpResponse::BadRequest().body(err.to_string()),
+        WebhookErrors::WebhookDosentExist => HttpResponse::NotFound().finish(),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("")]
+pub async fn get_project_webhooks(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    match get_webhooks(&data.config.storage.data_path, &claims.project_id).await {
+        Ok(webhooks) => HttpResponse::Ok().json(json!(webhooks)),
+        Err(err) => webhook_error_response(err),
+    }
+}
+
+/// Registers a webhook. The response is the only time its secret is shown.
+#[post("")]
+pub async fn create_project_webhook(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_webhook: web::Json<NewWebhook>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    let webhook = create_webhook(
+        &data.config.storage.data_path,
+        &data.config.webhooks,
+        &claims.project_id,
+        &new_webhook,
+    )
+    .await;
+    let mut entry =
+        AuditEntry::from_result((&*claims).into(), AuditAction::WebhookCreate, &webhook);
+    if let Ok(webhook) = &webhook {
+        entry = entry.with_detail(format!("webhook {}", webhook.info.webhook_id));
+    }
+    audit(&data, &req, &claims.project_id, entry);
+
+    match webhook {
+        Ok(webhook) => HttpResponse::Ok().json(webhook),
+        Err(err) => webhook_error_response(err),
+    }
+}
+
+#[delete("/{webhook_id}")]
+pub async fn delete_project_webhook(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    webhook_id: web::Path<Uuid>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    let deleted = delete_webhook(
+        &data.config.storage.data_path,
+        &claims.project_id,
+        &webhook_id,
+    )
+    .await;
+    let entry = AuditEntry::from_result((&*claims).into(), AuditAction::WebhookDelete, &deleted);
+    audit(
+        &data,
+        &req,
+        &claims.project_id,
+        entry.with_detail(format!("webhook {}", webhook_id)),
+    );
+
+    match deleted {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => webhook_error_response(err),
+    }
+}
+
+/// Every attempt to deliver an event to the webhook, oldest first.
+#[get("/{webhook_id}/deliveries")]
+pub async fn get_deliveries(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    webhook_id: web::Path<Uuid>,
+    query: web::Query<DeliveryQuery>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    match get_webhook_deliveries(
+        &data.config.storage.data_path,
+        &claims.project_id,
+        &webhook_id,
+        &query,
+    )
+    .await
+    {
+        Ok(deliveries) => HttpResponse::Ok().json(json!(deliveries)),
+        Err(err) => webhook_error_response(err),
+    }
+}
+
+/// Sends the webhook a `ping` event and answers with how the delivery went.
+#[post("/{webhook_id}/test")]
+pub async fn send_test_delivery(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    webhook_id: web::Path<Uuid>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    if let Err(res) = check_permission(&claims, Permission::Manage) {
+        return res;
+    }
+
+    match test_webhook(
+        &data.config.storage.data_path,
+        &data.config.webhooks,
+        &claims.project_id,
+        &webhook_id,
+    )
+    .await
+    {
+        Ok(delivery) => HttpResponse::Ok().json(delivery),
+        Err(err) => webhook_error_response(err),
+    }
+}
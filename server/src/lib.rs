@@ -0,0 +1,7 @@
+pub mod app_data;
+pub mod config;
+pub mod controlers;
+pub mod middlewares;
+pub mod migrations;
+pub mod models;
+pub mod utility;
@@ -3,60 +3,179 @@ use actix_web::{get, web, App, HttpServer};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use dotenv::dotenv;
 // use sqlx::{self, Pool, Postgres};
-use std::env::var;
+use std::collections::{HashMap, HashSet};
+use std::env::args;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 
-use crate::controlers::image_data::*;
-use crate::controlers::project_info::*;
-use crate::middlewares::auth::jwt_validator;
-
-mod app_data;
-mod controlers;
-mod middlewares;
-mod models;
-mod utility;
+use server::app_data::{AppData, FileLocks};
+use server::config::Config;
+use server::controlers::api_key::*;
+use server::controlers::audit_log::*;
+use server::controlers::image_data::*;
+use server::controlers::openapi::openapi_routes;
+use server::controlers::project_events::project_event_routes;
+use server::controlers::project_info::*;
+use server::controlers::session::*;
+use server::controlers::user_info::*;
+use server::controlers::webhook::webhook_routes;
+use server::middlewares::auth::jwt_validator;
+use server::middlewares::login_throttle::LoginThrottle;
+use server::middlewares::rate_limit::{RateLimit, RateLimiter};
+use server::migrations;
+use server::models::backup::create_snapshot;
+use server::models::project_events::subscribe_all;
+use server::models::session::read_revoked_tokens;
+use server::models::webhook::dispatch_event;
+use server::utility::blob_store::init_blob_store;
+use server::utility::{init_password_hashing, jwt_token};
 
 #[get("/")]
 async fn index() -> web::Json<String> {
     web::Json("hello world!".to_owned())
 }
 
+/// Takes a backup snapshot every `backup.interval_minutes`, if set.
+fn spawn_scheduled_backups(config: Arc<Config>) {
+    let interval = match (&config.backup.target, config.backup.interval_minutes) {
+        (Some(_), Some(minutes)) => Duration::from_secs(minutes * 60),
+        _ => return,
+    };
+
+    actix_web::rt::spawn(async move {
+        loop {
+            actix_web::rt::time::sleep(interval).await;
+
+            let config = config.clone();
+            let snapshot = web::block(move || {
+                let target = config.backup.target.as_deref().unwrap();
+                create_snapshot(&config.storage.data_path, Path::new(target), &config.backup)
+            })
+            .await;
+
+            match snapshot {
+                Ok(Ok(summary)) => println!(
+                    "backup snapshot {}: {} file(s), {} new bytes",
+                    summary.snapshot_id, summary.files, summary.new_bytes
+                ),
+                Ok(Err(err)) => println!("backup failed: {}", err),
+                Err(err) => println!("backup failed: {}", err),
+            }
+        }
+    });
+}
+
+/// Posts the events of every project to the webhooks registered for them.
+fn spawn_webhook_dispatcher(config: Arc<Config>) {
+    let mut events = subscribe_all();
+
+    actix_web::rt::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok((project_id, event)) => {
+                    if let Err(err) = dispatch_event(
+                        &config.storage.data_path,
+                        &config.webhooks,
+                        &project_id,
+                        &event,
+                    ) {
+                        println!("webhooks of project {}: {}", project_id, err);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    println!("webhook dispatcher missed {} event(s)", missed)
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    std::env::set_var("RUST_LOG", "debug");
-    std::env::set_var("RUST_LOG", "actix_web=info");
-    env_logger::init();
 
-    let data_path = var("DATA_PATH").expect("Couldn't find DATA_PATH from environment variable.");
-    let input_path =
-        var("INPUT_PATH").expect("Couldn't find INPUT_PATH from environment variable.");
+    let args: Vec<String> = args().skip(1).collect();
+    let (config, positional) = match Config::load(&args) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    env_logger::Builder::new()
+        .parse_filters(&config.server.log_level)
+        .init();
 
-    let port = var("PORT")
-        .unwrap_or("8080".to_owned())
-        .parse::<u16>()
-        .unwrap();
+    init_blob_store(&config.blob_store, &config.storage.data_path)
+        .map_err(std::io::Error::other)?;
 
-    println!("Starting web server.");
+    if positional.len() == 2 && positional[0] == "migrate-keys" {
+        return migrations::migrate_keys(&config.storage.data_path, &positional[1])
+            .await
+            .map_err(std::io::Error::other);
+    }
+    if positional.len() == 2 && positional[0] == "migrate-content-types" {
+        return migrations::migrate_content_types(&config.storage.data_path, &positional[1])
+            .await
+            .map_err(std::io::Error::other);
+    }
 
-    let app_data_var = app_data::AppData {
-        data_path,
-        input_path,
+    init_password_hashing(&config.password_hash).map_err(std::io::Error::other)?;
+    jwt_token::init_jwt_keys(&config.jwt).map_err(std::io::Error::other)?;
+
+    println!(
+        "Starting web server on {}:{}.",
+        config.server.host, config.server.port
+    );
+
+    let revoked_tokens = read_revoked_tokens(&config.storage.data_path);
+    let rate_limiter = RateLimiter::from_config(&config.rate_limits);
+    let bind_address = (config.server.host.clone(), config.server.port);
+
+    let app_data_var = AppData {
+        config: Arc::new(config),
+        key_ring: Arc::new(RwLock::new(HashMap::new())),
+        rotation_jobs: Arc::new(Mutex::new(HashSet::new())),
+        revoked_tokens: Arc::new(RwLock::new(revoked_tokens)),
+        login_throttle: LoginThrottle::default(),
+        rate_limiter,
+        file_locks: FileLocks::default(),
     };
 
+    spawn_scheduled_backups(app_data_var.config.clone());
+    spawn_webhook_dispatcher(app_data_var.config.clone());
+
     HttpServer::new(move || {
         let bearer_middleware = HttpAuthentication::bearer(jwt_validator);
 
         App::new()
             .app_data(web::Data::new(app_data_var.clone()))
             // .service(web::scope("/api").service(index))
+            .configure(well_known_routes)
+            .configure(openapi_routes)
             .service(
-                web::scope("/api/auth").configure(project_pre_auth),
+                web::scope("/api/auth")
+                    .wrap(RateLimit::new("auth"))
+                    .configure(user_pre_auth)
+                    .configure(session_pre_auth)
+                    .configure(project_pre_auth),
                 // .service(user_login)
                 // .service(register_user),
             )
             .service(
                 web::scope("/api")
                     .wrap(bearer_middleware)
+                    .configure(project_routes)
+                    .configure(user_routes)
+                    .configure(member_routes)
+                    .configure(api_key_routes)
+                    .configure(audit_log_routes)
+                    .configure(project_event_routes)
+                    .configure(webhook_routes)
                     .configure(image_routes),
                 // .configure(user_info_config)
                 // .configure(user_file_config)
@@ -64,7 +183,7 @@ async fn main() -> std::io::Result<()> {
             )
             .wrap(Logger::default())
     })
-    .bind(("127.0.0.1", port))?
+    .bind(bind_address)?
     .run()
     .await
 }
@@ -1 +1,3 @@
 pub mod auth;
+pub mod login_throttle;
+pub mod rate_limit;
@@ -1,22 +1,74 @@
-use actix_web::{dev::ServiceRequest, Error, HttpMessage};
+use actix_web::{dev::ServiceRequest, web, Error, HttpMessage, HttpResponse};
 use actix_web_httpauth::extractors::{
     bearer::{self, BearerAuth},
     AuthenticationError,
 };
 
-use crate::utility::jwt_token::validate_token;
+use std::net::IpAddr;
+use uuid::Uuid;
 
+use crate::app_data::AppData;
+use crate::models::api_key::{verify_api_key, ApiKeyErrors, API_KEY_PREFIX};
+use crate::models::audit_log::*;
+use crate::models::user_info::Permission;
+use crate::utility::jwt_token::{validate_token, Claims};
+
+/// Accepts either a JWT from login or an API key as bearer token.
 pub async fn jwt_validator(
     req: ServiceRequest,
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
     let jwt_token = credentials.token();
-    match validate_token(jwt_token) {
-        Ok(token) => {
-            req.extensions_mut().insert(token);
+    let data = req.app_data::<web::Data<AppData>>().cloned();
+
+    let ip = req.peer_addr().map(|addr| addr.ip());
+
+    let claims = match jwt_token.starts_with(API_KEY_PREFIX) {
+        true => data.and_then(|data| {
+            match verify_api_key(&data.config.storage.data_path, jwt_token, ip) {
+                Ok(api_key) => Some(api_key.claims()),
+                Err(err) => {
+                    println!("{}", err);
+                    // a known key used where it may not be, worth a line in the audit log
+                    if let ApiKeyErrors::ApiKeyExpired { project_id, key_id }
+                    | ApiKeyErrors::IpNotAllowed { project_id, key_id } = err
+                    {
+                        let entry = AuditEntry::new(
+                            AuditActor::ApiKey(key_id),
+                            AuditAction::AuthRejected,
+                            AuditOutcome::Failure,
+                        );
+                        audit_rejected(&data, &project_id, entry.with_detail(err.to_string()), ip);
+                    }
+                    None
+                }
+            }
+        }),
+        false => validate_token(jwt_token).ok().filter(|claims| match &data {
+            Some(data) if data.is_token_revoked(&claims.jti) => {
+                let entry = AuditEntry::new(
+                    claims.into(),
+                    AuditAction::AuthRejected,
+                    AuditOutcome::Failure,
+                );
+                audit_rejected(
+                    data,
+                    &claims.project_id,
+                    entry.with_detail("revoked access token"),
+                    ip,
+                );
+                false
+            }
+            _ => true,
+        }),
+    };
+
+    match claims {
+        Some(claims) => {
+            req.extensions_mut().insert(claims);
             Ok(req)
         }
-        Err(_) => {
+        None => {
             let config = req
                 .app_data::<bearer::Config>()
                 .cloned()
@@ -26,3 +78,30 @@ pub async fn jwt_validator(
         }
     }
 }
+
+fn audit_rejected(data: &AppData, project_id: &Uuid, mut entry: AuditEntry, ip: Option<IpAddr>) {
+    entry.ip = ip.map(|ip| ip.to_string());
+    if let Err(err) = write_audit_entry(&data.config.storage.data_path, project_id, &entry) {
+        println!("{}: {:?}", err, entry);
+    }
+}
+
+/// Checks that the role in the token, or the scopes of the API key, grant
+/// `permission`, for use at the top of every `/api` handler.
+pub fn check_permission(claims: &Claims, permission: Permission) -> Result<(), HttpResponse> {
+    match &claims.scopes {
+        Some(scopes) => match scopes.iter().any(|scope| scope.allows(permission)) {
+            true => Ok(()),
+            false => {
+                Err(HttpResponse::Forbidden().body("The API key has no scope that allows this."))
+            }
+        },
+        None => match claims.role.allows(permission) {
+            true => Ok(()),
+            false => Err(HttpResponse::Forbidden().body(format!(
+                "The {:?} role is not allowed to do this.",
+                claims.role
+            ))),
+        },
+    }
+}
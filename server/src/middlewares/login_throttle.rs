@@ -0,0 +1,275 @@
+use actix_web::HttpResponse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::utility::file_utilities::{login_unlocks_json, object_to_byte_vec, replace_file_atomic};
+
+/// How many failed logins are tolerated before they slow down and lock out.
+pub struct LoginPolicy {
+    /// Failures allowed without any delay.
+    pub free_attempts: u32,
+    /// Upper bound of the delay, which doubles with every further failure.
+    pub max_backoff: Duration,
+    /// Failures after which logins are refused for `lockout`.
+    pub lockout_failures: u32,
+    pub lockout: Duration,
+}
+
+/// Failures are forgotten after this long without a new one.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Per project (or user) name, whether or not it exists.
+pub const NAME_POLICY: LoginPolicy = LoginPolicy {
+    free_attempts: 3,
+    max_backoff: Duration::from_secs(5 * 60),
+    lockout_failures: 10,
+    lockout: Duration::from_secs(15 * 60),
+};
+
+/// Per client address. Looser, several people may share one address.
+pub const IP_POLICY: LoginPolicy = LoginPolicy {
+    free_attempts: 10,
+    max_backoff: Duration::from_secs(5 * 60),
+    lockout_failures: 50,
+    lockout: Duration::from_secs(60 * 60),
+};
+
+#[derive(Debug, Clone)]
+struct FailedLogins {
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Instant,
+    /// `ip:` keys the failures of a name key came from.
+    addresses: HashSet<String>,
+}
+
+/// Failed login attempts kept in memory, keyed by `project:<name>`,
+/// `user:<name>` or `ip:<address>`.
+#[derive(Debug, Clone, Default)]
+pub struct LoginThrottle {
+    attempts: Arc<Mutex<HashMap<String, FailedLogins>>>,
+}
+
+pub fn project_key(project_name: &str) -> String {
+    format!("project:{}", project_name)
+}
+
+pub fn user_key(user_name: &str) -> String {
+    format!("user:{}", user_name)
+}
+
+pub fn ip_key(ip: Option<IpAddr>) -> String {
+    match ip {
+        Some(ip) => format!("ip:{}", ip),
+        None => "ip:unknown".to_owned(),
+    }
+}
+
+impl LoginThrottle {
+    /// `Err` with the time left when any of `keys` may not try again yet.
+    pub fn check(&self, keys: &[&str]) -> Result<(), Duration> {
+        let attempts = self.attempts.lock().unwrap();
+        let now = Instant::now();
+
+        let wait = keys
+            .iter()
+            .filter_map(|key| attempts.get(*key))
+            .map(|failed| failed.blocked_until.saturating_duration_since(now))
+            .max()
+            .unwrap_or(Duration::ZERO);
+
+        match wait.is_zero() {
+            true => Ok(()),
+            false => Err(wait),
+        }
+    }
+
+    pub fn record_failure(&self, keys: &[(&str, &LoginPolicy)]) {
+        let mut attempts = self.attempts.lock().unwrap();
+        let now = Instant::now();
+
+        // drop entries nobody tried for a while, so the map doesn't grow forever
+        attempts.retain(|_, failed| {
+            failed.blocked_until > now || now - failed.last_failure < FORGET_AFTER
+        });
+
+        let addresses: Vec<&str> = keys
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|key| key.starts_with("ip:"))
+            .collect();
+
+        for (key, policy) in keys {
+            let failed = attempts
+                .entry(key.to_string())
+                .or_insert_with(|| FailedLogins {
+                    failures: 0,
+                    last_failure: now,
+                    blocked_until: now,
+                    addresses: HashSet::new(),
+                });
+
+            if now - failed.last_failure >= FORGET_AFTER {
+                failed.failures = 0;
+            }
+            failed.failures += 1;
+            failed.last_failure = now;
+            failed.blocked_until = now + delay(failed.failures, policy);
+            if !key.starts_with("ip:") {
+                failed
+                    .addresses
+                    .extend(addresses.iter().map(|address| address.to_string()));
+            }
+        }
+    }
+
+    /// Forgets the failures of a key, after a successful login.
+    pub fn clear(&self, key: &str) {
+        self.attempts.lock().unwrap().remove(key);
+    }
+
+    /// Lifts the lockout of a name for an admin: forgets its failures and
+    /// those of the addresses that failed to log in to it.
+    pub fn unlock(&self, key: &str) {
+        let mut attempts = self.attempts.lock().unwrap();
+
+        if let Some(failed) = attempts.remove(key) {
+            for address in failed.addresses {
+                attempts.remove(&address);
+            }
+        }
+    }
+
+    /// Applies the unlocks `sorter-admin unlock` left in the data folder,
+    /// which can't reach the memory of a running server.
+    pub fn apply_unlock_requests(&self, data_path: &str) {
+        let path = login_unlocks_json(data_path);
+        if !path.exists() {
+            return;
+        }
+
+        for key in read_unlock_requests(data_path) {
+            self.unlock(&key);
+        }
+        if let Err(err) = fs::remove_file(&path) {
+            println!("{}: {}", path.display(), err);
+        }
+    }
+}
+
+fn read_unlock_requests(data_path: &str) -> Vec<String> {
+    fs::read_to_string(login_unlocks_json(data_path))
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Leaves an unlock of `key` for the server to apply on the next login.
+pub fn request_unlock(data_path: &str, key: &str) -> std::io::Result<()> {
+    let mut keys = read_unlock_requests(data_path);
+    if !keys.iter().any(|requested| requested == key) {
+        keys.push(key.to_owned());
+    }
+
+    replace_file_atomic(
+        &login_unlocks_json(data_path),
+        object_to_byte_vec(&keys).as_slice(),
+    )
+}
+
+fn delay(failures: u32, policy: &LoginPolicy) -> Duration {
+    if failures >= policy.lockout_failures {
+        return policy.lockout;
+    }
+    if failures <= policy.free_attempts {
+        return Duration::ZERO;
+    }
+
+    let exponent = (failures - policy.free_attempts - 1).min(16);
+    Duration::from_secs(1 << exponent).min(policy.max_backoff)
+}
+
+/// The response for a login that is refused because of earlier failures.
+pub fn too_many_attempts(retry_after: Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", retry_after.as_secs().max(1).to_string()))
+        .body("Too many failed logins, try again later.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use uuid::Uuid;
+
+    fn lock_out(throttle: &LoginThrottle, name_key: &str, ip_key: &str) {
+        for _ in 0..NAME_POLICY.lockout_failures {
+            throttle.record_failure(&[(name_key, &NAME_POLICY), (ip_key, &IP_POLICY)]);
+        }
+    }
+
+    #[test]
+    fn locks_out_after_too_many_failures() {
+        let throttle = LoginThrottle::default();
+        let name_key = project_key("locked");
+        let ip_key = ip_key(Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))));
+
+        for _ in 0..NAME_POLICY.free_attempts {
+            throttle.record_failure(&[(&name_key, &NAME_POLICY), (&ip_key, &IP_POLICY)]);
+        }
+        assert!(throttle.check(&[&name_key, &ip_key]).is_ok());
+
+        lock_out(&throttle, &name_key, &ip_key);
+        let retry_after = throttle.check(&[&name_key, &ip_key]).unwrap_err();
+        assert!(retry_after > NAME_POLICY.max_backoff);
+    }
+
+    #[test]
+    fn unlock_clears_the_name_and_its_addresses() {
+        let throttle = LoginThrottle::default();
+        let name_key = project_key("locked");
+        let attacker = ip_key(Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))));
+        let bystander = ip_key(Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 8))));
+
+        lock_out(&throttle, &name_key, &attacker);
+        for _ in 0..IP_POLICY.lockout_failures {
+            throttle.record_failure(&[
+                (&project_key("other"), &NAME_POLICY),
+                (&bystander, &IP_POLICY),
+            ]);
+        }
+
+        throttle.unlock(&name_key);
+        assert!(throttle.check(&[&name_key, &attacker]).is_ok());
+        // addresses that never tried this name stay locked
+        assert!(throttle.check(&[&bystander]).is_err());
+    }
+
+    #[test]
+    fn applies_unlocks_left_in_the_data_folder() {
+        let data_path = std::env::temp_dir().join(format!("throttle_{}", Uuid::new_v4()));
+        fs::create_dir_all(&data_path).unwrap();
+        let data_path = data_path.to_str().unwrap();
+
+        let throttle = LoginThrottle::default();
+        let name_key = project_key("locked");
+        let ip_key = ip_key(None);
+        lock_out(&throttle, &name_key, &ip_key);
+
+        throttle.apply_unlock_requests(data_path);
+        assert!(throttle.check(&[&name_key]).is_err());
+
+        request_unlock(data_path, &name_key).unwrap();
+        request_unlock(data_path, &name_key).unwrap();
+        assert_eq!(read_unlock_requests(data_path), vec![name_key.clone()]);
+
+        throttle.apply_unlock_requests(data_path);
+        assert!(throttle.check(&[&name_key, &ip_key]).is_ok());
+        assert!(!login_unlocks_json(data_path).exists());
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+use ::serde::{Deserialize, Serialize};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error, HttpMessage, HttpResponse,
+};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::app_data::AppData;
+use crate::utility::jwt_token::Claims;
+
+/// `requests` per `per`, refilled continuously; a client may burst up to
+/// `requests` at once. Written `requests/seconds` in the config.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RateLimitRule {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is full again and can be forgotten.
+    full_at: Instant,
+}
+
+/// Token buckets of every rate limited route, keyed by route name and
+/// project id, or client IP for requests without a token.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    rules: Arc<HashMap<String, RateLimitRule>>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(rules: HashMap<String, RateLimitRule>) -> Self {
+        RateLimiter {
+            rules: Arc::new(rules),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The default rules, overridden by the `rate_limits` of the config.
+    pub fn from_config(overrides: &HashMap<String, RateLimitRule>) -> Self {
+        let mut rules = HashMap::from([
+            ("auth".to_owned(), rule(30, 60)),
+            ("save".to_owned(), rule(60, 60)),
+            ("get".to_owned(), rule(600, 60)),
+            ("tags".to_owned(), rule(120, 60)),
+            ("download".to_owned(), rule(10, 60)),
+        ]);
+        rules.extend(
+            overrides
+                .iter()
+                .map(|(route, rule)| (route.to_owned(), *rule)),
+        );
+
+        RateLimiter::new(rules)
+    }
+
+    /// Takes a token from the bucket of `key` on `route`, or returns how long
+    /// until the next one is available.
+    fn take(&self, route: &str, key: &str) -> Result<(), Duration> {
+        self.take_at(route, key, Instant::now())
+    }
+
+    fn take_at(&self, route: &str, key: &str, now: Instant) -> Result<(), Duration> {
+        let rule = match self.rules.get(route) {
+            Some(rule) if rule.requests > 0 => *rule,
+            _ => return Ok(()),
+        };
+        let refill_per_sec = rule.requests as f64 / rule.per.as_secs_f64();
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // full buckets carry no state, drop them once in a while
+        if buckets.len() > 10_000 {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+
+        let bucket = buckets
+            .entry(format!("{}:{}", route, key))
+            .or_insert(Bucket {
+                tokens: rule.requests as f64,
+                updated: now,
+                full_at: now,
+            });
+
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * refill_per_sec;
+        bucket.tokens = (bucket.tokens + refilled).min(rule.requests as f64);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / refill_per_sec,
+            ));
+        }
+
+        bucket.tokens -= 1.0;
+        bucket.full_at =
+            now + Duration::from_secs_f64((rule.requests as f64 - bucket.tokens) / refill_per_sec);
+        Ok(())
+    }
+}
+
+fn rule(requests: u32, seconds: u64) -> RateLimitRule {
+    RateLimitRule {
+        requests,
+        per: Duration::from_secs(seconds),
+    }
+}
+
+impl FromStr for RateLimitRule {
+    type Err = String;
+
+    fn from_str(limit: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' is not requests/seconds", limit);
+        let (requests, seconds) = limit.trim().split_once('/').ok_or_else(invalid)?;
+        let requests = requests.parse::<u32>().map_err(|_| invalid())?;
+        let seconds = seconds
+            .parse::<u64>()
+            .ok()
+            .filter(|seconds| *seconds > 0)
+            .ok_or_else(invalid)?;
+
+        Ok(rule(requests, seconds))
+    }
+}
+
+impl TryFrom<String> for RateLimitRule {
+    type Error = String;
+
+    fn try_from(limit: String) -> Result<Self, Self::Error> {
+        limit.parse()
+    }
+}
+
+impl From<RateLimitRule> for String {
+    fn from(rule: RateLimitRule) -> Self {
+        format!("{}/{}", rule.requests, rule.per.as_secs())
+    }
+}
+
+/// Rate limits the wrapped route with the rule named `route` in
+/// `AppData::rate_limiter`. Routes behind the bearer middleware are limited
+/// per project, others per client IP.
+pub struct RateLimit {
+    route: &'static str,
+}
+
+impl RateLimit {
+    pub fn new(route: &'static str) -> Self {
+        RateLimit { route }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service,
+            route: self.route,
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    route: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let key = match req.extensions().get::<Claims>() {
+            Some(claims) => format!("project:{}", claims.project_id),
+            None => match req.peer_addr() {
+                Some(addr) => format!("ip:{}", addr.ip()),
+                None => "ip:unknown".to_owned(),
+            },
+        };
+
+        let limited = req
+            .app_data::<web::Data<AppData>>()
+            .map(|data| data.rate_limiter.take(self.route, &key))
+            .unwrap_or(Ok(()));
+
+        if let Err(retry_after) = limited {
+            let res = HttpResponse::TooManyRequests()
+                .insert_header((
+                    "Retry-After",
+                    (retry_after.as_secs_f64().ceil() as u64).max(1).to_string(),
+                ))
+                .body("Too many requests, slow down.");
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(|res| res.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(HashMap::from([
+            ("save".to_owned(), rule(3, 60)),
+            ("off".to_owned(), rule(0, 60)),
+        ]))
+    }
+
+    #[test]
+    fn allows_a_burst_then_refills() {
+        let limiter = limiter();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            limiter.take_at("save", "project:a", start).unwrap();
+        }
+        let retry_after = limiter.take_at("save", "project:a", start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(20));
+
+        // one token every 20 seconds
+        let later = start + Duration::from_secs(19);
+        assert!(limiter.take_at("save", "project:a", later).is_err());
+        let later = start + Duration::from_secs(20);
+        limiter.take_at("save", "project:a", later).unwrap();
+        assert!(limiter.take_at("save", "project:a", later).is_err());
+    }
+
+    #[test]
+    fn refills_up_to_the_burst_size() {
+        let limiter = limiter();
+        let start = Instant::now();
+        limiter.take_at("save", "project:a", start).unwrap();
+
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..3 {
+            limiter.take_at("save", "project:a", later).unwrap();
+        }
+        assert!(limiter.take_at("save", "project:a", later).is_err());
+    }
+
+    #[test]
+    fn buckets_are_per_route_and_key() {
+        let limiter = RateLimiter::new(HashMap::from([
+            ("save".to_owned(), rule(1, 60)),
+            ("auth".to_owned(), rule(1, 60)),
+        ]));
+        let now = Instant::now();
+
+        limiter.take_at("save", "project:a", now).unwrap();
+        assert!(limiter.take_at("save", "project:a", now).is_err());
+        limiter.take_at("save", "project:b", now).unwrap();
+        limiter.take_at("auth", "project:a", now).unwrap();
+    }
+
+    #[test]
+    fn routes_without_a_limit_pass() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            limiter.take_at("off", "ip:127.0.0.1", now).unwrap();
+            limiter.take_at("unknown", "ip:127.0.0.1", now).unwrap();
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let parsed: RateLimitRule = "60/30".parse().unwrap();
+        assert_eq!(parsed.requests, 60);
+        assert_eq!(parsed.per, Duration::from_secs(30));
+        assert_eq!(String::from(parsed), "60/30");
+
+        for invalid in ["60", "60/0", "a/60", "60/b", "-1/60", ""] {
+            assert!(invalid.parse::<RateLimitRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn config_overrides_defaults() {
+        let limiter = RateLimiter::from_config(&HashMap::from([("save".to_owned(), rule(1, 60))]));
+
+        assert_eq!(limiter.rules["save"].requests, 1);
+        assert_eq!(limiter.rules["get"].requests, 600);
+    }
+}
@@ -0,0 +1,74 @@
+use std::io::stdin;
+
+use crate::models::{
+    image_data::{detect_content_types, migrate_legacy_images},
+    project_info::*,
+};
+
+/// Prints `prompt` and reads one line from stdin, e.g. a password for the
+/// command line tools.
+pub fn read_line(prompt: &str) -> Result<String, String> {
+    println!("{}", prompt);
+    let mut line = String::new();
+    stdin()
+        .read_line(&mut line)
+        .map_err(|err| err.to_string())?;
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Moves a project off the legacy password-salt image key: unwraps (or creates)
+/// the project data key and re-encrypts every image still using the old key.
+/// Meant to be run with the server stopped, `server migrate-keys <project_name>`,
+/// reading the project password from stdin.
+pub async fn migrate_keys(data_path: &str, project_name: &str) -> Result<(), String> {
+    let login = ProjectLoginInfo {
+        project_name: project_name.to_owned(),
+        password: read_line(&format!("Password for project {}:", project_name))?,
+    };
+
+    let (project, project_keys) = project_login(data_path, &login)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let migration = migrate_legacy_images(data_path, &project, &project_keys.current)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    println!(
+        "Re-encrypted {} image(s) of project {}.",
+        migration.migrated, project.project_name
+    );
+    match migration.failed.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "{} image(s) could not be re-encrypted: {:?}",
+            migration.failed.len(),
+            migration.failed
+        )),
+    }
+}
+
+/// Records the content type of images saved before types were detected on
+/// upload, `server migrate-content-types <project_name>`. Encrypted images are
+/// read with the project keys, so the project password is read from stdin.
+pub async fn migrate_content_types(data_path: &str, project_name: &str) -> Result<(), String> {
+    let login = ProjectLoginInfo {
+        project_name: project_name.to_owned(),
+        password: read_line(&format!("Password for project {}:", project_name))?,
+    };
+
+    let (project, project_keys) = project_login(data_path, &login)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let detected = detect_content_types(data_path, &project, Some(&project_keys))
+        .await
+        .map_err(|err| err.to_string())?;
+
+    println!(
+        "Detected the content type of {} image(s) of project {}.",
+        detected, project.project_name
+    );
+    Ok(())
+}
@@ -1,2 +1,12 @@
+pub mod api_key;
+pub mod audit_log;
+pub mod backup;
+pub mod fsck;
 pub mod image_data;
+pub mod key_rotation;
+pub mod project_archive;
+pub mod project_events;
 pub mod project_info;
+pub mod session;
+pub mod user_info;
+pub mod webhook;
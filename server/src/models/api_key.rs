@@ -0,0 +1,460 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{NaiveDateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+use crate::models::user_info::{Permission, Role};
+use crate::utility::file_utilities::*;
+use crate::utility::jwt_token::Claims;
+
+/// Every API key starts with this, so the auth middleware can tell keys and
+/// JWTs apart.
+pub const API_KEY_PREFIX: &str = "sorter_";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiKeyScope {
+    Read,
+    Upload,
+    Tag,
+    Admin,
+}
+
+impl ApiKeyScope {
+    pub fn allows(&self, permission: Permission) -> bool {
+        match self {
+            ApiKeyScope::Read => permission == Permission::Read,
+            ApiKeyScope::Upload => permission == Permission::Upload,
+            ApiKeyScope::Tag => permission == Permission::Tag,
+            ApiKeyScope::Admin => true,
+        }
+    }
+}
+
+/// An API key of a project, stored in `api_keys.json`. Only a SHA-256 hash of
+/// the key is kept, the key itself is shown once when it is created.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub key_id: Uuid,
+    pub project_id: Uuid,
+    pub name: String,
+    /// Start of the key, e.g. `sorter_1a2b3c4d`, to recognise it in listings.
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<ApiKeyScope>,
+    /// IP addresses or CIDR ranges the key may be used from, any if empty.
+    pub allowed_ips: Vec<String>,
+    pub expires_date: Option<NaiveDateTime>,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    #[serde(default)]
+    pub expires_date: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKeyInfo {
+    pub key_id: Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub allowed_ips: Vec<String>,
+    pub expires_date: Option<NaiveDateTime>,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    pub api_key: String,
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+}
+
+#[derive(Debug)]
+pub enum ApiKeyErrors {
+    InvalidApiKey,
+    ApiKeyExpired { project_id: Uuid, key_id: Uuid },
+    IpNotAllowed { project_id: Uuid, key_id: Uuid },
+    InvalidIpAllowlist(String),
+    NoScopes,
+    ApiKeyDosentExist,
+    FailedToReadApiKeys,
+    FailedToSaveApiKeys,
+}
+
+impl fmt::Display for ApiKeyErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiKeyErrors::InvalidApiKey => write!(f, "invalid API key"),
+            ApiKeyErrors::ApiKeyExpired { .. } => write!(f, "API key expired"),
+            ApiKeyErrors::IpNotAllowed { .. } => {
+                write!(f, "API key can't be used from this address")
+            }
+            ApiKeyErrors::InvalidIpAllowlist(entry) => {
+                write!(f, "'{}' is not an IP address or CIDR range", entry)
+            }
+            ApiKeyErrors::NoScopes => write!(f, "an API key needs at least one scope"),
+            ApiKeyErrors::ApiKeyDosentExist => write!(f, "API key doesn't exist"),
+            ApiKeyErrors::FailedToReadApiKeys => write!(f, "failed to read API keys"),
+            ApiKeyErrors::FailedToSaveApiKeys => write!(f, "failed to save API keys"),
+        }
+    }
+}
+
+impl From<&ApiKey> for ApiKeyInfo {
+    fn from(api_key: &ApiKey) -> Self {
+        ApiKeyInfo {
+            key_id: api_key.key_id,
+            name: api_key.name.clone(),
+            prefix: api_key.prefix.clone(),
+            scopes: api_key.scopes.clone(),
+            allowed_ips: api_key.allowed_ips.clone(),
+            expires_date: api_key.expires_date,
+            created_date: api_key.created_date,
+        }
+    }
+}
+
+impl ApiKey {
+    /// Claims for requests made with this key. Permissions come from
+    /// `scopes`, the role is never consulted.
+    pub fn claims(&self) -> Claims {
+        Claims {
+            nbf: self.created_date.timestamp() as u64,
+            iat: self.created_date.timestamp() as u64,
+            exp: self
+                .expires_date
+                .map(|date| date.timestamp() as u64)
+                .unwrap_or(u64::MAX),
+            iss: String::new(),
+            aud: String::new(),
+            project_id: self.project_id,
+            user_id: None,
+            role: Role::Viewer,
+            jti: self.key_id,
+            scopes: Some(self.scopes.clone()),
+        }
+    }
+
+    fn allows_ip(&self, ip: Option<IpAddr>) -> bool {
+        if self.allowed_ips.is_empty() {
+            return true;
+        }
+
+        match ip {
+            Some(ip) => self.allowed_ips.iter().any(|entry| ip_matches(entry, &ip)),
+            None => false,
+        }
+    }
+}
+
+fn hash_api_key(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
+
+/// Parses an allowlist entry, an address or a CIDR range, into the network
+/// and its prefix length. `None` when it isn't either, or the prefix is longer
+/// than the address.
+fn parse_ip_entry(entry: &str) -> Option<(IpAddr, u32)> {
+    let (network, prefix_len) = match entry.split_once('/') {
+        Some((network, prefix_len)) => (
+            network.parse::<IpAddr>().ok()?,
+            Some(prefix_len.parse::<u32>().ok()?),
+        ),
+        None => (entry.parse::<IpAddr>().ok()?, None),
+    };
+
+    let max_len = match network {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    match prefix_len.unwrap_or(max_len) {
+        prefix_len if prefix_len <= max_len => Some((network, prefix_len)),
+        _ => None,
+    }
+}
+
+/// Whether `ip` is the address or falls in the CIDR range `entry`. IPv4 peers
+/// that connected over IPv6 (`::ffff:a.b.c.d`) match IPv4 entries.
+fn ip_matches(entry: &str, ip: &IpAddr) -> bool {
+    let Some((network, prefix_len)) = parse_ip_entry(entry) else {
+        return false;
+    };
+
+    match (network, ip.to_canonical()) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), ip) => {
+            let ip = match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        (IpAddr::V4(_), IpAddr::V6(_)) => false,
+    }
+}
+
+/// Held across every read-modify-write of `api_keys.json`, which all
+/// projects share.
+static API_KEYS_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+pub async fn create_api_key(
+    data_path: &str,
+    project_id: &Uuid,
+    new_key: &NewApiKey,
+) -> Result<CreatedApiKey, ApiKeyErrors> {
+    if new_key.scopes.is_empty() {
+        return Err(ApiKeyErrors::NoScopes);
+    }
+    if let Some(entry) = new_key
+        .allowed_ips
+        .iter()
+        .find(|entry| parse_ip_entry(entry).is_none())
+    {
+        return Err(ApiKeyErrors::InvalidIpAllowlist(entry.clone()));
+    }
+
+    let _api_keys = API_KEYS_LOCK.lock().await;
+    let mut api_keys = read_api_keys(data_path)?;
+
+    let prefix = format!(
+        "{}{}",
+        API_KEY_PREFIX,
+        hex::encode(rand::random::<[u8; 4]>())
+    );
+    let key = format!("{}_{}", prefix, hex::encode(rand::random::<[u8; 32]>()));
+
+    let api_key = ApiKey {
+        key_id: Uuid::new_v4(),
+        project_id: *project_id,
+        name: new_key.name.clone(),
+        prefix,
+        key_hash: hash_api_key(&key),
+        scopes: new_key.scopes.clone(),
+        allowed_ips: new_key.allowed_ips.clone(),
+        expires_date: new_key.expires_date,
+        created_date: Utc::now().naive_utc(),
+    };
+    api_keys.push(api_key.clone());
+    write_api_keys(data_path, &api_keys)?;
+
+    Ok(CreatedApiKey {
+        api_key: key,
+        info: ApiKeyInfo::from(&api_key),
+    })
+}
+
+pub async fn get_api_keys(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<Vec<ApiKeyInfo>, ApiKeyErrors> {
+    Ok(read_api_keys(data_path)?
+        .iter()
+        .filter(|api_key| api_key.project_id == *project_id)
+        .map(ApiKeyInfo::from)
+        .collect())
+}
+
+pub async fn revoke_api_key(
+    data_path: &str,
+    project_id: &Uuid,
+    key_id: &Uuid,
+) -> Result<(), ApiKeyErrors> {
+    let _api_keys = API_KEYS_LOCK.lock().await;
+    let mut api_keys = read_api_keys(data_path)?;
+    let count = api_keys.len();
+
+    api_keys.retain(|api_key| !(api_key.project_id == *project_id && api_key.key_id == *key_id));
+    if api_keys.len() == count {
+        return Err(ApiKeyErrors::ApiKeyDosentExist);
+    }
+
+    write_api_keys(data_path, &api_keys)
+}
+
+/// Removes every API key of a project.
+pub async fn delete_project_api_keys(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<(), ApiKeyErrors> {
+    let _api_keys = API_KEYS_LOCK.lock().await;
+    let mut api_keys = read_api_keys(data_path)?;
+    api_keys.retain(|api_key| api_key.project_id != *project_id);
+
+    write_api_keys(data_path, &api_keys)
+}
+
+/// Looks up the key sent by a client and checks its expiry and IP allowlist.
+pub fn verify_api_key(
+    data_path: &str,
+    key: &str,
+    ip: Option<IpAddr>,
+) -> Result<ApiKey, ApiKeyErrors> {
+    let key_hash = hash_api_key(key);
+
+    let api_key = read_api_keys(data_path)?
+        .into_iter()
+        .find(|api_key| api_key.key_hash == key_hash)
+        .ok_or(ApiKeyErrors::InvalidApiKey)?;
+
+    if let Some(expires_date) = api_key.expires_date {
+        if expires_date <= Utc::now().naive_utc() {
+            return Err(ApiKeyErrors::ApiKeyExpired {
+                project_id: api_key.project_id,
+                key_id: api_key.key_id,
+            });
+        }
+    }
+
+    if !api_key.allows_ip(ip) {
+        return Err(ApiKeyErrors::IpNotAllowed {
+            project_id: api_key.project_id,
+            key_id: api_key.key_id,
+        });
+    }
+
+    Ok(api_key)
+}
+
+fn read_api_keys(data_path: &str) -> Result<Vec<ApiKey>, ApiKeyErrors> {
+    let path = api_keys_json(data_path);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let data = fs::read_to_string(path).map_err(|_| ApiKeyErrors::FailedToReadApiKeys)?;
+    serde_json::from_str(&data).map_err(|_| ApiKeyErrors::FailedToReadApiKeys)
+}
+
+fn write_api_keys(data_path: &str, api_keys: &[ApiKey]) -> Result<(), ApiKeyErrors> {
+    replace_file_atomic(
+        &api_keys_json(data_path),
+        object_to_byte_vec(api_keys).as_slice(),
+    )
+    .map_err(|_| ApiKeyErrors::FailedToSaveApiKeys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_each_allowlist_entry() {
+        assert!(parse_ip_entry("10.0.0.1").is_some());
+        assert!(parse_ip_entry("10.0.0.0/8").is_some());
+        assert!(parse_ip_entry("0.0.0.0/0").is_some());
+        assert!(parse_ip_entry("2001:db8::/32").is_some());
+        assert!(parse_ip_entry("2001:db8::/128").is_some());
+
+        assert!(parse_ip_entry("10.0.0.0/33").is_none());
+        assert!(parse_ip_entry("2001:db8::/129").is_none());
+        assert!(parse_ip_entry("2001:db8::/").is_none());
+        assert!(parse_ip_entry("example.com").is_none());
+    }
+
+    #[test]
+    fn matches_addresses_and_ranges() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert!(ip_matches("10.0.0.1", &ip("10.0.0.1")));
+        assert!(!ip_matches("10.0.0.1", &ip("10.0.0.2")));
+        assert!(ip_matches("10.0.0.0/8", &ip("10.200.3.4")));
+        assert!(!ip_matches("10.0.0.0/8", &ip("11.0.0.1")));
+        assert!(ip_matches("0.0.0.0/0", &ip("198.51.100.1")));
+        assert!(ip_matches("2001:db8::/32", &ip("2001:db8:1::5")));
+        assert!(!ip_matches("2001:db8::/32", &ip("2001:db9::5")));
+        assert!(!ip_matches("2001:db8::/129", &ip("2001:db8::")));
+
+        // IPv4 peers on a dual stack socket
+        assert!(ip_matches("10.0.0.0/8", &ip("::ffff:10.1.2.3")));
+        assert!(ip_matches("::ffff:10.0.0.0/104", &ip("10.1.2.3")));
+        assert!(!ip_matches("10.0.0.0/8", &ip("::1")));
+    }
+
+    fn new_key(n: usize) -> NewApiKey {
+        NewApiKey {
+            name: format!("key {}", n),
+            scopes: vec![ApiKeyScope::Read],
+            allowed_ips: Vec::new(),
+            expires_date: None,
+        }
+    }
+
+    #[test]
+    fn concurrent_changes_keep_every_key() {
+        let data_path = std::env::temp_dir().join(format!("api_keys_{}", Uuid::new_v4()));
+        fs::create_dir_all(&data_path).unwrap();
+        let data_path = data_path.to_str().unwrap().to_owned();
+        let (project_id, deleted_project_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let revoked = actix_web::rt::System::new().block_on(async {
+            let mut revoked = vec![];
+            for n in 0..4 {
+                create_api_key(&data_path, &deleted_project_id, &new_key(n))
+                    .await
+                    .unwrap();
+                let created = create_api_key(&data_path, &project_id, &new_key(n))
+                    .await
+                    .unwrap();
+                revoked.push(created.info.key_id);
+            }
+            revoked
+        });
+
+        // every worker has its own runtime, like the server's workers
+        let mut workers: Vec<_> = (0..16)
+            .map(|n| {
+                let data_path = data_path.clone();
+                std::thread::spawn(move || {
+                    actix_web::rt::System::new().block_on(async {
+                        create_api_key(&data_path, &project_id, &new_key(n))
+                            .await
+                            .unwrap();
+                    })
+                })
+            })
+            .collect();
+        workers.extend(revoked.into_iter().map(|key_id| {
+            let data_path = data_path.clone();
+            std::thread::spawn(move || {
+                actix_web::rt::System::new().block_on(async {
+                    revoke_api_key(&data_path, &project_id, &key_id)
+                        .await
+                        .unwrap();
+                })
+            })
+        }));
+        workers.push({
+            let data_path = data_path.clone();
+            std::thread::spawn(move || {
+                actix_web::rt::System::new().block_on(async {
+                    delete_project_api_keys(&data_path, &deleted_project_id)
+                        .await
+                        .unwrap();
+                })
+            })
+        });
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let api_keys = read_api_keys(&data_path).unwrap();
+        assert_eq!(api_keys.len(), 16);
+        assert!(api_keys.iter().all(|key| key.project_id == project_id));
+
+        fs::remove_dir_all(&data_path).unwrap();
+    }
+}
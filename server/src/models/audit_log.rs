@@ -0,0 +1,201 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{NaiveDateTime, Utc};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use uuid::Uuid;
+
+use crate::utility::file_utilities::*;
+use crate::utility::jwt_token::Claims;
+
+/// Who did something: the shared project login, a user, an API key, or
+/// somebody who didn't get in.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum AuditActor {
+    Project,
+    User(Uuid),
+    ApiKey(Uuid),
+    Anonymous,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    Logout,
+    AuthRejected,
+    Upload,
+    Fetch,
+    Download,
+    Tag,
+    Delete,
+    PasswordChange,
+    KeyRotation,
+    LoginUnlock,
+    MemberAdd,
+    MemberUpdate,
+    MemberRemove,
+    ApiKeyCreate,
+    ApiKeyRevoke,
+    Fsck,
+    Export,
+    Import,
+    WebhookCreate,
+    WebhookDelete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+/// One line of a project's `audit_log.jsonl`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: NaiveDateTime,
+    pub actor: AuditActor,
+    pub action: AuditAction,
+    pub image_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub outcome: AuditOutcome,
+    /// Why it failed, or what else is worth knowing.
+    pub detail: Option<String>,
+}
+
+/// Filters for reading the audit log, all optional.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    /// User or API key id.
+    pub actor_id: Option<Uuid>,
+    pub image_id: Option<Uuid>,
+    pub outcome: Option<AuditOutcome>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    /// Return only the newest `limit` matching entries.
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum AuditLogErrors {
+    FailedToWriteAuditLog,
+    FailedToReadAuditLog,
+}
+
+impl fmt::Display for AuditLogErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditLogErrors::FailedToWriteAuditLog => write!(f, "failed to write the audit log"),
+            AuditLogErrors::FailedToReadAuditLog => write!(f, "failed to read the audit log"),
+        }
+    }
+}
+
+impl From<&Claims> for AuditActor {
+    fn from(claims: &Claims) -> Self {
+        match (&claims.scopes, claims.user_id) {
+            (Some(_), _) => AuditActor::ApiKey(claims.jti),
+            (None, Some(user_id)) => AuditActor::User(user_id),
+            (None, None) => AuditActor::Project,
+        }
+    }
+}
+
+impl AuditEntry {
+    pub fn new(actor: AuditActor, action: AuditAction, outcome: AuditOutcome) -> Self {
+        AuditEntry {
+            timestamp: Utc::now().naive_utc(),
+            actor,
+            action,
+            image_id: None,
+            ip: None,
+            outcome,
+            detail: None,
+        }
+    }
+
+    /// An entry for the outcome of `result`, with the error as detail.
+    pub fn from_result<T, E: fmt::Display>(
+        actor: AuditActor,
+        action: AuditAction,
+        result: &Result<T, E>,
+    ) -> Self {
+        match result {
+            Ok(_) => AuditEntry::new(actor, action, AuditOutcome::Success),
+            Err(err) => {
+                AuditEntry::new(actor, action, AuditOutcome::Failure).with_detail(err.to_string())
+            }
+        }
+    }
+
+    pub fn with_image(mut self, image_id: Uuid) -> Self {
+        self.image_id = Some(image_id);
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    fn matches(&self, query: &AuditQuery) -> bool {
+        let actor_id = match self.actor {
+            AuditActor::User(id) | AuditActor::ApiKey(id) => Some(id),
+            _ => None,
+        };
+
+        query.action.is_none_or(|action| self.action == action)
+            && query.actor_id.is_none_or(|id| actor_id == Some(id))
+            && query.image_id.is_none_or(|id| self.image_id == Some(id))
+            && query.outcome.is_none_or(|outcome| self.outcome == outcome)
+            && query.from.is_none_or(|from| self.timestamp >= from)
+            && query.to.is_none_or(|to| self.timestamp <= to)
+    }
+}
+
+/// Appends an entry to the project's audit log. Entries are only ever
+/// appended, never changed or removed.
+pub fn write_audit_entry(
+    data_path: &str,
+    project_id: &Uuid,
+    entry: &AuditEntry,
+) -> Result<(), AuditLogErrors> {
+    let mut line = serde_json::to_vec(entry).map_err(|_| AuditLogErrors::FailedToWriteAuditLog)?;
+    line.push(b'\n');
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(audit_log_jsonl(data_path, project_id))
+        .and_then(|mut file| file.write_all(&line))
+        .map_err(|_| AuditLogErrors::FailedToWriteAuditLog)
+}
+
+/// The entries matching `query`, oldest first.
+pub async fn read_audit_log(
+    data_path: &str,
+    project_id: &Uuid,
+    query: &AuditQuery,
+) -> Result<Vec<AuditEntry>, AuditLogErrors> {
+    let path = audit_log_jsonl(data_path, project_id);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let data = fs::read_to_string(path).map_err(|_| AuditLogErrors::FailedToReadAuditLog)?;
+    let mut entries: Vec<AuditEntry> = data
+        .lines()
+        .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+        .filter(|entry| entry.matches(query))
+        .collect();
+
+    if let Some(limit) = query.limit {
+        let skip = entries.len().saturating_sub(limit);
+        entries.drain(..skip);
+    }
+
+    Ok(entries)
+}
@@ -0,0 +1,425 @@
+use ::serde::{de::DeserializeOwned, Deserialize, Serialize};
+use chrono::{Duration, NaiveDateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+
+use crate::config::BackupConfig;
+use crate::models::project_info::ProjectInfo;
+use crate::utility::encrypted_blob::hash_file;
+use crate::utility::file_utilities::*;
+
+/// Folders of the data folder that are never backed up.
+const SKIPPED_DIRS: [&str; 2] = ["quarantine", "imports"];
+
+/// One backup of the data folder. The files themselves are kept once per
+/// content in the `blobs` folder of the target, so a snapshot only adds the
+/// files that are new or changed since the one before.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub snapshot_id: String,
+    pub created_date: NaiveDateTime,
+    pub files: Vec<SnapshotFile>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// Relative to the data folder, with `/` separators.
+    pub path: String,
+    pub size: u64,
+    /// Modification time in nanoseconds, with the size used to tell whether
+    /// a file changed without reading it.
+    pub modified: u64,
+    pub sha256: String,
+}
+
+/// An entry of `catalog.json` in the backup target.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotSummary {
+    pub snapshot_id: String,
+    pub created_date: NaiveDateTime,
+    pub files: usize,
+    pub bytes: u64,
+    /// What this snapshot added to the target.
+    pub new_bytes: u64,
+}
+
+#[derive(Debug)]
+pub enum BackupErrors {
+    SnapshotDosentExist,
+    ProjectDosentExist,
+    ProjectAllreadyExists,
+    FailedToReadFile(String),
+    FailedToWriteFile(String),
+    Corrupted(String),
+}
+
+impl fmt::Display for BackupErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupErrors::SnapshotDosentExist => write!(f, "snapshot doesn't exist"),
+            BackupErrors::ProjectDosentExist => write!(f, "project isn't in the snapshot"),
+            BackupErrors::ProjectAllreadyExists => {
+                write!(f, "another project has the name of the restored one")
+            }
+            BackupErrors::FailedToReadFile(err) => write!(f, "failed to read: {}", err),
+            BackupErrors::FailedToWriteFile(err) => write!(f, "failed to write: {}", err),
+            BackupErrors::Corrupted(err) => write!(f, "backup is corrupted: {}", err),
+        }
+    }
+}
+
+fn read_error(path: &Path, err: impl fmt::Display) -> BackupErrors {
+    BackupErrors::FailedToReadFile(format!("{:?}: {}", path, err))
+}
+
+fn write_error(path: &Path, err: impl fmt::Display) -> BackupErrors {
+    BackupErrors::FailedToWriteFile(format!("{:?}: {}", path, err))
+}
+
+fn catalog_json(target: &Path) -> PathBuf {
+    target.join("catalog.json")
+}
+
+fn snapshot_json(target: &Path, snapshot_id: &str) -> PathBuf {
+    target
+        .join("snapshots")
+        .join(format!("{}.json", snapshot_id))
+}
+
+fn blob_path(target: &Path, sha256: &str) -> PathBuf {
+    target.join("blobs").join(&sha256[..2]).join(sha256)
+}
+
+/// Backs up the data folder to `target`. Files whose size and modification
+/// time match the last snapshot aren't read again, and only content the target
+/// doesn't have yet is copied. Old snapshots are pruned per `config` afterwards.
+pub fn create_snapshot(
+    data_path: &str,
+    target: &Path,
+    config: &BackupConfig,
+) -> Result<SnapshotSummary, BackupErrors> {
+    let mut catalog = read_catalog(target)?;
+    let previous: HashMap<String, SnapshotFile> = match catalog.last() {
+        Some(last) => read_snapshot(target, &last.snapshot_id)?
+            .files
+            .into_iter()
+            .map(|file| (file.path.to_owned(), file))
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    let now = Utc::now().naive_utc();
+    let mut snapshot = Snapshot {
+        snapshot_id: now.format("%Y%m%dT%H%M%S%.3fZ").to_string(),
+        created_date: now,
+        files: vec![],
+    };
+    let mut new_bytes = 0;
+
+    for path in data_files(Path::new(data_path))? {
+        let relative = relative_path(data_path, &path);
+        let metadata = path.metadata().map_err(|err| read_error(&path, err))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_nanos() as u64)
+            .unwrap_or_default();
+
+        let sha256 = match previous.get(&relative) {
+            Some(file) if file.size == metadata.len() && file.modified == modified => {
+                file.sha256.to_owned()
+            }
+            _ => hex::encode(hash_file(&path).map_err(|err| read_error(&path, err))?),
+        };
+
+        let stored = blob_path(target, &sha256);
+        if !stored.exists() {
+            copy_atomic(&path, &stored)?;
+            new_bytes += metadata.len();
+        }
+
+        snapshot.files.push(SnapshotFile {
+            path: relative,
+            size: metadata.len(),
+            modified,
+            sha256,
+        });
+    }
+
+    let snapshot_path = snapshot_json(target, &snapshot.snapshot_id);
+    fs::create_dir_all(snapshot_path.parent().unwrap())
+        .and_then(|_| replace_file_atomic(&snapshot_path, &object_to_byte_vec(&snapshot)))
+        .map_err(|err| write_error(&snapshot_path, err))?;
+
+    let summary = SnapshotSummary {
+        snapshot_id: snapshot.snapshot_id.to_owned(),
+        created_date: snapshot.created_date,
+        files: snapshot.files.len(),
+        bytes: snapshot.files.iter().map(|file| file.size).sum(),
+        new_bytes,
+    };
+    catalog.push(summary.clone());
+    write_catalog(target, &catalog)?;
+
+    prune_snapshots(target, config)?;
+    Ok(summary)
+}
+
+/// Drops snapshots beyond the newest `keep_last` that are also older than
+/// `keep_days`, then every blob no remaining snapshot refers to.
+pub fn prune_snapshots(target: &Path, config: &BackupConfig) -> Result<usize, BackupErrors> {
+    let mut catalog = read_catalog(target)?;
+    let keep_from = catalog.len().saturating_sub(config.keep_last);
+    let cutoff = config
+        .keep_days
+        .map(|days| Utc::now().naive_utc() - Duration::days(days as i64));
+
+    let (pruned, kept): (Vec<_>, Vec<_>) =
+        catalog.drain(..).enumerate().partition(|(index, summary)| {
+            *index < keep_from && cutoff.is_none_or(|cutoff| summary.created_date < cutoff)
+        });
+    if pruned.is_empty() {
+        return Ok(0);
+    }
+
+    let kept: Vec<SnapshotSummary> = kept.into_iter().map(|(_, summary)| summary).collect();
+    write_catalog(target, &kept)?;
+    for (_, summary) in pruned.iter() {
+        let path = snapshot_json(target, &summary.snapshot_id);
+        fs::remove_file(&path).map_err(|err| write_error(&path, err))?;
+    }
+
+    let mut referenced = HashSet::new();
+    for summary in kept.iter() {
+        for file in read_snapshot(target, &summary.snapshot_id)?.files {
+            referenced.insert(file.sha256);
+        }
+    }
+    for path in data_files(&target.join("blobs"))? {
+        if !referenced.contains(op_osstr_to_str(path.file_name()).as_str()) {
+            fs::remove_file(&path).map_err(|err| write_error(&path, err))?;
+        }
+    }
+
+    Ok(pruned.len())
+}
+
+pub fn read_catalog(target: &Path) -> Result<Vec<SnapshotSummary>, BackupErrors> {
+    let path = catalog_json(target);
+    match path.exists() {
+        true => read_json(&path),
+        false => Ok(vec![]),
+    }
+}
+
+pub fn read_snapshot(target: &Path, snapshot_id: &str) -> Result<Snapshot, BackupErrors> {
+    let path = snapshot_json(target, snapshot_id);
+    if !path.exists() {
+        return Err(BackupErrors::SnapshotDosentExist);
+    }
+    read_json(&path)
+}
+
+/// Finds a snapshot by id, `latest`, or a point in time like
+/// `2023-11-02T14:00:00`, giving the last snapshot taken at or before it.
+pub fn find_snapshot(target: &Path, at: &str) -> Result<Snapshot, BackupErrors> {
+    let catalog = read_catalog(target)?;
+
+    let summary = match at {
+        "latest" => catalog.last(),
+        _ => match at.parse::<NaiveDateTime>() {
+            Ok(at) => catalog
+                .iter()
+                .rev()
+                .find(|summary| summary.created_date <= at),
+            Err(_) => catalog.iter().find(|summary| summary.snapshot_id == at),
+        },
+    };
+
+    match summary {
+        Some(summary) => read_snapshot(target, &summary.snapshot_id),
+        None => Err(BackupErrors::SnapshotDosentExist),
+    }
+}
+
+/// Puts the data folder back as it was in `snapshot`. What is there now is
+/// moved to the quarantine folder first, nothing gets deleted. Meant to be run
+/// with the server stopped.
+pub fn restore_data_dir(
+    data_path: &str,
+    target: &Path,
+    snapshot: &Snapshot,
+) -> Result<usize, BackupErrors> {
+    let set_aside = restore_quarantine(data_path, &snapshot.snapshot_id);
+    let entries = fs::read_dir(data_path).map_err(|err| read_error(Path::new(data_path), err))?;
+
+    for entry in entries.flatten() {
+        let file_name = op_osstr_to_str(Some(&entry.file_name()));
+        if SKIPPED_DIRS.contains(&file_name.as_str()) {
+            continue;
+        }
+        move_into(&entry.path(), &set_aside.join(&file_name))?;
+    }
+
+    for file in snapshot.files.iter() {
+        restore_file(data_path, target, file)?;
+    }
+
+    Ok(snapshot.files.len())
+}
+
+/// Puts one project back as it was in `snapshot`: its folder, and its entry in
+/// the global project index. The current folder is moved to the quarantine
+/// folder first. Works for projects deleted since, too.
+pub fn restore_project(
+    data_path: &str,
+    target: &Path,
+    snapshot: &Snapshot,
+    project_id: &Uuid,
+) -> Result<ProjectInfo, BackupErrors> {
+    let prefix = format!("{}/", project_id);
+    let files: Vec<&SnapshotFile> = snapshot
+        .files
+        .iter()
+        .filter(|file| file.path.starts_with(&prefix))
+        .collect();
+
+    let project: ProjectInfo = match files
+        .iter()
+        .find(|file| file.path == format!("{}project.json", prefix))
+    {
+        Some(file) => serde_json::from_slice(&read_snapshot_file(target, file)?)
+            .map_err(|err| BackupErrors::Corrupted(format!("{}: {}", file.path, err)))?,
+        None => return Err(BackupErrors::ProjectDosentExist),
+    };
+
+    let global_path = global_project_json(data_path);
+    let mut projects: Vec<ProjectInfo> = match global_path.exists() {
+        true => read_json(&global_path)?,
+        false => vec![],
+    };
+    if projects.iter().any(|other| {
+        other.project_name == project.project_name && other.project_id != project.project_id
+    }) {
+        return Err(BackupErrors::ProjectAllreadyExists);
+    }
+
+    let project_path = project_dir(data_path, project_id);
+    if project_path.exists() {
+        let set_aside = restore_quarantine(data_path, &snapshot.snapshot_id);
+        move_into(&project_path, &set_aside.join(project_id.to_string()))?;
+    }
+    for file in files {
+        restore_file(data_path, target, file)?;
+    }
+
+    projects.retain(|other| other.project_id != *project_id);
+    projects.push(project.clone());
+    replace_file_atomic(&global_path, &object_to_byte_vec(&projects))
+        .map_err(|err| write_error(&global_path, err))?;
+
+    Ok(project)
+}
+
+fn restore_quarantine(data_path: &str, snapshot_id: &str) -> PathBuf {
+    quarantine_dir(data_path).join(format!(
+        "{}-before-restore-{}",
+        Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+        snapshot_id
+    ))
+}
+
+fn restore_file(data_path: &str, target: &Path, file: &SnapshotFile) -> Result<(), BackupErrors> {
+    let path = Path::new(data_path).join(&file.path);
+    fs::create_dir_all(path.parent().unwrap()).map_err(|err| write_error(&path, err))?;
+    copy_atomic(&blob_path(target, &file.sha256), &path)?;
+
+    let sha256 = hex::encode(hash_file(&path).map_err(|err| read_error(&path, err))?);
+    match sha256 == file.sha256 {
+        true => Ok(()),
+        false => Err(BackupErrors::Corrupted(format!(
+            "{} doesn't match its checksum",
+            file.path
+        ))),
+    }
+}
+
+/// The content of `file` as it was backed up.
+pub fn read_snapshot_file(target: &Path, file: &SnapshotFile) -> Result<Vec<u8>, BackupErrors> {
+    let path = blob_path(target, &file.sha256);
+    fs::read(&path).map_err(|err| read_error(&path, err))
+}
+
+fn move_into(path: &Path, destination: &Path) -> Result<(), BackupErrors> {
+    fs::create_dir_all(destination.parent().unwrap())
+        .and_then(|_| fs::rename(path, destination))
+        .map_err(|err| write_error(path, err))
+}
+
+/// Copies to a temporary file next to `destination` first, so an interrupted
+/// backup never leaves a partial blob that looks complete.
+fn copy_atomic(source: &Path, destination: &Path) -> Result<(), BackupErrors> {
+    let mut temp_path = destination.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    fs::create_dir_all(destination.parent().unwrap())
+        .and_then(|_| fs::copy(source, &temp_path))
+        .and_then(|_| fs::rename(&temp_path, destination))
+        .map(|_| ())
+        .map_err(|err| write_error(destination, err))
+}
+
+/// Every file below `dir`, without temporary files and the folders that aren't
+/// backed up.
+fn data_files(dir: &Path) -> Result<Vec<PathBuf>, BackupErrors> {
+    let mut files = vec![];
+    if !dir.exists() {
+        return Ok(files);
+    }
+
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        let entries = fs::read_dir(&current).map_err(|err| read_error(&current, err))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_name = op_osstr_to_str(path.file_name());
+
+            if path.is_dir() {
+                if current != dir || !SKIPPED_DIRS.contains(&file_name.as_str()) {
+                    dirs.push(path);
+                }
+            } else if !file_name.ends_with(".tmp") {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn relative_path(data_path: &str, path: &Path) -> String {
+    path.strip_prefix(data_path)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+fn write_catalog(target: &Path, catalog: &[SnapshotSummary]) -> Result<(), BackupErrors> {
+    let path = catalog_json(target);
+    replace_file_atomic(&path, &object_to_byte_vec(catalog)).map_err(|err| write_error(&path, err))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, BackupErrors> {
+    let data = fs::read_to_string(path).map_err(|err| read_error(path, err))?;
+    serde_json::from_str(&data)
+        .map_err(|err| BackupErrors::Corrupted(format!("{:?}: {}", path, err)))
+}
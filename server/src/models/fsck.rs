@@ -0,0 +1,670 @@
+use ::serde::{de::DeserializeOwned, Deserialize, Serialize};
+use chrono::Utc;
+use image::load_from_memory;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::models::image_data::{
+    blocking_lock_image_index, get_image_key, image_blob_key, write_project_images, ImageData,
+};
+use crate::models::project_info::{blocking_lock_project_index, ProjectInfo};
+use crate::utility::blob_store::{blob_store, BlobStore};
+use crate::utility::encrypted_blob::{expected_blob_len, is_envelope, open_blob, read_blob};
+use crate::utility::encryption::ProjectKeys;
+use crate::utility::file_utilities::*;
+
+/// Files in a project folder that aren't image blobs.
+pub const PROJECT_FILES: [&str; 7] = [
+    "project.json",
+    "project_images.json",
+    "project_members.json",
+    "audit_log.jsonl",
+    "key_rotation.json",
+    "webhooks.json",
+    "webhook_deliveries.jsonl",
+];
+
+/// What to do about the problems found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RepairMode {
+    /// Move whatever doesn't add up to the quarantine folder and drop index
+    /// entries whose file is gone.
+    Quarantine,
+    /// Like `Quarantine`, but files and project folders that can still be read
+    /// are added back to the indexes instead.
+    Reindex,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct FsckRepair {
+    pub repair: RepairMode,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FsckIssue {
+    /// A JSON file that is missing or can't be parsed.
+    BrokenJson { path: String, error: String },
+    /// In the global project index, but the folder is gone.
+    MissingProjectFolder { project_id: Uuid },
+    /// A project folder the global project index doesn't list.
+    OrphanProjectFolder { folder: String },
+    /// In the image index, but the blob is gone.
+    MissingBlob { project_id: Uuid, image_id: Uuid },
+    /// A blob of a project the image index doesn't list.
+    OrphanBlob { project_id: Uuid, file_name: String },
+    SizeMismatch {
+        project_id: Uuid,
+        image_id: Uuid,
+        expected: u64,
+        actual: u64,
+    },
+    /// Can't be decoded, or doesn't decrypt and authenticate with its key.
+    Unreadable {
+        project_id: Uuid,
+        image_id: Uuid,
+        error: String,
+    },
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FsckReport {
+    pub images_checked: usize,
+    /// Encrypted images whose key isn't unlocked, only their size was checked.
+    pub images_skipped: usize,
+    pub issues: Vec<FsckIssue>,
+    /// What a repair did, empty without one.
+    pub repairs: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum FsckErrors {
+    ProjectDosentExist,
+    FailedToReadProjects(String),
+    FailedToReadBlobs(String),
+    FailedToRepair(String),
+}
+
+impl fmt::Display for FsckErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsckErrors::ProjectDosentExist => write!(f, "project doesn't exist"),
+            FsckErrors::FailedToReadProjects(err) => {
+                write!(f, "failed to read the project index: {}", err)
+            }
+            FsckErrors::FailedToReadBlobs(err) => {
+                write!(f, "failed to read the blob store: {}", err)
+            }
+            FsckErrors::FailedToRepair(err) => write!(f, "failed to repair: {}", err),
+        }
+    }
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// A repair in progress; everything it moves away goes to one timestamped
+/// folder under `quarantine_dir`.
+struct Repair<'a> {
+    mode: RepairMode,
+    data_path: &'a str,
+    store: Arc<dyn BlobStore>,
+    quarantine: PathBuf,
+}
+
+impl<'a> Repair<'a> {
+    fn new(data_path: &'a str, mode: RepairMode) -> Self {
+        Repair {
+            mode,
+            data_path,
+            store: blob_store(data_path),
+            quarantine: quarantine_dir(data_path)
+                .join(Utc::now().format("%Y%m%dT%H%M%S%.3f").to_string()),
+        }
+    }
+
+    /// Moves a file or folder of the data folder into quarantine, keeping its
+    /// path relative to the data folder.
+    fn quarantine(&self, path: &Path, report: &mut FsckReport) -> Result<(), FsckErrors> {
+        let relative = path.strip_prefix(self.data_path).unwrap_or(path);
+        let target = self.quarantine.join(relative);
+
+        fs::create_dir_all(target.parent().unwrap())
+            .and_then(|_| fs::rename(path, &target))
+            .map_err(|err| FsckErrors::FailedToRepair(format!("{:?}: {}", path, err)))?;
+
+        report
+            .repairs
+            .push(format!("moved {:?} to {:?}", relative, target));
+        Ok(())
+    }
+
+    /// Moves a blob out of the store into quarantine, as `<project_id>/<file>`
+    /// like a file of the data folder.
+    fn quarantine_blob(&self, blob_key: &str, report: &mut FsckReport) -> Result<(), FsckErrors> {
+        let target = self.quarantine.join(blob_key);
+
+        fs::create_dir_all(target.parent().unwrap())
+            .and_then(|_| File::create(&target))
+            .and_then(|mut file| io::copy(&mut self.store.get(blob_key)?, &mut file))
+            .and_then(|_| self.store.delete(blob_key))
+            .map_err(|err| FsckErrors::FailedToRepair(format!("{}: {}", blob_key, err)))?;
+
+        report
+            .repairs
+            .push(format!("moved blob {} to {:?}", blob_key, target));
+        Ok(())
+    }
+
+    /// Keeps index entries that were dropped, so they can be put back by hand.
+    fn save_removed<T: Serialize>(&self, name: &str, entries: &[T]) -> Result<(), FsckErrors> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.quarantine)
+            .and_then(|_| fs::write(self.quarantine.join(name), object_to_byte_vec(entries)))
+            .map_err(|err| FsckErrors::FailedToRepair(format!("{}: {}", name, err)))
+    }
+}
+
+/// Checks the whole data folder: the global project index against the project
+/// folders, and every project with `fsck_project`. Encrypted images are only
+/// decrypted for projects in `project_keys`.
+pub fn fsck_data_dir(
+    data_path: &str,
+    project_keys: &HashMap<Uuid, ProjectKeys>,
+    repair: Option<RepairMode>,
+) -> Result<FsckReport, FsckErrors> {
+    let mut report = FsckReport::default();
+    let repair = repair.map(|mode| Repair::new(data_path, mode));
+    let global_path = global_project_json(data_path);
+
+    // only held for the global index, every project then takes its image index lock
+    let projects_lock = blocking_lock_project_index();
+    let mut projects = match read_json::<Vec<ProjectInfo>>(&global_path) {
+        Ok(projects) => projects.unwrap_or_default(),
+        Err(err) => {
+            report.issues.push(FsckIssue::BrokenJson {
+                path: path_string(&global_path),
+                error: err,
+            });
+            if let Some(repair) = &repair {
+                repair.quarantine(&global_path, &mut report)?;
+            }
+            vec![]
+        }
+    };
+    let rebuild = !report.issues.is_empty();
+    let mut changed = false;
+
+    let mut removed = vec![];
+    for project in projects.iter() {
+        if !project_dir(data_path, &project.project_id).is_dir() {
+            report.issues.push(FsckIssue::MissingProjectFolder {
+                project_id: project.project_id,
+            });
+            removed.push(project.clone());
+        }
+    }
+    if let Some(repair) = &repair {
+        if !removed.is_empty() {
+            projects.retain(|project| {
+                !removed
+                    .iter()
+                    .any(|removed| removed.project_id == project.project_id)
+            });
+            repair.save_removed("removed_projects.json", &removed)?;
+            report.repairs.push(format!(
+                "removed {} project(s) without a folder from the index",
+                removed.len()
+            ));
+            changed = true;
+        }
+    }
+
+    for folder in project_folders(data_path)? {
+        let project_id = match Uuid::parse_str(&folder) {
+            Ok(project_id) => project_id,
+            Err(_) => continue,
+        };
+        if projects
+            .iter()
+            .any(|project| project.project_id == project_id)
+        {
+            continue;
+        }
+
+        // with a broken global index every folder is unknown, that's one issue
+        if !rebuild {
+            report.issues.push(FsckIssue::OrphanProjectFolder {
+                folder: folder.to_owned(),
+            });
+        }
+
+        let repair = match &repair {
+            Some(repair) => repair,
+            None => continue,
+        };
+        let project = read_json::<ProjectInfo>(&project_info_json(data_path, &project_id))
+            .ok()
+            .flatten()
+            .filter(|project| project.project_id == project_id)
+            .filter(|project| {
+                !projects
+                    .iter()
+                    .any(|other| other.project_name == project.project_name)
+            });
+
+        // rebuilding a broken index always keeps the folders that can be read
+        match project.filter(|_| rebuild || repair.mode == RepairMode::Reindex) {
+            Some(project) => {
+                report.repairs.push(format!(
+                    "added project {} back to the index",
+                    project.project_name
+                ));
+                projects.push(project);
+                changed = true;
+            }
+            None => {
+                repair.quarantine(&project_dir(data_path, &project_id), &mut report)?;
+                // blobs kept outside of the data folder go along
+                for blob_key in list_blobs(&*repair.store, &project_id)? {
+                    repair.quarantine_blob(&blob_key, &mut report)?;
+                }
+            }
+        }
+    }
+
+    if (changed || rebuild) && repair.is_some() {
+        replace_file_atomic(&global_path, object_to_byte_vec(&projects).as_slice())
+            .map_err(|err| FsckErrors::FailedToRepair(err.to_string()))?;
+    }
+    drop(projects_lock);
+
+    for project in projects.iter() {
+        if !project_dir(data_path, &project.project_id).is_dir() {
+            continue;
+        }
+        check_project(
+            data_path,
+            project,
+            project_keys.get(&project.project_id),
+            repair.as_ref(),
+            &mut report,
+        )?;
+    }
+
+    Ok(report)
+}
+
+/// Checks one project: its `project.json`, the image index against the blobs
+/// of the project, and every blob's size and content. Encrypted images
+/// are decrypted when their key is in `project_keys`.
+/// Takes the image index lock, so it runs outside the async runtime.
+pub fn fsck_project(
+    data_path: &str,
+    project_id: &Uuid,
+    project_keys: Option<&ProjectKeys>,
+    repair: Option<RepairMode>,
+) -> Result<FsckReport, FsckErrors> {
+    let mut report = FsckReport::default();
+    let repair = repair.map(|mode| Repair::new(data_path, mode));
+
+    let project = read_json::<Vec<ProjectInfo>>(&global_project_json(data_path))
+        .map_err(FsckErrors::FailedToReadProjects)?
+        .unwrap_or_default()
+        .into_iter()
+        .find(|project| project.project_id == *project_id)
+        .ok_or(FsckErrors::ProjectDosentExist)?;
+
+    if !project_dir(data_path, project_id).is_dir() {
+        report.issues.push(FsckIssue::MissingProjectFolder {
+            project_id: *project_id,
+        });
+        return Ok(report);
+    }
+
+    check_project(
+        data_path,
+        &project,
+        project_keys,
+        repair.as_ref(),
+        &mut report,
+    )?;
+    Ok(report)
+}
+
+fn check_project(
+    data_path: &str,
+    project: &ProjectInfo,
+    project_keys: Option<&ProjectKeys>,
+    repair: Option<&Repair>,
+    report: &mut FsckReport,
+) -> Result<(), FsckErrors> {
+    let project_id = project.project_id;
+    let store = blob_store(data_path);
+    // uploads can't change the index while its blobs are checked against it
+    let _index = blocking_lock_image_index(&project_id);
+
+    // the global index has a full copy of every project.json
+    let info_path = project_info_json(data_path, &project_id);
+    if let Err(err) = read_json::<ProjectInfo>(&info_path)
+        .and_then(|info| info.map(|_| ()).ok_or_else(|| "file is missing".to_owned()))
+    {
+        report.issues.push(FsckIssue::BrokenJson {
+            path: path_string(&info_path),
+            error: err,
+        });
+        if let Some(repair) = repair {
+            if info_path.exists() {
+                repair.quarantine(&info_path, report)?;
+            }
+            replace_file_atomic(&info_path, object_to_byte_vec(project).as_slice())
+                .map_err(|err| FsckErrors::FailedToRepair(err.to_string()))?;
+            report.repairs.push(format!(
+                "restored {:?} from the global index",
+                path_string(&info_path)
+            ));
+        }
+    }
+
+    let index_path = project_images_json(data_path, &project_id);
+    let mut changed = false;
+    let mut images = match read_json::<Vec<ImageData>>(&index_path)
+        .and_then(|images| images.ok_or_else(|| "file is missing".to_owned()))
+    {
+        Ok(images) => images,
+        Err(err) => {
+            report.issues.push(FsckIssue::BrokenJson {
+                path: path_string(&index_path),
+                error: err,
+            });
+            match repair {
+                Some(repair) => {
+                    if index_path.exists() {
+                        repair.quarantine(&index_path, report)?;
+                    }
+                    // every blob is an orphan now, and dealt with as one below
+                    changed = true;
+                    vec![]
+                }
+                None => return Ok(()),
+            }
+        }
+    };
+
+    let mut removed = vec![];
+    for image in images.iter() {
+        let blob_key = image_blob_key(&project_id, image);
+        let blob_len = match store.size(&blob_key) {
+            Ok(Some(blob_len)) => blob_len,
+            Ok(None) => {
+                report.issues.push(FsckIssue::MissingBlob {
+                    project_id,
+                    image_id: image.image_id,
+                });
+                removed.push(image.clone());
+                continue;
+            }
+            Err(err) => return Err(FsckErrors::FailedToReadBlobs(err.to_string())),
+        };
+
+        match check_blob(project, image, &*store, &blob_key, blob_len, project_keys) {
+            Ok(true) => report.images_checked += 1,
+            Ok(false) => report.images_skipped += 1,
+            Err(issue) => {
+                report.issues.push(issue);
+                if let Some(repair) = repair {
+                    repair.quarantine_blob(&blob_key, report)?;
+                }
+                removed.push(image.clone());
+            }
+        }
+    }
+
+    if let Some(repair) = repair {
+        if !removed.is_empty() {
+            images.retain(|image| {
+                !removed
+                    .iter()
+                    .any(|removed| removed.image_id == image.image_id)
+            });
+            repair.save_removed(&format!("{}_removed_images.json", project_id), &removed)?;
+            report.repairs.push(format!(
+                "removed {} image(s) from the index of {}",
+                removed.len(),
+                project.project_name
+            ));
+            changed = true;
+        }
+    }
+
+    let known: Vec<String> = images
+        .iter()
+        .map(|image| image_blob_key(&project_id, image))
+        .collect();
+    for blob_key in list_blobs(&*store, &project_id)? {
+        if known.contains(&blob_key) {
+            continue;
+        }
+
+        let file_name = blob_file_name(&blob_key);
+        report.issues.push(FsckIssue::OrphanBlob {
+            project_id,
+            file_name: file_name.to_owned(),
+        });
+
+        let repair = match repair {
+            Some(repair) => repair,
+            None => continue,
+        };
+        let image = match repair.mode {
+            RepairMode::Reindex => reindex_blob(project, &*store, &blob_key, project_keys),
+            RepairMode::Quarantine => None,
+        };
+        match image {
+            Some(image) => {
+                report
+                    .repairs
+                    .push(format!("added {} back to the image index", file_name));
+                images.push(image);
+                changed = true;
+            }
+            None => repair.quarantine_blob(&blob_key, report)?,
+        }
+    }
+
+    if changed {
+        write_project_images(data_path, &project_id, &images)
+            .map_err(|err| FsckErrors::FailedToRepair(err.to_string()))?;
+    }
+
+    Ok(())
+}
+
+/// `Ok(true)` when the blob was read back completely, `Ok(false)` when it is
+/// encrypted with a key that isn't unlocked and only its size was checked.
+fn check_blob(
+    project: &ProjectInfo,
+    image: &ImageData,
+    store: &dyn BlobStore,
+    blob_key: &str,
+    blob_len: u64,
+    project_keys: Option<&ProjectKeys>,
+) -> Result<bool, FsckIssue> {
+    let unreadable = |error: String| FsckIssue::Unreadable {
+        project_id: project.project_id,
+        image_id: image.image_id,
+        error,
+    };
+    let size_mismatch = |expected: u64, actual: u64| FsckIssue::SizeMismatch {
+        project_id: project.project_id,
+        image_id: image.image_id,
+        expected,
+        actual,
+    };
+
+    // plain images are re-encoded on upload, so only their content is checked
+    if !image.is_encrypted {
+        return decode_blob(store, blob_key)
+            .map(|_| true)
+            .map_err(unreadable);
+    }
+
+    if let Some(expected) = store
+        .get(blob_key)
+        .and_then(|blob| expected_blob_len(blob, image.image_size))
+        .map_err(|err| unreadable(err.to_string()))?
+    {
+        if expected != blob_len {
+            return Err(size_mismatch(expected, blob_len));
+        }
+    }
+
+    let key = match get_image_key(project, image, project_keys) {
+        Ok(key) => key,
+        Err(_) => return Ok(false),
+    };
+    let plain_len = plain_len(store, blob_key, &key).map_err(unreadable)?;
+    if plain_len != image.image_size {
+        return Err(size_mismatch(image.image_size, plain_len));
+    }
+
+    Ok(true)
+}
+
+/// An index entry for a blob the index lost, if it can be read with one of the
+/// project keys, or as a plain image.
+fn reindex_blob(
+    project: &ProjectInfo,
+    store: &dyn BlobStore,
+    blob_key: &str,
+    project_keys: Option<&ProjectKeys>,
+) -> Option<ImageData> {
+    let path = Path::new(blob_file_name(blob_key));
+    let mut image = ImageData {
+        image_id: Uuid::new_v4(),
+        image_name: op_osstr_to_str(path.file_stem()),
+        mime: path
+            .extension()
+            .map(|ext| op_osstr_to_str(Some(ext)))
+            .unwrap_or_default(),
+        original_image_name: op_osstr_to_str(path.file_name()),
+        image_size: store.size(blob_key).ok()??,
+        created_date: Utc::now().naive_utc(),
+        is_encrypted: false,
+        tags: vec![],
+        key_id: None,
+        sha256: None,
+        content_type: None,
+    };
+
+    let candidates: Vec<Option<Uuid>> = match is_envelope(store.get(blob_key).ok()?).ok()? {
+        true => project_keys
+            .map(|keys| {
+                std::iter::once(&keys.current)
+                    .chain(keys.previous.as_ref())
+                    .map(|key| Some(key.key_id))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        false if decode_blob(store, blob_key).is_ok() => return Some(image),
+        // could be a blob from before data keys
+        false => vec![None],
+    };
+
+    image.is_encrypted = true;
+    for key_id in candidates {
+        image.key_id = key_id;
+        if let Ok(key) = get_image_key(project, &image, project_keys) {
+            if let Ok(plain_len) = plain_len(store, blob_key, &key) {
+                image.image_size = plain_len;
+                return Some(image);
+            }
+        }
+    }
+
+    None
+}
+
+fn plain_len(store: &dyn BlobStore, blob_key: &str, key: &[u8]) -> Result<u64, String> {
+    let blob = store.get(blob_key).map_err(|err| err.to_string())?;
+    let mut len = 0;
+    for chunk in open_blob(blob, Some(key)).map_err(|err| err.to_string())? {
+        len += chunk.map_err(|err| err.to_string())?.len() as u64;
+    }
+    Ok(len)
+}
+
+/// Decodes a plain image blob, which is only checked for being an image.
+fn decode_blob(store: &dyn BlobStore, blob_key: &str) -> Result<(), String> {
+    let data = store
+        .get(blob_key)
+        .and_then(|blob| read_blob(blob, None))
+        .map_err(|err| err.to_string())?;
+    load_from_memory(&data)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+/// Blob candidates of a project: every blob that isn't one of the project's
+/// JSON files, which share the project folder with blobs in the data folder,
+/// or a temporary file of a write in progress.
+fn list_blobs(store: &dyn BlobStore, project_id: &Uuid) -> Result<Vec<String>, FsckErrors> {
+    let blobs = store
+        .list(&format!("{}/", project_id))
+        .map_err(|err| FsckErrors::FailedToReadBlobs(err.to_string()))?;
+
+    Ok(blobs
+        .into_iter()
+        .filter(|blob_key| {
+            let file_name = blob_file_name(blob_key);
+            !PROJECT_FILES.contains(&file_name) && !file_name.ends_with(".tmp")
+        })
+        .collect())
+}
+
+fn blob_file_name(blob_key: &str) -> &str {
+    blob_key.rsplit('/').next().unwrap_or(blob_key)
+}
+
+fn project_folders(data_path: &str) -> Result<Vec<String>, FsckErrors> {
+    let entries =
+        fs::read_dir(data_path).map_err(|err| FsckErrors::FailedToReadProjects(err.to_string()))?;
+
+    let mut folders: Vec<String> = entries
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    folders.sort();
+    Ok(folders)
+}
+
+/// `Ok(None)` when the file doesn't exist, `Err` when it can't be read or
+/// parsed.
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, String> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let data = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    serde_json::from_str(&data)
+        .map(Some)
+        .map_err(|err| err.to_string())
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
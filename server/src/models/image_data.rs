@@ -1,17 +1,33 @@
 use ::serde::{Deserialize, Serialize};
+use actix_web::web;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use chrono::{NaiveDateTime, Utc};
-use image::open as openImage;
-use std::io::Read;
-use std::{fs::File, path::PathBuf};
+use image::{open as openImage, ImageFormat};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::OwnedMutexGuard;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-use crate::utility::encryption::{decrypt_bytes, encrypt_bytes};
-use crate::utility::file_utilities::{create_file_write_all, object_to_byte_vec, op_osstr_to_str};
+use crate::utility::archive::{zip_stream, ArchiveEntry};
+use crate::utility::blob_store::{blob_store, put_reader, BlobStore};
+use crate::utility::encrypted_blob::{
+    encrypt_stream, hash_blob, hash_file, open_blob, open_blob_range, read_blob, PlainChunks,
+};
+use crate::utility::encryption::{get_legacy_key, DataKey, ProjectKeys};
+use crate::utility::file_utilities::*;
 use crate::utility::genarate_salt;
 
-use super::project_info::ProjectInfo;
+use super::project_events::{image_index_events, publish};
+use super::project_info::{ProjectInfo, ProjectQuota};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
 pub struct ImageData {
     pub image_id: Uuid,
     pub image_name: String,
@@ -21,20 +37,48 @@ pub struct ImageData {
     pub created_date: NaiveDateTime,
     pub is_encrypted: bool,
     pub tags: Vec<String>,
+    /// Data key the blob is encrypted with, `None` for the legacy salt key.
+    #[serde(default)]
+    pub key_id: Option<Uuid>,
+    /// Hex SHA-256 of the content as it is sent, the image's ETag. Filled in
+    /// on first download for images saved before it was recorded.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// MIME type detected from the content, `mime` is only the file extension.
+    /// Set by `server migrate-content-types` for images saved before it was
+    /// recorded.
+    #[serde(default)]
+    pub content_type: Option<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
 pub struct ReqImageData {
     pub image_id: Uuid,
+    /// Send the image as an attachment named like the uploaded file.
+    #[serde(default)]
+    pub download: bool,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// A saved image. Its content is only read from the blob store (and
+/// decrypted) once it is opened.
 pub struct ResponseImageData {
-    pub data: Vec<u8>,
     pub metadata: ImageData,
+    /// Length of the content as it is sent; plain images are sent as they were
+    /// re-encoded on upload, not as uploaded.
+    pub content_len: u64,
+    store: Arc<dyn BlobStore>,
+    blob_key: String,
+    key: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// `len` bytes of an image from byte `start` on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub len: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct UploadImage {
     pub image_path: String,
     pub image_name: Option<String>,
@@ -42,6 +86,119 @@ pub struct UploadImage {
     pub encrypt: bool,
 }
 
+/// New tags of an image, separated by `;` like on upload.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ImageTags {
+    pub image_id: Uuid,
+    pub image_tags: String,
+}
+
+/// Which images to match, every given filter has to match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ImageSearch {
+    /// Images carrying all of these tags.
+    pub tags: Option<Vec<String>>,
+    /// Part of the image name or the original file name, ignoring case.
+    pub name: Option<String>,
+    pub mime: Option<String>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+}
+
+/// Folders of a bulk download.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FolderLayout {
+    #[default]
+    Flat,
+    /// A folder per image named after its first tag, `untagged` without one.
+    Tag,
+    /// A folder per day the image was added, `YYYY-MM-DD`.
+    Date,
+}
+
+/// Images to download at once, the listed ones or those matching `search`,
+/// or all of them when neither is given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct BulkDownload {
+    pub image_ids: Option<Vec<Uuid>>,
+    pub search: Option<ImageSearch>,
+    #[serde(default)]
+    pub layout: FolderLayout,
+}
+
+/// Images per page of a listing unless asked otherwise, and at most.
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageSort {
+    #[default]
+    CreatedDate,
+    /// By image name, ignoring case.
+    Name,
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// A page of the images of a project. Images with the same sort value are
+/// ordered by id, so pages neither skip nor repeat images.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImageListQuery {
+    #[serde(default)]
+    pub sort: ImageSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Images per page, at most `MAX_PAGE_SIZE`.
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page, the listing starts after it.
+    pub cursor: Option<String>,
+    /// Comma separated `ImageData` fields to return, all of them if not given.
+    pub fields: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ImagePage {
+    /// The images, with only the requested fields.
+    #[schema(value_type = Vec<Object>)]
+    pub images: Vec<serde_json::Map<String, serde_json::Value>>,
+    /// Cursor of the next page, `None` on the last one.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortKey {
+    CreatedDate(NaiveDateTime),
+    Name(String),
+    Size(u64),
+}
+
+/// Where a listing stopped, handed to clients as URL safe base64 JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ImageCursor {
+    sort: ImageSort,
+    order: SortOrder,
+    key: SortKey,
+    image_id: Uuid,
+}
+
+/// Storage used by a project, as recorded in its image index.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ProjectUsage {
+    pub bytes: u64,
+    pub images: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TempImage {
     pub temp_file_path: String,
@@ -58,7 +215,68 @@ pub enum ImageDataError {
     ProjectDosentExists,
     FailedToSaveImage,
     ImageNotFound,
+    EncryptionKeyUnavailable,
     DecryptionError(String),
+    QuotaExceeded(String),
+    FailedToReadIndex(String),
+    InvalidListQuery(String),
+    InvalidImage(String),
+    InvalidUploadPath(String),
+    KeyChanged,
+}
+
+impl fmt::Display for ImageDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageDataError::ProjectDosentExists => write!(f, "project doesn't exist"),
+            ImageDataError::FailedToSaveImage => write!(f, "failed to save image"),
+            ImageDataError::ImageNotFound => write!(f, "image not found"),
+            ImageDataError::EncryptionKeyUnavailable => {
+                write!(f, "project encryption key is not unlocked")
+            }
+            ImageDataError::KeyChanged => {
+                write!(f, "the project encryption key changed during the upload")
+            }
+            ImageDataError::DecryptionError(err) => write!(f, "failed to decrypt image: {}", err),
+            ImageDataError::QuotaExceeded(err) => write!(f, "project quota exceeded: {}", err),
+            ImageDataError::FailedToReadIndex(err) => {
+                write!(f, "failed to read the image index: {}", err)
+            }
+            ImageDataError::InvalidListQuery(err) => write!(f, "invalid image listing: {}", err),
+            ImageDataError::InvalidImage(err) => write!(f, "not an image: {}", err),
+            ImageDataError::InvalidUploadPath(path) => {
+                write!(f, "'{}' is not a file in the upload folder", path)
+            }
+        }
+    }
+}
+
+/// Held while a project's image index is read, changed and written back, so
+/// changes made at the same time don't overwrite each other. Every writer of
+/// `project_images.json` takes it, reading alone doesn't need it as the index
+/// is always replaced as a whole.
+pub type ImageIndexGuard = OwnedMutexGuard<()>;
+
+static INDEX_LOCKS: OnceLock<Mutex<HashMap<Uuid, Arc<tokio::sync::Mutex<()>>>>> = OnceLock::new();
+
+fn index_lock(project_id: &Uuid) -> Arc<tokio::sync::Mutex<()>> {
+    INDEX_LOCKS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap()
+        .entry(*project_id)
+        .or_default()
+        .clone()
+}
+
+pub async fn lock_image_index(project_id: &Uuid) -> ImageIndexGuard {
+    index_lock(project_id).lock_owned().await
+}
+
+/// `lock_image_index` for code running outside the async runtime, e.g. in
+/// `web::block`.
+pub fn blocking_lock_image_index(project_id: &Uuid) -> ImageIndexGuard {
+    index_lock(project_id).blocking_lock_owned()
 }
 
 impl ImageData {
@@ -72,6 +290,9 @@ impl ImageData {
             created_date: Utc::now().naive_utc(),
             is_encrypted: temp_image.encrypt,
             tags: temp_image.image_tags,
+            key_id: None,
+            sha256: None,
+            content_type: None,
         }
     }
 
@@ -80,27 +301,91 @@ impl ImageData {
     }
 }
 
+impl ImageSearch {
+    pub fn matches(&self, image: &ImageData) -> bool {
+        let name = self.name.as_ref().map(|name| name.to_lowercase());
+
+        self.tags
+            .as_ref()
+            .is_none_or(|tags| tags.iter().all(|tag| image.tags.contains(tag)))
+            && name.is_none_or(|name| {
+                image.image_name.to_lowercase().contains(&name)
+                    || image.original_image_name.to_lowercase().contains(&name)
+            })
+            && self
+                .mime
+                .as_ref()
+                .is_none_or(|mime| image.mime.eq_ignore_ascii_case(mime))
+            && self.from.is_none_or(|from| image.created_date >= from)
+            && self.to.is_none_or(|to| image.created_date < to)
+    }
+}
+
+impl FolderLayout {
+    /// Path of `image` inside a bulk download.
+    fn entry_path(&self, image: &ImageData) -> String {
+        let file_name = format!("{}.{}", image.image_name, image.mime);
+
+        match self {
+            FolderLayout::Flat => file_name,
+            FolderLayout::Tag => {
+                let folder = image
+                    .tags
+                    .iter()
+                    .map(|tag| tag.replace(['/', '\\', ':'], "_"))
+                    .find(|tag| !tag.trim_matches('.').is_empty())
+                    .unwrap_or("untagged".to_owned());
+                format!("{}/{}", folder, file_name)
+            }
+            FolderLayout::Date => {
+                format!("{}/{}", image.created_date.format("%Y-%m-%d"), file_name)
+            }
+        }
+    }
+}
+
 impl TempImage {
-    fn from_upload_image(upload_image: UploadImage, input_path: &str) -> Self {
-        let binding = PathBuf::from(format!("{}//{}", input_path, upload_image.image_path));
-        let temp_img_path = binding.as_path();
-        let temp_img_metadata = temp_img_path.metadata().unwrap();
+    /// The file `upload_image.image_path` names in `input_path`, which has to
+    /// stay inside it: no absolute paths, `..` or symlinks out of it.
+    fn from_upload_image(
+        upload_image: UploadImage,
+        input_path: &str,
+    ) -> Result<Self, ImageDataError> {
+        let image_path = upload_image.image_path.clone();
+        let invalid = || ImageDataError::InvalidUploadPath(image_path.clone());
 
-        TempImage {
-            temp_file_path: temp_img_path.to_str().unwrap().to_owned(),
+        let relative_path = Path::new(&upload_image.image_path);
+        if relative_path.as_os_str().is_empty()
+            || !relative_path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(invalid());
+        }
+        let input_path = fs::canonicalize(input_path).map_err(|_| invalid())?;
+        let temp_img_path =
+            fs::canonicalize(input_path.join(relative_path)).map_err(|_| invalid())?;
+        if !temp_img_path.starts_with(&input_path) {
+            return Err(invalid());
+        }
+        let temp_img_metadata = temp_img_path.metadata().map_err(|_| invalid())?;
+        if !temp_img_metadata.is_file() {
+            return Err(invalid());
+        }
+
+        Ok(TempImage {
+            temp_file_path: temp_img_path.to_str().ok_or_else(invalid)?.to_owned(),
             temp_file_size: temp_img_metadata.len(),
             encrypt: upload_image.encrypt,
-            temp_image_name: op_osstr_to_str(temp_img_path.file_name()),
-            temp_image_mime: op_osstr_to_str(temp_img_path.extension()),
+            temp_image_name: op_osstr_to_str(relative_path.file_name()),
+            temp_image_mime: op_osstr_to_str(relative_path.extension()),
             image_name: upload_image.image_name.unwrap_or(genarate_salt(7)),
             image_tags: upload_image
                 .image_tags
                 .split(';')
-                .collect::<Vec<&str>>()
-                .iter()
                 .map(|s| s.to_string())
                 .collect(),
-        }
+        })
     }
 }
 
@@ -109,7 +394,11 @@ pub async fn upload_image(
     image_path: &str,
     temp_img: UploadImage,
     project_id: Uuid,
-) -> Result<(), ImageDataError> {
+    project_keys: Option<&ProjectKeys>,
+    default_quota: &ProjectQuota,
+) -> Result<ImageData, ImageDataError> {
+    // held from the quota check until the new image is in the index
+    let _index = lock_image_index(&project_id).await;
     let images = read_project_images(data_path, &project_id).await;
 
     if images.is_err() {
@@ -117,53 +406,106 @@ pub async fn upload_image(
     }
     let mut images = images?;
 
-    let project_info = get_project_info(data_path, &project_id).await;
-    if project_info.is_err() {
-        return Err(project_info.err().unwrap());
+    if temp_img.encrypt && project_keys.is_none() {
+        return Err(ImageDataError::EncryptionKeyUnavailable);
     }
-    let project_info = project_info?;
 
-    let temp_img = TempImage::from_upload_image(temp_img, image_path);
+    let temp_img = TempImage::from_upload_image(temp_img, image_path)?;
+
+    let project_info = get_project_info(data_path, &project_id).await?;
+    check_quota(
+        &project_info.get_quota(default_quota),
+        &images,
+        temp_img.temp_file_size,
+    )?;
+
     let temp_path = temp_img.temp_file_path.to_owned();
-    println!("{:#?}", temp_img);
-    let img_data = ImageData::new(temp_img);
-    println!("{:#?}", img_data);
-
-    let image_path = PathBuf::from(format!(
-        "{}\\{}\\{}.{}",
-        data_path,
-        project_id.to_string(),
-        img_data.image_name,
-        img_data.mime
-    ));
-
-    match image_path.exists() {
-        true => Err(ImageDataError::FailedToSaveImage),
-        false => {
-            println!("{:#?}", image_path);
-
-            let enc_key = if img_data.is_encrypted {
-                Some(get_encryption_key(&project_info))
-            } else {
-                None
-            };
-            save_temp_image(temp_path, image_path.to_str().unwrap().to_owned(), enc_key).await;
+    let mut img_data = ImageData::new(temp_img);
 
-            images.append(&mut vec![img_data.clone()]);
-            let project_path = PathBuf::from(
-                data_path.to_owned() + "\\" + &project_id.to_string() + "\\project_images.json",
-            );
-            create_file_write_all(&project_path, object_to_byte_vec(&images).as_slice());
+    let store = blob_store(data_path);
+    let blob_key = image_blob_key(&project_id, &img_data);
 
-            Ok(())
+    let enc_key = match img_data.is_encrypted {
+        true => project_keys.map(|keys| &keys.current),
+        false => None,
+    };
+    img_data.key_id = enc_key.map(|key| key.key_id);
+
+    let extension = img_data.mime.clone();
+    let enc_key = enc_key.map(|key| key.key.to_vec());
+    let saved = web::block({
+        let (store, blob_key) = (store.clone(), blob_key.clone());
+        move || match store.size(&blob_key) {
+            Ok(None) => save_temp_image(
+                &*store,
+                &temp_path,
+                &blob_key,
+                &extension,
+                enc_key.as_deref(),
+            ),
+            Ok(Some(_)) | Err(_) => Err(ImageDataError::FailedToSaveImage),
+        }
+    })
+    .await
+    .map_err(|_| ImageDataError::FailedToSaveImage)??;
+    img_data.sha256 = Some(saved.sha256);
+    img_data.content_type = Some(saved.content_type);
+
+    // the keys were read before the upload; a key rotation or password reset
+    // since then may drop the key, and the image would never be readable
+    if let Some(key_id) = img_data.key_id {
+        let project_info = get_project_info(data_path, &project_id).await?;
+        if project_info.key_info.map(|key_info| key_info.key_id) != Some(key_id) {
+            let _ = web::block(move || store.delete(&blob_key)).await;
+            return Err(ImageDataError::KeyChanged);
         }
     }
+
+    images.push(img_data.clone());
+    write_project_images(data_path, &project_id, &images)?;
+    Ok(img_data)
+}
+
+pub fn get_project_usage(images: &[ImageData]) -> ProjectUsage {
+    ProjectUsage {
+        bytes: images.iter().map(|image| image.image_size).sum(),
+        images: images.len() as u64,
+    }
+}
+
+fn check_quota(
+    quota: &ProjectQuota,
+    images: &[ImageData],
+    new_image_size: u64,
+) -> Result<(), ImageDataError> {
+    let usage = get_project_usage(images);
+
+    if let Some(max_images) = quota.max_images {
+        if usage.images + 1 > max_images {
+            return Err(ImageDataError::QuotaExceeded(format!(
+                "the project can hold at most {} images",
+                max_images
+            )));
+        }
+    }
+
+    if let Some(max_bytes) = quota.max_bytes {
+        if usage.bytes + new_image_size > max_bytes {
+            return Err(ImageDataError::QuotaExceeded(format!(
+                "{} of {} bytes used, the image needs {}",
+                usage.bytes, max_bytes, new_image_size
+            )));
+        }
+    }
+
+    Ok(())
 }
 
 pub async fn get_saved_image(
     data_path: &str,
     project_id: &Uuid,
     image_id: &Uuid,
+    project_keys: Option<&ProjectKeys>,
 ) -> Result<ResponseImageData, ImageDataError> {
     let image_data = get_project_image(data_path, project_id, image_id).await;
 
@@ -174,56 +516,486 @@ pub async fn get_saved_image(
 
     let project_info = get_project_info(data_path, project_id).await;
 
-    let mut res_img = ResponseImageData {
-        data: Vec::new(),
-        metadata: image_data.clone(),
-    };
-
     if project_info.is_err() {
         return Err(project_info.err().unwrap());
     }
 
     let project_info = project_info?;
 
-    let image_path = format!(
-        "{}{}\\{}.{}",
-        data_path, project_info.project_id, image_data.image_name, image_data.mime
-    );
-    println!("image path : {}", image_path);
-    let mut file = File::open(image_path).unwrap();
-    let mut buffer: Vec<u8> = Vec::new();
-    let err = file.read_to_end(&mut buffer);
-    println!("{:#?}", err);
+    let mut image_data = image_data;
+    let store = blob_store(data_path);
+    let blob_key = image_blob_key(&project_info.project_id, &image_data);
 
-    if err.is_err() {
-        return Err(ImageDataError::DecryptionError(err.unwrap().to_string()));
+    let key = match image_data.is_encrypted {
+        true => Some(get_image_key(&project_info, &image_data, project_keys)?),
+        false => None,
+    };
+    let (content_len, sha256) = {
+        let (store, blob_key, key) = (store.clone(), blob_key.clone(), key.clone());
+        let is_encrypted = image_data.is_encrypted;
+        let image_size = image_data.image_size;
+        let has_sha256 = image_data.sha256.is_some();
+
+        web::block(move || {
+            let content_len = match is_encrypted {
+                true => Some(image_size),
+                false => store.size(&blob_key)?,
+            };
+            let sha256 = match has_sha256 || content_len.is_none() {
+                true => None,
+                false => Some(hash_blob(store.get(&blob_key)?, key.as_deref())?),
+            };
+            Ok::<_, std::io::Error>((content_len, sha256))
+        })
+        .await
+        .map_err(|err| ImageDataError::DecryptionError(err.to_string()))?
+        .map_err(|err| ImageDataError::DecryptionError(err.to_string()))?
+    };
+    let content_len = content_len.ok_or(ImageDataError::ImageNotFound)?;
+
+    if let Some(sha256) = sha256 {
+        image_data.sha256 = Some(hex::encode(sha256));
+        save_image_hash(data_path, project_id, &image_data).await?;
+    }
+
+    Ok(ResponseImageData {
+        metadata: image_data,
+        content_len,
+        store,
+        blob_key,
+        key,
+    })
+}
+
+impl ResponseImageData {
+    /// The content, or just `range` of it. Returns the range that was opened,
+    /// `None` for all of it: legacy encrypted images are always read whole.
+    pub async fn open(
+        &self,
+        range: Option<ByteRange>,
+    ) -> Result<(PlainChunks, Option<ByteRange>), ImageDataError> {
+        let (store, blob_key, key) = (self.store.clone(), self.blob_key.clone(), self.key.clone());
+
+        web::block(move || {
+            if let Some(range) = range {
+                let chunks =
+                    open_blob_range(&*store, &blob_key, key.as_deref(), range.start, range.len)?;
+                if let Some(chunks) = chunks {
+                    return Ok((check_first_chunk(chunks)?, Some(range)));
+                }
+            }
+
+            Ok((open_image(&*store, &blob_key, key.as_deref())?, None))
+        })
+        .await
+        .map_err(|err| ImageDataError::DecryptionError(err.to_string()))?
+        .map_err(|err: std::io::Error| ImageDataError::DecryptionError(err.to_string()))
     }
+}
 
-    match image_data.is_encrypted {
-        false => {
-            res_img.data = buffer;
+/// Records the content hash of an image that has none yet, only changing
+/// that entry of the index as it is by now.
+async fn save_image_hash(
+    data_path: &str,
+    project_id: &Uuid,
+    image_data: &ImageData,
+) -> Result<(), ImageDataError> {
+    let _index = lock_image_index(project_id).await;
+    let mut images = read_project_images(data_path, project_id).await?;
+
+    match images
+        .iter_mut()
+        .find(|image| image.image_id == image_data.image_id && image.sha256.is_none())
+    {
+        Some(image) => {
+            image.sha256 = image_data.sha256.clone();
+            write_project_images(data_path, project_id, &images)
         }
-        true => {
-            // println!("buffer data : {}", buffer);
+        None => Ok(()),
+    }
+}
 
-            let dec_bytes = decrypt_bytes(
-                std::str::from_utf8(&buffer).unwrap(),
-                &get_encryption_key(&project_info),
-            );
+/// Opens a blob like open_blob() and reads its first chunk before anything is
+/// sent, so a tampered or undecryptable image is an error rather than a cut
+/// off body.
+fn open_image(
+    store: &dyn BlobStore,
+    blob_key: &str,
+    key: Option<&[u8]>,
+) -> std::io::Result<PlainChunks> {
+    check_first_chunk(open_blob(store.get(blob_key)?, key)?)
+}
+
+fn check_first_chunk(mut chunks: PlainChunks) -> std::io::Result<PlainChunks> {
+    match chunks.next() {
+        Some(Ok(first)) => Ok(Box::new(std::iter::once(Ok(first)).chain(chunks))),
+        Some(Err(err)) => Err(err),
+        None => Ok(Box::new(std::iter::empty())),
+    }
+}
+
+/// The selected images as a zip archive, decrypted like get_saved_image() while
+/// the archive is sent. Keys are looked up before the first byte, so a locked
+/// project is an error response; a blob failing later cuts the archive off.
+pub async fn download_images(
+    data_path: &str,
+    project_id: &Uuid,
+    download: &BulkDownload,
+    project_keys: Option<&ProjectKeys>,
+) -> Result<(usize, PlainChunks), ImageDataError> {
+    let project_info = get_project_info(data_path, project_id).await?;
+    let images = read_project_images(data_path, project_id).await?;
+
+    let mut selected: Vec<ImageData> = match &download.image_ids {
+        Some(image_ids) => {
+            let mut selected = vec![];
+            for image_id in image_ids {
+                match images.iter().find(|image| image.image_id == *image_id) {
+                    Some(_)
+                        if selected
+                            .iter()
+                            .any(|image: &ImageData| image.image_id == *image_id) => {}
+                    Some(image) => selected.push(image.clone()),
+                    None => return Err(ImageDataError::ImageNotFound),
+                }
+            }
+            selected
+        }
+        None => images,
+    };
+    if let Some(search) = &download.search {
+        selected.retain(|image| search.matches(image));
+    }
+
+    let store = blob_store(data_path);
+    let mut entries = vec![];
+    for image in selected.iter() {
+        let blob_key = image_blob_key(project_id, image);
+        let key = match image.is_encrypted {
+            true => Some(get_image_key(&project_info, image, project_keys)?),
+            false => None,
+        };
+
+        let store = store.clone();
+        entries.push(ArchiveEntry::chunks(
+            &download.layout.entry_path(image),
+            image.image_size,
+            move || open_image(&*store, &blob_key, key.as_deref()),
+        ));
+    }
+
+    Ok((entries.len(), zip_stream(entries)))
+}
+
+/// Outcome of `migrate_legacy_images`.
+#[derive(Debug, Default)]
+pub struct LegacyMigration {
+    pub migrated: usize,
+    /// Images left on the legacy key, they can be tried again later.
+    pub failed: Vec<Uuid>,
+}
+
+/// Re-encrypts every image still using the legacy password-salt key with
+/// `data_key`. Each image is moved under the index lock and the index saved
+/// right after, so an interrupted run can simply be started again. Images that
+/// fail are skipped and stay readable with the legacy key, as long as the
+/// password hash is kept.
+pub async fn migrate_legacy_images(
+    data_path: &str,
+    project_info: &ProjectInfo,
+    data_key: &DataKey,
+) -> Result<LegacyMigration, ImageDataError> {
+    let project_id = project_info.project_id;
+    let legacy_images: Vec<Uuid> = read_project_images(data_path, &project_id)
+        .await?
+        .into_iter()
+        .filter(|image| image.is_encrypted && image.key_id.is_none())
+        .map(|image| image.image_id)
+        .collect();
+    let mut migration = LegacyMigration::default();
+
+    for image_id in legacy_images {
+        let _index = lock_image_index(&project_id).await;
+        // the image may have changed since the index was first read
+        let mut images = read_project_images(data_path, &project_id).await?;
+        let image = match images.iter_mut().find(|image| image.image_id == image_id) {
+            Some(image) if image.key_id.is_none() => image,
+            _ => continue,
+        };
+
+        let store = blob_store(data_path);
+        let blob_key = image_blob_key(&project_id, image);
+        let legacy_key = get_legacy_encryption_key(project_info);
+        let new_key = data_key.key.to_vec();
+
+        let res = web::block(move || {
+            let read = |key: &[u8]| {
+                store
+                    .get(&blob_key)
+                    .and_then(|blob| read_blob(blob, Some(key)))
+            };
+
+            // a previous run may have rewritten the blob but died before saving the index
+            if read(&new_key).is_ok() {
+                return Ok(());
+            }
+            let plain = read(&legacy_key?)
+                .map_err(|err| ImageDataError::DecryptionError(err.to_string()))?;
+
+            let mut encrypted = Vec::new();
+            encrypt_stream(plain.as_slice(), &mut encrypted, &new_key)
+                .map_err(|_| ImageDataError::FailedToSaveImage)?;
+            put_reader(&*store, &blob_key, encrypted.as_slice())
+                .map_err(|_| ImageDataError::FailedToSaveImage)?;
+            Ok(())
+        })
+        .await
+        .unwrap_or(Err(ImageDataError::FailedToSaveImage));
+
+        if let Err(err) = res {
+            log::warn!("failed to migrate image {}: {}", image_id, err);
+            migration.failed.push(image_id);
+            continue;
+        }
+
+        image.key_id = Some(data_key.key_id);
+        write_project_images(data_path, &project_id, &images)?;
+        migration.migrated += 1;
+
+        log::debug!("migrated image {}", image_id);
+    }
 
-            if dec_bytes.is_err() {
-                return Err(ImageDataError::DecryptionError(
-                    dec_bytes.unwrap_err().to_string(),
-                ));
+    Ok(migration)
+}
+
+/// A page of the image index, see `ImageListQuery`. Only the index is read.
+pub async fn list_project_images(
+    data_path: &str,
+    project_id: &Uuid,
+    query: &ImageListQuery,
+) -> Result<ImagePage, ImageDataError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let fields = match &query.fields {
+        Some(fields) => Some(parse_image_fields(fields)?),
+        None => None,
+    };
+    let after = match &query.cursor {
+        Some(cursor) => Some(decode_cursor(cursor, query)?),
+        None => None,
+    };
+
+    let mut images: Vec<(SortKey, ImageData)> = read_project_images(data_path, project_id)
+        .await?
+        .into_iter()
+        .map(|image| (sort_key(&image, query.sort), image))
+        .filter(|(key, image)| match &after {
+            None => true,
+            Some(after) => {
+                let position = (key, &image.image_id).cmp(&(&after.key, &after.image_id));
+                match query.order {
+                    SortOrder::Asc => position.is_gt(),
+                    SortOrder::Desc => position.is_lt(),
+                }
             }
-            res_img.data = dec_bytes.ok().unwrap();
+        })
+        .collect();
+
+    images.sort_by(|(a_key, a), (b_key, b)| (a_key, &a.image_id).cmp(&(b_key, &b.image_id)));
+    if query.order == SortOrder::Desc {
+        images.reverse();
+    }
+
+    let next_cursor = match images.len() > limit {
+        true => {
+            let (key, image) = &images[limit - 1];
+            Some(encode_cursor(&ImageCursor {
+                sort: query.sort,
+                order: query.order,
+                key: key.clone(),
+                image_id: image.image_id,
+            }))
         }
+        false => None,
+    };
+
+    let images = images
+        .into_iter()
+        .take(limit)
+        .map(|(_, image)| {
+            let mut summary = match serde_json::to_value(image) {
+                Ok(serde_json::Value::Object(summary)) => summary,
+                _ => serde_json::Map::new(),
+            };
+            if let Some(fields) = &fields {
+                summary.retain(|field, _| fields.contains(field));
+            }
+            summary
+        })
+        .collect();
+
+    Ok(ImagePage {
+        images,
+        next_cursor,
+    })
+}
+
+/// Fields of a serialized `ImageData`, the ones a listing can select.
+const IMAGE_FIELDS: [&str; 11] = [
+    "image_id",
+    "image_name",
+    "mime",
+    "original_image_name",
+    "image_size",
+    "created_date",
+    "is_encrypted",
+    "tags",
+    "key_id",
+    "sha256",
+    "content_type",
+];
+
+fn sort_key(image: &ImageData, sort: ImageSort) -> SortKey {
+    match sort {
+        ImageSort::CreatedDate => SortKey::CreatedDate(image.created_date),
+        ImageSort::Name => SortKey::Name(image.image_name.to_lowercase()),
+        ImageSort::Size => SortKey::Size(image.image_size),
+    }
+}
+
+/// The requested fields, which all have to be fields of `ImageData`.
+fn parse_image_fields(fields: &str) -> Result<Vec<String>, ImageDataError> {
+    fields
+        .split(',')
+        .map(|field| field.trim())
+        .filter(|field| !field.is_empty())
+        .map(|field| match IMAGE_FIELDS.contains(&field) {
+            true => Ok(field.to_owned()),
+            false => Err(ImageDataError::InvalidListQuery(format!(
+                "unknown field {}",
+                field
+            ))),
+        })
+        .collect()
+}
+
+fn encode_cursor(cursor: &ImageCursor) -> String {
+    URL_SAFE_NO_PAD.encode(object_to_byte_vec(cursor))
+}
+
+fn decode_cursor(cursor: &str, query: &ImageListQuery) -> Result<ImageCursor, ImageDataError> {
+    let cursor: ImageCursor = URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(ImageDataError::InvalidListQuery("bad cursor".to_owned()))?;
+
+    match cursor.sort == query.sort && cursor.order == query.order {
+        true => Ok(cursor),
+        false => Err(ImageDataError::InvalidListQuery(
+            "the cursor is for another sort order".to_owned(),
+        )),
+    }
+}
+
+pub async fn update_image_tags(
+    data_path: &str,
+    project_id: &Uuid,
+    image_tags: &ImageTags,
+) -> Result<ImageData, ImageDataError> {
+    let _index = lock_image_index(project_id).await;
+    let mut images = read_project_images(data_path, project_id).await?;
+
+    let image = images
+        .iter_mut()
+        .find(|image| image.image_id == image_tags.image_id)
+        .ok_or(ImageDataError::ImageNotFound)?;
+    image.tags = image_tags
+        .image_tags
+        .split(';')
+        .map(|s| s.to_string())
+        .collect();
+    let image = image.clone();
+
+    write_project_images(data_path, project_id, &images)?;
+
+    Ok(image)
+}
+
+/// Removes the image from the index, which publishes `ImageDeleted`, and then
+/// its blob. A blob left behind by a failed delete is found by fsck.
+pub async fn delete_image(
+    data_path: &str,
+    project_id: &Uuid,
+    image_id: &Uuid,
+) -> Result<ImageData, ImageDataError> {
+    let _index = lock_image_index(project_id).await;
+    let mut images = read_project_images(data_path, project_id).await?;
+
+    let position = images
+        .iter()
+        .position(|image| image.image_id == *image_id)
+        .ok_or(ImageDataError::ImageNotFound)?;
+    let image = images.remove(position);
+    write_project_images(data_path, project_id, &images)?;
+
+    let store = blob_store(data_path);
+    let blob_key = image_blob_key(project_id, &image);
+    let deleted = match web::block(move || store.delete(&blob_key)).await {
+        Ok(res) => res.map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    if let Err(err) = deleted {
+        log::warn!(
+            "failed to delete the blob of image {}: {}",
+            image.image_id,
+            err
+        );
     }
 
-    Ok(res_img)
+    Ok(image)
+}
+
+/// Writes every image of a project decrypted into `out_dir`, named like in the
+/// project folder, along with the index as `images.json`. Returns the number of
+/// exported images.
+pub async fn export_project_images(
+    data_path: &str,
+    project_id: &Uuid,
+    project_keys: Option<&ProjectKeys>,
+    out_dir: &Path,
+) -> Result<usize, ImageDataError> {
+    let project_info = get_project_info(data_path, project_id).await?;
+    let images = read_project_images(data_path, project_id).await?;
+
+    fs::create_dir_all(out_dir).map_err(|_| ImageDataError::FailedToSaveImage)?;
+    let store = blob_store(data_path);
+
+    for image in images.iter() {
+        let key = match image.is_encrypted {
+            true => Some(get_image_key(&project_info, image, project_keys)?),
+            false => None,
+        };
+        let plain = store
+            .get(&image_blob_key(project_id, image))
+            .and_then(|blob| read_blob(blob, key.as_deref()))
+            .map_err(|err| ImageDataError::DecryptionError(err.to_string()))?;
+
+        let out_path = out_dir.join(image_file_name(image));
+        fs::write(out_path, plain).map_err(|_| ImageDataError::FailedToSaveImage)?;
+    }
+
+    create_file_write_all(
+        &out_dir.join("images.json"),
+        object_to_byte_vec(&images).as_slice(),
+    );
+
+    Ok(images.len())
 }
 
-async fn get_project_image(
+pub async fn get_project_image(
     data_path: &str,
     project_id: &Uuid,
     image_id: &Uuid,
@@ -245,13 +1017,11 @@ async fn get_project_image(
     Err(ImageDataError::ImageNotFound)
 }
 
-async fn read_project_images(
+pub async fn read_project_images(
     data_path: &str,
     project_id: &Uuid,
 ) -> Result<Vec<ImageData>, ImageDataError> {
-    let project_path = PathBuf::from(
-        data_path.to_owned() + "\\" + &project_id.to_string() + "\\project_images.json",
-    );
+    let project_path = project_images_json(data_path, project_id);
     if !project_path.exists() {
         return Err(ImageDataError::ProjectDosentExists);
     }
@@ -260,16 +1030,37 @@ async fn read_project_images(
     let mut data = String::new();
     file.read_to_string(&mut data).unwrap();
 
-    let images: Vec<ImageData> = serde_json::from_str(&data).unwrap();
-    Ok(images)
+    serde_json::from_str(&data).map_err(|err| ImageDataError::FailedToReadIndex(err.to_string()))
+}
+
+/// Saves the image index and publishes what changed in it, so every change
+/// of an image is seen by event subscribers whichever way it was made. The
+/// caller holds `lock_image_index` since reading the images it writes.
+pub fn write_project_images(
+    data_path: &str,
+    project_id: &Uuid,
+    images: &[ImageData],
+) -> Result<(), ImageDataError> {
+    let index_path = project_images_json(data_path, project_id);
+    let old: Vec<ImageData> = fs::read_to_string(&index_path)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default();
+
+    replace_file_atomic(&index_path, object_to_byte_vec(images).as_slice())
+        .map_err(|_| ImageDataError::FailedToSaveImage)?;
+
+    for event in image_index_events(&old, images) {
+        publish(project_id, event);
+    }
+    Ok(())
 }
 
-async fn get_project_info(
+pub async fn get_project_info(
     data_path: &str,
     project_id: &Uuid,
 ) -> Result<ProjectInfo, ImageDataError> {
-    let project_path =
-        PathBuf::from(data_path.to_owned() + "\\" + &project_id.to_string() + "\\project.json");
+    let project_path = project_info_json(data_path, project_id);
     if !project_path.exists() {
         return Err(ImageDataError::ProjectDosentExists);
     }
@@ -282,26 +1073,448 @@ async fn get_project_info(
     Ok(project_info)
 }
 
-async fn save_temp_image(temp_path: String, image_path: String, encryption_key: Option<String>) {
-    match encryption_key {
-        Some(encryption_key) => {
-            let mut file = File::open(temp_path).unwrap();
-            let mut buffer: Vec<u8> = Vec::new();
-            let err = file.read_to_end(&mut buffer);
-            println!("{:#?}", err);
-
-            let encrypted_image = encrypt_bytes(buffer, &encryption_key);
-            create_file_write_all(&PathBuf::from(&image_path), &encrypted_image);
+impl ImageData {
+    /// The detected content type, or else one guessed from the extension.
+    pub fn mime_type(&self) -> String {
+        match &self.content_type {
+            Some(content_type) => content_type.to_owned(),
+            None => detect_content_type(&[], &self.mime),
         }
+    }
+}
+
+/// Name of the blob of an image, like the file it used to be in the project
+/// folder.
+pub fn image_file_name(image_data: &ImageData) -> String {
+    format!("{}.{}", image_data.image_name, image_data.mime)
+}
+
+/// Key of the blob of an image in the blob store.
+pub fn image_blob_key(project_id: &Uuid, image_data: &ImageData) -> String {
+    format!("{}/{}", project_id, image_file_name(image_data))
+}
+
+/// Hash and type of the content of a saved image, as it will be sent.
+struct SavedContent {
+    sha256: String,
+    content_type: String,
+}
+
+/// Writes the uploaded file as blob `blob_key`, encrypted when there is a
+/// key. Nothing is under the key when it fails.
+fn save_temp_image(
+    store: &dyn BlobStore,
+    temp_path: &str,
+    blob_key: &str,
+    extension: &str,
+    encryption_key: Option<&[u8]>,
+) -> Result<SavedContent, ImageDataError> {
+    let saved = match encryption_key {
+        Some(encryption_key) => File::open(temp_path)
+            .and_then(|temp_file| {
+                let mut writer = store.put(blob_key)?;
+                encrypt_stream(temp_file, &mut writer, encryption_key)?;
+                writer.finish()
+            })
+            .and_then(|_| {
+                let mut head = Vec::new();
+                File::open(temp_path)?
+                    .take(CONTENT_HEAD_LEN)
+                    .read_to_end(&mut head)?;
+                Ok(SavedContent {
+                    sha256: hex::encode(hash_file(Path::new(temp_path))?),
+                    content_type: detect_content_type(&head, extension),
+                })
+            }),
         None => {
-            let temp_img = openImage(temp_path).unwrap().into_rgb8();
-            let _ = temp_img.save(image_path.to_owned());
+            // plain images are re-encoded, which needs them to be images
+            let (temp_img, format) = openImage(temp_path)
+                .and_then(|temp_img| Ok((temp_img, ImageFormat::from_path(blob_key)?)))
+                .map_err(|err| ImageDataError::InvalidImage(err.to_string()))?;
+            let mut encoded = Cursor::new(Vec::new());
+            temp_img
+                .into_rgb8()
+                .write_to(&mut encoded, format)
+                .map_err(std::io::Error::other)
+                .and_then(|_| put_reader(store, blob_key, encoded.get_ref().as_slice()))
+                .map(|_| SavedContent {
+                    sha256: hex::encode(Sha256::digest(encoded.get_ref())),
+                    content_type: detect_content_type(encoded.get_ref(), extension),
+                })
+        }
+    };
+
+    match saved {
+        Ok(saved) => {
+            log::debug!("saved image to {}", blob_key);
+            Ok(saved)
+        }
+        Err(err) => {
+            log::warn!("failed to save image to {}: {}", blob_key, err);
+            Err(ImageDataError::FailedToSaveImage)
         }
     }
+}
+
+/// Detects the content type of every image that has none recorded yet, from
+/// the start of its content, for images saved before types were detected on
+/// upload. Images that can't be read, e.g. with a key that isn't unlocked, are
+/// left out. Returns the number of images updated.
+pub async fn detect_content_types(
+    data_path: &str,
+    project_info: &ProjectInfo,
+    project_keys: Option<&ProjectKeys>,
+) -> Result<usize, ImageDataError> {
+    let project_id = project_info.project_id;
+    let images = read_project_images(data_path, &project_id).await?;
+    let store = blob_store(data_path);
+    let mut detected = HashMap::new();
+
+    for image in images.iter().filter(|image| image.content_type.is_none()) {
+        let key = match image.is_encrypted {
+            true => match get_image_key(project_info, image, project_keys) {
+                Ok(key) => Some(key),
+                Err(_) => continue,
+            },
+            false => None,
+        };
 
-    println!("saved image to {}", image_path);
+        let (store, blob_key) = (store.clone(), image_blob_key(&project_id, image));
+        let head = web::block(move || {
+            store
+                .get(&blob_key)
+                .and_then(|blob| open_blob(blob, key.as_deref()))
+                .and_then(|mut chunks| chunks.next().unwrap_or(Ok(vec![])))
+        })
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+        match head {
+            Ok(head) => {
+                detected.insert(image.image_id, detect_content_type(&head, &image.mime));
+            }
+            Err(err) => log::warn!("can't detect type of image {}: {}", image.image_id, err),
+        }
+    }
+    if detected.is_empty() {
+        return Ok(0);
+    }
+
+    // only the types are set, on the index as it is by now
+    let _index = lock_image_index(&project_id).await;
+    let mut images = read_project_images(data_path, &project_id).await?;
+    let mut updated = 0;
+    for image in images
+        .iter_mut()
+        .filter(|image| image.content_type.is_none())
+    {
+        if let Some(content_type) = detected.remove(&image.image_id) {
+            image.content_type = Some(content_type);
+            updated += 1;
+        }
+    }
+
+    if updated > 0 {
+        write_project_images(data_path, &project_id, &images)?;
+    }
+    Ok(updated)
 }
 
-fn get_encryption_key(project_info: &ProjectInfo) -> String {
-    project_info.password_hash.split(':').collect::<Vec<&str>>()[1].to_owned()
+/// Picks the key an encrypted image was written with.
+pub fn get_image_key(
+    project_info: &ProjectInfo,
+    image_data: &ImageData,
+    project_keys: Option<&ProjectKeys>,
+) -> Result<Vec<u8>, ImageDataError> {
+    match image_data.key_id {
+        None => get_legacy_encryption_key(project_info),
+        Some(key_id) => project_keys
+            .and_then(|keys| keys.find(&key_id))
+            .map(|key| key.key.to_vec())
+            .ok_or(ImageDataError::EncryptionKeyUnavailable),
+    }
+}
+
+/// Key of images encrypted before per-project data keys, derived from the
+/// password salt. Only used to read and migrate those images.
+fn get_legacy_encryption_key(project_info: &ProjectInfo) -> Result<Vec<u8>, ImageDataError> {
+    match project_info.password_hash.split(':').nth(1) {
+        Some(salt) => Ok(get_legacy_key(salt)),
+        None => Err(ImageDataError::EncryptionKeyUnavailable),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::project_events::{subscribe, ProjectEvent};
+    use crate::models::project_info::{
+        create_project_info, project_login, reset_project_password, ProjectLoginInfo,
+    };
+    use futures_util::future::join_all;
+
+    async fn project_with_inputs(inputs: usize) -> (String, String, ProjectInfo) {
+        let root = std::env::temp_dir().join(format!("images_{}", Uuid::new_v4()));
+        let (data_path, input_path) = (root.join("data"), root.join("input"));
+        fs::create_dir_all(&data_path).unwrap();
+        fs::create_dir_all(&input_path).unwrap();
+        for n in 0..inputs {
+            image::RgbImage::new(2, 2)
+                .save(input_path.join(format!("{}.png", n)))
+                .unwrap();
+        }
+
+        let data_path = data_path.to_str().unwrap().to_owned();
+        let login = ProjectLoginInfo {
+            project_name: "images".to_owned(),
+            password: "password".to_owned(),
+        };
+        let project = create_project_info(&data_path, &login).await.unwrap();
+
+        (data_path, input_path.to_str().unwrap().to_owned(), project)
+    }
+
+    fn upload(n: usize) -> UploadImage {
+        UploadImage {
+            image_path: format!("{}.png", n),
+            image_name: Some(format!("image {}", n)),
+            image_tags: "new".to_owned(),
+            encrypt: false,
+        }
+    }
+
+    #[actix_web::test]
+    async fn overlapping_changes_keep_every_image() {
+        let (data_path, input_path, project) = project_with_inputs(8).await;
+        let project_id = project.project_id;
+        let quota = ProjectQuota::default();
+
+        let first = upload_image(&data_path, &input_path, upload(0), project_id, None, &quota)
+            .await
+            .unwrap();
+
+        let uploads = (1..8)
+            .map(|n| upload_image(&data_path, &input_path, upload(n), project_id, None, &quota));
+        let retag = ImageTags {
+            image_id: first.image_id,
+            image_tags: "sorted".to_owned(),
+        };
+        let (uploaded, retagged) = futures_util::join!(
+            join_all(uploads),
+            update_image_tags(&data_path, &project_id, &retag)
+        );
+        assert!(uploaded.iter().all(|image| image.is_ok()));
+        retagged.unwrap();
+
+        let images = read_project_images(&data_path, &project_id).await.unwrap();
+        assert_eq!(images.len(), 8);
+        assert_eq!(images[0].tags, vec!["sorted".to_owned()]);
+
+        fs::remove_dir_all(Path::new(&data_path).parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn uploads_only_read_the_upload_folder() {
+        let (data_path, input_path, project) = project_with_inputs(1).await;
+        let outside = Path::new(&input_path).parent().unwrap().join("secret.png");
+        image::RgbImage::new(2, 2).save(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, Path::new(&input_path).join("link.png")).unwrap();
+
+        for image_path in [
+            outside.to_str().unwrap(),
+            "../secret.png",
+            "./../secret.png",
+            "link.png",
+            "missing.png",
+            "",
+        ] {
+            let upload = UploadImage {
+                image_path: image_path.to_owned(),
+                ..upload(0)
+            };
+            let res = upload_image(
+                &data_path,
+                &input_path,
+                upload,
+                project.project_id,
+                None,
+                &ProjectQuota::default(),
+            )
+            .await;
+            assert!(
+                matches!(res, Err(ImageDataError::InvalidUploadPath(_))),
+                "{:?} was read",
+                image_path
+            );
+        }
+
+        let images = read_project_images(&data_path, &project.project_id)
+            .await
+            .unwrap();
+        assert!(images.is_empty());
+
+        fs::remove_dir_all(Path::new(&data_path).parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn first_download_records_only_the_hash() {
+        let (data_path, input_path, project) = project_with_inputs(1).await;
+        let project_id = project.project_id;
+        let image = upload_image(
+            &data_path,
+            &input_path,
+            upload(0),
+            project_id,
+            None,
+            &ProjectQuota::default(),
+        )
+        .await
+        .unwrap();
+
+        // as saved before hashes were recorded
+        let mut images = read_project_images(&data_path, &project_id).await.unwrap();
+        images[0].sha256 = None;
+        let index = lock_image_index(&project_id).await;
+        write_project_images(&data_path, &project_id, &images).unwrap();
+        drop(index);
+
+        let retag = ImageTags {
+            image_id: image.image_id,
+            image_tags: "sorted".to_owned(),
+        };
+        let (saved, retagged) = futures_util::join!(
+            get_saved_image(&data_path, &project_id, &image.image_id, None),
+            update_image_tags(&data_path, &project_id, &retag)
+        );
+        assert_eq!(saved.unwrap().metadata.sha256, image.sha256);
+        retagged.unwrap();
+
+        let images = read_project_images(&data_path, &project_id).await.unwrap();
+        assert_eq!(images[0].sha256, image.sha256);
+        assert_eq!(images[0].tags, vec!["sorted".to_owned()]);
+
+        fs::remove_dir_all(Path::new(&data_path).parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn detects_missing_content_types() {
+        let (data_path, input_path, project) = project_with_inputs(2).await;
+        let project_id = project.project_id;
+        let quota = ProjectQuota::default();
+        for n in 0..2 {
+            upload_image(&data_path, &input_path, upload(n), project_id, None, &quota)
+                .await
+                .unwrap();
+        }
+
+        // as saved before types were detected
+        let mut images = read_project_images(&data_path, &project_id).await.unwrap();
+        images[0].content_type = None;
+        let index = lock_image_index(&project_id).await;
+        write_project_images(&data_path, &project_id, &images).unwrap();
+        drop(index);
+
+        assert_eq!(
+            detect_content_types(&data_path, &project, None)
+                .await
+                .unwrap(),
+            1
+        );
+        let images = read_project_images(&data_path, &project_id).await.unwrap();
+        assert_eq!(images[0].content_type.as_deref(), Some("image/png"));
+        assert_eq!(images[1].content_type.as_deref(), Some("image/png"));
+
+        // nothing is left to do the second time
+        assert_eq!(
+            detect_content_types(&data_path, &project, None)
+                .await
+                .unwrap(),
+            0
+        );
+
+        fs::remove_dir_all(Path::new(&data_path).parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn uploads_on_a_dropped_key_are_not_indexed() {
+        let (data_path, input_path, project) = project_with_inputs(1).await;
+        let project_id = project.project_id;
+        let login = ProjectLoginInfo {
+            project_name: "images".to_owned(),
+            password: "password".to_owned(),
+        };
+        let (_, stale_keys) = project_login(&data_path, &login).await.unwrap();
+
+        // the key changes after the uploader read it
+        reset_project_password(&data_path, &project_id, "new password", false)
+            .await
+            .unwrap();
+
+        let encrypted = UploadImage {
+            encrypt: true,
+            ..upload(0)
+        };
+        let res = upload_image(
+            &data_path,
+            &input_path,
+            encrypted,
+            project_id,
+            Some(&stale_keys),
+            &ProjectQuota::default(),
+        )
+        .await;
+
+        assert!(matches!(res, Err(ImageDataError::KeyChanged)));
+        assert!(read_project_images(&data_path, &project_id)
+            .await
+            .unwrap()
+            .is_empty());
+        let blobs = blob_store(&data_path)
+            .list(&format!("{}/", project_id))
+            .unwrap();
+        assert!(
+            blobs.iter().all(|key| !key.ends_with(".png")),
+            "{:?}",
+            blobs
+        );
+
+        fs::remove_dir_all(Path::new(&data_path).parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn delete_publishes_the_event_and_drops_the_blob() {
+        let (data_path, input_path, project) = project_with_inputs(2).await;
+        let project_id = project.project_id;
+        let quota = ProjectQuota::default();
+        let mut uploaded = vec![];
+        for n in 0..2 {
+            let image = upload_image(&data_path, &input_path, upload(n), project_id, None, &quota)
+                .await
+                .unwrap();
+            uploaded.push(image);
+        }
+        let mut events = subscribe(&project_id);
+
+        let deleted = delete_image(&data_path, &project_id, &uploaded[0].image_id)
+            .await
+            .unwrap();
+
+        assert_eq!(deleted.image_id, uploaded[0].image_id);
+        match events.try_recv().unwrap() {
+            ProjectEvent::ImageDeleted { image_id } => assert_eq!(image_id, deleted.image_id),
+            event => panic!("unexpected {:?}", event),
+        }
+        let images = read_project_images(&data_path, &project_id).await.unwrap();
+        assert_eq!(images, vec![uploaded[1].clone()]);
+        let store = blob_store(&data_path);
+        assert_eq!(
+            store.size(&image_blob_key(&project_id, &deleted)).unwrap(),
+            None
+        );
+        assert!(matches!(
+            delete_image(&data_path, &project_id, &deleted.image_id).await,
+            Err(ImageDataError::ImageNotFound)
+        ));
+
+        fs::remove_dir_all(Path::new(&data_path).parent().unwrap()).unwrap();
+    }
 }
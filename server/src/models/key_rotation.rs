@@ -0,0 +1,230 @@
+use ::serde::{Deserialize, Serialize};
+use actix_web::web;
+use chrono::{NaiveDateTime, Utc};
+use std::fmt;
+use std::fs;
+use uuid::Uuid;
+
+use crate::models::image_data::*;
+use crate::models::project_events::{publish, ProjectEvent};
+use crate::models::project_info::{finish_key_rotation, ProjectInfoErrors};
+use crate::utility::blob_store::blob_store;
+use crate::utility::encrypted_blob::{hash_blob, reencrypt_blob};
+use crate::utility::encryption::ProjectKeys;
+use crate::utility::file_utilities::*;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RotationStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Progress of a key rotation, saved to `key_rotation.json` after every image.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyRotation {
+    pub new_key_id: Uuid,
+    pub status: RotationStatus,
+    pub total_images: usize,
+    pub rotated_images: usize,
+    pub failed_images: Vec<Uuid>,
+    pub started_date: NaiveDateTime,
+    pub updated_date: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub enum KeyRotationError {
+    ImageData(ImageDataError),
+    ProjectInfo(ProjectInfoErrors),
+    FailedToSaveProgress,
+}
+
+impl fmt::Display for KeyRotationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyRotationError::ImageData(err) => write!(f, "{}", err),
+            KeyRotationError::ProjectInfo(err) => write!(f, "{}", err),
+            KeyRotationError::FailedToSaveProgress => {
+                write!(f, "failed to save key rotation progress")
+            }
+        }
+    }
+}
+
+impl From<ImageDataError> for KeyRotationError {
+    fn from(err: ImageDataError) -> Self {
+        KeyRotationError::ImageData(err)
+    }
+}
+
+impl From<ProjectInfoErrors> for KeyRotationError {
+    fn from(err: ProjectInfoErrors) -> Self {
+        KeyRotationError::ProjectInfo(err)
+    }
+}
+
+impl KeyRotation {
+    fn new(new_key_id: Uuid, total_images: usize) -> Self {
+        KeyRotation {
+            new_key_id,
+            status: RotationStatus::Running,
+            total_images,
+            rotated_images: 0,
+            failed_images: vec![],
+            started_date: Utc::now().naive_utc(),
+            updated_date: Utc::now().naive_utc(),
+        }
+    }
+}
+
+pub async fn get_key_rotation(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<Option<KeyRotation>, KeyRotationError> {
+    let rotation_path = key_rotation_json(data_path, project_id);
+    if !rotation_path.exists() {
+        return Ok(None);
+    }
+
+    let data =
+        fs::read_to_string(rotation_path).map_err(|_| KeyRotationError::FailedToSaveProgress)?;
+    let rotation =
+        serde_json::from_str(&data).map_err(|_| KeyRotationError::FailedToSaveProgress)?;
+
+    Ok(Some(rotation))
+}
+
+fn save_key_rotation(
+    data_path: &str,
+    project_id: &Uuid,
+    rotation: &mut KeyRotation,
+) -> Result<(), KeyRotationError> {
+    rotation.updated_date = Utc::now().naive_utc();
+
+    replace_file_atomic(
+        &key_rotation_json(data_path, project_id),
+        object_to_byte_vec(rotation).as_slice(),
+    )
+    .map_err(|_| KeyRotationError::FailedToSaveProgress)?;
+
+    publish(project_id, ProjectEvent::key_rotation(rotation));
+    Ok(())
+}
+
+/// Encrypted images not on `new_key_id` yet, leaving out the ones in `failed`.
+async fn pending_images(
+    data_path: &str,
+    project_id: &Uuid,
+    new_key_id: &Uuid,
+    failed: &[Uuid],
+) -> Result<Vec<ImageData>, KeyRotationError> {
+    Ok(read_project_images(data_path, project_id)
+        .await?
+        .into_iter()
+        .filter(|image| image.is_encrypted && image.key_id != Some(*new_key_id))
+        .filter(|image| !failed.contains(&image.image_id))
+        .collect())
+}
+
+/// Re-encrypts every encrypted image of the project that isn't on
+/// `project_keys.current` yet. Each blob is verified before it replaces the
+/// old one and progress is saved after every image, so a rotation that was
+/// interrupted continues with the images that are left when run again. Once
+/// all images are moved the previous key is dropped from the project.
+pub async fn run_key_rotation(
+    data_path: &str,
+    project_id: &Uuid,
+    project_keys: &ProjectKeys,
+) -> Result<KeyRotation, KeyRotationError> {
+    let project_info = get_project_info(data_path, project_id).await?;
+    let new_key = project_keys.current.clone();
+
+    let mut pending = pending_images(data_path, project_id, &new_key.key_id, &[]).await?;
+
+    let mut rotation = match get_key_rotation(data_path, project_id).await? {
+        Some(rotation) if rotation.new_key_id == new_key.key_id => rotation,
+        _ => KeyRotation::new(new_key.key_id, pending.len()),
+    };
+    rotation.status = RotationStatus::Running;
+    rotation.failed_images = vec![];
+    rotation.total_images = rotation.rotated_images + pending.len();
+    save_key_rotation(data_path, project_id, &mut rotation)?;
+
+    // uploads that started before the rotation can still index images on the
+    // previous key, so the index is checked again until none are left
+    loop {
+        for image in pending {
+            // the blob is rewritten under the lock too, so the image can't change meanwhile
+            let index = lock_image_index(project_id).await;
+            let store = blob_store(data_path);
+            let blob_key = image_blob_key(project_id, &image);
+            let old_key = get_image_key(&project_info, &image, Some(project_keys));
+            let new_key_bytes = new_key.key.to_vec();
+
+            let res = match old_key {
+                Ok(old_key) => web::block(move || {
+                    let hash = |key: &[u8]| {
+                        store
+                            .get(&blob_key)
+                            .and_then(|blob| hash_blob(blob, Some(key)))
+                    };
+                    // a crash after the blob was replaced but before the index was
+                    // saved leaves a blob that is already on the new key
+                    if hash(&old_key).is_err() && hash(&new_key_bytes).is_ok() {
+                        return Ok(());
+                    }
+                    reencrypt_blob(&*store, &blob_key, &old_key, &new_key_bytes)
+                })
+                .await
+                .map_err(|err| err.to_string())
+                .and_then(|res| res.map_err(|err| err.to_string())),
+                Err(err) => Err(err.to_string()),
+            };
+
+            match res {
+                Ok(()) => {
+                    // re-read the index, images may have been uploaded meanwhile
+                    let mut images = read_project_images(data_path, project_id).await?;
+                    if let Some(entry) = images.iter_mut().find(|i| i.image_id == image.image_id) {
+                        entry.key_id = Some(new_key.key_id);
+                    }
+                    write_project_images(data_path, project_id, &images)?;
+                    rotation.rotated_images += 1;
+                }
+                Err(err) => {
+                    log::warn!("failed to rotate key of image {}: {}", image.image_id, err);
+                    rotation.failed_images.push(image.image_id);
+                }
+            }
+
+            drop(index);
+            save_key_rotation(data_path, project_id, &mut rotation)?;
+        }
+
+        // the last check and dropping the previous key happen under the index
+        // lock, uploads check their key under it before indexing
+        let _index = lock_image_index(project_id).await;
+        pending = pending_images(
+            data_path,
+            project_id,
+            &new_key.key_id,
+            &rotation.failed_images,
+        )
+        .await?;
+        if !pending.is_empty() {
+            rotation.total_images += pending.len();
+            continue;
+        }
+
+        if rotation.failed_images.is_empty() {
+            finish_key_rotation(data_path, project_id).await?;
+            rotation.status = RotationStatus::Completed;
+        } else {
+            rotation.status = RotationStatus::Failed;
+        }
+        break;
+    }
+    save_key_rotation(data_path, project_id, &mut rotation)?;
+
+    Ok(rotation)
+}
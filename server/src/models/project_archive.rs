@@ -0,0 +1,553 @@
+use ::serde::{Deserialize, Serialize};
+use actix_web::web;
+use chrono::{NaiveDateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path};
+use tar::Archive;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::models::fsck::PROJECT_FILES;
+use crate::models::image_data::{image_blob_key, image_file_name, read_project_images, ImageData};
+use crate::models::project_info::*;
+use crate::utility::archive::{tar_stream, ArchiveEntry};
+use crate::utility::blob_store::{blob_store, put_reader};
+use crate::utility::encrypted_blob::{hash_blob, hash_file, open_blob, PlainChunks};
+use crate::utility::file_utilities::*;
+
+const ARCHIVE_FORMAT: &str = "sorter-project";
+const ARCHIVE_VERSION: u32 = 1;
+const MANIFEST_PATH: &str = "manifest.json";
+const IMAGES_DIR: &str = "images/";
+
+/// First entry of a project archive, describing every other entry.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub format: String,
+    pub version: u32,
+    pub exported_date: NaiveDateTime,
+    pub project_id: Uuid,
+    pub project_name: String,
+    pub image_count: usize,
+    pub files: Vec<ArchiveFile>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArchiveFile {
+    pub path: String,
+    pub size: u64,
+    /// Hex SHA-256 of the file as stored in the archive.
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ArchiveImport {
+    /// Import under another name, e.g. when the name is taken on this server.
+    pub project_name: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ProjectArchiveErrors {
+    ProjectDosentExist,
+    ProjectAllreadyExists,
+    RotationInProgress,
+    InvalidArchive(String),
+    FailedToReadFile(String),
+    FailedToSaveProject,
+}
+
+impl fmt::Display for ProjectArchiveErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectArchiveErrors::ProjectDosentExist => write!(f, "project doesn't exist"),
+            ProjectArchiveErrors::ProjectAllreadyExists => {
+                write!(f, "a project with that name already exists")
+            }
+            ProjectArchiveErrors::RotationInProgress => {
+                write!(f, "a key rotation of the project hasn't finished")
+            }
+            ProjectArchiveErrors::InvalidArchive(err) => write!(f, "invalid archive: {}", err),
+            ProjectArchiveErrors::FailedToReadFile(err) => write!(f, "failed to read: {}", err),
+            ProjectArchiveErrors::FailedToSaveProject => write!(f, "failed to save the project"),
+        }
+    }
+}
+
+fn invalid(err: impl fmt::Display) -> ProjectArchiveErrors {
+    ProjectArchiveErrors::InvalidArchive(err.to_string())
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// A tar archive of the project: the manifest, `project.json`, the image index
+/// and every blob as stored, so encrypted images stay encrypted and are opened
+/// with the same project password after an import. Members, API keys and the
+/// audit log belong to this server and are left out.
+pub async fn export_project_archive(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<PlainChunks, ProjectArchiveErrors> {
+    let project = find_project(data_path, project_id)
+        .await
+        .map_err(|_| ProjectArchiveErrors::ProjectDosentExist)?;
+    // blobs would be on two different keys
+    if project.previous_key_info.is_some() {
+        return Err(ProjectArchiveErrors::RotationInProgress);
+    }
+    let images = read_project_images(data_path, project_id)
+        .await
+        .map_err(|err| ProjectArchiveErrors::FailedToReadFile(err.to_string()))?;
+
+    let mut entries = vec![];
+    let mut files = vec![];
+    for (path, bytes) in [
+        ("project.json", object_to_byte_vec(&project)),
+        ("project_images.json", object_to_byte_vec(&images)),
+    ] {
+        files.push(ArchiveFile {
+            path: path.to_owned(),
+            size: bytes.len() as u64,
+            sha256: sha256_hex(&bytes),
+        });
+        entries.push(ArchiveEntry::bytes(path, bytes));
+    }
+
+    let store = blob_store(data_path);
+    let blobs: Vec<(String, String)> = images
+        .iter()
+        .map(|image| {
+            (
+                image_blob_key(project_id, image),
+                format!("{}{}", IMAGES_DIR, image_file_name(image)),
+            )
+        })
+        .collect();
+
+    // the manifest needs every blob hashed before the first byte is sent
+    let blob_files = {
+        let store = store.clone();
+        web::block(move || {
+            blobs
+                .into_iter()
+                .map(|(blob_key, path)| {
+                    let read_error = |err: io::Error| {
+                        ProjectArchiveErrors::FailedToReadFile(format!("{}: {}", path, err))
+                    };
+                    let sha256 = store
+                        .get(&blob_key)
+                        .and_then(|blob| hash_blob(blob, None))
+                        .map_err(read_error)?;
+                    let size = store
+                        .size(&blob_key)
+                        .and_then(|size| {
+                            size.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+                        })
+                        .map_err(read_error)?;
+                    Ok((blob_key, path, size, sha256))
+                })
+                .collect::<Result<Vec<_>, ProjectArchiveErrors>>()
+        })
+        .await
+        .map_err(|err| ProjectArchiveErrors::FailedToReadFile(err.to_string()))??
+    };
+
+    for (blob_key, path, size, sha256) in blob_files {
+        let store = store.clone();
+        files.push(ArchiveFile {
+            path: path.to_owned(),
+            size,
+            sha256: hex::encode(sha256),
+        });
+        entries.push(ArchiveEntry::chunks(&path, size, move || {
+            open_blob(store.get(&blob_key)?, None)
+        }));
+    }
+
+    let manifest = ArchiveManifest {
+        format: ARCHIVE_FORMAT.to_owned(),
+        version: ARCHIVE_VERSION,
+        exported_date: Utc::now().naive_utc(),
+        project_id: project.project_id,
+        project_name: project.project_name.to_owned(),
+        image_count: images.len(),
+        files,
+    };
+    entries.insert(
+        0,
+        ArchiveEntry::bytes(MANIFEST_PATH, object_to_byte_vec(&manifest)),
+    );
+
+    Ok(tar_stream(entries))
+}
+
+/// Adds the project in the archive at `archive_path` to this server. The
+/// archive is unpacked into a staging folder and checked against its manifest
+/// before anything is registered. The project keeps its id unless that id is
+/// taken here, then it gets a new one; a taken name is an error, pass
+/// `project_name` to import under another one.
+pub async fn import_project_archive(
+    data_path: &str,
+    archive_path: &Path,
+    project_name: Option<&str>,
+) -> Result<ProjectInfo, ProjectArchiveErrors> {
+    let staging = imports_dir(data_path).join(Uuid::new_v4().to_string());
+    fs::create_dir_all(&staging).map_err(|_| ProjectArchiveErrors::FailedToSaveProject)?;
+
+    // unpacking and storing the blobs is all blocking I/O
+    let result = {
+        let (data_path, archive_path) = (data_path.to_owned(), archive_path.to_owned());
+        let project_name = project_name.map(str::to_owned);
+        let staging = staging.clone();
+        web::block(move || {
+            import_staged(&data_path, &archive_path, &staging, project_name.as_deref())
+        })
+        .await
+        .unwrap_or(Err(ProjectArchiveErrors::FailedToSaveProject))
+    };
+
+    if staging.exists() {
+        let _ = fs::remove_dir_all(&staging);
+    }
+    result
+}
+
+fn import_staged(
+    data_path: &str,
+    archive_path: &Path,
+    staging: &Path,
+    project_name: Option<&str>,
+) -> Result<ProjectInfo, ProjectArchiveErrors> {
+    let manifest = unpack_archive(archive_path, staging)?;
+    if manifest.format != ARCHIVE_FORMAT || manifest.version != ARCHIVE_VERSION {
+        return Err(invalid(format!(
+            "unsupported format {} version {}",
+            manifest.format, manifest.version
+        )));
+    }
+
+    let mut project: ProjectInfo = read_staged_json(staging, "project.json")?;
+    let images: Vec<ImageData> = read_staged_json(staging, "project_images.json")?;
+    if project.project_id != manifest.project_id {
+        return Err(invalid("project.json doesn't belong to the manifest"));
+    }
+    if project.previous_key_info.is_some() {
+        return Err(ProjectArchiveErrors::RotationInProgress);
+    }
+
+    let mut image_ids = HashSet::new();
+    for image in images.iter() {
+        if !image_ids.insert(image.image_id) {
+            return Err(invalid(format!("image {} is listed twice", image.image_id)));
+        }
+        // the name comes from the archive and is joined onto the staging
+        // folder and the blob key, so it gets the same check as the entries
+        let file_name = image_file_name(image);
+        if !is_blob_file_name(&file_name) {
+            return Err(invalid(format!(
+                "image {} has an invalid name {}",
+                image.image_id, file_name
+            )));
+        }
+        if !staging.join(&file_name).is_file() {
+            return Err(invalid(format!("image {} has no file", file_name)));
+        }
+    }
+
+    let _projects = blocking_lock_project_index();
+    let mut projects: Vec<ProjectInfo> = read_global_projects(data_path)?;
+
+    if let Some(project_name) = project_name {
+        project.project_name = project_name.to_owned();
+    }
+    if projects
+        .iter()
+        .any(|other| other.project_name == project.project_name)
+    {
+        return Err(ProjectArchiveErrors::ProjectAllreadyExists);
+    }
+    while projects
+        .iter()
+        .any(|other| other.project_id == project.project_id)
+        || project_dir(data_path, &project.project_id).exists()
+    {
+        project.project_id = Uuid::new_v4();
+    }
+
+    replace_file_atomic(
+        &staging.join("project.json"),
+        object_to_byte_vec(&project).as_slice(),
+    )
+    .map_err(|_| ProjectArchiveErrors::FailedToSaveProject)?;
+    store_staged_blobs(data_path, staging, &project.project_id, &images)?;
+
+    let dir = project_dir(data_path, &project.project_id);
+    fs::create_dir_all(&dir).map_err(|_| ProjectArchiveErrors::FailedToSaveProject)?;
+    for file_name in ["project.json", "project_images.json"] {
+        fs::rename(staging.join(file_name), dir.join(file_name))
+            .map_err(|_| ProjectArchiveErrors::FailedToSaveProject)?;
+    }
+
+    projects.push(project.clone());
+    replace_file_atomic(
+        &global_project_json(data_path),
+        object_to_byte_vec(&projects).as_slice(),
+    )
+    .map_err(|_| ProjectArchiveErrors::FailedToSaveProject)?;
+
+    Ok(project)
+}
+
+/// Moves the unpacked blobs into the blob store. Blobs stored before a failure
+/// are removed again.
+fn store_staged_blobs(
+    data_path: &str,
+    staging: &Path,
+    project_id: &Uuid,
+    images: &[ImageData],
+) -> Result<(), ProjectArchiveErrors> {
+    let store = blob_store(data_path);
+    let mut stored: Vec<String> = vec![];
+
+    for image in images {
+        let blob_key = image_blob_key(project_id, image);
+        let res = File::open(staging.join(image_file_name(image)))
+            .and_then(|file| put_reader(&*store, &blob_key, file));
+
+        if let Err(err) = res {
+            log::warn!("failed to store blob {}: {}", blob_key, err);
+            for blob_key in stored {
+                let _ = store.delete(&blob_key);
+            }
+            return Err(ProjectArchiveErrors::FailedToSaveProject);
+        }
+        stored.push(blob_key);
+    }
+
+    Ok(())
+}
+
+/// Unpacks the archive into `staging` and checks every entry against the
+/// manifest, which has to come first.
+fn unpack_archive(
+    archive_path: &Path,
+    staging: &Path,
+) -> Result<ArchiveManifest, ProjectArchiveErrors> {
+    let file = File::open(archive_path)
+        .map_err(|err| ProjectArchiveErrors::FailedToReadFile(err.to_string()))?;
+    let mut archive = Archive::new(file);
+    let mut entries = archive.entries().map_err(invalid)?;
+
+    let manifest: ArchiveManifest = match entries.next() {
+        Some(Ok(entry)) if entry_path(&entry)? == MANIFEST_PATH => {
+            serde_json::from_reader(entry).map_err(invalid)?
+        }
+        Some(Err(err)) => return Err(invalid(err)),
+        _ => return Err(invalid("the archive doesn't start with a manifest")),
+    };
+
+    let mut unpacked = HashSet::new();
+    for entry in entries {
+        let mut entry = entry.map_err(invalid)?;
+        let path = entry_path(&entry)?;
+        if !entry.header().entry_type().is_file() {
+            return Err(invalid(format!("{} is not a file", path)));
+        }
+
+        let listed = manifest
+            .files
+            .iter()
+            .find(|file| file.path == path)
+            .ok_or_else(|| invalid(format!("{} is not in the manifest", path)))?;
+        let target = staging.join(staged_file_name(&path)?);
+        if !unpacked.insert(path.to_owned()) {
+            return Err(invalid(format!("{} is in the archive twice", path)));
+        }
+
+        let mut out =
+            File::create(&target).map_err(|_| ProjectArchiveErrors::FailedToSaveProject)?;
+        let size = io::copy(&mut entry, &mut out).map_err(invalid)?;
+        let sha256 = hash_file(&target).map_err(invalid)?;
+
+        if size != listed.size || hex::encode(sha256) != listed.sha256 {
+            return Err(invalid(format!("{} doesn't match its checksum", path)));
+        }
+    }
+
+    if let Some(missing) = manifest
+        .files
+        .iter()
+        .find(|file| !unpacked.contains(&file.path))
+    {
+        return Err(invalid(format!("{} is missing", missing.path)));
+    }
+
+    Ok(manifest)
+}
+
+fn entry_path(entry: &tar::Entry<File>) -> Result<String, ProjectArchiveErrors> {
+    let path = entry.path().map_err(invalid)?;
+    path.to_str()
+        .map(|path| path.to_owned())
+        .ok_or_else(|| invalid("entry path is not UTF-8"))
+}
+
+/// Blobs go flat into the project folder next to the JSON files, so only plain
+/// file names are accepted and none that would replace a project file.
+fn staged_file_name(path: &str) -> Result<String, ProjectArchiveErrors> {
+    if path == "project.json" || path == "project_images.json" {
+        return Ok(path.to_owned());
+    }
+
+    let file_name = path
+        .strip_prefix(IMAGES_DIR)
+        .ok_or_else(|| invalid(format!("unexpected entry {}", path)))?;
+    if is_blob_file_name(file_name) {
+        Ok(file_name.to_owned())
+    } else {
+        Err(invalid(format!("unexpected entry {}", path)))
+    }
+}
+
+fn is_blob_file_name(file_name: &str) -> bool {
+    let mut components = Path::new(file_name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !PROJECT_FILES.contains(&file_name)
+        && !file_name.ends_with(".tmp")
+}
+
+fn read_staged_json<T: ::serde::de::DeserializeOwned>(
+    staging: &Path,
+    file_name: &str,
+) -> Result<T, ProjectArchiveErrors> {
+    let data = fs::read_to_string(staging.join(file_name))
+        .map_err(|err| invalid(format!("{}: {}", file_name, err)))?;
+    serde_json::from_str(&data).map_err(|err| invalid(format!("{}: {}", file_name, err)))
+}
+
+fn read_global_projects(data_path: &str) -> Result<Vec<ProjectInfo>, ProjectArchiveErrors> {
+    let path = global_project_json(data_path);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let data = fs::read_to_string(path)
+        .map_err(|err| ProjectArchiveErrors::FailedToReadFile(err.to_string()))?;
+    serde_json::from_str(&data)
+        .map_err(|err| ProjectArchiveErrors::FailedToReadFile(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_archive(path: &Path, project: &ProjectInfo, files: &[(&str, Vec<u8>)]) {
+        let manifest = ArchiveManifest {
+            format: ARCHIVE_FORMAT.to_owned(),
+            version: ARCHIVE_VERSION,
+            exported_date: Utc::now().naive_utc(),
+            project_id: project.project_id,
+            project_name: project.project_name.to_owned(),
+            image_count: files.len() - 2,
+            files: files
+                .iter()
+                .map(|(path, bytes)| ArchiveFile {
+                    path: (*path).to_owned(),
+                    size: bytes.len() as u64,
+                    sha256: sha256_hex(bytes),
+                })
+                .collect(),
+        };
+
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        let manifest = object_to_byte_vec(&manifest);
+        for (path, bytes) in [(MANIFEST_PATH, &manifest)]
+            .into_iter()
+            .chain(files.iter().map(|(path, bytes)| (*path, bytes)))
+        {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(bytes.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, bytes.as_slice())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[actix_web::test]
+    async fn rejects_image_names_leaving_the_staging_folder() {
+        let root = std::env::temp_dir().join(format!("archive_{}", Uuid::new_v4()));
+        let (source, data_path) = (root.join("source"), root.join("data"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&data_path).unwrap();
+        let login = ProjectLoginInfo {
+            project_name: "archived".to_owned(),
+            password: "password".to_owned(),
+        };
+        let project = create_project_info(source.to_str().unwrap(), &login)
+            .await
+            .unwrap();
+
+        // a blob one folder up from the staging folder, where the import
+        // would pick it up if the name wasn't checked
+        let imports = imports_dir(data_path.to_str().unwrap());
+        fs::create_dir_all(&imports).unwrap();
+        fs::write(imports.join("escape.png"), b"x").unwrap();
+
+        for (image_name, mime) in [
+            ("../escape", "png"),
+            ("x", "png/../../escape"),
+            ("/etc/passwd", ""),
+        ] {
+            let image = ImageData {
+                image_id: Uuid::new_v4(),
+                image_name: image_name.to_owned(),
+                mime: mime.to_owned(),
+                original_image_name: "escape.png".to_owned(),
+                image_size: 1,
+                created_date: Utc::now().naive_utc(),
+                is_encrypted: false,
+                tags: vec![],
+                key_id: None,
+                sha256: None,
+                content_type: None,
+            };
+            let archive_path = root.join("project.tar");
+            write_archive(
+                &archive_path,
+                &project,
+                &[
+                    ("project.json", object_to_byte_vec(&project)),
+                    ("project_images.json", object_to_byte_vec(&vec![image])),
+                ],
+            );
+
+            let res =
+                import_project_archive(data_path.to_str().unwrap(), &archive_path, None).await;
+            match res {
+                Err(ProjectArchiveErrors::InvalidArchive(err)) => {
+                    assert!(err.contains("invalid name"), "{}", err)
+                }
+                res => panic!(
+                    "{}.{} was imported: {:?}",
+                    image_name,
+                    mime,
+                    res.map(|_| ())
+                ),
+            }
+        }
+        assert!(!project_dir(data_path.to_str().unwrap(), &project.project_id).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
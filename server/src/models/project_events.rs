@@ -0,0 +1,160 @@
+use ::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use super::image_data::ImageData;
+use super::key_rotation::{KeyRotation, RotationStatus};
+
+/// Events a subscriber can fall behind by before it misses some.
+const EVENT_BUFFER: usize = 256;
+
+/// Something that changed in a project, published by the models whenever they
+/// save it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProjectEvent {
+    ImageAdded {
+        image: ImageData,
+    },
+    /// Anything but the tags changed, e.g. the key the image is encrypted with.
+    ImageUpdated {
+        image: ImageData,
+    },
+    ImageTagged {
+        image_id: Uuid,
+        tags: Vec<String>,
+    },
+    ImageDeleted {
+        image_id: Uuid,
+    },
+    JobProgress {
+        job: Job,
+        status: JobStatus,
+        done: usize,
+        total: usize,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Job {
+    KeyRotation,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+impl ProjectEvent {
+    /// Name of the event, its `type`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProjectEvent::ImageAdded { .. } => "image_added",
+            ProjectEvent::ImageUpdated { .. } => "image_updated",
+            ProjectEvent::ImageTagged { .. } => "image_tagged",
+            ProjectEvent::ImageDeleted { .. } => "image_deleted",
+            ProjectEvent::JobProgress { .. } => "job_progress",
+        }
+    }
+
+    pub fn key_rotation(rotation: &KeyRotation) -> Self {
+        ProjectEvent::JobProgress {
+            job: Job::KeyRotation,
+            status: match rotation.status {
+                RotationStatus::Running => JobStatus::Running,
+                RotationStatus::Completed => JobStatus::Completed,
+                RotationStatus::Failed => JobStatus::Failed,
+            },
+            done: rotation.rotated_images + rotation.failed_images.len(),
+            total: rotation.total_images,
+        }
+    }
+}
+
+/// The events that turn the image index `old` into `new`.
+pub fn image_index_events(old: &[ImageData], new: &[ImageData]) -> Vec<ProjectEvent> {
+    let old: HashMap<Uuid, &ImageData> = old.iter().map(|image| (image.image_id, image)).collect();
+    let mut events = vec![];
+
+    for image in new {
+        match old.get(&image.image_id) {
+            None => events.push(ProjectEvent::ImageAdded {
+                image: image.clone(),
+            }),
+            Some(old_image) if *old_image == image => {}
+            Some(old_image) => {
+                let untagged = ImageData {
+                    tags: image.tags.clone(),
+                    ..(*old_image).clone()
+                };
+                events.push(match untagged == *image {
+                    true => ProjectEvent::ImageTagged {
+                        image_id: image.image_id,
+                        tags: image.tags.clone(),
+                    },
+                    false => ProjectEvent::ImageUpdated {
+                        image: image.clone(),
+                    },
+                });
+            }
+        }
+    }
+
+    for image_id in old.keys() {
+        if !new.iter().any(|image| image.image_id == *image_id) {
+            events.push(ProjectEvent::ImageDeleted {
+                image_id: *image_id,
+            });
+        }
+    }
+
+    events
+}
+
+static SUBSCRIBERS: OnceLock<Mutex<HashMap<Uuid, broadcast::Sender<ProjectEvent>>>> =
+    OnceLock::new();
+
+fn subscribers() -> &'static Mutex<HashMap<Uuid, broadcast::Sender<ProjectEvent>>> {
+    SUBSCRIBERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Receives the events of every project, for the webhook dispatcher.
+static ALL_SUBSCRIBERS: OnceLock<broadcast::Sender<(Uuid, ProjectEvent)>> = OnceLock::new();
+
+/// Sends `event` to everyone subscribed to the project, if anyone is.
+pub fn publish(project_id: &Uuid, event: ProjectEvent) {
+    if let Some(sender) = ALL_SUBSCRIBERS.get() {
+        let _ = sender.send((*project_id, event.clone()));
+    }
+
+    let mut subscribers = subscribers().lock().unwrap();
+
+    if let Some(sender) = subscribers.get(project_id) {
+        if sender.send(event).is_err() {
+            // everyone unsubscribed
+            subscribers.remove(project_id);
+        }
+    }
+}
+
+pub fn subscribe(project_id: &Uuid) -> broadcast::Receiver<ProjectEvent> {
+    subscribers()
+        .lock()
+        .unwrap()
+        .entry(*project_id)
+        .or_insert_with(|| broadcast::channel(EVENT_BUFFER).0)
+        .subscribe()
+}
+
+/// The events of every project, with the project they happened in.
+pub fn subscribe_all() -> broadcast::Receiver<(Uuid, ProjectEvent)> {
+    ALL_SUBSCRIBERS
+        .get_or_init(|| broadcast::channel(EVENT_BUFFER).0)
+        .subscribe()
+}
@@ -1,28 +1,75 @@
 use ::serde::{Deserialize, Serialize};
+use actix_web::web;
 use chrono::{NaiveDateTime, Utc};
+use std::fmt;
 use std::io::Read;
-use std::{fs, fs::File, path::PathBuf};
+use std::{fs, fs::File};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-use crate::models::image_data::ImageData;
-use crate::utility::{file_utilities::*, hash_password, verify_password};
+use crate::models::image_data::{
+    lock_image_index, migrate_legacy_images, read_project_images, ImageData,
+};
+use crate::models::user_info::{retain_member_keys, seal_member_keys};
+use crate::utility::blob_store::blob_store;
+use crate::utility::encryption::{
+    derive_key_encryption_key, unwrap_data_key, wrap_data_key, DataKey, KdfParams, ProjectKeys,
+};
+use crate::utility::{
+    dummy_verify_password, file_utilities::*, hash_password, is_legacy_password_hash,
+    password_needs_rehash, verify_password,
+};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
 pub struct ProjectInfo {
     pub project_id: Uuid,
     pub project_name: String,
     pub password_hash: String,
     pub created_date: NaiveDateTime,
+    #[serde(default)]
+    pub key_info: Option<WrappedKeyInfo>,
+    /// The key being rotated away from, kept until every blob is re-encrypted.
+    #[serde(default)]
+    pub previous_key_info: Option<WrappedKeyInfo>,
+    #[serde(default)]
+    pub quota: ProjectQuota,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Storage limits of a project. Limits left unset fall back to the `quota`
+/// of the server config, and to no limit when that isn't set either.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct ProjectQuota {
+    pub max_bytes: Option<u64>,
+    pub max_images: Option<u64>,
+}
+
+/// The project data key, wrapped by a key derived from the project password.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct WrappedKeyInfo {
+    pub key_id: Uuid,
+    pub kdf: KdfParams,
+    pub wrapped_key: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
 pub struct ProjectLoginInfo {
     pub project_name: String,
     pub password: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct Projects {
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct ProjectPasswordChange {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct ProjectPassword {
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Projects {
     pub project_id: Uuid,
     pub project_name: String,
     pub created_date: NaiveDateTime,
@@ -31,9 +78,39 @@ struct Projects {
 #[derive(Debug)]
 pub enum ProjectInfoErrors {
     FailedToCreateProjectFolder,
+    FailedToSaveProject,
     ProjectAllreadyExists,
     ProjectDosentExist,
     WrongPassword,
+    KeyDerivationFailed(String),
+    FailedToMigrateImages(String),
+    EncryptedImages(usize),
+}
+
+impl fmt::Display for ProjectInfoErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectInfoErrors::FailedToCreateProjectFolder => {
+                write!(f, "failed to create the project folder")
+            }
+            ProjectInfoErrors::FailedToSaveProject => write!(f, "failed to save the project"),
+            ProjectInfoErrors::ProjectAllreadyExists => write!(f, "project already exists"),
+            ProjectInfoErrors::ProjectDosentExist => write!(f, "project doesn't exist"),
+            ProjectInfoErrors::WrongPassword => write!(f, "wrong password"),
+            ProjectInfoErrors::KeyDerivationFailed(err) => {
+                write!(f, "failed to unlock the project key: {}", err)
+            }
+            ProjectInfoErrors::FailedToMigrateImages(err) => {
+                write!(f, "failed to migrate legacy encrypted images: {}", err)
+            }
+            ProjectInfoErrors::EncryptedImages(count) => write!(
+                f,
+                "{} encrypted image(s) can't be read anymore after a reset, their key is \
+                 locked with the old password",
+                count
+            ),
+        }
+    }
 }
 
 impl ProjectInfo {
@@ -43,65 +120,163 @@ impl ProjectInfo {
             project_name: project_name.to_owned(),
             password_hash: password_hash.to_owned(),
             created_date: Utc::now().naive_utc(),
+            key_info: None,
+            previous_key_info: None,
+            quota: ProjectQuota::default(),
+        }
+    }
+
+    /// The quota of the project with the server defaults filled in.
+    pub fn get_quota(&self, defaults: &ProjectQuota) -> ProjectQuota {
+        ProjectQuota {
+            max_bytes: self.quota.max_bytes.or(defaults.max_bytes),
+            max_images: self.quota.max_images.or(defaults.max_images),
+        }
+    }
+
+    /// Unwraps every key of the project with the project password.
+    pub fn unlock_keys(&self, password: &str) -> Result<ProjectKeys, ProjectInfoErrors> {
+        let current = match &self.key_info {
+            Some(key_info) => key_info.unlock(password)?,
+            None => {
+                return Err(ProjectInfoErrors::KeyDerivationFailed(
+                    "project has no data key".to_owned(),
+                ))
+            }
+        };
+        let previous = match &self.previous_key_info {
+            Some(key_info) => Some(key_info.unlock(password)?),
+            None => None,
+        };
+
+        Ok(ProjectKeys { current, previous })
+    }
+}
+
+impl WrappedKeyInfo {
+    /// Wraps `data_key` with a key derived from `password` using fresh kdf params.
+    pub fn new(password: &str, data_key: &DataKey) -> Result<Self, ProjectInfoErrors> {
+        let kdf = KdfParams::generate();
+        let kek = derive_key_encryption_key(password, &kdf)
+            .map_err(|err| ProjectInfoErrors::KeyDerivationFailed(err.to_string()))?;
+
+        Ok(WrappedKeyInfo {
+            key_id: data_key.key_id,
+            kdf,
+            wrapped_key: wrap_data_key(data_key, &kek),
+        })
+    }
+
+    pub fn unlock(&self, password: &str) -> Result<DataKey, ProjectInfoErrors> {
+        let kek = derive_key_encryption_key(password, &self.kdf)
+            .map_err(|err| ProjectInfoErrors::KeyDerivationFailed(err.to_string()))?;
+
+        unwrap_data_key(self.key_id, &self.wrapped_key, &kek)
+            .map_err(|err| ProjectInfoErrors::KeyDerivationFailed(err.to_string()))
+    }
+}
+
+impl From<&ProjectInfo> for Projects {
+    fn from(project: &ProjectInfo) -> Self {
+        Projects {
+            project_id: project.project_id,
+            project_name: project.project_name.to_owned(),
+            created_date: project.created_date,
         }
     }
 }
 
-pub async fn get_all_project_infos(data_path: &str) -> Result<Vec<ProjectInfo>, ProjectInfoErrors> {
-    read_global_project_info(data_path).await
+pub async fn get_all_project_infos(data_path: &str) -> Result<Vec<Projects>, ProjectInfoErrors> {
+    let projects = read_global_project_info(data_path).await?;
+
+    Ok(projects.iter().map(Projects::from).collect())
+}
+
+/// Held across every read-modify-write of the global `project.json`.
+static PROJECT_INDEX_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+pub async fn lock_project_index() -> tokio::sync::MutexGuard<'static, ()> {
+    PROJECT_INDEX_LOCK.lock().await
+}
+
+/// `lock_project_index` for code running outside the async runtime, e.g. in
+/// `web::block`.
+pub fn blocking_lock_project_index() -> tokio::sync::MutexGuard<'static, ()> {
+    PROJECT_INDEX_LOCK.blocking_lock()
 }
 
 pub async fn create_project_info(
     data_path: &str,
     project_creation: &ProjectLoginInfo,
 ) -> Result<ProjectInfo, ProjectInfoErrors> {
+    let (password_hash, key_info) = {
+        let password = project_creation.password.clone();
+        run_kdf(move || {
+            let key_info = WrappedKeyInfo::new(&password, &DataKey::generate())?;
+            Ok((hash_password(&password), key_info))
+        })
+        .await?
+    };
+
+    let _projects = lock_project_index().await;
     let info = extract_project_info(data_path, &project_creation.project_name).await;
     if info.is_err() {
         return Err(info.err().unwrap());
     }
     let (mut projects, project) = info.ok().unwrap();
 
-    if !project.is_none() {
+    if project.is_some() {
         return Err(ProjectInfoErrors::ProjectAllreadyExists);
     }
 
-    let project = ProjectInfo::new(
-        &project_creation.project_name,
-        &hash_password(&project_creation.password),
-    );
-
-    let image_dir = PathBuf::from(data_path.to_owned() + &project.project_id.to_string());
-    let project_data =
-        PathBuf::from(data_path.to_owned() + &project.project_id.to_string() + "\\project.json");
-    let image_data = PathBuf::from(
-        data_path.to_owned() + &project.project_id.to_string() + "\\project_images.json",
-    );
+    let mut project = ProjectInfo::new(&project_creation.project_name, &password_hash);
+    project.key_info = Some(key_info);
 
-    let image_dir = fs::create_dir(image_dir);
+    let image_dir = fs::create_dir(project_dir(data_path, &project.project_id));
     if image_dir.is_err() {
         return Err(ProjectInfoErrors::FailedToCreateProjectFolder);
     }
 
-    create_file_write_all(&project_data, object_to_byte_vec(&project).as_slice());
-    create_file_write_all(
-        &image_data,
-        object_to_byte_vec(&ImageData::new_vec()).as_slice(),
-    );
+    replace_file_atomic(
+        &project_info_json(data_path, &project.project_id),
+        object_to_byte_vec(&project).as_slice(),
+    )
+    .and_then(|_| {
+        replace_file_atomic(
+            &project_images_json(data_path, &project.project_id),
+            object_to_byte_vec(&ImageData::new_vec()).as_slice(),
+        )
+    })
+    .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
 
-    projects.append(&mut vec![project.clone()]);
-    let golbal_project_json = PathBuf::from(data_path.to_owned() + "project.json");
-    create_file_write_all(
-        &golbal_project_json,
+    projects.push(project.clone());
+    replace_file_atomic(
+        &global_project_json(data_path),
         object_to_byte_vec(&projects).as_slice(),
-    );
+    )
+    .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
 
     Ok(project)
 }
 
+/// Argon2 takes long enough to stall every other request on the worker, so
+/// password checks and key derivations run on the blocking pool.
+async fn run_kdf<T, F>(f: F) -> Result<T, ProjectInfoErrors>
+where
+    F: FnOnce() -> Result<T, ProjectInfoErrors> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f)
+        .await
+        .map_err(|err| ProjectInfoErrors::KeyDerivationFailed(err.to_string()))?
+}
+
+/// Verifies the password and unwraps the project data keys. Projects created
+/// before data keys existed get one generated and stored here.
 pub async fn project_login(
     data_path: &str,
     project_login_info: &ProjectLoginInfo,
-) -> Result<ProjectInfo, ProjectInfoErrors> {
+) -> Result<(ProjectInfo, ProjectKeys), ProjectInfoErrors> {
     let info = extract_project_info(data_path, &project_login_info.project_name).await;
     if info.is_err() {
         return Err(info.err().unwrap());
@@ -109,16 +284,308 @@ pub async fn project_login(
     let (_, project) = info.ok().unwrap();
 
     if project.is_none() {
+        let password = project_login_info.password.clone();
+        run_kdf(move || {
+            dummy_verify_password(&password);
+            Ok(())
+        })
+        .await?;
         return Err(ProjectInfoErrors::ProjectDosentExist);
     }
 
-    let project = project.unwrap();
+    let (mut project, project_keys, new_key) = {
+        let (mut project, password) = (project.unwrap(), project_login_info.password.clone());
+        run_kdf(move || {
+            if !verify_password(&password, &project.password_hash) {
+                return Err(ProjectInfoErrors::WrongPassword);
+            }
+            let new_key = project.key_info.is_none();
+            if new_key {
+                project.key_info = Some(WrappedKeyInfo::new(&password, &DataKey::generate())?);
+            }
+            let project_keys = project.unlock_keys(&password)?;
+            Ok((project, project_keys, new_key))
+        })
+        .await?
+    };
+    if new_key {
+        update_project_info(data_path, &project).await?;
+    }
+
+    // the data key is saved by now, so only the legacy images still need the
+    // old hash: the legacy image key is derived from its salt
+    let mut rehash = password_needs_rehash(&project.password_hash);
+    if rehash && is_legacy_password_hash(&project.password_hash) {
+        rehash = match migrate_legacy_images(data_path, &project, &project_keys.current).await {
+            Ok(migration) if migration.failed.is_empty() => true,
+            Ok(migration) => {
+                println!(
+                    "{} legacy image(s) not migrated, keeping the legacy password hash",
+                    migration.failed.len()
+                );
+                false
+            }
+            Err(err) => {
+                println!("failed to migrate legacy images: {}", err);
+                false
+            }
+        };
+    }
+
+    if rehash {
+        let mut upgraded = project.clone();
+        let password = project_login_info.password.clone();
+        upgraded.password_hash = run_kdf(move || Ok(hash_password(&password))).await?;
+        match update_project_info(data_path, &upgraded).await {
+            Ok(_) => project = upgraded,
+            Err(err) => println!("failed to upgrade the password hash: {}", err),
+        }
+    }
+
+    Ok((project, project_keys))
+}
+
+/// Changes the project password. Only the wrapping of the data keys changes,
+/// the images themselves are not touched.
+pub async fn change_project_password(
+    data_path: &str,
+    project_id: &Uuid,
+    password_change: &ProjectPasswordChange,
+) -> Result<ProjectInfo, ProjectInfoErrors> {
+    let mut project = find_project(data_path, project_id).await?;
+
+    if !verify_password(&password_change.old_password, &project.password_hash) {
+        return Err(ProjectInfoErrors::WrongPassword);
+    }
+
+    let project_keys = project.unlock_keys(&password_change.old_password)?;
+
+    // the legacy image key is derived from the legacy hash's salt, so images
+    // still using it have to move to the data key before the hash changes
+    let migration = migrate_legacy_images(data_path, &project, &project_keys.current)
+        .await
+        .map_err(|err| ProjectInfoErrors::FailedToMigrateImages(err.to_string()))?;
+    if !migration.failed.is_empty() {
+        return Err(ProjectInfoErrors::FailedToMigrateImages(format!(
+            "{} image(s) could not be re-encrypted",
+            migration.failed.len()
+        )));
+    }
+
+    project.password_hash = hash_password(&password_change.new_password);
+    project.key_info = Some(WrappedKeyInfo::new(
+        &password_change.new_password,
+        &project_keys.current,
+    )?);
+    project.previous_key_info = match &project_keys.previous {
+        Some(previous) => Some(WrappedKeyInfo::new(
+            &password_change.new_password,
+            previous,
+        )?),
+        None => None,
+    };
+
+    update_project_info(data_path, &project).await?;
+    Ok(project)
+}
+
+/// Replaces the project data key with a fresh one. The old key is kept as
+/// `previous_key_info` until a key rotation job has moved every blob off it.
+/// If an earlier rotation never finished its keys are returned instead, so the
+/// job can pick up where it stopped.
+pub async fn begin_key_rotation(
+    data_path: &str,
+    project_id: &Uuid,
+    password: &str,
+) -> Result<(ProjectInfo, ProjectKeys), ProjectInfoErrors> {
+    let mut project = find_project(data_path, project_id).await?;
+
+    if !verify_password(password, &project.password_hash) {
+        return Err(ProjectInfoErrors::WrongPassword);
+    }
+    if project.previous_key_info.is_some() {
+        let project_keys = project.unlock_keys(password)?;
+        return Ok((project, project_keys));
+    }
+
+    let old_keys = project.unlock_keys(password)?;
+    let new_key = DataKey::generate();
+
+    project.previous_key_info = project.key_info.take();
+    project.key_info = Some(WrappedKeyInfo::new(password, &new_key)?);
+    update_project_info(data_path, &project).await?;
+
+    let project_keys = ProjectKeys {
+        current: new_key,
+        previous: Some(old_keys.current),
+    };
+    seal_member_keys(data_path, project_id, &project_keys)
+        .await
+        .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+
+    Ok((project, project_keys))
+}
+
+/// Forgets the previous data key once no blob is encrypted with it anymore.
+pub async fn finish_key_rotation(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<ProjectInfo, ProjectInfoErrors> {
+    let mut project = find_project(data_path, project_id).await?;
+
+    project.previous_key_info = None;
+    update_project_info(data_path, &project).await?;
+
+    if let Some(key_info) = &project.key_info {
+        retain_member_keys(data_path, project_id, &key_info.key_id)
+            .await
+            .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+    }
+    Ok(project)
+}
+
+pub async fn find_project(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<ProjectInfo, ProjectInfoErrors> {
+    let projects = read_global_project_info(data_path).await?;
+
+    projects
+        .into_iter()
+        .find(|project| project.project_id == *project_id)
+        .ok_or(ProjectInfoErrors::ProjectDosentExist)
+}
+
+pub async fn find_project_by_name(
+    data_path: &str,
+    project_name: &str,
+) -> Result<ProjectInfo, ProjectInfoErrors> {
+    let (_, project) = extract_project_info(data_path, project_name).await?;
+    project.ok_or(ProjectInfoErrors::ProjectDosentExist)
+}
+
+/// Removes a project from the global index and deletes its folder with all
+/// images, members and logs.
+pub async fn delete_project_info(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<ProjectInfo, ProjectInfoErrors> {
+    let projects_lock = lock_project_index().await;
+    let mut projects = read_global_project_info(data_path).await?;
+    let index = projects
+        .iter()
+        .position(|project| project.project_id == *project_id)
+        .ok_or(ProjectInfoErrors::ProjectDosentExist)?;
+    let project = projects.remove(index);
+
+    replace_file_atomic(
+        &global_project_json(data_path),
+        object_to_byte_vec(&projects).as_slice(),
+    )
+    .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+    drop(projects_lock);
+
+    let store = blob_store(data_path);
+    let blob_keys = store
+        .list(&format!("{}/", project_id))
+        .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+    for blob_key in blob_keys {
+        store
+            .delete(&blob_key)
+            .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+    }
+
+    let project_path = project_dir(data_path, project_id);
+    if project_path.exists() {
+        fs::remove_dir_all(project_path).map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+    }
+
+    Ok(project)
+}
+
+/// Sets a new project password without knowing the old one. The old data keys
+/// can't be unwrapped without it, so the project gets a fresh data key and
+/// images encrypted before can no longer be read. Unless `force` is set that
+/// is refused while the project has encrypted images.
+pub async fn reset_project_password(
+    data_path: &str,
+    project_id: &Uuid,
+    new_password: &str,
+    force: bool,
+) -> Result<ProjectInfo, ProjectInfoErrors> {
+    // no upload can index an image on the old key while the key is replaced
+    let _index = lock_image_index(project_id).await;
+    let mut project = find_project(data_path, project_id).await?;
+
+    let images = read_project_images(data_path, project_id)
+        .await
+        .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+    let encrypted = images.iter().filter(|image| image.is_encrypted).count();
+    if encrypted > 0 && !force {
+        return Err(ProjectInfoErrors::EncryptedImages(encrypted));
+    }
+
+    let (password_hash, key_info, data_key) = {
+        let new_password = new_password.to_owned();
+        run_kdf(move || {
+            let data_key = DataKey::generate();
+            let key_info = WrappedKeyInfo::new(&new_password, &data_key)?;
+            Ok((hash_password(&new_password), key_info, data_key))
+        })
+        .await?
+    };
+    project.password_hash = password_hash;
+    project.key_info = Some(key_info);
+    project.previous_key_info = None;
+    update_project_info(data_path, &project).await?;
+
+    // members get the new key, the keys sealed to them before are useless now
+    let project_keys = ProjectKeys {
+        current: data_key,
+        previous: None,
+    };
+    seal_member_keys(data_path, project_id, &project_keys)
+        .await
+        .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+    retain_member_keys(data_path, project_id, &project_keys.current.key_id)
+        .await
+        .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+
+    let rotation_path = key_rotation_json(data_path, project_id);
+    if rotation_path.exists() {
+        fs::remove_file(rotation_path).map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+    }
+
+    Ok(project)
+}
+
+/// Writes `project` to its own `project.json` and replaces its entry in the
+/// global project index.
+pub async fn update_project_info(
+    data_path: &str,
+    project: &ProjectInfo,
+) -> Result<(), ProjectInfoErrors> {
+    let _projects = lock_project_index().await;
+    let mut projects = read_global_project_info(data_path).await?;
 
-    if !verify_password(&project_login_info.password, &project.password_hash) {
-        Err(ProjectInfoErrors::WrongPassword)
-    } else {
-        Ok(project)
+    match projects
+        .iter_mut()
+        .find(|p| p.project_id == project.project_id)
+    {
+        Some(entry) => *entry = project.clone(),
+        None => return Err(ProjectInfoErrors::ProjectDosentExist),
     }
+
+    replace_file_atomic(
+        &project_info_json(data_path, &project.project_id),
+        object_to_byte_vec(project).as_slice(),
+    )
+    .map_err(|_| ProjectInfoErrors::FailedToSaveProject)?;
+    replace_file_atomic(
+        &global_project_json(data_path),
+        object_to_byte_vec(&projects).as_slice(),
+    )
+    .map_err(|_| ProjectInfoErrors::FailedToSaveProject)
 }
 
 async fn extract_project_info(
@@ -138,7 +605,7 @@ async fn extract_project_info(
 }
 
 async fn read_global_project_info(data_path: &str) -> Result<Vec<ProjectInfo>, ProjectInfoErrors> {
-    let golbal_project_json = PathBuf::from(data_path.to_owned() + "project.json");
+    let golbal_project_json = global_project_json(data_path);
     if !golbal_project_json.exists() {
         return Ok(vec![]);
     }
@@ -152,7 +619,7 @@ async fn read_global_project_info(data_path: &str) -> Result<Vec<ProjectInfo>, P
     Ok(projects)
 }
 
-fn search_project(projects: &Vec<ProjectInfo>, project_name: &str) -> Option<ProjectInfo> {
+fn search_project(projects: &[ProjectInfo], project_name: &str) -> Option<ProjectInfo> {
     for project in projects {
         if project.project_name == project_name {
             return Some(project.to_owned());
@@ -160,3 +627,207 @@ fn search_project(projects: &Vec<ProjectInfo>, project_name: &str) -> Option<Pro
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::image_data::{image_blob_key, read_project_images, write_project_images};
+    use crate::utility::blob_store::put_reader;
+    use crate::utility::encryption::{encrypt_bytes, get_legacy_key};
+    use sha2::{Digest, Sha256};
+
+    const PASSWORD: &str = "legacy password";
+    const SALT: &str = "0123456789abcdef";
+
+    fn legacy_image(name: &str) -> ImageData {
+        ImageData {
+            image_id: Uuid::new_v4(),
+            image_name: name.to_owned(),
+            mime: "png".to_owned(),
+            original_image_name: format!("{}.png", name),
+            image_size: 5,
+            created_date: Utc::now().naive_utc(),
+            is_encrypted: true,
+            tags: vec![],
+            key_id: None,
+            sha256: None,
+            content_type: None,
+        }
+    }
+
+    /// A project from before data keys, with a legacy SHA-256 password hash
+    /// and an image encrypted with the key derived from its salt. With
+    /// `broken` a second image has no blob at all.
+    async fn legacy_project(broken: bool) -> (String, ProjectInfo, Vec<ImageData>) {
+        let data_path = std::env::temp_dir().join(format!("legacy_{}", Uuid::new_v4()));
+        fs::create_dir_all(&data_path).unwrap();
+        let data_path = data_path.to_str().unwrap().to_owned();
+
+        let login = ProjectLoginInfo {
+            project_name: "legacy".to_owned(),
+            password: PASSWORD.to_owned(),
+        };
+        let mut project = create_project_info(&data_path, &login).await.unwrap();
+        let hash = format!("{:X}", Sha256::digest(format!("{}{}", PASSWORD, SALT)));
+        project.password_hash = format!("{}:{}", hash, SALT);
+        project.key_info = None;
+        update_project_info(&data_path, &project).await.unwrap();
+
+        let mut images = vec![legacy_image("readable")];
+        let blob = encrypt_bytes(b"image".to_vec(), &get_legacy_key(SALT));
+        let store = blob_store(&data_path);
+        put_reader(
+            &*store,
+            &image_blob_key(&project.project_id, &images[0]),
+            blob.as_slice(),
+        )
+        .unwrap();
+        if broken {
+            images.push(legacy_image("missing"));
+        }
+        write_project_images(&data_path, &project.project_id, &images).unwrap();
+
+        (data_path, project, images)
+    }
+
+    fn login() -> ProjectLoginInfo {
+        ProjectLoginInfo {
+            project_name: "legacy".to_owned(),
+            password: PASSWORD.to_owned(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn login_migrates_legacy_images_and_upgrades_the_hash() {
+        let (data_path, project, _) = legacy_project(false).await;
+
+        let (logged_in, keys) = project_login(&data_path, &login()).await.unwrap();
+
+        assert!(!is_legacy_password_hash(&logged_in.password_hash));
+        let stored = find_project(&data_path, &project.project_id).await.unwrap();
+        assert_eq!(stored.password_hash, logged_in.password_hash);
+        assert!(stored.key_info.is_some());
+        let images = read_project_images(&data_path, &project.project_id)
+            .await
+            .unwrap();
+        assert_eq!(images[0].key_id, Some(keys.current.key_id));
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn login_skips_images_that_fail_to_migrate() {
+        let (data_path, project, images) = legacy_project(true).await;
+
+        let (logged_in, keys) = project_login(&data_path, &login()).await.unwrap();
+
+        // the missing image still needs the legacy key, so the hash stays
+        assert_eq!(logged_in.password_hash, project.password_hash);
+        let stored = find_project(&data_path, &project.project_id).await.unwrap();
+        assert_eq!(stored.password_hash, project.password_hash);
+        assert!(stored.key_info.is_some());
+
+        let migrated = read_project_images(&data_path, &project.project_id)
+            .await
+            .unwrap();
+        assert_eq!(migrated[0].key_id, Some(keys.current.key_id));
+        assert_eq!(migrated[1].image_id, images[1].image_id);
+        assert_eq!(migrated[1].key_id, None);
+
+        // the next login tries again and still gets the same data key
+        let (_, again) = project_login(&data_path, &login()).await.unwrap();
+        assert_eq!(again.current.key_id, keys.current.key_id);
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn login_leaves_the_worker_free() {
+        let data_path = std::env::temp_dir().join(format!("login_{}", Uuid::new_v4()));
+        fs::create_dir_all(&data_path).unwrap();
+        let data_path = data_path.to_str().unwrap().to_owned();
+        let login = ProjectLoginInfo {
+            project_name: "busy".to_owned(),
+            password: PASSWORD.to_owned(),
+        };
+        create_project_info(&data_path, &login).await.unwrap();
+
+        // the test runs on a single thread, so the ticker only gets a turn
+        // while the login waits on the blocking pool
+        let ticks = std::rc::Rc::new(std::cell::Cell::new(0));
+        let ticker = {
+            let ticks = ticks.clone();
+            actix_web::rt::spawn(async move {
+                loop {
+                    actix_web::rt::time::sleep(std::time::Duration::from_millis(1)).await;
+                    ticks.set(ticks.get() + 1);
+                }
+            })
+        };
+        project_login(&data_path, &login).await.unwrap();
+        ticker.abort();
+
+        assert!(ticks.get() > 2, "{} ticks", ticks.get());
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn reset_keeps_encrypted_images_unless_forced() {
+        let (data_path, project, _) = legacy_project(false).await;
+
+        let res = reset_project_password(&data_path, &project.project_id, "new", false).await;
+        assert!(matches!(res, Err(ProjectInfoErrors::EncryptedImages(1))));
+        let stored = find_project(&data_path, &project.project_id).await.unwrap();
+        assert_eq!(stored.password_hash, project.password_hash);
+
+        let reset = reset_project_password(&data_path, &project.project_id, "new", true)
+            .await
+            .unwrap();
+        assert!(verify_password("new", &reset.password_hash));
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_creations_keep_every_project() {
+        let data_path = std::env::temp_dir().join(format!("projects_{}", Uuid::new_v4()));
+        fs::create_dir_all(&data_path).unwrap();
+        let data_path = data_path.to_str().unwrap().to_owned();
+
+        // every worker has its own runtime, like the server's workers
+        let workers: Vec<_> = ["a", "b", "c", "d", "e", "f", "a"]
+            .into_iter()
+            .map(|project_name| {
+                let data_path = data_path.clone();
+                std::thread::spawn(move || {
+                    actix_web::rt::System::new().block_on(async {
+                        let login = ProjectLoginInfo {
+                            project_name: project_name.to_owned(),
+                            password: PASSWORD.to_owned(),
+                        };
+                        create_project_info(&data_path, &login).await.map(|_| ())
+                    })
+                })
+            })
+            .collect();
+        let results: Vec<_> = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect();
+
+        let taken = results
+            .iter()
+            .filter(|res| matches!(res, Err(ProjectInfoErrors::ProjectAllreadyExists)))
+            .count();
+        assert_eq!(taken, 1);
+        let projects = actix_web::rt::System::new()
+            .block_on(get_all_project_infos(&data_path))
+            .unwrap();
+        let mut names: Vec<_> = projects.into_iter().map(|p| p.project_name).collect();
+        names.sort();
+        assert_eq!(names, ["a", "b", "c", "d", "e", "f"]);
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+}
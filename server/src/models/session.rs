@@ -0,0 +1,394 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{NaiveDateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::models::project_info::{find_project, ProjectInfo};
+use crate::models::user_info::{read_project_members, Role};
+use crate::utility::file_utilities::*;
+use crate::utility::jwt_token::{access_token_lifetime, generate_token, refresh_token_lifetime};
+
+/// A refresh token handed out on login, stored by its SHA-256 hash in
+/// `sessions.json`. Every refresh replaces the token with a new one of the
+/// same family; using a replaced token again revokes the whole family.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub token_hash: String,
+    pub family_id: Uuid,
+    pub project_id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub used: bool,
+    pub created_date: NaiveDateTime,
+    pub expires_date: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RefreshTokenInfo {
+    pub refresh_token: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    /// Seconds until the access token expires.
+    pub expires_in: i64,
+}
+
+#[derive(Debug)]
+pub enum SessionErrors {
+    InvalidRefreshToken,
+    RefreshTokenReused,
+    RefreshTokenExpired,
+    NotAMember,
+    ProjectDosentExist,
+    FailedToReadSessions,
+    FailedToSaveSessions,
+}
+
+impl fmt::Display for SessionErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionErrors::InvalidRefreshToken => write!(f, "invalid refresh token"),
+            SessionErrors::RefreshTokenReused => {
+                write!(f, "refresh token was already used, the session is revoked")
+            }
+            SessionErrors::RefreshTokenExpired => write!(f, "refresh token expired"),
+            SessionErrors::NotAMember => write!(f, "user is no longer a member of the project"),
+            SessionErrors::ProjectDosentExist => write!(f, "project doesn't exist"),
+            SessionErrors::FailedToReadSessions => write!(f, "failed to read sessions"),
+            SessionErrors::FailedToSaveSessions => write!(f, "failed to save sessions"),
+        }
+    }
+}
+
+fn hash_refresh_token(refresh_token: &str) -> String {
+    hex::encode(Sha256::digest(refresh_token.as_bytes()))
+}
+
+/// Starts a new session and returns its first access and refresh token.
+pub async fn create_session(
+    data_path: &str,
+    project: &ProjectInfo,
+    user_id: Option<Uuid>,
+    role: Role,
+) -> Result<TokenPair, SessionErrors> {
+    let mut sessions = read_sessions(data_path)?;
+    let token_pair = issue_tokens(&mut sessions, project, user_id, role, Uuid::new_v4());
+    write_sessions(data_path, &sessions)?;
+
+    Ok(token_pair)
+}
+
+/// Swaps a refresh token for a new access and refresh token. The role is
+/// looked up again, so role changes and removed members take effect on the
+/// next refresh.
+pub async fn refresh_session(
+    data_path: &str,
+    refresh_token: &str,
+) -> Result<TokenPair, SessionErrors> {
+    let mut sessions = read_sessions(data_path)?;
+    let token_hash = hash_refresh_token(refresh_token);
+
+    let session = sessions
+        .iter_mut()
+        .find(|session| session.token_hash == token_hash)
+        .ok_or(SessionErrors::InvalidRefreshToken)?;
+
+    if session.used {
+        // somebody else has a copy of this token
+        let family_id = session.family_id;
+        sessions.retain(|session| session.family_id != family_id);
+        write_sessions(data_path, &sessions)?;
+        return Err(SessionErrors::RefreshTokenReused);
+    }
+
+    if session.expires_date <= Utc::now().naive_utc() {
+        return Err(SessionErrors::RefreshTokenExpired);
+    }
+
+    session.used = true;
+    let session = session.clone();
+
+    let project = find_project(data_path, &session.project_id)
+        .await
+        .map_err(|_| SessionErrors::ProjectDosentExist)?;
+
+    let role = match session.user_id {
+        Some(user_id) => {
+            let member = read_project_members(data_path, &project.project_id)
+                .map_err(|_| SessionErrors::FailedToReadSessions)?
+                .into_iter()
+                .find(|member| member.user_id == user_id);
+
+            match member {
+                Some(member) => member.role,
+                None => {
+                    sessions.retain(|s| s.family_id != session.family_id);
+                    write_sessions(data_path, &sessions)?;
+                    return Err(SessionErrors::NotAMember);
+                }
+            }
+        }
+        None => Role::Owner,
+    };
+
+    let token_pair = issue_tokens(
+        &mut sessions,
+        &project,
+        session.user_id,
+        role,
+        session.family_id,
+    );
+    write_sessions(data_path, &sessions)?;
+
+    Ok(token_pair)
+}
+
+/// Ends the session the refresh token belongs to.
+pub async fn revoke_session(data_path: &str, refresh_token: &str) -> Result<(), SessionErrors> {
+    let mut sessions = read_sessions(data_path)?;
+    let token_hash = hash_refresh_token(refresh_token);
+
+    let family_id = sessions
+        .iter()
+        .find(|session| session.token_hash == token_hash)
+        .map(|session| session.family_id)
+        .ok_or(SessionErrors::InvalidRefreshToken)?;
+
+    sessions.retain(|session| session.family_id != family_id);
+    write_sessions(data_path, &sessions)
+}
+
+/// Ends all sessions of a user, or with `user_id` `None` all sessions that
+/// logged in with the project password of `project_id`.
+pub async fn revoke_sessions(
+    data_path: &str,
+    project_id: Option<&Uuid>,
+    user_id: Option<&Uuid>,
+) -> Result<(), SessionErrors> {
+    let mut sessions = read_sessions(data_path)?;
+
+    sessions.retain(|session| match user_id {
+        Some(user_id) => session.user_id.as_ref() != Some(user_id),
+        None => session.user_id.is_some() || Some(&session.project_id) != project_id,
+    });
+
+    write_sessions(data_path, &sessions)
+}
+
+/// Ends every session of a project, user logins included.
+pub async fn delete_project_sessions(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<(), SessionErrors> {
+    let mut sessions = read_sessions(data_path)?;
+    sessions.retain(|session| session.project_id != *project_id);
+
+    write_sessions(data_path, &sessions)
+}
+
+fn issue_tokens(
+    sessions: &mut Vec<Session>,
+    project: &ProjectInfo,
+    user_id: Option<Uuid>,
+    role: Role,
+    family_id: Uuid,
+) -> TokenPair {
+    let now = Utc::now().naive_utc();
+    let refresh_token = hex::encode(rand::random::<[u8; 32]>());
+
+    // drop sessions that can't be used anymore
+    sessions.retain(|session| session.expires_date > now);
+
+    sessions.push(Session {
+        token_hash: hash_refresh_token(&refresh_token),
+        family_id,
+        project_id: project.project_id,
+        user_id,
+        used: false,
+        created_date: now,
+        expires_date: now + refresh_token_lifetime(),
+    });
+
+    TokenPair {
+        access_token: generate_token(project, user_id, role),
+        refresh_token,
+        token_type: "Bearer".to_owned(),
+        expires_in: access_token_lifetime().num_seconds(),
+    }
+}
+
+fn read_sessions(data_path: &str) -> Result<Vec<Session>, SessionErrors> {
+    let path = sessions_json(data_path);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let data = fs::read_to_string(path).map_err(|_| SessionErrors::FailedToReadSessions)?;
+    serde_json::from_str(&data).map_err(|_| SessionErrors::FailedToReadSessions)
+}
+
+fn write_sessions(data_path: &str, sessions: &[Session]) -> Result<(), SessionErrors> {
+    replace_file_atomic(
+        &sessions_json(data_path),
+        object_to_byte_vec(sessions).as_slice(),
+    )
+    .map_err(|_| SessionErrors::FailedToSaveSessions)
+}
+
+/// Revoked access token ids with the time their token expires, from
+/// `revoked_tokens.json`.
+pub fn read_revoked_tokens(data_path: &str) -> HashMap<Uuid, u64> {
+    fs::read_to_string(revoked_tokens_json(data_path))
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+pub fn write_revoked_tokens(
+    data_path: &str,
+    revoked_tokens: &HashMap<Uuid, u64>,
+) -> Result<(), SessionErrors> {
+    replace_file_atomic(
+        &revoked_tokens_json(data_path),
+        object_to_byte_vec(revoked_tokens).as_slice(),
+    )
+    .map_err(|_| SessionErrors::FailedToSaveSessions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::JwtConfig;
+    use crate::models::project_info::{create_project_info, ProjectLoginInfo};
+    use crate::utility::jwt_token::{init_jwt_keys, validate_token};
+
+    async fn project_session() -> (String, ProjectInfo, TokenPair) {
+        init_jwt_keys(&JwtConfig {
+            secret: Some("session test secret".to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let data_path = std::env::temp_dir().join(format!("session_{}", Uuid::new_v4()));
+        fs::create_dir_all(&data_path).unwrap();
+        let data_path = data_path.to_str().unwrap().to_owned();
+
+        let login = ProjectLoginInfo {
+            project_name: "sessions".to_owned(),
+            password: "password".to_owned(),
+        };
+        let project = create_project_info(&data_path, &login).await.unwrap();
+        let tokens = create_session(&data_path, &project, None, Role::Owner)
+            .await
+            .unwrap();
+
+        (data_path, project, tokens)
+    }
+
+    #[actix_web::test]
+    async fn refresh_rotates_the_token() {
+        let (data_path, project, first) = project_session().await;
+
+        let second = refresh_session(&data_path, &first.refresh_token)
+            .await
+            .unwrap();
+        assert_ne!(second.refresh_token, first.refresh_token);
+        let claims = validate_token(&second.access_token).unwrap();
+        assert_eq!(claims.project_id, project.project_id);
+
+        let third = refresh_session(&data_path, &second.refresh_token)
+            .await
+            .unwrap();
+        assert_ne!(third.refresh_token, second.refresh_token);
+
+        // only hashes are stored, all of one family
+        let stored = fs::read_to_string(sessions_json(&data_path)).unwrap();
+        assert!(!stored.contains(&first.refresh_token));
+        let sessions = read_sessions(&data_path).unwrap();
+        assert_eq!(sessions.len(), 3);
+        assert!(sessions
+            .iter()
+            .all(|s| s.family_id == sessions[0].family_id));
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn reusing_a_token_revokes_its_family() {
+        let (data_path, project, first) = project_session().await;
+        let other = create_session(&data_path, &project, None, Role::Owner)
+            .await
+            .unwrap();
+
+        let second = refresh_session(&data_path, &first.refresh_token)
+            .await
+            .unwrap();
+        assert!(matches!(
+            refresh_session(&data_path, &first.refresh_token).await,
+            Err(SessionErrors::RefreshTokenReused)
+        ));
+
+        // the token the thief or the owner got in between is gone too
+        assert!(matches!(
+            refresh_session(&data_path, &second.refresh_token).await,
+            Err(SessionErrors::InvalidRefreshToken)
+        ));
+        assert!(matches!(
+            refresh_session(&data_path, &first.refresh_token).await,
+            Err(SessionErrors::InvalidRefreshToken)
+        ));
+
+        // other sessions of the project are left alone
+        refresh_session(&data_path, &other.refresh_token)
+            .await
+            .unwrap();
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn expired_and_unknown_tokens_fail() {
+        let (data_path, _, tokens) = project_session().await;
+
+        assert!(matches!(
+            refresh_session(&data_path, "not a token").await,
+            Err(SessionErrors::InvalidRefreshToken)
+        ));
+
+        let mut sessions = read_sessions(&data_path).unwrap();
+        sessions[0].expires_date = Utc::now().naive_utc() - chrono::Duration::seconds(1);
+        write_sessions(&data_path, &sessions).unwrap();
+        assert!(matches!(
+            refresh_session(&data_path, &tokens.refresh_token).await,
+            Err(SessionErrors::RefreshTokenExpired)
+        ));
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn logout_ends_the_family() {
+        let (data_path, _, first) = project_session().await;
+        let second = refresh_session(&data_path, &first.refresh_token)
+            .await
+            .unwrap();
+
+        revoke_session(&data_path, &second.refresh_token)
+            .await
+            .unwrap();
+        assert!(read_sessions(&data_path).unwrap().is_empty());
+        assert!(matches!(
+            refresh_session(&data_path, &second.refresh_token).await,
+            Err(SessionErrors::InvalidRefreshToken)
+        ));
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+}
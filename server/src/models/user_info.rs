@@ -0,0 +1,761 @@
+use ::serde::{Deserialize, Serialize};
+use actix_web::web;
+use chrono::{NaiveDateTime, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::OwnedMutexGuard;
+use uuid::Uuid;
+
+use crate::models::project_info::{ProjectInfo, Projects, WrappedKeyInfo};
+use crate::utility::encryption::{
+    get_public_key, open_sealed_key, seal_data_key, DataKey, ProjectKeys, SealedKey,
+};
+use crate::utility::{
+    dummy_verify_password, file_utilities::*, hash_password, password_needs_rehash, verify_password,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Owner,
+    Editor,
+    Viewer,
+    Uploader,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    /// Fetch images and project details.
+    Read,
+    /// Add images.
+    Upload,
+    /// Change the tags of images.
+    Tag,
+    /// Change the project password, rotate keys and manage members.
+    Manage,
+}
+
+impl Role {
+    pub fn allows(&self, permission: Permission) -> bool {
+        match self {
+            Role::Owner => true,
+            Role::Editor => matches!(
+                permission,
+                Permission::Read | Permission::Upload | Permission::Tag
+            ),
+            Role::Viewer => permission == Permission::Read,
+            Role::Uploader => permission == Permission::Upload,
+        }
+    }
+}
+
+/// A user account. The X25519 secret is wrapped by a key derived from the
+/// user's password; project data keys are sealed to the public key, so an
+/// owner can grant access without knowing the user's password.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub user_id: Uuid,
+    pub user_name: String,
+    pub password_hash: String,
+    pub public_key: String,
+    pub secret_key_info: WrappedKeyInfo,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserRegisterInfo {
+    pub user_name: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserLoginInfo {
+    pub user_name: String,
+    pub password: String,
+    pub project_name: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserPasswordChange {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+/// Membership of a user in a project, stored in `project_members.json`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProjectMember {
+    pub user_id: Uuid,
+    pub role: Role,
+    pub sealed_keys: Vec<SealedKey>,
+    pub added_date: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewProjectMember {
+    pub user_name: String,
+    pub role: Role,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemberRole {
+    pub role: Role,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProjectMemberInfo {
+    pub user_id: Uuid,
+    pub user_name: String,
+    pub role: Role,
+    pub added_date: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserProject {
+    pub project: Projects,
+    pub role: Role,
+}
+
+#[derive(Debug)]
+pub enum UserInfoErrors {
+    UserAllreadyExists,
+    UserDosentExist,
+    ProjectDosentExist,
+    WrongPassword,
+    NotAMember,
+    MemberAllreadyExists,
+    LastOwner,
+    EncryptionKeyUnavailable,
+    FailedToSaveUser,
+    FailedToReadUsers,
+    KeyDerivationFailed(String),
+}
+
+impl fmt::Display for UserInfoErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserInfoErrors::UserAllreadyExists => write!(f, "user already exists"),
+            UserInfoErrors::UserDosentExist => write!(f, "user doesn't exist"),
+            UserInfoErrors::ProjectDosentExist => write!(f, "project doesn't exist"),
+            UserInfoErrors::WrongPassword => write!(f, "wrong password"),
+            UserInfoErrors::NotAMember => write!(f, "user is not a member of the project"),
+            UserInfoErrors::MemberAllreadyExists => {
+                write!(f, "user is already a member of the project")
+            }
+            UserInfoErrors::LastOwner => write!(f, "the project has to keep an owner"),
+            UserInfoErrors::EncryptionKeyUnavailable => {
+                write!(f, "project encryption key is not unlocked")
+            }
+            UserInfoErrors::FailedToSaveUser => write!(f, "failed to save user"),
+            UserInfoErrors::FailedToReadUsers => write!(f, "failed to read users"),
+            UserInfoErrors::KeyDerivationFailed(err) => {
+                write!(f, "failed to unlock the user key: {}", err)
+            }
+        }
+    }
+}
+
+impl UserInfo {
+    fn new(user_name: &str, password: &str) -> Result<Self, UserInfoErrors> {
+        let secret = DataKey::generate();
+        let secret_key_info = WrappedKeyInfo::new(password, &secret)
+            .map_err(|err| UserInfoErrors::KeyDerivationFailed(err.to_string()))?;
+
+        Ok(UserInfo {
+            user_id: Uuid::new_v4(),
+            user_name: user_name.to_owned(),
+            password_hash: hash_password(password),
+            public_key: hex::encode(get_public_key(&secret)),
+            secret_key_info,
+            created_date: Utc::now().naive_utc(),
+        })
+    }
+
+    fn unlock_secret(&self, password: &str) -> Result<DataKey, UserInfoErrors> {
+        self.secret_key_info
+            .unlock(password)
+            .map_err(|err| UserInfoErrors::KeyDerivationFailed(err.to_string()))
+    }
+
+    fn get_public_key(&self) -> Result<Vec<u8>, UserInfoErrors> {
+        hex::decode(&self.public_key)
+            .map_err(|err| UserInfoErrors::KeyDerivationFailed(err.to_string()))
+    }
+}
+
+impl ProjectMember {
+    fn new(
+        user: &UserInfo,
+        role: Role,
+        project_keys: &ProjectKeys,
+    ) -> Result<Self, UserInfoErrors> {
+        let mut member = ProjectMember {
+            user_id: user.user_id,
+            role,
+            sealed_keys: vec![],
+            added_date: Utc::now().naive_utc(),
+        };
+        member.seal_keys(user, project_keys)?;
+
+        Ok(member)
+    }
+
+    fn seal_keys(
+        &mut self,
+        user: &UserInfo,
+        project_keys: &ProjectKeys,
+    ) -> Result<(), UserInfoErrors> {
+        let public_key = user.get_public_key()?;
+
+        self.sealed_keys = std::iter::once(&project_keys.current)
+            .chain(project_keys.previous.as_ref())
+            .map(|key| seal_data_key(key, &public_key))
+            .collect();
+        Ok(())
+    }
+
+    fn open_keys(&self, project: &ProjectInfo, secret: &DataKey) -> Option<ProjectKeys> {
+        let open = |key_info: &WrappedKeyInfo| {
+            self.sealed_keys
+                .iter()
+                .find(|sealed| sealed.key_id == key_info.key_id)
+                .and_then(|sealed| open_sealed_key(sealed, secret).ok())
+        };
+
+        let current = open(project.key_info.as_ref()?)?;
+        let previous = match &project.previous_key_info {
+            Some(key_info) => Some(open(key_info)?),
+            None => None,
+        };
+
+        Some(ProjectKeys { current, previous })
+    }
+}
+
+/// Guards every read-modify-write of `users.json`. Password hashing runs
+/// outside of it, so one login doesn't hold up every other.
+static USERS_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+static MEMBER_LOCKS: OnceLock<Mutex<HashMap<Uuid, Arc<tokio::sync::Mutex<()>>>>> = OnceLock::new();
+
+/// Held across every read-modify-write of a project's `project_members.json`.
+async fn lock_project_members(project_id: &Uuid) -> OwnedMutexGuard<()> {
+    let lock = MEMBER_LOCKS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap()
+        .entry(*project_id)
+        .or_default()
+        .clone();
+    lock.lock_owned().await
+}
+
+/// Owners are the only members that can manage the project, so the last one
+/// can't be demoted or removed.
+fn check_owner_left(members: &[ProjectMember], user_id: &Uuid) -> Result<(), UserInfoErrors> {
+    let mut owners = members.iter().filter(|member| member.role == Role::Owner);
+    match (owners.next(), owners.next()) {
+        (Some(owner), None) if owner.user_id == *user_id => Err(UserInfoErrors::LastOwner),
+        _ => Ok(()),
+    }
+}
+
+/// Argon2 takes long enough to stall every other request on the worker, so
+/// password checks and key derivations run on the blocking pool.
+async fn run_kdf<T, F>(f: F) -> Result<T, UserInfoErrors>
+where
+    F: FnOnce() -> Result<T, UserInfoErrors> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f)
+        .await
+        .map_err(|err| UserInfoErrors::KeyDerivationFailed(err.to_string()))?
+}
+
+pub async fn register_user(
+    data_path: &str,
+    register_info: &UserRegisterInfo,
+) -> Result<UserInfo, UserInfoErrors> {
+    let name_taken = |users: &[UserInfo]| {
+        users
+            .iter()
+            .any(|user| user.user_name == register_info.user_name)
+    };
+
+    {
+        let _users = USERS_LOCK.lock().await;
+        if name_taken(&read_users(data_path)?) {
+            return Err(UserInfoErrors::UserAllreadyExists);
+        }
+    }
+
+    let user = {
+        let register_info = register_info.clone();
+        run_kdf(move || UserInfo::new(&register_info.user_name, &register_info.password)).await?
+    };
+
+    // the name may have been taken while the password was hashed
+    let _users = USERS_LOCK.lock().await;
+    let mut users = read_users(data_path)?;
+    if name_taken(&users) {
+        return Err(UserInfoErrors::UserAllreadyExists);
+    }
+    users.push(user.clone());
+    write_users(data_path, &users)?;
+
+    Ok(user)
+}
+
+/// Verifies the user's password and membership of the project and opens the
+/// project keys sealed for the user. The keys are `None` when the membership
+/// has no key for the project's current data key.
+pub async fn user_login(
+    data_path: &str,
+    login_info: &UserLoginInfo,
+) -> Result<(ProjectInfo, UserInfo, ProjectMember, Option<ProjectKeys>), UserInfoErrors> {
+    let user = {
+        let _users = USERS_LOCK.lock().await;
+        find_user_by_name(data_path, &login_info.user_name)
+    };
+
+    let (mut user, secret, new_hash) = {
+        let password = login_info.password.clone();
+        run_kdf(move || {
+            let user = match user {
+                Ok(user) => user,
+                Err(err) => {
+                    dummy_verify_password(&password);
+                    return Err(err);
+                }
+            };
+            if !verify_password(&password, &user.password_hash) {
+                return Err(UserInfoErrors::WrongPassword);
+            }
+            let secret = user.unlock_secret(&password)?;
+            let new_hash =
+                password_needs_rehash(&user.password_hash).then(|| hash_password(&password));
+            Ok((user, secret, new_hash))
+        })
+        .await?
+    };
+
+    if let Some(new_hash) = new_hash {
+        let _users = USERS_LOCK.lock().await;
+        let mut users = read_users(data_path)?;
+        // only the hash that was just verified is replaced, a password changed
+        // in the meantime stays
+        if let Some(entry) = users
+            .iter_mut()
+            .find(|entry| entry.user_id == user.user_id)
+            .filter(|entry| entry.password_hash == user.password_hash)
+        {
+            entry.password_hash = new_hash.clone();
+            write_users(data_path, &users)?;
+            user.password_hash = new_hash;
+        }
+    }
+
+    let project = get_project_by_name(data_path, &login_info.project_name).await?;
+    let member = read_project_members(data_path, &project.project_id)?
+        .into_iter()
+        .find(|member| member.user_id == user.user_id)
+        .ok_or(UserInfoErrors::NotAMember)?;
+
+    let project_keys = member.open_keys(&project, &secret);
+
+    Ok((project, user, member, project_keys))
+}
+
+/// Changes a user's password. Only the user's own secret is re-wrapped, the
+/// keys sealed to the user stay valid.
+pub async fn change_user_password(
+    data_path: &str,
+    user_id: &Uuid,
+    password_change: &UserPasswordChange,
+) -> Result<(), UserInfoErrors> {
+    let user = {
+        let _users = USERS_LOCK.lock().await;
+        find_user(data_path, user_id)?
+    };
+
+    let changed = {
+        let (mut user, password_change) = (user.clone(), password_change.clone());
+        run_kdf(move || {
+            if !verify_password(&password_change.old_password, &user.password_hash) {
+                return Err(UserInfoErrors::WrongPassword);
+            }
+
+            let secret = user.unlock_secret(&password_change.old_password)?;
+            user.secret_key_info = WrappedKeyInfo::new(&password_change.new_password, &secret)
+                .map_err(|err| UserInfoErrors::KeyDerivationFailed(err.to_string()))?;
+            user.password_hash = hash_password(&password_change.new_password);
+            Ok(user)
+        })
+        .await?
+    };
+
+    let _users = USERS_LOCK.lock().await;
+    let mut users = read_users(data_path)?;
+    match users.iter_mut().find(|entry| entry.user_id == *user_id) {
+        // the old password was checked against this hash, if it changed since
+        // the old password may not be the current one anymore
+        Some(entry) if entry.password_hash == user.password_hash => *entry = changed,
+        Some(_) => return Err(UserInfoErrors::WrongPassword),
+        None => return Err(UserInfoErrors::UserDosentExist),
+    }
+    write_users(data_path, &users)
+}
+
+pub async fn get_user_projects(
+    data_path: &str,
+    user_id: &Uuid,
+) -> Result<Vec<UserProject>, UserInfoErrors> {
+    let projects = read_global_projects(data_path)?;
+    let mut user_projects = vec![];
+
+    for project in projects.iter() {
+        let members = read_project_members(data_path, &project.project_id)?;
+        if let Some(member) = members.iter().find(|member| member.user_id == *user_id) {
+            user_projects.push(UserProject {
+                project: Projects::from(project),
+                role: member.role,
+            });
+        }
+    }
+
+    Ok(user_projects)
+}
+
+pub async fn get_project_members(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<Vec<ProjectMemberInfo>, UserInfoErrors> {
+    let users = read_users(data_path)?;
+    let members = read_project_members(data_path, project_id)?;
+
+    Ok(members
+        .iter()
+        .filter_map(|member| {
+            let user = users.iter().find(|user| user.user_id == member.user_id)?;
+            Some(ProjectMemberInfo {
+                user_id: member.user_id,
+                user_name: user.user_name.to_owned(),
+                role: member.role,
+                added_date: member.added_date,
+            })
+        })
+        .collect())
+}
+
+/// Adds a user to the project, sealing the project keys (which the caller has
+/// unlocked) to the user's public key.
+pub async fn add_project_member(
+    data_path: &str,
+    project_id: &Uuid,
+    new_member: &NewProjectMember,
+    project_keys: Option<&ProjectKeys>,
+) -> Result<ProjectMemberInfo, UserInfoErrors> {
+    let project_keys = project_keys.ok_or(UserInfoErrors::EncryptionKeyUnavailable)?;
+    let user = find_user_by_name(data_path, &new_member.user_name)?;
+    let _members = lock_project_members(project_id).await;
+    let mut members = read_project_members(data_path, project_id)?;
+
+    if members.iter().any(|member| member.user_id == user.user_id) {
+        return Err(UserInfoErrors::MemberAllreadyExists);
+    }
+
+    let member = ProjectMember::new(&user, new_member.role, project_keys)?;
+    members.push(member.clone());
+    write_project_members(data_path, project_id, &members)?;
+
+    Ok(ProjectMemberInfo {
+        user_id: user.user_id,
+        user_name: user.user_name,
+        role: member.role,
+        added_date: member.added_date,
+    })
+}
+
+pub async fn update_member_role(
+    data_path: &str,
+    project_id: &Uuid,
+    user_id: &Uuid,
+    role: Role,
+) -> Result<(), UserInfoErrors> {
+    let _members = lock_project_members(project_id).await;
+    let mut members = read_project_members(data_path, project_id)?;
+
+    if role != Role::Owner {
+        check_owner_left(&members, user_id)?;
+    }
+    match members.iter_mut().find(|member| member.user_id == *user_id) {
+        Some(member) => member.role = role,
+        None => return Err(UserInfoErrors::NotAMember),
+    }
+
+    write_project_members(data_path, project_id, &members)
+}
+
+pub async fn remove_project_member(
+    data_path: &str,
+    project_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<(), UserInfoErrors> {
+    let _members = lock_project_members(project_id).await;
+    let mut members = read_project_members(data_path, project_id)?;
+    let count = members.len();
+
+    check_owner_left(&members, user_id)?;
+
+    members.retain(|member| member.user_id != *user_id);
+    if members.len() == count {
+        return Err(UserInfoErrors::NotAMember);
+    }
+
+    write_project_members(data_path, project_id, &members)
+}
+
+/// Seals `project_keys` to every member again, e.g. after a new data key was
+/// created for a key rotation.
+pub async fn seal_member_keys(
+    data_path: &str,
+    project_id: &Uuid,
+    project_keys: &ProjectKeys,
+) -> Result<(), UserInfoErrors> {
+    let users = read_users(data_path)?;
+    let _members = lock_project_members(project_id).await;
+    let mut members = read_project_members(data_path, project_id)?;
+
+    for member in members.iter_mut() {
+        if let Some(user) = users.iter().find(|user| user.user_id == member.user_id) {
+            member.seal_keys(user, project_keys)?;
+        }
+    }
+
+    write_project_members(data_path, project_id, &members)
+}
+
+/// Drops the sealed keys of every member except the one for `key_id`.
+pub async fn retain_member_keys(
+    data_path: &str,
+    project_id: &Uuid,
+    key_id: &Uuid,
+) -> Result<(), UserInfoErrors> {
+    let _members = lock_project_members(project_id).await;
+    let mut members = read_project_members(data_path, project_id)?;
+
+    for member in members.iter_mut() {
+        member.sealed_keys.retain(|sealed| sealed.key_id == *key_id);
+    }
+
+    write_project_members(data_path, project_id, &members)
+}
+
+async fn get_project_by_name(
+    data_path: &str,
+    project_name: &str,
+) -> Result<ProjectInfo, UserInfoErrors> {
+    read_global_projects(data_path)?
+        .into_iter()
+        .find(|project| project.project_name == project_name)
+        .ok_or(UserInfoErrors::ProjectDosentExist)
+}
+
+fn find_user(data_path: &str, user_id: &Uuid) -> Result<UserInfo, UserInfoErrors> {
+    read_users(data_path)?
+        .into_iter()
+        .find(|user| user.user_id == *user_id)
+        .ok_or(UserInfoErrors::UserDosentExist)
+}
+
+fn find_user_by_name(data_path: &str, user_name: &str) -> Result<UserInfo, UserInfoErrors> {
+    read_users(data_path)?
+        .into_iter()
+        .find(|user| user.user_name == user_name)
+        .ok_or(UserInfoErrors::UserDosentExist)
+}
+
+fn read_users(data_path: &str) -> Result<Vec<UserInfo>, UserInfoErrors> {
+    read_json_or_default(&users_json(data_path))
+}
+
+fn write_users(data_path: &str, users: &[UserInfo]) -> Result<(), UserInfoErrors> {
+    replace_file_atomic(&users_json(data_path), object_to_byte_vec(users).as_slice())
+        .map_err(|_| UserInfoErrors::FailedToSaveUser)
+}
+
+fn read_global_projects(data_path: &str) -> Result<Vec<ProjectInfo>, UserInfoErrors> {
+    read_json_or_default(&global_project_json(data_path))
+}
+
+pub fn read_project_members(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<Vec<ProjectMember>, UserInfoErrors> {
+    if !project_dir(data_path, project_id).exists() {
+        return Err(UserInfoErrors::ProjectDosentExist);
+    }
+
+    read_json_or_default(&project_members_json(data_path, project_id))
+}
+
+fn write_project_members(
+    data_path: &str,
+    project_id: &Uuid,
+    members: &[ProjectMember],
+) -> Result<(), UserInfoErrors> {
+    replace_file_atomic(
+        &project_members_json(data_path, project_id),
+        object_to_byte_vec(members).as_slice(),
+    )
+    .map_err(|_| UserInfoErrors::FailedToSaveUser)
+}
+
+fn read_json_or_default<T: ::serde::de::DeserializeOwned + Default>(
+    path: &std::path::Path,
+) -> Result<T, UserInfoErrors> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+
+    let data = fs::read_to_string(path).map_err(|_| UserInfoErrors::FailedToReadUsers)?;
+    serde_json::from_str(&data).map_err(|_| UserInfoErrors::FailedToReadUsers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::join_all;
+
+    fn temp_data_path() -> String {
+        let data_path = std::env::temp_dir().join(format!("users_{}", Uuid::new_v4()));
+        fs::create_dir_all(&data_path).unwrap();
+        data_path.to_str().unwrap().to_owned()
+    }
+
+    fn register_info(user_name: &str) -> UserRegisterInfo {
+        UserRegisterInfo {
+            user_name: user_name.to_owned(),
+            password: "password".to_owned(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn concurrent_registrations_keep_every_user() {
+        let data_path = temp_data_path();
+
+        let registrations: Vec<_> = ["a", "b", "c", "d", "e", "a"]
+            .into_iter()
+            .map(register_info)
+            .collect();
+        let results = join_all(
+            registrations
+                .iter()
+                .map(|info| register_user(&data_path, info)),
+        )
+        .await;
+
+        let taken = results
+            .iter()
+            .filter(|res| matches!(res, Err(UserInfoErrors::UserAllreadyExists)))
+            .count();
+        assert_eq!(taken, 1);
+        let mut stored: Vec<String> = read_users(&data_path)
+            .unwrap()
+            .into_iter()
+            .map(|user| user.user_name)
+            .collect();
+        stored.sort();
+        assert_eq!(stored, ["a", "b", "c", "d", "e"]);
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn login_leaves_the_worker_free() {
+        let data_path = temp_data_path();
+        register_user(&data_path, &register_info("user"))
+            .await
+            .unwrap();
+
+        // the test runs on a single thread, so the ticker only gets a turn
+        // while the login waits on the blocking pool
+        let ticks = std::rc::Rc::new(std::cell::Cell::new(0));
+        let ticker = {
+            let ticks = ticks.clone();
+            actix_web::rt::spawn(async move {
+                loop {
+                    actix_web::rt::time::sleep(std::time::Duration::from_millis(1)).await;
+                    ticks.set(ticks.get() + 1);
+                }
+            })
+        };
+        let login = UserLoginInfo {
+            user_name: "user".to_owned(),
+            password: "password".to_owned(),
+            project_name: "missing".to_owned(),
+        };
+        let res = user_login(&data_path, &login).await;
+        ticker.abort();
+
+        assert!(matches!(res, Err(UserInfoErrors::ProjectDosentExist)));
+        assert!(ticks.get() > 2, "{} ticks", ticks.get());
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn keeps_the_last_owner() {
+        let data_path = temp_data_path();
+        let project = crate::models::project_info::create_project_info(
+            &data_path,
+            &crate::models::project_info::ProjectLoginInfo {
+                project_name: "members".to_owned(),
+                password: "password".to_owned(),
+            },
+        )
+        .await
+        .unwrap();
+        let project_id = project.project_id;
+        let project_keys = ProjectKeys {
+            current: DataKey::generate(),
+            previous: None,
+        };
+
+        let mut user_ids = vec![];
+        for (user_name, role) in [("owner", Role::Owner), ("editor", Role::Editor)] {
+            register_user(&data_path, &register_info(user_name))
+                .await
+                .unwrap();
+            let new_member = NewProjectMember {
+                user_name: user_name.to_owned(),
+                role,
+            };
+            let member =
+                add_project_member(&data_path, &project_id, &new_member, Some(&project_keys))
+                    .await
+                    .unwrap();
+            user_ids.push(member.user_id);
+        }
+        let (owner, editor) = (user_ids[0], user_ids[1]);
+
+        let demoted = update_member_role(&data_path, &project_id, &owner, Role::Editor).await;
+        assert!(matches!(demoted, Err(UserInfoErrors::LastOwner)));
+        let removed = remove_project_member(&data_path, &project_id, &owner).await;
+        assert!(matches!(removed, Err(UserInfoErrors::LastOwner)));
+
+        // with a second owner the first one can go
+        update_member_role(&data_path, &project_id, &editor, Role::Owner)
+            .await
+            .unwrap();
+        remove_project_member(&data_path, &project_id, &owner)
+            .await
+            .unwrap();
+        let members = read_project_members(&data_path, &project_id).unwrap();
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].role, Role::Owner);
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+}
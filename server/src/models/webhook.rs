@@ -0,0 +1,932 @@
+use ::serde::{Deserialize, Serialize};
+use actix_web::{rt, web};
+use chrono::{NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::config::WebhookConfig;
+use crate::models::project_events::ProjectEvent;
+use crate::utility::file_utilities::*;
+
+/// `sha256=<hex HMAC-SHA256 of the body>`, keyed with the webhook's secret.
+pub const SIGNATURE_HEADER: &str = "X-Sorter-Signature";
+pub const EVENT_HEADER: &str = "X-Sorter-Event";
+/// Same for every attempt of a delivery, so receivers can drop repeats.
+pub const DELIVERY_HEADER: &str = "X-Sorter-Delivery";
+
+/// The project events a webhook can be sent. Sorting an image is tagging it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    ImageAdded,
+    ImageTagged,
+    ImageDeleted,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 3] = [
+        WebhookEvent::ImageAdded,
+        WebhookEvent::ImageTagged,
+        WebhookEvent::ImageDeleted,
+    ];
+
+    pub fn of(event: &ProjectEvent) -> Option<Self> {
+        match event {
+            ProjectEvent::ImageAdded { .. } => Some(WebhookEvent::ImageAdded),
+            ProjectEvent::ImageTagged { .. } => Some(WebhookEvent::ImageTagged),
+            ProjectEvent::ImageDeleted { .. } => Some(WebhookEvent::ImageDeleted),
+            _ => None,
+        }
+    }
+}
+
+/// A URL the project's events are posted to, stored in `webhooks.json`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Webhook {
+    pub webhook_id: Uuid,
+    pub url: String,
+    /// Key of the payload signatures, shown once when the webhook is created.
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewWebhook {
+    pub url: String,
+    /// All of them when not given.
+    #[serde(default)]
+    pub events: Option<Vec<WebhookEvent>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookInfo {
+    pub webhook_id: Uuid,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CreatedWebhook {
+    pub secret: String,
+    #[serde(flatten)]
+    pub info: WebhookInfo,
+}
+
+/// One attempt to deliver an event, a line of `webhook_deliveries.jsonl`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub delivery_id: Uuid,
+    pub webhook_id: Uuid,
+    /// The event's `type`, `ping` for test deliveries.
+    pub event: String,
+    pub attempt: u32,
+    pub date: NaiveDateTime,
+    /// HTTP status the receiver answered with, if it answered.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub success: bool,
+}
+
+/// What is posted to a webhook.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    pub delivery_id: Uuid,
+    pub webhook_id: Uuid,
+    pub project_id: Uuid,
+    pub date: NaiveDateTime,
+    pub event: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeliveryQuery {
+    /// Return only the newest `limit` attempts.
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum WebhookErrors {
+    InvalidUrl(String),
+    AddressNotAllowed(String),
+    NoEvents,
+    WebhookDosentExist,
+    FailedToReadWebhooks,
+    FailedToSaveWebhooks,
+    FailedToReadDeliveryLog,
+    FailedToWriteDeliveryLog,
+}
+
+impl fmt::Display for WebhookErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebhookErrors::InvalidUrl(url) => write!(f, "'{}' is not an http(s) URL", url),
+            WebhookErrors::AddressNotAllowed(url) => {
+                write!(f, "'{}' doesn't resolve to a public address", url)
+            }
+            WebhookErrors::NoEvents => write!(f, "a webhook needs at least one event"),
+            WebhookErrors::WebhookDosentExist => write!(f, "webhook doesn't exist"),
+            WebhookErrors::FailedToReadWebhooks => write!(f, "failed to read webhooks"),
+            WebhookErrors::FailedToSaveWebhooks => write!(f, "failed to save webhooks"),
+            WebhookErrors::FailedToReadDeliveryLog => {
+                write!(f, "failed to read the webhook delivery log")
+            }
+            WebhookErrors::FailedToWriteDeliveryLog => {
+                write!(f, "failed to write the webhook delivery log")
+            }
+        }
+    }
+}
+
+impl From<&Webhook> for WebhookInfo {
+    fn from(webhook: &Webhook) -> Self {
+        WebhookInfo {
+            webhook_id: webhook.webhook_id,
+            url: webhook.url.clone(),
+            events: webhook.events.clone(),
+            created_date: webhook.created_date,
+        }
+    }
+}
+
+/// The value of the signature header for `body`.
+pub fn sign_payload(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// `host:port` of an http(s) URL, `None` for anything else.
+fn url_netloc(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let default_port = match scheme {
+        "http" => 80,
+        "https" => 443,
+        _ => return None,
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host_port = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+
+    let (host, port) = match host_port.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']')? {
+            (host, "") => (host, None),
+            (host, port) => (host, Some(port.strip_prefix(':')?)),
+        },
+        None => match host_port.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (host_port, None),
+        },
+    };
+    let port: u16 = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return None;
+    }
+
+    match host.contains(':') {
+        true => Some(format!("[{}]:{}", host, port)),
+        false => Some(format!("{}:{}", host, port)),
+    }
+}
+
+/// Whether `ip` is on the public internet. Loopback, link-local (and with it
+/// cloud metadata services), private, shared and reserved ranges are not.
+/// `IpAddr::is_global` would do, but isn't stable.
+fn is_public_address(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+                // NAT64 and 6to4 carry an IPv4 address that may be private
+                || (segments[0] == 0x64 && segments[1] == 0xff9b)
+                || segments[0] == 0x2002
+                || segments[..6] == [0; 6])
+        }
+    }
+}
+
+/// Resolves `host:port`, failing if any of its addresses isn't public and
+/// `allow_private` isn't set. Deliveries connect through this too, so a name
+/// that resolves differently by then is still checked.
+fn resolve_webhook_host(netloc: &str, allow_private: bool) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+    if !allow_private && addrs.iter().any(|addr| !is_public_address(addr.ip())) {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} resolves to a private or local address", netloc),
+        ));
+    }
+    Ok(addrs)
+}
+
+async fn check_webhook_url(url: &str, config: &WebhookConfig) -> Result<(), WebhookErrors> {
+    let netloc = url_netloc(url).ok_or_else(|| WebhookErrors::InvalidUrl(url.to_owned()))?;
+    let allow_private = config.allow_private_addresses;
+
+    match web::block(move || resolve_webhook_host(&netloc, allow_private)).await {
+        Ok(Ok(_)) => Ok(()),
+        _ => Err(WebhookErrors::AddressNotAllowed(url.to_owned())),
+    }
+}
+
+pub async fn create_webhook(
+    data_path: &str,
+    config: &WebhookConfig,
+    project_id: &Uuid,
+    new_webhook: &NewWebhook,
+) -> Result<CreatedWebhook, WebhookErrors> {
+    check_webhook_url(&new_webhook.url, config).await?;
+    let events = match &new_webhook.events {
+        Some(events) if events.is_empty() => return Err(WebhookErrors::NoEvents),
+        Some(events) => events.clone(),
+        None => WebhookEvent::ALL.to_vec(),
+    };
+
+    let mut webhooks = read_webhooks(data_path, project_id)?;
+
+    let webhook = Webhook {
+        webhook_id: Uuid::new_v4(),
+        url: new_webhook.url.clone(),
+        secret: hex::encode(rand::random::<[u8; 32]>()),
+        events,
+        created_date: Utc::now().naive_utc(),
+    };
+    webhooks.push(webhook.clone());
+    write_webhooks(data_path, project_id, &webhooks)?;
+
+    Ok(CreatedWebhook {
+        secret: webhook.secret.clone(),
+        info: WebhookInfo::from(&webhook),
+    })
+}
+
+pub async fn get_webhooks(
+    data_path: &str,
+    project_id: &Uuid,
+) -> Result<Vec<WebhookInfo>, WebhookErrors> {
+    Ok(read_webhooks(data_path, project_id)?
+        .iter()
+        .map(WebhookInfo::from)
+        .collect())
+}
+
+/// Removes a webhook. Its deliveries stay in the log.
+pub async fn delete_webhook(
+    data_path: &str,
+    project_id: &Uuid,
+    webhook_id: &Uuid,
+) -> Result<(), WebhookErrors> {
+    let mut webhooks = read_webhooks(data_path, project_id)?;
+    let count = webhooks.len();
+
+    webhooks.retain(|webhook| webhook.webhook_id != *webhook_id);
+    if webhooks.len() == count {
+        return Err(WebhookErrors::WebhookDosentExist);
+    }
+
+    write_webhooks(data_path, project_id, &webhooks)
+}
+
+/// The delivery attempts of a webhook, oldest first.
+pub async fn get_webhook_deliveries(
+    data_path: &str,
+    project_id: &Uuid,
+    webhook_id: &Uuid,
+    query: &DeliveryQuery,
+) -> Result<Vec<WebhookDelivery>, WebhookErrors> {
+    find_webhook(data_path, project_id, webhook_id)?;
+
+    let path = webhook_deliveries_jsonl(data_path, project_id);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let data = fs::read_to_string(path).map_err(|_| WebhookErrors::FailedToReadDeliveryLog)?;
+    let mut deliveries: Vec<WebhookDelivery> = data
+        .lines()
+        .filter_map(|line| serde_json::from_str::<WebhookDelivery>(line).ok())
+        .filter(|delivery| delivery.webhook_id == *webhook_id)
+        .collect();
+
+    if let Some(limit) = query.limit {
+        let skip = deliveries.len().saturating_sub(limit);
+        deliveries.drain(..skip);
+    }
+
+    Ok(deliveries)
+}
+
+/// Sends a `ping` event to the webhook once, without retrying, and returns
+/// how that went.
+pub async fn test_webhook(
+    data_path: &str,
+    config: &WebhookConfig,
+    project_id: &Uuid,
+    webhook_id: &Uuid,
+) -> Result<WebhookDelivery, WebhookErrors> {
+    let webhook = find_webhook(data_path, project_id, webhook_id)?;
+    let payload = WebhookPayload {
+        delivery_id: Uuid::new_v4(),
+        webhook_id: webhook.webhook_id,
+        project_id: *project_id,
+        date: Utc::now().naive_utc(),
+        event: json!({ "type": "ping" }),
+    };
+
+    let delivery = attempt_delivery(config, &webhook, &payload, "ping", 1).await;
+    write_delivery(data_path, config, project_id, &delivery)?;
+
+    Ok(delivery)
+}
+
+/// Posts `event` to every webhook of the project that wants it. Each delivery
+/// is retried in the background until it succeeds or runs out of attempts.
+pub fn dispatch_event(
+    data_path: &str,
+    config: &WebhookConfig,
+    project_id: &Uuid,
+    event: &ProjectEvent,
+) -> Result<(), WebhookErrors> {
+    let webhook_event = match WebhookEvent::of(event) {
+        Some(webhook_event) => webhook_event,
+        None => return Ok(()),
+    };
+
+    for webhook in read_webhooks(data_path, project_id)? {
+        if !webhook.events.contains(&webhook_event) {
+            continue;
+        }
+
+        let payload = WebhookPayload {
+            delivery_id: Uuid::new_v4(),
+            webhook_id: webhook.webhook_id,
+            project_id: *project_id,
+            date: Utc::now().naive_utc(),
+            event: serde_json::to_value(event).unwrap(),
+        };
+        rt::spawn(deliver(
+            data_path.to_owned(),
+            config.clone(),
+            *project_id,
+            webhook,
+            payload,
+            event.name(),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn deliver(
+    data_path: String,
+    config: WebhookConfig,
+    project_id: Uuid,
+    webhook: Webhook,
+    payload: WebhookPayload,
+    event: &'static str,
+) {
+    let mut attempt = 1;
+
+    loop {
+        let delivery = attempt_delivery(&config, &webhook, &payload, event, attempt).await;
+        if let Err(err) = write_delivery(&data_path, &config, &project_id, &delivery) {
+            println!("{}", err);
+        }
+        if delivery.success || attempt >= config.max_attempts {
+            return;
+        }
+
+        rt::time::sleep(retry_delay(&config, attempt)).await;
+        attempt += 1;
+
+        // don't keep retrying a webhook that was deleted in the meantime
+        if find_webhook(&data_path, &project_id, &webhook.webhook_id).is_err() {
+            return;
+        }
+    }
+}
+
+/// Wait after failed attempt `attempt`, doubling from `retry_delay_seconds`.
+fn retry_delay(config: &WebhookConfig, attempt: u32) -> Duration {
+    let backoff = config
+        .retry_delay_seconds
+        .saturating_mul(1 << (attempt - 1).min(16));
+    Duration::from_secs(backoff)
+}
+
+async fn attempt_delivery(
+    config: &WebhookConfig,
+    webhook: &Webhook,
+    payload: &WebhookPayload,
+    event: &str,
+    attempt: u32,
+) -> WebhookDelivery {
+    let body = serde_json::to_vec(payload).unwrap();
+    let allow_private = config.allow_private_addresses;
+    let request = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(config.timeout_seconds))
+        .redirects(0)
+        .resolver(move |netloc: &str| resolve_webhook_host(netloc, allow_private))
+        .build()
+        .post(&webhook.url)
+        .set("Content-Type", "application/json")
+        .set(EVENT_HEADER, event)
+        .set(DELIVERY_HEADER, &payload.delivery_id.to_string())
+        .set(SIGNATURE_HEADER, &sign_payload(&webhook.secret, &body));
+
+    let result = web::block(move || match request.send_bytes(&body) {
+        // redirects aren't followed, they are answers too
+        Ok(response) if !(200..300).contains(&response.status()) => (
+            Some(response.status()),
+            Some(format!(
+                "receiver answered with status {}",
+                response.status()
+            )),
+        ),
+        Ok(response) => (Some(response.status()), None),
+        Err(ureq::Error::Status(status, _)) => (
+            Some(status),
+            Some(format!("receiver answered with status {}", status)),
+        ),
+        Err(ureq::Error::Transport(err)) => (None, Some(err.to_string())),
+    })
+    .await;
+    let (status, error) = result.unwrap_or_else(|err| (None, Some(err.to_string())));
+
+    WebhookDelivery {
+        delivery_id: payload.delivery_id,
+        webhook_id: webhook.webhook_id,
+        event: event.to_owned(),
+        attempt,
+        date: Utc::now().naive_utc(),
+        status,
+        success: error.is_none(),
+        error,
+    }
+}
+
+fn find_webhook(
+    data_path: &str,
+    project_id: &Uuid,
+    webhook_id: &Uuid,
+) -> Result<Webhook, WebhookErrors> {
+    read_webhooks(data_path, project_id)?
+        .into_iter()
+        .find(|webhook| webhook.webhook_id == *webhook_id)
+        .ok_or(WebhookErrors::WebhookDosentExist)
+}
+
+fn read_webhooks(data_path: &str, project_id: &Uuid) -> Result<Vec<Webhook>, WebhookErrors> {
+    let path = webhooks_json(data_path, project_id);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let data = fs::read_to_string(path).map_err(|_| WebhookErrors::FailedToReadWebhooks)?;
+    serde_json::from_str(&data).map_err(|_| WebhookErrors::FailedToReadWebhooks)
+}
+
+fn write_webhooks(
+    data_path: &str,
+    project_id: &Uuid,
+    webhooks: &[Webhook],
+) -> Result<(), WebhookErrors> {
+    replace_file_atomic(
+        &webhooks_json(data_path, project_id),
+        object_to_byte_vec(webhooks).as_slice(),
+    )
+    .map_err(|_| WebhookErrors::FailedToSaveWebhooks)
+}
+
+/// Appends to a project's delivery log, dropping the oldest entries beyond
+/// `max_log_entries`. Deliveries run concurrently, so writes are serialized.
+fn write_delivery(
+    data_path: &str,
+    config: &WebhookConfig,
+    project_id: &Uuid,
+    delivery: &WebhookDelivery,
+) -> Result<(), WebhookErrors> {
+    static DELIVERY_LOG: Mutex<()> = Mutex::new(());
+
+    let mut line =
+        serde_json::to_vec(delivery).map_err(|_| WebhookErrors::FailedToWriteDeliveryLog)?;
+    line.push(b'\n');
+
+    let _guard = DELIVERY_LOG.lock().unwrap();
+    let path = webhook_deliveries_jsonl(data_path, project_id);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&line))
+        .map_err(|_| WebhookErrors::FailedToWriteDeliveryLog)?;
+
+    let data = fs::read_to_string(&path).map_err(|_| WebhookErrors::FailedToWriteDeliveryLog)?;
+    let lines: Vec<&str> = data.lines().collect();
+    if lines.len() <= config.max_log_entries {
+        return Ok(());
+    }
+
+    let mut kept = lines[lines.len() - config.max_log_entries..].join("\n");
+    kept.push('\n');
+    replace_file_atomic(&path, kept.as_bytes()).map_err(|_| WebhookErrors::FailedToWriteDeliveryLog)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Headers and body of a request the receiver got.
+    type Received = (Vec<(String, String)>, Vec<u8>);
+
+    /// A webhook receiver answering with `statuses` in turn, then 200s, and
+    /// keeping the headers and body of every request.
+    #[derive(Default)]
+    struct Receiver {
+        statuses: Mutex<Vec<u16>>,
+        requests: Mutex<Vec<Received>>,
+    }
+
+    impl Receiver {
+        fn start(statuses: &[u16]) -> (Arc<Receiver>, String) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/hook", listener.local_addr().unwrap());
+            let receiver = Arc::new(Receiver {
+                statuses: Mutex::new(statuses.iter().rev().copied().collect()),
+                ..Default::default()
+            });
+
+            let server = receiver.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    server.handle(stream);
+                }
+            });
+            (receiver, url)
+        }
+
+        fn handle(&self, mut stream: TcpStream) {
+            let mut reader = BufReader::new(&stream);
+            let mut headers = vec![];
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(": ") {
+                    Some((name, value)) => headers.push((name.to_lowercase(), value.to_owned())),
+                    None => break,
+                }
+            }
+            let length = headers
+                .iter()
+                .find(|(name, _)| name == "content-length")
+                .map_or(0, |(_, len)| len.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            self.requests.lock().unwrap().push((headers, body));
+
+            let status = self.statuses.lock().unwrap().pop().unwrap_or(200);
+            let response = format!(
+                "HTTP/1.1 {} Status\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                status
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+
+        fn header(&self, index: usize, name: &str) -> String {
+            let requests = self.requests.lock().unwrap();
+            let (headers, _) = &requests[index];
+            headers
+                .iter()
+                .find(|(header, _)| header == &name.to_lowercase())
+                .map(|(_, value)| value.clone())
+                .unwrap()
+        }
+    }
+
+    fn test_config() -> WebhookConfig {
+        WebhookConfig {
+            max_attempts: 3,
+            retry_delay_seconds: 0,
+            timeout_seconds: 5,
+            max_log_entries: 100,
+            allow_private_addresses: true,
+        }
+    }
+
+    /// A data folder with one project holding a webhook on `url`.
+    async fn project_with_webhook(url: &str) -> (String, Uuid, Webhook) {
+        let project_id = Uuid::new_v4();
+        let data_path = std::env::temp_dir().join(format!("webhook_{}", Uuid::new_v4()));
+        fs::create_dir_all(data_path.join(project_id.to_string())).unwrap();
+        let data_path = data_path.to_str().unwrap().to_owned();
+
+        let new_webhook = NewWebhook {
+            url: url.to_owned(),
+            events: None,
+        };
+        create_webhook(&data_path, &test_config(), &project_id, &new_webhook)
+            .await
+            .unwrap();
+        let webhook = read_webhooks(&data_path, &project_id).unwrap().remove(0);
+
+        (data_path, project_id, webhook)
+    }
+
+    fn payload(webhook: &Webhook, project_id: Uuid) -> WebhookPayload {
+        WebhookPayload {
+            delivery_id: Uuid::new_v4(),
+            webhook_id: webhook.webhook_id,
+            project_id,
+            date: Utc::now().naive_utc(),
+            event: json!({ "type": "image_added" }),
+        }
+    }
+
+    async fn deliveries(
+        data_path: &str,
+        project_id: &Uuid,
+        webhook: &Webhook,
+    ) -> Vec<WebhookDelivery> {
+        get_webhook_deliveries(
+            data_path,
+            project_id,
+            &webhook.webhook_id,
+            &DeliveryQuery::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn signs_the_exact_body() {
+        let (receiver, url) = Receiver::start(&[]);
+        let (data_path, project_id, webhook) = project_with_webhook(&url).await;
+        let payload = payload(&webhook, project_id);
+
+        let delivery = attempt_delivery(&test_config(), &webhook, &payload, "image_added", 1).await;
+        assert!(delivery.success);
+
+        let body = receiver.requests.lock().unwrap()[0].1.clone();
+        let mut mac = Hmac::<Sha256>::new_from_slice(webhook.secret.as_bytes()).unwrap();
+        mac.update(&body);
+        let expected = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        assert_eq!(receiver.header(0, SIGNATURE_HEADER), expected);
+        assert_eq!(receiver.header(0, EVENT_HEADER), "image_added");
+        assert_eq!(
+            receiver.header(0, DELIVERY_HEADER),
+            payload.delivery_id.to_string()
+        );
+
+        let sent: WebhookPayload = serde_json::from_slice(&body).unwrap();
+        assert_eq!(sent.delivery_id, payload.delivery_id);
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn retries_server_errors_and_logs_every_attempt() {
+        let (receiver, url) = Receiver::start(&[503, 500]);
+        let (data_path, project_id, webhook) = project_with_webhook(&url).await;
+        let payload = payload(&webhook, project_id);
+
+        deliver(
+            data_path.clone(),
+            test_config(),
+            project_id,
+            webhook.clone(),
+            payload.clone(),
+            "image_added",
+        )
+        .await;
+
+        let requests = receiver.requests.lock().unwrap().len();
+        assert_eq!(requests, 3);
+        for index in 0..3 {
+            assert_eq!(
+                receiver.header(index, DELIVERY_HEADER),
+                payload.delivery_id.to_string()
+            );
+        }
+
+        let log = deliveries(&data_path, &project_id, &webhook).await;
+        let attempts: Vec<(u32, Option<u16>, bool)> = log
+            .iter()
+            .map(|delivery| (delivery.attempt, delivery.status, delivery.success))
+            .collect();
+        assert_eq!(
+            attempts,
+            vec![
+                (1, Some(503), false),
+                (2, Some(500), false),
+                (3, Some(200), true)
+            ]
+        );
+        assert!(log.iter().all(|d| d.delivery_id == payload.delivery_id));
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn gives_up_after_max_attempts() {
+        let (receiver, url) = Receiver::start(&[503; 10]);
+        let (data_path, project_id, webhook) = project_with_webhook(&url).await;
+
+        deliver(
+            data_path.clone(),
+            test_config(),
+            project_id,
+            webhook.clone(),
+            payload(&webhook, project_id),
+            "image_added",
+        )
+        .await;
+
+        assert_eq!(receiver.requests.lock().unwrap().len(), 3);
+        let log = deliveries(&data_path, &project_id, &webhook).await;
+        assert_eq!(log.len(), 3);
+        assert!(log.iter().all(|d| !d.success && d.status == Some(503)));
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let config = WebhookConfig {
+            retry_delay_seconds: 2,
+            ..test_config()
+        };
+        let delays: Vec<u64> = (1..=4)
+            .map(|attempt| retry_delay(&config, attempt).as_secs())
+            .collect();
+        assert_eq!(delays, vec![2, 4, 8, 16]);
+        assert_eq!(retry_delay(&config, 100), Duration::from_secs(2 << 16));
+    }
+
+    #[actix_web::test]
+    async fn keeps_the_newest_log_entries() {
+        let (_, url) = Receiver::start(&[]);
+        let (data_path, project_id, webhook) = project_with_webhook(&url).await;
+        let config = WebhookConfig {
+            max_log_entries: 3,
+            ..test_config()
+        };
+
+        for attempt in 1..=5 {
+            let delivery = WebhookDelivery {
+                delivery_id: Uuid::new_v4(),
+                webhook_id: webhook.webhook_id,
+                event: "ping".to_owned(),
+                attempt,
+                date: Utc::now().naive_utc(),
+                status: Some(200),
+                error: None,
+                success: true,
+            };
+            write_delivery(&data_path, &config, &project_id, &delivery).unwrap();
+        }
+
+        let log = deliveries(&data_path, &project_id, &webhook).await;
+        let attempts: Vec<u32> = log.iter().map(|delivery| delivery.attempt).collect();
+        assert_eq!(attempts, vec![3, 4, 5]);
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+
+    #[test]
+    fn parses_webhook_urls() {
+        assert_eq!(
+            url_netloc("https://example.com/hook").unwrap(),
+            "example.com:443"
+        );
+        assert_eq!(url_netloc("http://example.com").unwrap(), "example.com:80");
+        assert_eq!(
+            url_netloc("http://user:pw@example.com:8080/a?b#c").unwrap(),
+            "example.com:8080"
+        );
+        assert_eq!(url_netloc("http://[::1]:8080/").unwrap(), "[::1]:8080");
+        assert_eq!(
+            url_netloc("https://[2001:db8::1]").unwrap(),
+            "[2001:db8::1]:443"
+        );
+
+        for url in [
+            "ftp://example.com",
+            "example.com",
+            "http://",
+            "http://:80/",
+            "http://example.com:port/",
+            "http://[::1]x/",
+            "http://exa mple.com/",
+        ] {
+            assert_eq!(url_netloc(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn only_public_addresses_are_allowed() {
+        let private: [IpAddr; 14] = [
+            Ipv4Addr::new(127, 0, 0, 1).into(),
+            Ipv4Addr::new(169, 254, 169, 254).into(),
+            Ipv4Addr::new(10, 1, 2, 3).into(),
+            Ipv4Addr::new(172, 16, 0, 1).into(),
+            Ipv4Addr::new(192, 168, 1, 1).into(),
+            Ipv4Addr::new(100, 64, 0, 1).into(),
+            Ipv4Addr::new(0, 0, 0, 0).into(),
+            Ipv4Addr::new(255, 255, 255, 255).into(),
+            Ipv6Addr::LOCALHOST.into(),
+            Ipv6Addr::UNSPECIFIED.into(),
+            "fe80::1".parse().unwrap(),
+            "fd00::1".parse().unwrap(),
+            "::ffff:127.0.0.1".parse().unwrap(),
+            "64:ff9b::a00:1".parse().unwrap(),
+        ];
+        for ip in private {
+            assert!(!is_public_address(ip), "{}", ip);
+        }
+
+        let public: [IpAddr; 3] = [
+            Ipv4Addr::new(93, 184, 216, 34).into(),
+            "2606:4700::1111".parse().unwrap(),
+            "::ffff:93.184.216.34".parse().unwrap(),
+        ];
+        for ip in public {
+            assert!(is_public_address(ip), "{}", ip);
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejects_local_receivers() {
+        let (receiver, url) = Receiver::start(&[]);
+        let (data_path, project_id, webhook) = project_with_webhook(&url).await;
+        let config = WebhookConfig {
+            allow_private_addresses: false,
+            ..test_config()
+        };
+
+        for url in [
+            url.as_str(),
+            "http://localhost/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.1/",
+            "http://[::1]/",
+            "http://[::ffff:127.0.0.1]/",
+        ] {
+            let new_webhook = NewWebhook {
+                url: url.to_owned(),
+                events: None,
+            };
+            let res = create_webhook(&data_path, &config, &project_id, &new_webhook).await;
+            assert!(
+                matches!(res, Err(WebhookErrors::AddressNotAllowed(_))),
+                "{}",
+                url
+            );
+        }
+
+        // a webhook registered before still isn't delivered to
+        let delivery =
+            attempt_delivery(&config, &webhook, &payload(&webhook, project_id), "ping", 1).await;
+        assert!(!delivery.success);
+        assert_eq!(delivery.status, None);
+        assert!(receiver.requests.lock().unwrap().is_empty());
+
+        fs::remove_dir_all(data_path).unwrap();
+    }
+}
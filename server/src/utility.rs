@@ -1,9 +1,19 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
 use rand::{distributions::Alphanumeric, Rng};
 use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
 
+use crate::config::PasswordHashConfig;
+
+pub mod archive;
+pub mod blob_store;
+pub mod encrypted_blob;
 pub mod encryption;
 pub mod file_utilities;
+pub mod jwks;
 pub mod jwt_token;
+pub mod s3;
 
 pub fn genarate_salt(salt_len: usize) -> String {
     rand::thread_rng()
@@ -13,21 +23,84 @@ pub fn genarate_salt(salt_len: usize) -> String {
         .collect()
 }
 
-pub fn hash_password(password: &str) -> String {
-    let mut sha = Sha256::new();
-    let salt = genarate_salt(64);
+static PASSWORD_HASH_PARAMS: OnceLock<Params> = OnceLock::new();
 
-    sha.update(password.to_owned() + &salt.to_owned());
-    let passcode_hash = sha.finalize();
+/// Sets the Argon2id cost parameters for new password hashes from the config,
+/// the argon2 defaults are used until then.
+pub fn init_password_hashing(config: &PasswordHashConfig) -> Result<(), String> {
+    let params = config.params().map_err(|err| err.to_string())?;
+    let _ = PASSWORD_HASH_PARAMS.set(params);
+    Ok(())
+}
 
-    let passcode_hash = format!("{:X}", passcode_hash) + ":" + &salt;
-    passcode_hash
+fn password_hash_params() -> Params {
+    PASSWORD_HASH_PARAMS.get().cloned().unwrap_or_default()
 }
 
+/// Hashes a password into an Argon2id PHC string,
+/// e.g. `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`.
+pub fn hash_password(password: &str) -> String {
+    let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, password_hash_params());
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).unwrap();
+
+    argon
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// Checks a password against a PHC string, or against a legacy `HEX:salt`
+/// SHA-256 hash from before Argon2id was used.
 pub fn verify_password(password: &str, passcode_hash: &str) -> bool {
-    let passcode_parts = passcode_hash.split(':').collect::<Vec<&str>>();
-    let passcode_hash = passcode_parts[0];
-    let passcode_salt = passcode_parts[1];
+    match PasswordHash::new(passcode_hash) {
+        Ok(parsed_hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok(),
+        Err(_) => verify_legacy_password(password, passcode_hash),
+    }
+}
+
+/// Spends as long as `verify_password` would, for logins with an unknown
+/// project or user name, so the response time doesn't tell whether it exists.
+/// Hashes with the configured parameters rather than verifying a cached hash,
+/// which could have been made before `init_password_hashing` ran.
+pub fn dummy_verify_password(password: &str) {
+    let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, password_hash_params());
+    let salt = SaltString::encode_b64(&[0; 16]).unwrap();
+
+    let _ = argon.hash_password(password.as_bytes(), &salt);
+}
+
+/// Whether a hash that just verified should be replaced by a fresh one: it is
+/// a legacy hash, or it was made with other Argon2id parameters than the
+/// configured ones.
+pub fn password_needs_rehash(passcode_hash: &str) -> bool {
+    let parsed_hash = match PasswordHash::new(passcode_hash) {
+        Ok(parsed_hash) => parsed_hash,
+        Err(_) => return true,
+    };
+
+    match Params::try_from(&parsed_hash) {
+        Ok(params) => {
+            let wanted = password_hash_params();
+            parsed_hash.algorithm != Algorithm::Argon2id.ident()
+                || params.m_cost() != wanted.m_cost()
+                || params.t_cost() != wanted.t_cost()
+                || params.p_cost() != wanted.p_cost()
+        }
+        Err(_) => true,
+    }
+}
+
+pub fn is_legacy_password_hash(passcode_hash: &str) -> bool {
+    PasswordHash::new(passcode_hash).is_err()
+}
+
+fn verify_legacy_password(password: &str, passcode_hash: &str) -> bool {
+    let (passcode_hash, passcode_salt) = match passcode_hash.split_once(':') {
+        Some(parts) => parts,
+        None => return false,
+    };
 
     let mut sha = Sha256::new();
     sha.update(password.to_owned() + passcode_salt);
@@ -35,7 +108,15 @@ pub fn verify_password(password: &str, passcode_hash: &str) -> bool {
 
     let user_passcode_hash = format!("{:X}", user_passcode_hash);
 
-    passcode_hash == user_passcode_hash
+    constant_time_eq(passcode_hash.as_bytes(), user_passcode_hash.as_bytes())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 // pub fn get_current_working_dir() -> std::io::Result<PathBuf> {
@@ -0,0 +1,280 @@
+use chrono::{Datelike, Timelike, Utc};
+use crc32fast::Hasher;
+use std::fs::File;
+use std::io;
+use std::iter::once;
+use std::path::PathBuf;
+use std::vec::IntoIter;
+use tar::{EntryType, Header};
+
+use crate::utility::encrypted_blob::{open_blob, PlainChunks};
+
+const BLOCK_LEN: u64 = 512;
+
+/// Where the content of an archive entry comes from.
+pub enum ArchiveSource {
+    Bytes(Vec<u8>),
+    /// Copied as is, encrypted blobs stay encrypted.
+    File(PathBuf),
+    /// Opened only when the entry is reached, e.g. a blob decrypted while it
+    /// is sent.
+    Chunks(Box<dyn FnOnce() -> io::Result<PlainChunks> + Send>),
+}
+
+pub struct ArchiveEntry {
+    pub path: String,
+    pub size: u64,
+    pub source: ArchiveSource,
+}
+
+impl ArchiveEntry {
+    pub fn bytes(path: &str, bytes: Vec<u8>) -> Self {
+        ArchiveEntry {
+            path: path.to_owned(),
+            size: bytes.len() as u64,
+            source: ArchiveSource::Bytes(bytes),
+        }
+    }
+
+    pub fn chunks(
+        path: &str,
+        size: u64,
+        open: impl FnOnce() -> io::Result<PlainChunks> + Send + 'static,
+    ) -> Self {
+        ArchiveEntry {
+            path: path.to_owned(),
+            size,
+            source: ArchiveSource::Chunks(Box::new(open)),
+        }
+    }
+
+    pub fn file(path: &str, file_path: PathBuf) -> io::Result<Self> {
+        Ok(ArchiveEntry {
+            path: path.to_owned(),
+            size: file_path.metadata()?.len(),
+            source: ArchiveSource::File(file_path),
+        })
+    }
+}
+
+/// A tar archive of `entries`, produced chunk by chunk while it is sent so
+/// the blobs are never read into memory as a whole.
+pub fn tar_stream(entries: Vec<ArchiveEntry>) -> PlainChunks {
+    let mtime = Utc::now().timestamp() as u64;
+
+    let chunks = entries
+        .into_iter()
+        .flat_map(move |entry| tar_entry(entry, mtime))
+        // the end of an archive is marked by two empty blocks
+        .chain(once(Ok(vec![0; 2 * BLOCK_LEN as usize])));
+
+    Box::new(chunks)
+}
+
+fn tar_entry(entry: ArchiveEntry, mtime: u64) -> PlainChunks {
+    let mut header = Header::new_ustar();
+    if let Err(err) = header.set_path(&entry.path) {
+        return Box::new(once(Err(err)));
+    }
+    header.set_size(entry.size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_entry_type(EntryType::Regular);
+    header.set_cksum();
+
+    let content = open_source(entry.source);
+    let padding = (BLOCK_LEN - entry.size % BLOCK_LEN) % BLOCK_LEN;
+
+    Box::new(
+        once(Ok(header.as_bytes().to_vec()))
+            .chain(content)
+            .chain(once(Ok(vec![0; padding as usize]))),
+    )
+}
+
+fn open_source(source: ArchiveSource) -> PlainChunks {
+    let opened = match source {
+        ArchiveSource::Bytes(bytes) => return Box::new(once(Ok(bytes))),
+        ArchiveSource::File(path) => {
+            File::open(path).and_then(|file| open_blob(Box::new(file), None))
+        }
+        ArchiveSource::Chunks(open) => open(),
+    };
+
+    match opened {
+        Ok(chunks) => chunks,
+        Err(err) => Box::new(once(Err(err))),
+    }
+}
+
+/// A zip archive of `entries`, produced chunk by chunk while it is sent. The
+/// entries are stored uncompressed, images hardly shrink, and their CRC and
+/// sizes follow their content in a data descriptor, so nothing has to be
+/// known or buffered up front. There is no zip64, an archive stops with an
+/// error once it would pass 4 GiB.
+pub fn zip_stream(entries: Vec<ArchiveEntry>) -> PlainChunks {
+    let now = Utc::now().naive_utc();
+    let dos_time = ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16;
+    let dos_date = (((now.year() - 1980).max(0) as u32) << 9) | (now.month() << 5) | now.day();
+
+    Box::new(ZipStream {
+        entries: entries.into_iter(),
+        current: None,
+        central_directory: vec![],
+        count: 0,
+        offset: 0,
+        dos_time,
+        dos_date: dos_date as u16,
+        done: false,
+    })
+}
+
+struct ZipStream {
+    entries: IntoIter<ArchiveEntry>,
+    current: Option<ZipEntry>,
+    central_directory: Vec<u8>,
+    count: u16,
+    /// Bytes sent so far, where the next local header starts.
+    offset: u64,
+    dos_time: u16,
+    dos_date: u16,
+    done: bool,
+}
+
+struct ZipEntry {
+    name: Vec<u8>,
+    header_offset: u64,
+    chunks: PlainChunks,
+    crc: Hasher,
+    size: u64,
+}
+
+const ZIP_LOCAL_HEADER: u32 = 0x04034b50;
+const ZIP_DATA_DESCRIPTOR: u32 = 0x08074b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x02014b50;
+const ZIP_END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+/// Sizes in a data descriptor, UTF-8 names.
+const ZIP_FLAGS: u16 = (1 << 3) | (1 << 11);
+const ZIP_VERSION: u16 = 20;
+
+impl ZipStream {
+    fn start_entry(&mut self, entry: ArchiveEntry) -> io::Result<Vec<u8>> {
+        let name = entry.path.into_bytes();
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&ZIP_LOCAL_HEADER.to_le_bytes());
+        header.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+        header.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // stored
+        header.extend_from_slice(&self.dos_time.to_le_bytes());
+        header.extend_from_slice(&self.dos_date.to_le_bytes());
+        header.extend_from_slice(&[0; 12]); // crc and sizes follow the data
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&name);
+
+        self.current = Some(ZipEntry {
+            name,
+            header_offset: self.offset,
+            chunks: open_source(entry.source),
+            crc: Hasher::new(),
+            size: 0,
+        });
+        self.count = self
+            .count
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("too many entries for a zip archive"))?;
+        Ok(header)
+    }
+
+    fn finish_entry(&mut self, entry: ZipEntry) -> io::Result<Vec<u8>> {
+        let crc = entry.crc.finalize();
+        let size = u32::try_from(entry.size)
+            .map_err(|_| io::Error::other("entry too large for a zip archive"))?;
+        let header_offset = u32::try_from(entry.header_offset)
+            .map_err(|_| io::Error::other("archive too large for a zip archive"))?;
+
+        let mut descriptor = Vec::with_capacity(16);
+        descriptor.extend_from_slice(&ZIP_DATA_DESCRIPTOR.to_le_bytes());
+        descriptor.extend_from_slice(&crc.to_le_bytes());
+        descriptor.extend_from_slice(&size.to_le_bytes());
+        descriptor.extend_from_slice(&size.to_le_bytes());
+
+        let central = &mut self.central_directory;
+        central.extend_from_slice(&ZIP_CENTRAL_HEADER.to_le_bytes());
+        central.extend_from_slice(&ZIP_VERSION.to_le_bytes()); // made by
+        central.extend_from_slice(&ZIP_VERSION.to_le_bytes()); // needed
+        central.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        central.extend_from_slice(&0u16.to_le_bytes());
+        central.extend_from_slice(&self.dos_time.to_le_bytes());
+        central.extend_from_slice(&self.dos_date.to_le_bytes());
+        central.extend_from_slice(&crc.to_le_bytes());
+        central.extend_from_slice(&size.to_le_bytes());
+        central.extend_from_slice(&size.to_le_bytes());
+        central.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        // extra field, comment, disk, internal and external attributes
+        central.extend_from_slice(&[0; 12]);
+        central.extend_from_slice(&header_offset.to_le_bytes());
+        central.extend_from_slice(&entry.name);
+
+        Ok(descriptor)
+    }
+
+    fn end_of_central_directory(&mut self) -> io::Result<Vec<u8>> {
+        let offset = u32::try_from(self.offset)
+            .map_err(|_| io::Error::other("archive too large for a zip archive"))?;
+
+        let mut end = std::mem::take(&mut self.central_directory);
+        let len = end.len() as u32;
+        end.extend_from_slice(&ZIP_END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        end.extend_from_slice(&[0; 4]); // disk numbers
+        end.extend_from_slice(&self.count.to_le_bytes());
+        end.extend_from_slice(&self.count.to_le_bytes());
+        end.extend_from_slice(&len.to_le_bytes());
+        end.extend_from_slice(&offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        Ok(end)
+    }
+
+    fn next_chunk(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if let Some(entry) = self.current.as_mut() {
+            match entry.chunks.next() {
+                Some(Ok(chunk)) => {
+                    entry.crc.update(&chunk);
+                    entry.size += chunk.len() as u64;
+                    return Some(Ok(chunk));
+                }
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    let entry = self.current.take().unwrap();
+                    return Some(self.finish_entry(entry));
+                }
+            }
+        }
+
+        match self.entries.next() {
+            Some(entry) => Some(self.start_entry(entry)),
+            None => {
+                self.done = true;
+                Some(self.end_of_central_directory())
+            }
+        }
+    }
+}
+
+impl Iterator for ZipStream {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let chunk = self.next_chunk();
+        match &chunk {
+            Some(Ok(bytes)) => self.offset += bytes.len() as u64,
+            // a broken archive isn't continued
+            _ => self.done = true,
+        }
+        chunk
+    }
+}
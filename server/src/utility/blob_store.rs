@@ -0,0 +1,300 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+
+use crate::config::{BlobBackend, BlobStoreConfig};
+use crate::utility::s3::S3BlobStore;
+
+/// A blob being read, streamed from wherever the store keeps it.
+pub type BlobReader = Box<dyn Read + Send>;
+
+/// A blob being written. Nothing is visible under its key until `finish`;
+/// dropping the writer without it throws the written data away.
+pub trait BlobWriter: Write + Send {
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Where image blobs are kept. Keys are `<project_id>/<file name>`, the JSON
+/// files of a project stay in its folder of the data folder whatever the store.
+pub trait BlobStore: Send + Sync {
+    fn put(&self, key: &str) -> io::Result<Box<dyn BlobWriter + '_>>;
+    /// `ErrorKind::NotFound` when there is no such blob.
+    fn get(&self, key: &str) -> io::Result<BlobReader>;
+    /// The blob from byte `start` to its end.
+    fn get_range(&self, key: &str, start: u64) -> io::Result<BlobReader>;
+    /// Size of the blob, `None` when there is no such blob.
+    fn size(&self, key: &str) -> io::Result<Option<u64>>;
+    fn delete(&self, key: &str) -> io::Result<()>;
+    /// Replaces `to` with `from`, used to swap in a blob that was written and
+    /// checked under a temporary key.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    /// Keys of every blob starting with `prefix`, sorted.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+}
+
+/// Writes everything from `reader` as blob `key`. Returns the bytes written.
+pub fn put_reader(store: &dyn BlobStore, key: &str, mut reader: impl Read) -> io::Result<u64> {
+    let mut writer = store.put(key)?;
+    let written = io::copy(&mut reader, &mut writer)?;
+    writer.finish()?;
+    Ok(written)
+}
+
+static BLOB_STORE: OnceLock<Arc<dyn BlobStore>> = OnceLock::new();
+
+/// Sets the blob store from the config; until then blobs are kept in the data
+/// folder.
+pub fn init_blob_store(config: &BlobStoreConfig, data_path: &str) -> Result<(), String> {
+    let store: Arc<dyn BlobStore> = match config.backend {
+        BlobBackend::Fs => Arc::new(FsBlobStore::new(data_path)),
+        BlobBackend::S3 => Arc::new(S3BlobStore::new(config)?),
+    };
+    let _ = BLOB_STORE.set(store);
+    Ok(())
+}
+
+/// The configured blob store, or the data folder itself when none is.
+pub fn blob_store(data_path: &str) -> Arc<dyn BlobStore> {
+    match BLOB_STORE.get() {
+        Some(store) => store.clone(),
+        None => Arc::new(FsBlobStore::new(data_path)),
+    }
+}
+
+/// Keys are `/` separated paths below the store, so every part has to be a
+/// plain name.
+pub fn check_blob_key(key: &str) -> io::Result<()> {
+    match key
+        .split('/')
+        .any(|part| part.is_empty() || part == "." || part == "..")
+    {
+        true => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid blob key {}", key),
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Blobs as files below `root`, the data folder, next to the JSON files of
+/// their project. This is how blobs were always stored.
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(root: &str) -> Self {
+        FsBlobStore {
+            root: PathBuf::from(root),
+        }
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        check_blob_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+struct FsBlobWriter {
+    file: Option<File>,
+    temp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl Write for FsBlobWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+impl BlobWriter for FsBlobWriter {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let file = self.file.take().unwrap();
+        file.sync_all()?;
+        fs::rename(&self.temp_path, &self.path)
+    }
+}
+
+impl Drop for FsBlobWriter {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn put(&self, key: &str) -> io::Result<Box<dyn BlobWriter + '_>> {
+        let path = self.path(key)?;
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        fs::create_dir_all(path.parent().unwrap())?;
+        Ok(Box::new(FsBlobWriter {
+            file: Some(File::create(&temp_path)?),
+            temp_path,
+            path,
+        }))
+    }
+
+    fn get(&self, key: &str) -> io::Result<BlobReader> {
+        Ok(Box::new(File::open(self.path(key)?)?))
+    }
+
+    fn get_range(&self, key: &str, start: u64) -> io::Result<BlobReader> {
+        let mut file = File::open(self.path(key)?)?;
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Box::new(file))
+    }
+
+    fn size(&self, key: &str) -> io::Result<Option<u64>> {
+        match self.path(key)?.metadata() {
+            Ok(metadata) if metadata.is_file() => Ok(Some(metadata.len())),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.path(from)?, self.path(to)?)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        // keys only ever have one folder, the project
+        let (folder, name_prefix) = prefix.rsplit_once('/').unwrap_or(("", prefix));
+        let dir = match folder.is_empty() {
+            true => self.root.clone(),
+            false => self.path(folder)?,
+        };
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut keys: Vec<String> = fs::read_dir(&dir)?
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| name.starts_with(name_prefix))
+            .map(|name| match folder.is_empty() {
+                true => name,
+                false => format!("{}/{}", folder, name),
+            })
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn temp_store() -> (FsBlobStore, PathBuf) {
+        let root = std::env::temp_dir().join(format!("blob_store_{}", Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        (FsBlobStore::new(root.to_str().unwrap()), root)
+    }
+
+    fn read_all(mut reader: BlobReader) -> Vec<u8> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn rejects_keys_leaving_the_root() {
+        let (store, root) = temp_store();
+
+        for key in [
+            "../escape",
+            "project/../../escape",
+            "project//image",
+            "",
+            "/image",
+        ] {
+            assert_eq!(
+                store.put(key).err().map(|err| err.kind()),
+                Some(ErrorKind::InvalidInput),
+                "{}",
+                key
+            );
+            assert_eq!(
+                store.get(key).err().unwrap().kind(),
+                ErrorKind::InvalidInput
+            );
+            assert_eq!(store.size(key).unwrap_err().kind(), ErrorKind::InvalidInput);
+            assert_eq!(
+                store.delete(key).unwrap_err().kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+        assert_eq!(
+            store
+                .rename("project/image", "../image")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn put_get_rename_round_trip() {
+        let (store, root) = temp_store();
+
+        assert_eq!(
+            put_reader(&store, "project/a", &b"0123456789"[..]).unwrap(),
+            10
+        );
+        assert_eq!(store.size("project/a").unwrap(), Some(10));
+        assert_eq!(read_all(store.get("project/a").unwrap()), b"0123456789");
+        assert_eq!(
+            read_all(store.get_range("project/a", 4).unwrap()),
+            b"456789"
+        );
+
+        store.rename("project/a", "project/b").unwrap();
+        assert_eq!(store.size("project/a").unwrap(), None);
+        assert_eq!(read_all(store.get("project/b").unwrap()), b"0123456789");
+        assert_eq!(store.list("project/").unwrap(), vec!["project/b"]);
+
+        store.delete("project/b").unwrap();
+        store.delete("project/b").unwrap();
+        assert_eq!(
+            store.get("project/b").err().unwrap().kind(),
+            ErrorKind::NotFound
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unfinished_writes_leave_nothing() {
+        let (store, root) = temp_store();
+
+        let mut writer = store.put("project/a").unwrap();
+        writer.write_all(b"partial").unwrap();
+        drop(writer);
+
+        assert_eq!(store.size("project/a").unwrap(), None);
+        assert!(fs::read_dir(root.join("project")).unwrap().next().is_none());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,676 @@
+use actix_web::web;
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use futures_util::{stream, Stream};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, ErrorKind, Read, Write};
+use std::path::Path;
+
+use super::blob_store::{BlobReader, BlobStore};
+use super::encryption::{decrypt_bytes, DATA_KEY_LEN};
+
+// Binary envelope for encrypted image blobs:
+//
+//   magic "AISB" | version u8 | algorithm u8 | chunk size u32 LE | nonce prefix [7]
+//   segment*     = ciphertext (chunk size bytes, the last one may be shorter) | tag [16]
+//
+// Every chunk is sealed on its own with AES-256-GCM, so blobs can be decrypted
+// while they are streamed out. The chunk nonce is the prefix, a big endian chunk
+// counter and a final-chunk flag, and the header is the aad of every chunk, so
+// reordered, dropped, truncated or re-headed segments fail authentication.
+
+pub const BLOB_MAGIC: &[u8; 4] = b"AISB";
+pub const BLOB_VERSION: u8 = 1;
+pub const ALGORITHM_AES_256_GCM: u8 = 1;
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+const NONCE_PREFIX_LEN: usize = 7;
+const HEADER_LEN: usize = 4 + 1 + 1 + 4 + NONCE_PREFIX_LEN;
+const TAG_LEN: usize = 16;
+const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Chunks of a blob's plain content, in order. An `Err` means the blob could not
+/// be read or failed authentication and nothing after it must be trusted.
+pub type PlainChunks = Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>;
+
+/// The chunks as a response body. Every chunk is read on the blocking thread
+/// pool, the S3 store reads them over blocking HTTP.
+pub fn chunk_stream(chunks: PlainChunks) -> impl Stream<Item = io::Result<web::Bytes>> {
+    stream::unfold(Some(chunks), |chunks| async move {
+        let mut chunks = chunks?;
+        match web::block(move || (chunks.next(), chunks)).await {
+            Ok((Some(chunk), chunks)) => Some((chunk.map(web::Bytes::from), Some(chunks))),
+            Ok((None, _)) => None,
+            Err(err) => Some((Err(io::Error::other(err)), None)),
+        }
+    })
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_owned())
+}
+
+fn chunk_nonce(prefix: &[u8], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// Reads until `buf` is full or the reader hits end of file.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+fn check_key(key: &[u8]) -> io::Result<()> {
+    match key.len() {
+        DATA_KEY_LEN => Ok(()),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "blob encryption needs a 32 byte key",
+        )),
+    }
+}
+
+/// Encrypts everything from `reader` into `writer` in the envelope format.
+/// Returns the number of plain bytes encrypted.
+pub fn encrypt_stream<R: Read, W: Write>(reader: R, mut writer: W, key: &[u8]) -> io::Result<u64> {
+    check_key(key)?;
+
+    let chunk_size = DEFAULT_CHUNK_SIZE;
+    let prefix: [u8; NONCE_PREFIX_LEN] = rand::random();
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(BLOB_MAGIC);
+    header.push(BLOB_VERSION);
+    header.push(ALGORITHM_AES_256_GCM);
+    header.extend_from_slice(&chunk_size.to_le_bytes());
+    header.extend_from_slice(&prefix);
+    writer.write_all(&header)?;
+
+    let mut reader = BufReader::new(reader);
+    let mut plain = vec![0u8; chunk_size as usize];
+    let mut sealed = vec![0u8; chunk_size as usize];
+    let mut tag = [0u8; TAG_LEN];
+    let mut counter: u32 = 0;
+    let mut total: u64 = 0;
+
+    loop {
+        let n = read_full(&mut reader, &mut plain)?;
+        let last = reader.fill_buf()?.is_empty();
+
+        let nonce = chunk_nonce(&prefix, counter, last);
+        let mut cipher = AesGcm::new(KeySize::KeySize256, key, &nonce, &header);
+        cipher.encrypt(&plain[..n], &mut sealed[..n], &mut tag);
+
+        writer.write_all(&sealed[..n])?;
+        writer.write_all(&tag)?;
+        total += n as u64;
+
+        if last {
+            break;
+        }
+        counter = counter
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("blob has too many chunks"))?;
+    }
+
+    writer.flush()?;
+    Ok(total)
+}
+
+/// Decrypts an envelope one chunk at a time.
+pub struct BlobDecryptor<R: Read> {
+    reader: BufReader<R>,
+    key: Vec<u8>,
+    header: Vec<u8>,
+    prefix: Vec<u8>,
+    chunk_size: usize,
+    counter: u32,
+    done: bool,
+}
+
+impl<R: Read> BlobDecryptor<R> {
+    /// Reads and checks the header. Fails for anything that isn't a supported
+    /// envelope.
+    pub fn new(reader: R, key: &[u8]) -> io::Result<Self> {
+        check_key(key)?;
+
+        let mut reader = BufReader::new(reader);
+        let mut header = vec![0u8; HEADER_LEN];
+        if read_full(&mut reader, &mut header)? != HEADER_LEN || &header[..4] != BLOB_MAGIC {
+            return Err(invalid_data("not an encrypted blob"));
+        }
+
+        Self::resume(header, reader, key, 0)
+    }
+
+    /// Decrypts from chunk `counter` on, with `reader` at the start of that
+    /// chunk's segment and `header` read from the start of the blob.
+    fn resume(header: Vec<u8>, reader: BufReader<R>, key: &[u8], counter: u32) -> io::Result<Self> {
+        check_key(key)?;
+        if header.len() != HEADER_LEN || &header[..4] != BLOB_MAGIC {
+            return Err(invalid_data("not an encrypted blob"));
+        }
+        if header[4] != BLOB_VERSION {
+            return Err(invalid_data("unsupported blob version"));
+        }
+        if header[5] != ALGORITHM_AES_256_GCM {
+            return Err(invalid_data("unsupported blob algorithm"));
+        }
+
+        let chunk_size = u32::from_le_bytes(header[6..10].try_into().unwrap());
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(invalid_data("invalid blob chunk size"));
+        }
+
+        Ok(BlobDecryptor {
+            reader,
+            key: key.to_vec(),
+            prefix: header[10..HEADER_LEN].to_vec(),
+            header,
+            chunk_size: chunk_size as usize,
+            counter,
+            done: false,
+        })
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let mut segment = vec![0u8; self.chunk_size + TAG_LEN];
+        let n = read_full(&mut self.reader, &mut segment)?;
+        if n < TAG_LEN {
+            return Err(invalid_data("encrypted blob is truncated"));
+        }
+        let last = self.reader.fill_buf()?.is_empty();
+
+        let (sealed, tag) = segment[..n].split_at(n - TAG_LEN);
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        let mut decipher = AesGcm::new(KeySize::KeySize256, &self.key, &nonce, &self.header);
+
+        let mut plain = vec![0u8; sealed.len()];
+        if !decipher.decrypt(sealed, &mut plain, tag) {
+            return Err(invalid_data("encrypted blob failed authentication"));
+        }
+
+        self.done = last;
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("blob has too many chunks"))?;
+        Ok(plain)
+    }
+}
+
+impl<R: Read> Iterator for BlobDecryptor<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let chunk = self.next_chunk();
+        if chunk.is_err() {
+            self.done = true;
+        }
+        Some(chunk)
+    }
+}
+
+/// Plain reading of an unencrypted blob, in chunks.
+struct PlainReader<R: Read> {
+    reader: R,
+    done: bool,
+}
+
+impl<R: Read> Iterator for PlainReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut chunk = vec![0u8; DEFAULT_CHUNK_SIZE as usize];
+        match read_full(&mut self.reader, &mut chunk) {
+            Ok(0) => {
+                self.done = true;
+                None
+            }
+            Ok(n) => {
+                chunk.truncate(n);
+                Some(Ok(chunk))
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Opens a blob for reading. Without a key the blob is returned as is, with a
+/// key it is decrypted from either the envelope format or the legacy
+/// [hexNonce]/[hexCipherText]/[hexMac] format of encrypt_bytes().
+pub fn open_blob(mut file: BlobReader, key: Option<&[u8]>) -> io::Result<PlainChunks> {
+    let key = match key {
+        Some(key) => key,
+        None => {
+            return Ok(Box::new(PlainReader {
+                reader: file,
+                done: false,
+            }))
+        }
+    };
+
+    let mut magic = [0u8; 4];
+    let n = read_full(&mut file, &mut magic)?;
+
+    if n == magic.len() && &magic == BLOB_MAGIC {
+        let reader = Cursor::new(magic).chain(file);
+        return Ok(Box::new(BlobDecryptor::new(reader, key)?));
+    }
+
+    // legacy hex blobs were only ever written whole, so they are read whole
+    let mut buffer = magic[..n].to_vec();
+    file.read_to_end(&mut buffer)?;
+    let iv_data_mac =
+        std::str::from_utf8(&buffer).map_err(|_| invalid_data("unknown blob format"))?;
+    let plain = decrypt_bytes(iv_data_mac, key).map_err(|err| invalid_data(&err.to_string()))?;
+
+    Ok(Box::new(std::iter::once(Ok(plain))))
+}
+
+/// `len` bytes of the plain content of blob `blob_key` from byte `start` on,
+/// reading only what is needed: from `start` for a plain blob, from the chunk
+/// holding `start` for an envelope. `None` for a legacy encrypted blob, it can
+/// only be read whole.
+pub fn open_blob_range(
+    store: &dyn BlobStore,
+    blob_key: &str,
+    key: Option<&[u8]>,
+    start: u64,
+    len: u64,
+) -> io::Result<Option<PlainChunks>> {
+    let key = match key {
+        Some(key) => key,
+        None => {
+            let reader = store.get_range(blob_key, start)?;
+            let chunks = PlainReader {
+                reader,
+                done: false,
+            };
+            return Ok(Some(slice_chunks(Box::new(chunks), 0, len)));
+        }
+    };
+
+    let mut header = vec![0u8; HEADER_LEN];
+    let n = read_full(&mut store.get(blob_key)?, &mut header)?;
+    if n < HEADER_LEN || &header[..4] != BLOB_MAGIC {
+        return Ok(None);
+    }
+    let chunk_size = u32::from_le_bytes(header[6..10].try_into().unwrap()) as u64;
+    if chunk_size == 0 {
+        return Err(invalid_data("invalid blob chunk size"));
+    }
+
+    let counter = start / chunk_size;
+    let offset = HEADER_LEN as u64 + counter * (chunk_size + TAG_LEN as u64);
+    let counter = u32::try_from(counter).map_err(|_| invalid_data("range out of the blob"))?;
+    let reader = BufReader::new(store.get_range(blob_key, offset)?);
+    let chunks = BlobDecryptor::resume(header, reader, key, counter)?;
+
+    let skip = start - counter as u64 * chunk_size;
+    Ok(Some(slice_chunks(Box::new(chunks), skip, len)))
+}
+
+/// Drops the first `skip` bytes of `chunks` and ends after `len` more.
+fn slice_chunks(chunks: PlainChunks, skip: u64, len: u64) -> PlainChunks {
+    let sliced = chunks
+        .scan((skip, len), |(skip, left), chunk| {
+            if *left == 0 {
+                return None;
+            }
+            let mut chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => return Some(Err(err)),
+            };
+
+            let skipped = (*skip).min(chunk.len() as u64);
+            chunk.drain(..skipped as usize);
+            *skip -= skipped;
+            chunk.truncate((*left).min(chunk.len() as u64) as usize);
+            *left -= chunk.len() as u64;
+            Some(Ok(chunk))
+        })
+        .filter(|chunk| !matches!(chunk, Ok(chunk) if chunk.is_empty()));
+
+    Box::new(sliced)
+}
+
+/// Reads a whole blob into memory, see open_blob().
+pub fn read_blob(reader: BlobReader, key: Option<&[u8]>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for chunk in open_blob(reader, key)? {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(data)
+}
+
+/// Lets the plain chunks of a blob be read as one continuous stream.
+pub struct ChunksReader {
+    chunks: PlainChunks,
+    current: Cursor<Vec<u8>>,
+}
+
+impl ChunksReader {
+    pub fn new(chunks: PlainChunks) -> Self {
+        ChunksReader {
+            chunks,
+            current: Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl Read for ChunksReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.chunks.next() {
+                Some(chunk) => self.current = Cursor::new(chunk?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// SHA-256 of the plain content of a blob, see open_blob().
+pub fn hash_blob(reader: BlobReader, key: Option<&[u8]>) -> io::Result<[u8; 32]> {
+    let mut sha = Sha256::new();
+    for chunk in open_blob(reader, key)? {
+        sha.update(chunk?);
+    }
+    Ok(sha.finalize().into())
+}
+
+/// SHA-256 of a file as it is, e.g. a blob copied out of the store.
+pub fn hash_file(path: &Path) -> io::Result<[u8; 32]> {
+    hash_blob(Box::new(File::open(path)?), None)
+}
+
+/// Whether the blob starts like an envelope blob.
+pub fn is_envelope(mut reader: BlobReader) -> io::Result<bool> {
+    let mut magic = [0u8; 4];
+    let n = read_full(&mut reader, &mut magic)?;
+    Ok(n == magic.len() && &magic == BLOB_MAGIC)
+}
+
+/// The size an envelope blob holding `plain_len` bytes has, going by the chunk
+/// size in its header. `None` when the blob isn't an envelope blob.
+pub fn expected_blob_len(mut reader: BlobReader, plain_len: u64) -> io::Result<Option<u64>> {
+    let mut header = [0u8; HEADER_LEN];
+    let n = read_full(&mut reader, &mut header)?;
+    if n < HEADER_LEN || &header[..4] != BLOB_MAGIC {
+        return Ok(None);
+    }
+
+    let chunk_size = u32::from_le_bytes([header[6], header[7], header[8], header[9]]) as u64;
+    if chunk_size == 0 {
+        return Ok(None);
+    }
+    let chunks = plain_len.div_ceil(chunk_size).max(1);
+
+    Ok(Some(
+        HEADER_LEN as u64 + plain_len + chunks * TAG_LEN as u64,
+    ))
+}
+
+/// Re-encrypts blob `blob_key` from `old_key` to `new_key`. The new blob is
+/// written next to the old one and decrypted again; it only replaces the old
+/// blob when its content hashes the same.
+pub fn reencrypt_blob(
+    store: &dyn BlobStore,
+    blob_key: &str,
+    old_key: &[u8],
+    new_key: &[u8],
+) -> io::Result<()> {
+    let temp_key = format!("{}.rotating", blob_key);
+
+    let expected = hash_blob(store.get(blob_key)?, Some(old_key))?;
+
+    let reader = ChunksReader::new(open_blob(store.get(blob_key)?, Some(old_key))?);
+    let mut writer = store.put(&temp_key)?;
+    encrypt_stream(reader, io::BufWriter::new(&mut writer), new_key)?;
+    writer.finish()?;
+
+    let checked = store
+        .get(&temp_key)
+        .and_then(|reader| hash_blob(reader, Some(new_key)));
+    match checked {
+        Ok(actual) if actual == expected => store.rename(&temp_key, blob_key),
+        Ok(_) => {
+            let _ = store.delete(&temp_key);
+            Err(invalid_data("re-encrypted blob doesn't match the original"))
+        }
+        Err(err) => {
+            let _ = store.delete(&temp_key);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::blob_store::{put_reader, FsBlobStore};
+    use std::fs;
+    use uuid::Uuid;
+
+    const CHUNK: usize = DEFAULT_CHUNK_SIZE as usize;
+    const SEGMENT: usize = CHUNK + TAG_LEN;
+
+    fn plain(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn encrypt(data: &[u8], key: &[u8]) -> Vec<u8> {
+        let mut blob = Vec::new();
+        assert_eq!(
+            encrypt_stream(data, &mut blob, key).unwrap(),
+            data.len() as u64
+        );
+        blob
+    }
+
+    fn decrypt(blob: Vec<u8>, key: &[u8]) -> io::Result<Vec<u8>> {
+        read_blob(Box::new(Cursor::new(blob)), Some(key))
+    }
+
+    /// The blob with its segments `segments` in that order.
+    fn reorder(blob: &[u8], segments: &[usize]) -> Vec<u8> {
+        let mut reordered = blob[..HEADER_LEN].to_vec();
+        let body: Vec<&[u8]> = blob[HEADER_LEN..].chunks(SEGMENT).collect();
+        for segment in segments {
+            reordered.extend_from_slice(body[*segment]);
+        }
+        reordered
+    }
+
+    #[test]
+    fn round_trips_every_length() {
+        let key = [7u8; DATA_KEY_LEN];
+        for len in [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, 3 * CHUNK + 5] {
+            let data = plain(len);
+            let blob = encrypt(&data, &key);
+
+            let segments = len.div_ceil(CHUNK).max(1);
+            assert_eq!(blob.len(), HEADER_LEN + len + segments * TAG_LEN, "{}", len);
+            assert_eq!(
+                expected_blob_len(Box::new(Cursor::new(blob.clone())), len as u64).unwrap(),
+                Some(blob.len() as u64)
+            );
+            assert!(decrypt(blob, &key).unwrap() == data, "{}", len);
+        }
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let blob = encrypt(&plain(100), &[1; DATA_KEY_LEN]);
+
+        assert!(decrypt(blob.clone(), &[2; DATA_KEY_LEN]).is_err());
+        assert!(decrypt(blob, &[1; 16]).is_err());
+    }
+
+    #[test]
+    fn truncated_blobs_fail() {
+        let key = [7u8; DATA_KEY_LEN];
+        let blob = encrypt(&plain(2 * CHUNK + 10), &key);
+
+        for len in [
+            0,
+            HEADER_LEN - 1,
+            HEADER_LEN,
+            HEADER_LEN + 10,
+            blob.len() - 1,
+            blob.len() - TAG_LEN,
+        ] {
+            assert!(decrypt(blob[..len].to_vec(), &key).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn dropping_the_last_chunk_fails() {
+        let key = [7u8; DATA_KEY_LEN];
+        let blob = encrypt(&plain(3 * CHUNK), &key);
+
+        // whole segments, the new last one was sealed as not being the last
+        for segments in [1, 2] {
+            let truncated = blob[..HEADER_LEN + segments * SEGMENT].to_vec();
+            let chunks: Vec<io::Result<Vec<u8>>> =
+                open_blob(Box::new(Cursor::new(truncated)), Some(&key))
+                    .unwrap()
+                    .collect();
+
+            let (last, read) = chunks.split_last().unwrap();
+            assert!(last.is_err(), "{}", segments);
+            assert_eq!(read.len(), segments - 1);
+            assert!(read.iter().all(|chunk| chunk.is_ok()));
+        }
+    }
+
+    #[test]
+    fn reordered_or_repeated_chunks_fail() {
+        let key = [7u8; DATA_KEY_LEN];
+        let blob = encrypt(&plain(3 * CHUNK + 5), &key);
+        assert!(decrypt(reorder(&blob, &[0, 1, 2, 3]), &key).is_ok());
+
+        for order in [[1, 0, 2, 3], [0, 2, 1, 3], [0, 0, 2, 3], [0, 1, 3, 2]] {
+            assert!(
+                decrypt(reorder(&blob, &order), &key).is_err(),
+                "{:?}",
+                order
+            );
+        }
+    }
+
+    #[test]
+    fn tampered_or_extended_blobs_fail() {
+        let key = [7u8; DATA_KEY_LEN];
+        let blob = encrypt(&plain(CHUNK + 5), &key);
+
+        // every header field is the aad of every chunk
+        for index in [
+            4,
+            5,
+            6,
+            12,
+            HEADER_LEN + 3,
+            HEADER_LEN + CHUNK + 1,
+            blob.len() - 1,
+        ] {
+            let mut tampered = blob.clone();
+            tampered[index] ^= 1;
+            assert!(decrypt(tampered, &key).is_err(), "{}", index);
+        }
+
+        let mut extended = blob.clone();
+        extended.extend_from_slice(&[0; TAG_LEN + 1]);
+        assert!(decrypt(extended, &key).is_err());
+
+        // a chunk of another blob with the same key
+        let other = encrypt(&plain(CHUNK + 5), &key);
+        let mut spliced = blob[..HEADER_LEN + SEGMENT].to_vec();
+        spliced.extend_from_slice(&other[HEADER_LEN + SEGMENT..]);
+        assert!(decrypt(spliced, &key).is_err());
+    }
+
+    #[test]
+    fn ranges_resume_mid_blob() {
+        let root = std::env::temp_dir().join(format!("encrypted_blob_{}", Uuid::new_v4()));
+        let store = FsBlobStore::new(root.to_str().unwrap());
+        let key = [7u8; DATA_KEY_LEN];
+        let data = plain(3 * CHUNK + 5);
+        put_reader(&store, "project/blob", encrypt(&data, &key).as_slice()).unwrap();
+        put_reader(&store, "project/plain", data.as_slice()).unwrap();
+
+        let ranges = [
+            (0, 10),
+            (5, CHUNK),
+            (CHUNK - 1, 2),
+            (CHUNK, CHUNK),
+            (2 * CHUNK + 7, CHUNK + 100),
+            (3 * CHUNK, 5),
+            (3 * CHUNK + 4, 1),
+        ];
+        for (start, len) in ranges {
+            let end = data.len().min(start + len);
+            for (blob_key, key) in [("project/blob", Some(&key[..])), ("project/plain", None)] {
+                let chunks = open_blob_range(&store, blob_key, key, start as u64, len as u64)
+                    .unwrap()
+                    .unwrap();
+                let mut read = Vec::new();
+                ChunksReader::new(chunks).read_to_end(&mut read).unwrap();
+                assert!(read == data[start..end], "{} {} {}", blob_key, start, len);
+            }
+        }
+
+        // a resumed read still authenticates the chunks it reads
+        let mut blob = Vec::new();
+        store
+            .get("project/blob")
+            .unwrap()
+            .read_to_end(&mut blob)
+            .unwrap();
+        blob[HEADER_LEN + 2 * SEGMENT + 1] ^= 1;
+        put_reader(&store, "project/blob", blob.as_slice()).unwrap();
+        let chunks = open_blob_range(&store, "project/blob", Some(&key), 2 * CHUNK as u64, 10)
+            .unwrap()
+            .unwrap();
+        assert!(ChunksReader::new(chunks)
+            .read_to_end(&mut Vec::new())
+            .is_err());
+        let chunks = open_blob_range(&store, "project/blob", Some(&key), 0, 10)
+            .unwrap()
+            .unwrap();
+        assert!(ChunksReader::new(chunks)
+            .read_to_end(&mut Vec::new())
+            .is_ok());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,28 +1,192 @@
+use argon2::{Algorithm, Argon2, Params, Version};
 use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
 use crypto::aes_gcm::AesGcm;
+use crypto::curve25519::{curve25519, curve25519_base};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::error::Error;
+use std::fmt;
+use std::io;
 use std::io::ErrorKind;
-use std::iter::repeat;
-use std::{io, str};
+use utoipa::ToSchema;
+use uuid::Uuid;
 
 // https://www.boringadv.com/2022/12/05/simple-encryption-in-rust
 // code is taken from above page
 
-/// gets a valid key. This must be exactly 16 bytes. if less than 16 bytes, it will be padded with 0.
-/// If more than 16 bytes, it will be truncated
-fn get_valid_key(key: &str) -> Vec<u8> {
-    let mut bytes = key.as_bytes().to_vec();
-    if bytes.len() < 16 {
-        for _ in 0..(16 - bytes.len()) {
-            bytes.push(0x00);
+/// Length in bytes of a project data-encryption key (AES-256).
+pub const DATA_KEY_LEN: usize = 32;
+
+/// Random per-project key used to encrypt image blobs. It is only ever
+/// persisted wrapped by a key derived from the project password, so the
+/// plain key exists in memory after a successful login only.
+#[derive(Clone)]
+pub struct DataKey {
+    pub key_id: Uuid,
+    pub key: [u8; DATA_KEY_LEN],
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DataKey")
+            .field("key_id", &self.key_id)
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+impl DataKey {
+    pub fn generate() -> Self {
+        DataKey {
+            key_id: Uuid::new_v4(),
+            key: rand::random(),
+        }
+    }
+}
+
+/// The unlocked keys of a project. New blobs are written with `current`;
+/// `previous` is only set while a key rotation is moving blobs off it.
+#[derive(Clone, Debug)]
+pub struct ProjectKeys {
+    pub current: DataKey,
+    pub previous: Option<DataKey>,
+}
+
+impl ProjectKeys {
+    pub fn find(&self, key_id: &Uuid) -> Option<&DataKey> {
+        std::iter::once(&self.current)
+            .chain(self.previous.as_ref())
+            .find(|key| key.key_id == *key_id)
+    }
+}
+
+/// Argon2id cost parameters used to derive the key-encryption key. They are
+/// stored next to the wrapped key so older projects keep unwrapping after the
+/// defaults change.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct KdfParams {
+    pub salt: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    pub fn generate() -> Self {
+        KdfParams {
+            salt: hex::encode(get_iv(16)),
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
         }
-    } else if bytes.len() > 16 {
-        bytes = bytes[0..16].to_vec();
     }
+}
+
+/// Derives the key-encryption key from the project password with Argon2id.
+pub fn derive_key_encryption_key(
+    password: &str,
+    kdf: &KdfParams,
+) -> Result<[u8; DATA_KEY_LEN], Box<dyn Error>> {
+    let salt = hex::decode(&kdf.salt)?;
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(DATA_KEY_LEN))?;
+    let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut kek = [0u8; DATA_KEY_LEN];
+    argon.hash_password_into(password.as_bytes(), &salt, &mut kek)?;
+
+    Ok(kek)
+}
+
+/// Encrypts the data key with the key-encryption key, using the same
+/// [hexNonce]/[hexCipherText]/[hexMac] layout as encrypt_bytes().
+pub fn wrap_data_key(data_key: &DataKey, kek: &[u8]) -> String {
+    String::from_utf8(encrypt_bytes(data_key.key.to_vec(), kek)).unwrap()
+}
+
+/// Reverses wrap_data_key(). Fails when the key-encryption key is wrong.
+pub fn unwrap_data_key(
+    key_id: Uuid,
+    wrapped_key: &str,
+    kek: &[u8],
+) -> Result<DataKey, Box<dyn Error>> {
+    let key = decrypt_bytes(wrapped_key, kek)?;
+    let key: [u8; DATA_KEY_LEN] = key
+        .try_into()
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "wrapped key has wrong length"))?;
+
+    Ok(DataKey { key_id, key })
+}
+
+/// A data key sealed to a user's X25519 public key, so a project owner can
+/// share it without knowing the user's password.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SealedKey {
+    pub key_id: Uuid,
+    pub ephemeral_public: String,
+    pub sealed_key: String,
+}
+
+/// Public half of an X25519 key pair whose secret is stored as a DataKey.
+pub fn get_public_key(secret: &DataKey) -> [u8; DATA_KEY_LEN] {
+    curve25519_base(&secret.key)
+}
+
+fn get_sealing_key(shared: &[u8], ephemeral_public: &[u8], recipient_public: &[u8]) -> Vec<u8> {
+    let mut sha = Sha256::new();
+    sha.update(shared);
+    sha.update(ephemeral_public);
+    sha.update(recipient_public);
+    sha.finalize().to_vec()
+}
+
+/// Seals `data_key` with an ephemeral X25519 key agreement against
+/// `recipient_public`; only the matching secret can open it.
+pub fn seal_data_key(data_key: &DataKey, recipient_public: &[u8]) -> SealedKey {
+    let ephemeral_secret: [u8; DATA_KEY_LEN] = rand::random();
+    let ephemeral_public = curve25519_base(&ephemeral_secret);
+    let shared = curve25519(&ephemeral_secret, recipient_public);
+    let sealing_key = get_sealing_key(&shared, &ephemeral_public, recipient_public);
+
+    SealedKey {
+        key_id: data_key.key_id,
+        ephemeral_public: hex::encode(ephemeral_public),
+        sealed_key: String::from_utf8(encrypt_bytes(data_key.key.to_vec(), &sealing_key)).unwrap(),
+    }
+}
+
+/// Reverses seal_data_key() with the recipient's secret.
+pub fn open_sealed_key(sealed: &SealedKey, secret: &DataKey) -> Result<DataKey, Box<dyn Error>> {
+    let ephemeral_public = hex::decode(&sealed.ephemeral_public)?;
+    let recipient_public = get_public_key(secret);
+    let shared = curve25519(&secret.key, &ephemeral_public);
+    let sealing_key = get_sealing_key(&shared, &ephemeral_public, &recipient_public);
+
+    unwrap_data_key(sealed.key_id, &sealed.sealed_key, &sealing_key)
+}
+
+/// gets the key used by projects created before per-project data keys existed.
+/// The password salt was used directly, padded with 0 or truncated to 16 bytes.
+/// Only kept so images can be migrated off it.
+pub fn get_legacy_key(key: &str) -> Vec<u8> {
+    let mut bytes = key.as_bytes().to_vec();
+    bytes.resize(16, 0x00);
 
     bytes
 }
 
+fn get_key_size(key: &[u8]) -> Result<KeySize, Box<dyn Error>> {
+    match key.len() {
+        16 => Ok(KeySize::KeySize128),
+        24 => Ok(KeySize::KeySize192),
+        32 => Ok(KeySize::KeySize256),
+        _ => Err(Box::new(io::Error::new(
+            ErrorKind::InvalidInput,
+            "invalid AES key length",
+        ))),
+    }
+}
+
 /// Creates an initial vector (iv). This is also called a nonce
 fn get_iv(size: usize) -> Vec<u8> {
     let mut iv = vec![];
@@ -34,11 +198,13 @@ fn get_iv(size: usize) -> Vec<u8> {
     iv
 }
 
+type IvDataMac = (Vec<u8>, Vec<u8>, Vec<u8>);
+
 /// orig must be a string of the form [hexNonce]/[hexCipherText]/[hexMac]. This
 /// is the data returned from encrypt(). This function splits the data, removes
 /// the hex encoding, and returns each as a list of bytes.
-fn split_iv_data_mac(orig: &str) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), Box<dyn Error>> {
-    let split: Vec<&str> = orig.split('/').into_iter().collect();
+fn split_iv_data_mac(orig: &str) -> Result<IvDataMac, Box<dyn Error>> {
+    let split: Vec<&str> = orig.split('/').collect();
 
     if split.len() != 3 {
         return Err(Box::new(io::Error::from(ErrorKind::Other)));
@@ -64,50 +230,47 @@ fn split_iv_data_mac(orig: &str) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), Box<dyn
     Ok((iv, data, mac))
 }
 
-///Decryption using AES-GCM 128
+///Decryption using AES-GCM, 128 or 256 bit depending on the key length.
 ///iv_data_mac is a string that contains the iv/nonce, data, and mac values. All these values
 /// must be hex encoded, and separated by "/" i.e. [hex(iv)/hex(data)/hex(mac)]. This function decodes
-/// the values. key (or password) is the raw (not hex encoded) password
-pub fn decrypt_bytes(iv_data_mac: &str, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+/// the values. key is the raw (not hex encoded) key
+pub fn decrypt_bytes(iv_data_mac: &str, key: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
     let (iv, data, mac) = split_iv_data_mac(iv_data_mac)?;
-    let key = get_valid_key(key);
-
-    let key_size = crypto::aes::KeySize::KeySize128;
+    let key_size = get_key_size(key)?;
 
     // I don't use the aad for verification. aad isn't encrypted anyway, so it's just specified
     // as &[].
-    let mut decipher = AesGcm::new(key_size, &key, &iv, &[]);
+    let mut decipher = AesGcm::new(key_size, key, &iv, &[]);
 
     // create a list where the decoded data will be saved. dst is transformed in place. It must be exactly the same
     // size as the encrypted data
-    let mut dst: Vec<u8> = repeat(0).take(data.len()).collect();
-    let result = decipher.decrypt(&data, &mut dst, &mac);
+    let mut dst: Vec<u8> = vec![0; data.len()];
 
-    if result {
-        println!("Successful decryption");
+    if !decipher.decrypt(&data, &mut dst, &mac) {
+        return Err(Box::new(io::Error::new(
+            ErrorKind::InvalidData,
+            "authentication tag mismatch",
+        )));
     }
-    // println!("\nDecrypted {}", str::from_utf8(&dst).unwrap());
 
     Ok(dst)
 }
 
-pub fn encrypt_bytes(data: Vec<u8>, password: &str) -> Vec<u8> {
-    let key_size = crypto::aes::KeySize::KeySize128;
+pub fn encrypt_bytes(data: Vec<u8>, key: &[u8]) -> Vec<u8> {
+    let key_size = get_key_size(key).expect("encryption key must be 16, 24 or 32 bytes");
 
-    //pad or truncate the key if necessary
-    let valid_key = get_valid_key(password);
     let iv = get_iv(12); //initial vector (iv), also called a nonce
-    let mut cipher = AesGcm::new(key_size, &valid_key, &iv, &[]);
+    let mut cipher = AesGcm::new(key_size, key, &iv, &[]);
 
     //create a vec of data.len 0's. This is where the encrypted data will be saved.
     //the encryption is performed in-place, so this vector of 0's will be converted
     //to the encrypted data
-    let mut encrypted: Vec<u8> = repeat(0).take(data.len()).collect();
+    let mut encrypted: Vec<u8> = vec![0; data.len()];
 
     //create a vec of 16 0's. This is for the mac. This library calls it a "tag", but it's really
     // the mac address. This vector will be modified in place, just like the "encrypted" vector
     // above
-    let mut mac: Vec<u8> = repeat(0).take(16).collect();
+    let mut mac: Vec<u8> = vec![0; 16];
 
     //encrypt data, put it into "encrypted"
     cipher.encrypt(&data, &mut encrypted, &mut mac[..]);
@@ -120,3 +283,90 @@ pub fn encrypt_bytes(data: Vec<u8>, password: &str) -> Vec<u8> {
 
     output.into_bytes()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters, the cost doesn't matter for what is tested.
+    fn test_kdf() -> KdfParams {
+        KdfParams {
+            m_cost: 64,
+            t_cost: 1,
+            p_cost: 1,
+            ..KdfParams::generate()
+        }
+    }
+
+    /// Flips one bit of the hex part `part` of a wrapped key.
+    fn tamper(wrapped: &str, part: usize) -> String {
+        let mut parts: Vec<Vec<u8>> = wrapped
+            .split('/')
+            .map(|part| hex::decode(part).unwrap())
+            .collect();
+        parts[part][0] ^= 1;
+        parts
+            .iter()
+            .map(hex::encode)
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+
+    #[test]
+    fn wrapped_key_round_trip() {
+        let kdf = test_kdf();
+        let data_key = DataKey::generate();
+        let kek = derive_key_encryption_key("password", &kdf).unwrap();
+
+        let wrapped = wrap_data_key(&data_key, &kek);
+        assert!(!wrapped.contains(&hex::encode(data_key.key)));
+
+        // the stored parameters derive the same key again
+        let kek = derive_key_encryption_key("password", &kdf).unwrap();
+        let unwrapped = unwrap_data_key(data_key.key_id, &wrapped, &kek).unwrap();
+        assert_eq!(unwrapped.key_id, data_key.key_id);
+        assert_eq!(unwrapped.key, data_key.key);
+    }
+
+    #[test]
+    fn wrong_password_or_salt_fails() {
+        let kdf = test_kdf();
+        let data_key = DataKey::generate();
+        let wrapped = wrap_data_key(
+            &data_key,
+            &derive_key_encryption_key("password", &kdf).unwrap(),
+        );
+
+        let wrong_password = derive_key_encryption_key("Password", &kdf).unwrap();
+        assert!(unwrap_data_key(data_key.key_id, &wrapped, &wrong_password).is_err());
+
+        let other_salt = derive_key_encryption_key("password", &test_kdf()).unwrap();
+        assert!(unwrap_data_key(data_key.key_id, &wrapped, &other_salt).is_err());
+    }
+
+    #[test]
+    fn tampered_wrapped_key_fails() {
+        let data_key = DataKey::generate();
+        let kek = derive_key_encryption_key("password", &test_kdf()).unwrap();
+        let wrapped = wrap_data_key(&data_key, &kek);
+
+        for part in 0..3 {
+            let tampered = tamper(&wrapped, part);
+            assert!(
+                unwrap_data_key(data_key.key_id, &tampered, &kek).is_err(),
+                "{}",
+                part
+            );
+        }
+        assert!(unwrap_data_key(data_key.key_id, &wrapped[1..], &kek).is_err());
+        assert!(unwrap_data_key(data_key.key_id, "not/a/key", &kek).is_err());
+    }
+
+    #[test]
+    fn wrapped_key_of_wrong_length_fails() {
+        let kek = derive_key_encryption_key("password", &test_kdf()).unwrap();
+        let short = String::from_utf8(encrypt_bytes(vec![7; 16], &kek)).unwrap();
+
+        assert!(unwrap_data_key(Uuid::new_v4(), &short, &kek).is_err());
+    }
+}
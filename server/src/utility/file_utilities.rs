@@ -3,6 +3,7 @@ use std::ffi::OsStr;
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use uuid::Uuid;
 
 pub fn _get_file_type(file_path: &str) -> String {
     let path = PathBuf::from(file_path);
@@ -14,6 +15,20 @@ pub fn _get_file_type_from_mime(mime: &str) -> String {
     (mime.split('/').collect::<Vec<&str>>()[1]).to_owned()
 }
 
+/// How much of a file detect_content_type() needs to look at.
+pub const CONTENT_HEAD_LEN: u64 = 512;
+
+/// MIME type of a file from its first bytes, or from its extension for
+/// formats the image crate doesn't know.
+pub fn detect_content_type(head: &[u8], extension: &str) -> String {
+    match image::guess_format(head) {
+        Ok(format) => format.to_mime_type().to_owned(),
+        Err(_) => mime_guess::from_ext(extension)
+            .first_or_octet_stream()
+            .to_string(),
+    }
+}
+
 pub fn op_osstr_to_str(ostr: Option<&OsStr>) -> String {
     ostr.unwrap().to_str().unwrap().to_owned()
 }
@@ -23,6 +38,134 @@ pub fn create_file_write_all(file_path: &Path, content: &[u8]) {
     file.write_all(content).unwrap();
 }
 
-pub fn object_to_byte_vec<T: Serialize>(object: &T) -> Vec<u8> {
+pub fn object_to_byte_vec<T: Serialize + ?Sized>(object: &T) -> Vec<u8> {
     serde_json::to_string(object).unwrap().as_bytes().to_vec()
 }
+
+/// The global project index, `<data_path>/project.json`.
+pub fn global_project_json(data_path: &str) -> PathBuf {
+    Path::new(data_path).join("project.json")
+}
+
+/// The folder holding a single project's files, `<data_path>/<project_id>`.
+pub fn project_dir(data_path: &str, project_id: &Uuid) -> PathBuf {
+    Path::new(data_path).join(project_id.to_string())
+}
+
+pub fn project_info_json(data_path: &str, project_id: &Uuid) -> PathBuf {
+    project_dir(data_path, project_id).join("project.json")
+}
+
+pub fn project_images_json(data_path: &str, project_id: &Uuid) -> PathBuf {
+    project_dir(data_path, project_id).join("project_images.json")
+}
+
+/// All user accounts, `<data_path>/users.json`.
+pub fn users_json(data_path: &str) -> PathBuf {
+    Path::new(data_path).join("users.json")
+}
+
+/// API keys of all projects, `<data_path>/api_keys.json`.
+pub fn api_keys_json(data_path: &str) -> PathBuf {
+    Path::new(data_path).join("api_keys.json")
+}
+
+/// Refresh token sessions, `<data_path>/sessions.json`.
+pub fn sessions_json(data_path: &str) -> PathBuf {
+    Path::new(data_path).join("sessions.json")
+}
+
+/// Access tokens revoked before they expire, `<data_path>/revoked_tokens.json`.
+pub fn revoked_tokens_json(data_path: &str) -> PathBuf {
+    Path::new(data_path).join("revoked_tokens.json")
+}
+
+/// Login lockouts lifted with `sorter-admin unlock` that the server hasn't
+/// applied yet, `<data_path>/login_unlocks.json`.
+pub fn login_unlocks_json(data_path: &str) -> PathBuf {
+    Path::new(data_path).join("login_unlocks.json")
+}
+
+/// Users with access to a project and their roles.
+pub fn project_members_json(data_path: &str, project_id: &Uuid) -> PathBuf {
+    project_dir(data_path, project_id).join("project_members.json")
+}
+
+/// Append-only log of what happened in a project, one JSON object per line.
+pub fn audit_log_jsonl(data_path: &str, project_id: &Uuid) -> PathBuf {
+    project_dir(data_path, project_id).join("audit_log.jsonl")
+}
+
+/// Progress of the project's last encryption key rotation.
+pub fn key_rotation_json(data_path: &str, project_id: &Uuid) -> PathBuf {
+    project_dir(data_path, project_id).join("key_rotation.json")
+}
+
+/// Webhooks registered for a project.
+pub fn webhooks_json(data_path: &str, project_id: &Uuid) -> PathBuf {
+    project_dir(data_path, project_id).join("webhooks.json")
+}
+
+/// Append-only log of every webhook delivery attempt of a project.
+pub fn webhook_deliveries_jsonl(data_path: &str, project_id: &Uuid) -> PathBuf {
+    project_dir(data_path, project_id).join("webhook_deliveries.jsonl")
+}
+
+/// Where fsck moves files it can't account for, `<data_path>/quarantine`.
+pub fn quarantine_dir(data_path: &str) -> PathBuf {
+    Path::new(data_path).join("quarantine")
+}
+
+/// Uploaded project archives and their unpacked contents while an import runs.
+pub fn imports_dir(data_path: &str) -> PathBuf {
+    Path::new(data_path).join("imports")
+}
+
+/// Writes `content` to a sibling temporary file and renames it over
+/// `file_path`, so a crash never leaves a half written file behind. Every
+/// write gets its own temporary file, two writers never share one.
+pub fn replace_file_atomic(file_path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut temp_path = file_path.as_os_str().to_owned();
+    temp_path.push(format!(".{}.tmp", Uuid::new_v4().simple()));
+    let temp_path = PathBuf::from(temp_path);
+
+    let written = fs::File::create(&temp_path)
+        .and_then(|mut file| file.write_all(content).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp_path, file_path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_replacements_dont_share_a_temp_file() {
+        let dir = std::env::temp_dir().join(format!("replace_{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("file.json");
+
+        let writers: Vec<_> = (0..16)
+            .map(|n| {
+                let file_path = file_path.clone();
+                std::thread::spawn(move || {
+                    let content = format!("{}", n).repeat(64 * 1024);
+                    replace_file_atomic(&file_path, content.as_bytes())
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+
+        // whoever renamed last, the file is one writer's content as a whole
+        let content = fs::read_to_string(&file_path).unwrap();
+        assert!((0..16).any(|n| content == format!("{}", n).repeat(64 * 1024)));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::Algorithm;
+use serde_json::{json, Value};
+
+const RSA_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const ED25519_OID: &[u8] = &[0x2b, 0x65, 0x70];
+
+/// A public key read from a PEM file, with what's needed for its JWK.
+pub enum PublicKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ed25519 { x: Vec<u8> },
+}
+
+impl PublicKey {
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            PublicKey::Rsa { .. } => Algorithm::RS256,
+            PublicKey::Ed25519 { .. } => Algorithm::EdDSA,
+        }
+    }
+
+    pub fn to_jwk(&self, kid: &str) -> Value {
+        match self {
+            PublicKey::Rsa { n, e } => json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": kid,
+                "n": URL_SAFE_NO_PAD.encode(n),
+                "e": URL_SAFE_NO_PAD.encode(e),
+            }),
+            PublicKey::Ed25519 { x } => json!({
+                "kty": "OKP",
+                "use": "sig",
+                "alg": "EdDSA",
+                "crv": "Ed25519",
+                "kid": kid,
+                "x": URL_SAFE_NO_PAD.encode(x),
+            }),
+        }
+    }
+}
+
+/// Reads an RSA (`PUBLIC KEY` or `RSA PUBLIC KEY`) or Ed25519 (`PUBLIC KEY`)
+/// public key PEM.
+pub fn parse_public_key_pem(pem_data: &[u8]) -> Result<PublicKey, String> {
+    let pem = pem::parse(pem_data).map_err(|err| err.to_string())?;
+
+    match pem.tag.as_str() {
+        "RSA PUBLIC KEY" => parse_rsa_public_key(&pem.contents),
+        "PUBLIC KEY" => parse_subject_public_key_info(&pem.contents),
+        tag => Err(format!("unsupported PEM block '{}'", tag)),
+    }
+}
+
+/// SubjectPublicKeyInfo ::= SEQUENCE { algorithm SEQUENCE { OID, .. }, BIT STRING }
+fn parse_subject_public_key_info(der: &[u8]) -> Result<PublicKey, String> {
+    let (spki, _) = der_element(der, 0x30)?;
+    let (algorithm, rest) = der_element(spki, 0x30)?;
+    let (oid, _) = der_element(algorithm, 0x06)?;
+    let (bits, _) = der_element(rest, 0x03)?;
+
+    // the first byte of a bit string is the number of unused bits
+    let key = bits.get(1..).ok_or("empty public key")?;
+
+    match oid {
+        RSA_OID => parse_rsa_public_key(key),
+        ED25519_OID if key.len() == 32 => Ok(PublicKey::Ed25519 { x: key.to_vec() }),
+        _ => Err("only RSA and Ed25519 public keys are supported".to_owned()),
+    }
+}
+
+/// RSAPublicKey ::= SEQUENCE { modulus INTEGER, publicExponent INTEGER }
+fn parse_rsa_public_key(der: &[u8]) -> Result<PublicKey, String> {
+    let (key, _) = der_element(der, 0x30)?;
+    let (n, rest) = der_element(key, 0x02)?;
+    let (e, _) = der_element(rest, 0x02)?;
+
+    Ok(PublicKey::Rsa {
+        n: strip_leading_zeros(n),
+        e: strip_leading_zeros(e),
+    })
+}
+
+/// Splits off one DER element with the expected tag, returning its content
+/// and whatever follows it.
+fn der_element(der: &[u8], tag: u8) -> Result<(&[u8], &[u8]), String> {
+    let invalid = || "invalid DER in public key".to_owned();
+
+    if der.first() != Some(&tag) {
+        return Err(invalid());
+    }
+
+    let first_len = *der.get(1).ok_or_else(invalid)?;
+    let (len, header_len) = match first_len {
+        len if len < 0x80 => (len as usize, 2),
+        0x81..=0x84 => {
+            let len_bytes = (first_len - 0x80) as usize;
+            let len = der
+                .get(2..2 + len_bytes)
+                .ok_or_else(invalid)?
+                .iter()
+                .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+            (len, 2 + len_bytes)
+        }
+        _ => return Err(invalid()),
+    };
+
+    let content = der.get(header_len..header_len + len).ok_or_else(invalid)?;
+    Ok((content, &der[header_len + len..]))
+}
+
+fn strip_leading_zeros(int: &[u8]) -> Vec<u8> {
+    let start = int.iter().position(|byte| *byte != 0).unwrap_or(int.len());
+    int[start..].to_vec()
+}
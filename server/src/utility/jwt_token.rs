@@ -1,10 +1,20 @@
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
 use serde::{Deserialize, Serialize};
-use std::env::var;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
 use uuid::Uuid;
 
+use crate::config::JwtConfig;
+use crate::models::api_key::ApiKeyScope;
 use crate::models::project_info::ProjectInfo;
+use crate::models::user_info::Role;
+use crate::utility::jwks::parse_public_key_pem;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
@@ -14,103 +24,229 @@ pub struct Claims {
     pub iss: String,
     pub aud: String,
     pub project_id: Uuid,
+    /// `None` when logged in with the shared project password.
+    pub user_id: Option<Uuid>,
+    pub role: Role,
+    /// Token id, used to revoke a single access token on logout.
+    pub jti: Uuid,
+    /// Set when the request was made with an API key, which is then checked
+    /// against these scopes instead of `role`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<ApiKeyScope>>,
 }
 
+struct VerifyingKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    decoding_key: DecodingKey,
+    jwk: Option<Value>,
+}
+
+/// Keys and settings used to sign and verify tokens, loaded once at startup.
+pub struct JwtKeys {
+    signing_kid: Option<String>,
+    signing_algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    verifying_keys: Vec<VerifyingKey>,
+    issuer: String,
+    audience: String,
+    access_token_lifetime: Duration,
+    refresh_token_lifetime: Duration,
+}
+
+static JWT_KEYS: OnceLock<JwtKeys> = OnceLock::new();
+
 #[derive(Debug)]
 pub enum JwtError {
     InvalidToken,
     ExpiredToken,
 }
 
-pub fn generate_token(project_info: &ProjectInfo) -> String {
-    let jwt_secret =
-        var("JWT_SECRET").expect("Couldn't find JWT SECRET from environment variable.");
-    let jwt_issuer =
-        var("JWT_ISSUER").expect("Couldn't find JWT_ISSUER from environment variable.");
-    let jwt_audience =
-        var("JWT_AUDIENCE").expect("Couldn't find JWT_AUDIENCE from environment variable.");
+pub fn access_token_lifetime() -> Duration {
+    jwt_keys().access_token_lifetime
+}
 
-    let current_time = Utc::now();
+pub fn refresh_token_lifetime() -> Duration {
+    jwt_keys().refresh_token_lifetime
+}
 
-    let claims = Claims {
-        nbf: current_time.timestamp() as u64,
-        iat: current_time.timestamp() as u64,
-        exp: (current_time + Duration::minutes(180)).timestamp() as u64,
-        iss: jwt_issuer,
-        aud: jwt_audience,
-        project_id: project_info.project_id,
+/// Loads the token keys. With `keys_dir` set every `<kid>.pub.pem` in it
+/// (RSA or Ed25519) is accepted when verifying, and tokens are signed with
+/// `<signing_kid>.pem`. Keeping the public key of a retired signing key
+/// around until its tokens expired rotates keys without logging anyone out.
+/// Without `keys_dir` tokens are signed with the HS256 `secret`.
+fn load_jwt_keys(config: &JwtConfig) -> Result<JwtKeys, String> {
+    let issuer = config.issuer.clone();
+    let audience = config.audience.clone();
+    let access_token_lifetime = Duration::minutes(config.access_token_minutes);
+    let refresh_token_lifetime = Duration::days(config.refresh_token_days);
+
+    let keys_dir = match &config.keys_dir {
+        Some(keys_dir) => keys_dir,
+        None => {
+            let jwt_secret = config
+                .secret
+                .as_ref()
+                .ok_or("jwt: set either keys_dir or secret")?;
+
+            return Ok(JwtKeys {
+                signing_kid: None,
+                signing_algorithm: Algorithm::HS256,
+                encoding_key: EncodingKey::from_secret(jwt_secret.as_ref()),
+                verifying_keys: vec![VerifyingKey {
+                    kid: None,
+                    algorithm: Algorithm::HS256,
+                    decoding_key: DecodingKey::from_secret(jwt_secret.as_ref()),
+                    jwk: None,
+                }],
+                issuer,
+                audience,
+                access_token_lifetime,
+                refresh_token_lifetime,
+            });
+        }
     };
 
-    let token_str = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(jwt_secret.as_ref()),
-    );
+    let mut verifying_keys = vec![];
+    let entries = fs::read_dir(keys_dir).map_err(|err| format!("{}: {}", keys_dir, err))?;
+    for entry in entries {
+        let path = entry.map_err(|err| err.to_string())?.path();
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        let kid = match file_name.strip_suffix(".pub.pem") {
+            Some(kid) => kid.to_owned(),
+            None => continue,
+        };
+
+        let pem_data = fs::read(&path).map_err(|err| format!("{:?}: {}", path, err))?;
+        let public_key =
+            parse_public_key_pem(&pem_data).map_err(|err| format!("{:?}: {}", path, err))?;
+        let algorithm = public_key.algorithm();
+        let decoding_key = match algorithm {
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem_data),
+            _ => DecodingKey::from_rsa_pem(&pem_data),
+        }
+        .map_err(|err| format!("{:?}: {}", path, err))?;
+
+        verifying_keys.push(VerifyingKey {
+            jwk: Some(public_key.to_jwk(&kid)),
+            kid: Some(kid),
+            algorithm,
+            decoding_key,
+        });
+    }
 
-    token_str.unwrap()
+    let signing_kid = config
+        .signing_kid
+        .clone()
+        .ok_or("jwt.signing_kid: needed with jwt.keys_dir")?;
+    let signing_algorithm = verifying_keys
+        .iter()
+        .find(|key| key.kid.as_deref() == Some(signing_kid.as_str()))
+        .map(|key| key.algorithm)
+        .ok_or(format!("No {}.pub.pem in {}.", signing_kid, keys_dir))?;
+
+    let private_path = Path::new(keys_dir).join(format!("{}.pem", signing_kid));
+    let private_pem =
+        fs::read(&private_path).map_err(|err| format!("{:?}: {}", private_path, err))?;
+    let encoding_key = match signing_algorithm {
+        Algorithm::EdDSA => EncodingKey::from_ed_pem(&private_pem),
+        _ => EncodingKey::from_rsa_pem(&private_pem),
+    }
+    .map_err(|err| format!("{:?}: {}", private_path, err))?;
+
+    Ok(JwtKeys {
+        signing_kid: Some(signing_kid),
+        signing_algorithm,
+        encoding_key,
+        verifying_keys,
+        issuer,
+        audience,
+        access_token_lifetime,
+        refresh_token_lifetime,
+    })
 }
 
-fn extract_claims_from_token(token: &str) -> Result<Claims, JwtError> {
-    let jwt_secret =
-        var("JWT_SECRET").expect("Couldn't find JWT SECRET from environment variable.");
-
-    let token_msg = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(jwt_secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    );
-
-    match token_msg {
-        Ok(token) => {
-            let claims = token.claims;
-
-            Ok(claims)
-        }
-        Err(error) => {
-            println!(
-                "Error occurred while trying to retrieve claims from the jwt token: {}",
-                error
-            );
+/// Loads the token keys at startup, so a broken key setup stops the server
+/// instead of the first login.
+pub fn init_jwt_keys(config: &JwtConfig) -> Result<(), String> {
+    let jwt_keys = load_jwt_keys(config)?;
+    let _ = JWT_KEYS.set(jwt_keys);
+    Ok(())
+}
 
-            Err(JwtError::InvalidToken)
-        }
-    }
+fn jwt_keys() -> &'static JwtKeys {
+    JWT_KEYS
+        .get()
+        .expect("init_jwt_keys must be called at startup.")
 }
 
-pub fn validate_token(token: &str) -> Result<Claims, JwtError> {
-    println!("jwt token : {token}");
-    let jwt_audience =
-        var("JWT_AUDIENCE").expect("Couldn't find JWT_AUDIENCE from environment variable.");
+/// The public signing keys as a JWK set, for `/.well-known/jwks.json`.
+pub fn get_jwks() -> Value {
+    let keys: Vec<&Value> = jwt_keys()
+        .verifying_keys
+        .iter()
+        .filter_map(|key| key.jwk.as_ref())
+        .collect();
 
-    let claims = extract_claims_from_token(token);
+    json!({ "keys": keys })
+}
 
-    match claims {
-        Ok(claims) => {
-            let current_time = Utc::now().timestamp() as u64;
+pub fn generate_token(project_info: &ProjectInfo, user_id: Option<Uuid>, role: Role) -> String {
+    let jwt_keys = jwt_keys();
+    let current_time = Utc::now();
 
-            println!("claims: {:#?}", claims);
+    let claims = Claims {
+        nbf: current_time.timestamp() as u64,
+        iat: current_time.timestamp() as u64,
+        exp: (current_time + access_token_lifetime()).timestamp() as u64,
+        iss: jwt_keys.issuer.clone(),
+        aud: jwt_keys.audience.clone(),
+        project_id: project_info.project_id,
+        user_id,
+        role,
+        jti: Uuid::new_v4(),
+        scopes: None,
+    };
 
-            if claims.iat > current_time && claims.nbf > current_time {
-                return Err(JwtError::InvalidToken);
-            }
+    let mut header = Header::new(jwt_keys.signing_algorithm);
+    header.kid = jwt_keys.signing_kid.clone();
 
-            if claims.exp <= current_time {
-                return Err(JwtError::ExpiredToken);
-            }
+    let token_str = encode(&header, &claims, &jwt_keys.encoding_key);
 
-            if claims.aud != jwt_audience {
-                return Err(JwtError::InvalidToken);
-            }
+    token_str.unwrap()
+}
 
-            Ok(claims)
-        }
+/// Checks the signature with the key named by the token's `kid` and the
+/// `exp`, `nbf`, `iss` and `aud` claims.
+pub fn validate_token(token: &str) -> Result<Claims, JwtError> {
+    let jwt_keys = jwt_keys();
+
+    let header = decode_header(token).map_err(|_| JwtError::InvalidToken)?;
+    let key = jwt_keys
+        .verifying_keys
+        .iter()
+        .find(|key| key.kid == header.kid && key.algorithm == header.alg)
+        .ok_or(JwtError::InvalidToken)?;
+
+    let mut validation = Validation::new(key.algorithm);
+    validation.validate_nbf = true;
+    validation.set_issuer(&[&jwt_keys.issuer]);
+    validation.set_audience(&[&jwt_keys.audience]);
+
+    match decode::<Claims>(token, &key.decoding_key, &validation) {
+        Ok(token) => Ok(token.claims),
         Err(error) => {
-            println!("JwtError: {:?}", error);
-            Err(error)
+            log::debug!(
+                "Error occurred while trying to retrieve claims from the jwt token: {}",
+                error
+            );
+            match error.kind() {
+                ErrorKind::ExpiredSignature => Err(JwtError::ExpiredToken),
+                _ => Err(JwtError::InvalidToken),
+            }
         }
     }
 }
-
-pub fn _regenerate_token(_token: String) -> String {
-    "".to_owned()
-}